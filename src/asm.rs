@@ -1,5 +1,11 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::io::{self, Write};
+
+use crate::ast::ESCAPES;
+use crate::disasm::{self, DecodedInstruction, Operand};
+use crate::vm::{Opcode, OperandKind};
 
 #[derive(Debug)]
 pub enum AssemblerError {
@@ -7,144 +13,396 @@ pub enum AssemblerError {
     UnknownInstruction(String),
     UnknownRegister(String),
     LabelNotFound(String),
+    /// A label defined by more than one of the objects passed to `link`.
+    DuplicateSymbol(String),
+}
+
+/// A module assembled by `Assembler::compile_object`, for `link`. Every
+/// label operand gets a relocation, since the module may not end up at
+/// address 0. In `code` a label this module defines holds its offset here,
+/// and any other label holds zero.
+#[derive(Debug, PartialEq)]
+pub struct ObjectFile {
+    pub code: Vec<u8>,
+    /// Labels defined in this module, by offset into `code`.
+    pub symbols: HashMap<String, usize>,
+    pub relocations: Vec<Relocation>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Relocation {
+    /// Offset into `code` of the 16-bit immediate to patch.
+    pub offset: usize,
+    pub label: String,
+}
+
+/// Which source line each instruction of a program came from. An entry
+/// covers the instructions from its offset up to the next entry's, so
+/// lookups work for any offset inside an instruction. The assembler's maps
+/// name lines of assembly; `codegen::codegen_bytes_with_map`'s name lines
+/// of the source program.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SourceMap {
+    // (offset, 1-based line), by increasing offset.
+    entries: Vec<(usize, usize)>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that the code from `offset` on came from `line`. Offsets must
+    /// not decrease; a second entry at the same offset replaces the first.
+    pub fn insert(&mut self, offset: usize, line: usize) {
+        match self.entries.last_mut() {
+            Some(last) if last.0 == offset => last.1 = line,
+            Some(last) if last.1 == line => {}
+            _ => self.entries.push((offset, line)),
+        }
+    }
+
+    /// The line of the code at `offset`, if any entry covers it.
+    pub fn line_at(&self, offset: usize) -> Option<usize> {
+        let index = self.entries.partition_point(|&(start, _)| start <= offset);
+        index.checked_sub(1).map(|index| self.entries[index].1)
+    }
+
+    /// The same offsets with each line passed through `f`, which may drop
+    /// it. Translates an assembler map into one over whatever the assembly
+    /// was generated from.
+    pub fn map_lines(&self, f: impl Fn(usize) -> Option<usize>) -> SourceMap {
+        let mut mapped = SourceMap::new();
+        for &(offset, line) in &self.entries {
+            if let Some(line) = f(line) {
+                mapped.insert(offset, line);
+            }
+        }
+        mapped
+    }
 }
 
 pub struct Assembler {
     symbols: HashMap<String, usize>,
+    // `.equ` constants of the program being compiled.
+    constants: HashMap<String, u16>,
+    entry_point: Option<usize>,
+    source_map: SourceMap,
+    nop_padding: bool,
+    verbose: bool,
+    log: Box<dyn Write>,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Assembler {
     pub fn new() -> Self {
         Self {
             symbols: HashMap::new(),
+            constants: HashMap::new(),
+            entry_point: None,
+            source_map: SourceMap::new(),
+            nop_padding: false,
+            verbose: false,
+            log: Box::new(io::stderr()),
         }
     }
 
+    /// Logs every instruction of the second pass with its resolved operands
+    /// and encoded bytes. Output goes to stderr unless redirected with
+    /// `set_log_writer`.
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    pub fn set_log_writer(&mut self, log: Box<dyn Write>) {
+        self.log = log;
+    }
+
+    /// Pads short programs with `NOP` instead of zero bytes, which decode as
+    /// `HLT`, so running into the padding is harmless.
+    pub fn set_nop_padding(&mut self, nop_padding: bool) {
+        self.nop_padding = nop_padding;
+    }
+
+    /// Address named by the last compiled program's `.entry label`
+    /// directive, for `VM::set_entry_point`.
+    pub fn entry_point(&self) -> Option<usize> {
+        self.entry_point
+    }
+
+    /// The source line of each instruction of the last compiled program.
+    pub fn source_map(&self) -> &SourceMap {
+        &self.source_map
+    }
+
     pub fn compile(&mut self, source: &str) -> Result<Vec<u8>, AssemblerError> {
+        let mut bytecode = Vec::new();
+        self.compile_into(source, &mut bytecode)?;
+        Ok(bytecode)
+    }
+
+    /// Like `compile`, but reuses `bytecode`'s allocation: the buffer is
+    /// cleared and then filled. On error it holds whatever was assembled
+    /// before the failure.
+    pub fn compile_into(&mut self, source: &str, bytecode: &mut Vec<u8>) -> Result<(), AssemblerError> {
+        self.assemble(source, bytecode, None)?;
+
+        let padding = if self.nop_padding { u8::from(Opcode::NOP) } else { 0 };
+        while bytecode.len() < 32 {
+            bytecode.push(padding);
+        }
+
+        Ok(())
+    }
+
+    /// Assembles `source` as one module of a larger program. Unlike
+    /// `compile`, a label defined in no line of `source` isn't an error, and
+    /// the code isn't padded, so modules can be laid out back to back.
+    /// Labels from earlier compiles don't resolve here.
+    pub fn compile_object(&mut self, source: &str) -> Result<ObjectFile, AssemblerError> {
+        self.symbols.clear();
+        let mut code = Vec::new();
+        let mut relocations = Vec::new();
+        self.assemble(source, &mut code, Some(&mut relocations))?;
+
+        Ok(ObjectFile {
+            code,
+            symbols: self.symbols.clone(),
+            relocations,
+        })
+    }
+
+    // Both passes. With `relocations`, label operands are recorded there and
+    // unknown labels aren't errors.
+    fn assemble(
+        &mut self,
+        source: &str,
+        bytecode: &mut Vec<u8>,
+        mut relocations: Option<&mut Vec<Relocation>>,
+    ) -> Result<(), AssemblerError> {
+        bytecode.clear();
+        self.constants.clear();
+        self.source_map = SourceMap::new();
         let mut first_pass_lines = Vec::new();
         let mut current_address = 0;
+        let mut entry_label = None;
+        // Everything after a `.data` line, up to any `.text`, lands after the
+        // code. Its labels are offsets into `data` until the code size is
+        // known.
+        let mut in_data = false;
+        let mut data = Vec::new();
+        let mut data_labels = Vec::new();
 
-        for line in source.lines() {
-            let line = line.split(';').next().unwrap_or("").trim();
+        for (index, line) in source.lines().enumerate() {
+            let line = strip_comment(line).trim();
 
             if line.is_empty() {
                 continue;
             }
 
-            if line.ends_with(':') {
-                let label = line[..line.len() - 1].trim();
-                self.symbols.insert(label.to_string(), current_address);
+            if let Some(label) = line.strip_suffix(':') {
+                let label = label.trim();
+                if in_data {
+                    data_labels.push((label, data.len()));
+                } else {
+                    self.symbols.insert(label.to_string(), current_address);
+                }
+                continue;
+            }
+
+            if line == ".data" || line == ".text" {
+                in_data = line == ".data";
+                continue;
+            }
+
+            if let Some(label) = line.strip_prefix(".entry") {
+                entry_label = Some(label.trim());
                 continue;
             }
 
-            first_pass_lines.push(line);
-            current_address += self.estimate_instruction_size(line)?;
+            if let Some(definition) = line.strip_prefix(".equ") {
+                self.define_constant(definition)?;
+                continue;
+            }
+
+            if in_data {
+                assemble_data(line, &mut data)?;
+                continue;
+            }
+
+            let lines = match expand_load_sugar(line) {
+                Some([load, loadm]) => vec![Cow::Owned(load), Cow::Owned(loadm)],
+                None => vec![Cow::Borrowed(line)],
+            };
+            for line in lines {
+                current_address += self.estimate_instruction_size(&line)?;
+                first_pass_lines.push((line, index + 1));
+            }
+        }
+        for (label, offset) in data_labels {
+            self.symbols.insert(label.to_string(), current_address + offset);
         }
 
+        // The entry label may be defined after the directive.
+        self.entry_point = match entry_label {
+            Some(label) => Some(
+                *self
+                    .symbols
+                    .get(label)
+                    .ok_or_else(|| AssemblerError::LabelNotFound(label.to_string()))?,
+            ),
+            None => None,
+        };
+
         // compile
-        let mut bytecode = Vec::new();
+        for (line, source_line) in first_pass_lines {
+            self.source_map.insert(bytecode.len(), source_line);
+            let instruction = self.assemble_instruction(&line, bytecode.len(), relocations.as_deref_mut())?;
+            instruction.encode(bytecode);
 
-        for line in first_pass_lines {
-            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if self.verbose {
+                let bytes: Vec<String> = bytecode[instruction.offset..]
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+                // The log is diagnostic only; a failing writer must not fail assembly.
+                let _ = writeln!(self.log, "{:04}: {} => [{}]", instruction.offset, instruction, bytes.join(" "));
+            }
+        }
+        bytecode.extend_from_slice(&data);
 
-            match tokens[0].to_uppercase().as_str() {
-                "LOAD" => {
-                    if tokens.len() < 3 {
-                        return Err(AssemblerError::SyntaxError(format!(
-                            "Invalid LOAD instruction: {}",
-                            line
-                        )));
-                    }
-                    let register = self.parse_register(&tokens[1])?;
-                    let value = self.parse_value(&tokens[2])?;
+        Ok(())
+    }
 
-                    bytecode.push(1); // LOAD opcode
-                    bytecode.push(register);
-                    bytecode.extend_from_slice(&value.to_be_bytes());
-                }
-                "ADD" | "SUB" | "MUL" | "DIV" => {
-                    if tokens.len() < 4 {
-                        return Err(AssemblerError::SyntaxError(format!(
-                            "Invalid arithmetic instruction: {}",
-                            line
-                        )));
-                    }
-                    let opcode = match tokens[0].to_uppercase().as_str() {
-                        "ADD" => 2,
-                        "SUB" => 3,
-                        "MUL" => 4,
-                        "DIV" => 5,
-                        _ => unreachable!(),
-                    };
-
-                    let reg1 = self.parse_register(&tokens[1])?;
-                    let reg2 = self.parse_register(&tokens[2])?;
-                    let reg3 = self.parse_register(&tokens[3])?;
-
-                    bytecode.push(opcode);
-                    bytecode.push(reg1);
-                    bytecode.push(reg2);
-                    bytecode.push(reg3);
-                }
-                "JMP" => {
-                    if tokens.len() < 2 {
-                        return Err(AssemblerError::SyntaxError(format!(
-                            "Invalid JMP instruction: {}",
-                            line
-                        )));
-                    }
-                    let target = self.parse_value(&tokens[1])?;
+    /// Line numbers (1-based) of instructions that can never execute: those
+    /// following an instruction that never falls through (`HLT`, `IGL`,
+    /// `JMP`, `JMPF` or `RET`) with no label in between. Jump targets live
+    /// in registers, so any label counts as reachable. Lines that don't
+    /// start with a known mnemonic are left to `compile` to report.
+    pub fn unreachable_instructions(source: &str) -> Vec<usize> {
+        let mut unreachable = Vec::new();
+        let mut reachable = true;
 
-                    bytecode.push(6); // JMP opcode
-                    bytecode.extend_from_slice(&target.to_be_bytes());
-                }
-                "JMPF" => {
-                    if tokens.len() < 2 {
-                        return Err(AssemblerError::SyntaxError(format!(
-                            "Invalid JMPF instruction: {}",
-                            line
-                        )));
-                    }
-                    let value = self.parse_value(&tokens[1])?;
+        for (index, line) in source.lines().enumerate() {
+            let line = strip_comment(line).trim();
 
-                    bytecode.push(7); // JMPF opcode
-                    bytecode.extend_from_slice(&value.to_be_bytes());
-                }
-                "PRINT" => {
-                    if tokens.len() < 2 {
-                        return Err(AssemblerError::SyntaxError(format!(
-                            "Invalid PRINT instruction: {}",
-                            line
-                        )));
-                    }
-                    let register = self.parse_register(&tokens[1])?;
+            if line.is_empty() || line.starts_with(".entry") {
+                continue;
+            }
 
-                    bytecode.push(8); // PRINT opcode
-                    bytecode.push(register);
-                }
-                "HLT" => {
-                    bytecode.push(0); // HLT opcode
-                }
-                _ => {
-                    return Err(AssemblerError::UnknownInstruction(tokens[0].to_string()));
-                }
+            if line.ends_with(':') {
+                reachable = true;
+                continue;
+            }
+
+            let mnemonic = line.split_whitespace().next().unwrap_or("");
+            let Some(opcode) = Opcode::from_mnemonic(mnemonic) else {
+                continue;
+            };
+
+            if !reachable {
+                unreachable.push(index + 1);
+            }
+            if matches!(
+                opcode,
+                Opcode::HLT | Opcode::IGL | Opcode::JMP | Opcode::JMPF | Opcode::RET
+            ) {
+                reachable = false;
             }
         }
 
+        unreachable
+    }
 
-        while bytecode.len() < 32 {
-            bytecode.push(0);
+    // `.equ NAME value` names a 16-bit value, usable wherever an immediate
+    // is. Constants are defined in the first pass, so instructions may use
+    // one before its line, but a `.equ` value may only name earlier ones.
+    // Each may be defined once.
+    fn define_constant(&mut self, definition: &str) -> Result<(), AssemblerError> {
+        let invalid = || AssemblerError::SyntaxError(format!("Invalid .equ directive: .equ{}", definition));
+        let (name, value) = match definition.split_whitespace().collect::<Vec<_>>()[..] {
+            [name, value] if name.starts_with(|c: char| c.is_alphabetic() || c == '_') => (name, value),
+            _ => return Err(invalid()),
+        };
+        if self.constants.contains_key(name) {
+            return Err(AssemblerError::SyntaxError(format!("Constant {} is already defined", name)));
         }
+        let value = self.parse_value(value)?;
+        self.constants.insert(name.to_string(), value);
+        Ok(())
+    }
 
-        Ok(bytecode)
+    fn assemble_instruction(
+        &self,
+        line: &str,
+        offset: usize,
+        mut relocations: Option<&mut Vec<Relocation>>,
+    ) -> Result<DecodedInstruction, AssemblerError> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let opcode = Opcode::from_mnemonic(tokens[0])
+            .ok_or_else(|| AssemblerError::UnknownInstruction(tokens[0].to_string()))?;
+        let kinds = opcode.operands();
+
+        if tokens.len() < kinds.len() + 1 {
+            return Err(AssemblerError::SyntaxError(format!(
+                "Invalid {:?} instruction: {}",
+                opcode, line
+            )));
+        }
+
+        let mut operands = Vec::new();
+        // Just past the opcode byte.
+        let mut operand_offset = offset + 1;
+        for (kind, token) in kinds.iter().zip(&tokens[1..]) {
+            let operand_start = operand_offset;
+            operand_offset += kind.size();
+            operands.push(match kind {
+                OperandKind::Register => Operand::Register(self.parse_register(token, 'r')?),
+                OperandKind::FloatRegister => {
+                    Operand::FloatRegister(self.parse_register(token, 'f')?)
+                }
+                OperandKind::RegisterPair => Operand::RegisterPair(self.parse_register_pair(token)?),
+                OperandKind::Immediate => match relocations.as_deref_mut() {
+                    Some(relocations) if self.is_label(token) => {
+                        relocations.push(Relocation {
+                            offset: operand_start,
+                            label: token.to_string(),
+                        });
+                        Operand::Immediate(self.symbols.get(*token).map_or(0, |&address| address as u16))
+                    }
+                    _ => Operand::Immediate(self.parse_value(token)?),
+                },
+                OperandKind::SignedImmediate => Operand::SignedImmediate(token.parse().map_err(|_| {
+                    AssemblerError::SyntaxError(format!("Invalid signed value: {}", token))
+                })?),
+                OperandKind::Float => Operand::Float(token.parse().map_err(|_| {
+                    AssemblerError::SyntaxError(format!("Invalid float: {}", token))
+                })?),
+            });
+        }
+
+        Ok(DecodedInstruction::new(offset, opcode, operands))
     }
 
-    fn parse_register(&self, token: &str) -> Result<u8, AssemblerError> {
-        if !token.starts_with('r') {
+    // Integer registers are written `rN`, float registers `fN`.
+    fn parse_register(&self, token: &str, prefix: char) -> Result<u8, AssemblerError> {
+        if !token.starts_with(prefix) {
             return Err(AssemblerError::UnknownRegister(token.to_string()));
         }
 
-        let register_num: usize = token[1..]
+        let index = &token[1..];
+        if index.starts_with('-') {
+            return Err(AssemblerError::SyntaxError(
+                "register index cannot be negative".to_string(),
+            ));
+        }
+
+        let register_num: usize = index
             .parse()
             .map_err(|_| AssemblerError::UnknownRegister(token.to_string()))?;
 
@@ -155,29 +413,319 @@ impl Assembler {
         Ok(register_num as u8)
     }
 
+    // A pair is written `rN:rN+1`, high word first.
+    fn parse_register_pair(&self, token: &str) -> Result<u8, AssemblerError> {
+        let invalid = || AssemblerError::SyntaxError(format!("Invalid register pair: {}", token));
+        let (high, low) = token.split_once(':').ok_or_else(invalid)?;
+        let high = self.parse_register(high, 'r')?;
+        if self.parse_register(low, 'r')? != high + 1 {
+            return Err(invalid());
+        }
+        Ok(high)
+    }
+
+    // An immediate is a number, a `.equ` constant or a label. A label
+    // resolves to its address, so `LOAD r0 label` followed by `JMP r0` is
+    // how computed jumps are written. The operand is 16 bits either way,
+    // which is what lets the first pass size instructions before labels are
+    // known.
     fn parse_value(&self, token: &str) -> Result<u16, AssemblerError> {
+        if let Some(value) = self.constants.get(token) {
+            return Ok(*value);
+        }
+
         // Check if the token is a label
         if let Some(label_value) = self.symbols.get(token) {
             return Ok(*label_value as u16);
         }
 
+        if self.is_label(token) {
+            return Err(AssemblerError::LabelNotFound(token.to_string()));
+        }
+
         token
             .parse()
             .map_err(|_| AssemblerError::SyntaxError(format!("Invalid value: {}", token)))
     }
 
+    // Whether an immediate operand names a label, defined yet or not.
+    fn is_label(&self, token: &str) -> bool {
+        !self.constants.contains_key(token) && token.starts_with(|c: char| c.is_alphabetic() || c == '.' || c == '_')
+    }
+
     fn estimate_instruction_size(&self, line: &str) -> Result<usize, AssemblerError> {
-        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let mnemonic = line.split_whitespace().next().unwrap_or("");
+
+        Opcode::from_mnemonic(mnemonic)
+            .map(Opcode::instruction_size)
+            .ok_or_else(|| AssemblerError::UnknownInstruction(mnemonic.to_string()))
+    }
+}
+
+// A line with its `;` comment removed. A `;` inside a string isn't one.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+
+// `LOAD rN @value` loads the word stored at address `value`, usually a
+// `.data` label, as `LOAD rN value` and then `LOADM rN rN`.
+fn expand_load_sugar(line: &str) -> Option<[String; 2]> {
+    match line.split_whitespace().collect::<Vec<_>>()[..] {
+        [mnemonic, register, value] if mnemonic.eq_ignore_ascii_case("LOAD") => {
+            let value = value.strip_prefix('@')?;
+            Some([format!("LOAD {} {}", register, value), format!("LOADM {} {}", register, register)])
+        }
+        _ => None,
+    }
+}
+
+// A line of the data section: `.asciiz "text"`, the text's bytes and a NUL,
+// with the escapes string literals have; or `.word n`, a big-endian 32-bit
+// integer as LOADM reads it.
+fn assemble_data(line: &str, data: &mut Vec<u8>) -> Result<(), AssemblerError> {
+    let invalid = || AssemblerError::SyntaxError(format!("Invalid data directive: {}", line));
+
+    if let Some(value) = line.strip_prefix(".word") {
+        let value: i32 = value.trim().parse().map_err(|_| invalid())?;
+        data.extend_from_slice(&value.to_be_bytes());
+        return Ok(());
+    }
+
+    let text = line
+        .strip_prefix(".asciiz")
+        .and_then(|text| text.trim().strip_prefix('"'))
+        .and_then(|text| text.strip_suffix('"'))
+        .ok_or_else(invalid)?;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => {
+                let escape = chars.next();
+                ESCAPES
+                    .iter()
+                    .find(|&&(name, _)| Some(name) == escape)
+                    .map(|&(_, value)| value)
+                    .ok_or_else(invalid)?
+            }
+            '"' | '\0' => return Err(invalid()),
+            c => c,
+        };
+        data.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+    }
+    data.push(0);
+    Ok(())
+}
+
+/// Emits bytecode from Rust, without going through assembly text:
+/// `ProgramBuilder::new().load(0, 10).add(0, 1, 2).hlt().build()`. Methods
+/// take register indices and immediates; `instruction` covers opcodes with
+/// no method of their own.
+#[derive(Debug, Default)]
+pub struct ProgramBuilder {
+    bytecode: Vec<u8>,
+}
+
+impl ProgramBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `opcode` with `operands`, which should be the kinds
+    /// `opcode.operands()` lists.
+    pub fn instruction(mut self, opcode: Opcode, operands: Vec<Operand>) -> Self {
+        DecodedInstruction::new(self.bytecode.len(), opcode, operands).encode(&mut self.bytecode);
+        self
+    }
+
+    fn registers(self, opcode: Opcode, registers: &[u8]) -> Self {
+        self.instruction(opcode, registers.iter().map(|&reg| Operand::Register(reg)).collect())
+    }
+
+    pub fn hlt(self) -> Self {
+        self.instruction(Opcode::HLT, Vec::new())
+    }
+
+    pub fn nop(self) -> Self {
+        self.instruction(Opcode::NOP, Vec::new())
+    }
+
+    pub fn load(self, reg: u8, value: u16) -> Self {
+        self.instruction(Opcode::LOAD, vec![Operand::Register(reg), Operand::Immediate(value)])
+    }
+
+    pub fn add(self, a: u8, b: u8, dest: u8) -> Self {
+        self.registers(Opcode::ADD, &[a, b, dest])
+    }
+
+    pub fn sub(self, a: u8, b: u8, dest: u8) -> Self {
+        self.registers(Opcode::SUB, &[a, b, dest])
+    }
+
+    pub fn mul(self, a: u8, b: u8, dest: u8) -> Self {
+        self.registers(Opcode::MUL, &[a, b, dest])
+    }
+
+    pub fn div(self, a: u8, b: u8, dest: u8) -> Self {
+        self.registers(Opcode::DIV, &[a, b, dest])
+    }
+
+    pub fn mov(self, dest: u8, src: u8) -> Self {
+        self.registers(Opcode::MOV, &[dest, src])
+    }
+
+    pub fn eq(self, a: u8, b: u8) -> Self {
+        self.registers(Opcode::EQ, &[a, b])
+    }
+
+    pub fn jmp(self, target: u8) -> Self {
+        self.registers(Opcode::JMP, &[target])
+    }
+
+    pub fn jeq(self, target: u8) -> Self {
+        self.registers(Opcode::JEQ, &[target])
+    }
+
+    pub fn jneq(self, target: u8) -> Self {
+        self.registers(Opcode::JNEQ, &[target])
+    }
+
+    pub fn print(self, reg: u8) -> Self {
+        self.registers(Opcode::PRINT, &[reg])
+    }
+
+    /// The bytecode, padded to the same 32 byte minimum as `compile`.
+    pub fn build(mut self) -> Vec<u8> {
+        self.bytecode.resize(self.bytecode.len().max(32), 0);
+        self.bytecode
+    }
+}
+
+/// Assembles `source` and renders a listing: each source line next to the
+/// address (hex) and encoded bytes of the instruction it produced. Labels
+/// show the address they name; blank lines, comments and directives get
+/// neither.
+pub fn listing(source: &str) -> Result<String, AssemblerError> {
+    let mut assembler = Assembler::new();
+    let bytecode = assembler.compile(source)?;
+    let mut instructions = disasm::instructions(&bytecode).map_while(Result::ok);
+    // Wide enough for the longest encoding (FLOAD), three characters a byte.
+    let width = 3 * Opcode::FLOAD.instruction_size();
+    let mut in_data = false;
+    let mut lines = Vec::new();
 
-        match tokens[0].to_uppercase().as_str() {
-            "LOAD" => Ok(4), // opcode (1) + register (1) + 16-bit value (2)
-            "ADD" | "SUB" | "MUL" | "DIV" => Ok(4), // opcode (1) + 3 registers (3)
-            "JMP" | "JMPF" => Ok(3), // opcode (1) + 16-bit value (2)
-            "PRINT" => Ok(2), // opcode (1) + register (1)
-            "HLT" => Ok(1),  // single byte opcode
-            _ => Err(AssemblerError::UnknownInstruction(tokens[0].to_string())),
+    for line in source.lines() {
+        let code = strip_comment(line).trim();
+        if code == ".data" || code == ".text" {
+            in_data = code == ".data";
         }
+        let (prefix, bytes) = if let Some(label) = code.strip_suffix(':') {
+            (format!("{:04x}", assembler.symbols[label.trim()]), String::new())
+        } else if code.is_empty() || code.starts_with('.') || in_data {
+            (String::new(), String::new())
+        } else {
+            // Sugar stands for two instructions.
+            let count = if expand_load_sugar(code).is_some() { 2 } else { 1 };
+            let line_instructions: Vec<_> = instructions.by_ref().take(count).collect();
+            let (Some(first), Some(last)) = (line_instructions.first(), line_instructions.last()) else {
+                break;
+            };
+            let end = last.offset + last.size();
+            let bytes: Vec<String> = bytecode[first.offset..end]
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            (format!("{:04x}", first.offset), bytes.join(" "))
+        };
+        let row = format!("{:<4}  {:<width$}  {}", prefix, bytes, line.trim_end());
+        lines.push(row.trim_end().to_string());
     }
+
+    Ok(lines.join("\n"))
+}
+
+/// Lays `objects` out back to back, in order, and patches each relocation
+/// with the final address of its label. Labels are global: every one must
+/// be defined exactly once across all the objects. The result is padded
+/// like `Assembler::compile`'s.
+pub fn link(objects: &[ObjectFile]) -> Result<Vec<u8>, AssemblerError> {
+    let mut code = Vec::new();
+    let mut symbols = HashMap::new();
+    let mut relocations = Vec::new();
+
+    for object in objects {
+        let base = code.len();
+        for (label, offset) in &object.symbols {
+            if symbols.insert(label.as_str(), base + offset).is_some() {
+                return Err(AssemblerError::DuplicateSymbol(label.clone()));
+            }
+        }
+        for relocation in &object.relocations {
+            relocations.push((base + relocation.offset, relocation.label.as_str()));
+        }
+        code.extend_from_slice(&object.code);
+    }
+
+    for (offset, label) in relocations {
+        let address = *symbols
+            .get(label)
+            .ok_or_else(|| AssemblerError::LabelNotFound(label.to_string()))?;
+        code[offset..offset + 2].copy_from_slice(&(address as u16).to_be_bytes());
+    }
+
+    code.resize(code.len().max(32), 0);
+    Ok(code)
+}
+
+/// 64-bit FNV-1a hash of a compiled program. The algorithm is fixed, so the
+/// result is stable across runs, platforms and compiler versions and can be
+/// used as a cache key.
+pub fn program_hash(bytecode: &[u8]) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    bytecode.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Every integer register operand in `program`, found by decoding it without running
+/// it. Decoding stops at a truncated trailing instruction.
+pub fn registers_referenced(program: &[u8]) -> HashSet<u8> {
+    disasm::instructions(program)
+        .map_while(Result::ok)
+        .flat_map(|instruction| instruction.operands)
+        .flat_map(|operand| match operand {
+            Operand::Register(reg) => vec![reg],
+            Operand::RegisterPair(reg) => vec![reg, reg.saturating_add(1)],
+            Operand::FloatRegister(_)
+            | Operand::Immediate(_)
+            | Operand::SignedImmediate(_)
+            | Operand::Float(_) => Vec::new(),
+        })
+        .collect()
+}
+
+/// The summed cost of every instruction in `program`, each counted once in
+/// the order it's laid out. Jumps aren't followed, so a loop counts as one
+/// pass: a lower bound on what running the program costs. Opcodes missing
+/// from `opcode_costs` cost nothing, and decoding stops at a truncated
+/// trailing instruction.
+pub fn estimate_cost(program: &[u8], opcode_costs: &HashMap<Opcode, u64>) -> u64 {
+    disasm::instructions(program)
+        .map_while(Result::ok)
+        .map(|instruction| opcode_costs.get(&instruction.opcode).copied().unwrap_or(0))
+        .sum()
 }
 
 impl fmt::Display for AssemblerError {
@@ -187,8 +735,601 @@ impl fmt::Display for AssemblerError {
             AssemblerError::UnknownInstruction(inst) => write!(f, "Unknown Instruction: {}", inst),
             AssemblerError::UnknownRegister(reg) => write!(f, "Unknown Register: {}", reg),
             AssemblerError::LabelNotFound(label) => write!(f, "Label Not Found: {}", label),
+            AssemblerError::DuplicateSymbol(label) => write!(f, "Duplicate Symbol: {}", label),
         }
     }
 }
 
-impl std::error::Error for AssemblerError {}
\ No newline at end of file
+impl std::error::Error for AssemblerError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::VM;
+    use crate::vm::{MEMORY_SIZE, VMError};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_verbose_log() {
+        let source = "start:\nLOAD r1 start\nADD r1 r1 r2";
+        let quiet = Assembler::new().compile(source).unwrap();
+
+        let buffer = SharedBuffer::default();
+        let mut assembler = Assembler::new();
+        assembler.set_verbose(true);
+        assembler.set_log_writer(Box::new(buffer.clone()));
+        let bytecode = assembler.compile(source).unwrap();
+
+        assert_eq!(bytecode, quiet);
+        assert_eq!(
+            String::from_utf8(buffer.0.borrow().clone()).unwrap(),
+            "0000: LOAD r1 0 => [01 01 00 00]\n0004: ADD r1 r1 r2 => [02 01 01 02]\n"
+        );
+    }
+
+    #[test]
+    fn test_compile_into_reuses_buffer() {
+        let mut assembler = Assembler::new();
+        let mut buffer = vec![0xAA; 64];
+        for source in ["LOAD r0 1\nHLT", "loop:\nLOAD r1 loop\nJMP r1"] {
+            assembler.compile_into(source, &mut buffer).unwrap();
+            assert_eq!(buffer, Assembler::new().compile(source).unwrap());
+        }
+    }
+
+    fn run_memory_program(source: &str) -> Result<VM, VMError> {
+        let mut vm = VM::new();
+        vm.add_program(Assembler::new().compile(source).unwrap());
+        vm.run().map(|_| vm)
+    }
+
+    #[test]
+    fn test_memory_round_trips_each_width() {
+        let vm = run_memory_program(
+            "
+            ; r1 = 0x12345678
+            LOAD r1 4660
+            LOAD r2 256
+            MUL r1 r2 r1
+            MUL r1 r2 r1
+            LOAD r3 22136
+            ADD r1 r3 r1
+
+            LOAD r0 100
+            STOREM r0 r1
+            LOADM r10 r0
+            LOADH r11 r0
+            LOADB r12 r0
+
+            LOAD r0 200
+            STOREH r0 r1
+            LOADH r13 r0
+            LOADM r14 r0
+
+            LOAD r0 300
+            STOREB r0 r1
+            LOADB r15 r0
+            LOAD r4 1
+            SUB r4 r2 r4
+            STOREB r0 r4
+            LOADB r16 r0
+            HLT
+            ",
+        )
+        .unwrap();
+        let registers = vm.get_registers();
+        assert_eq!(registers[10], 0x1234_5678);
+        assert_eq!(registers[11], 0x1234);
+        assert_eq!(registers[12], 0x12);
+        assert_eq!(registers[13], 0x5678);
+        assert_eq!(registers[14], 0x5678_0000);
+        assert_eq!(registers[15], 0x78);
+        // -255 stores its low byte, which loads back zero-extended.
+        assert_eq!(registers[16], 0x01);
+    }
+
+    #[test]
+    fn test_memory_accesses_are_bounds_checked() {
+        let last = "LOAD r0 65535\nLOAD r1 7\nSTOREB r0 r1\nLOADB r2 r0\nHLT";
+        assert_eq!(run_memory_program(last).unwrap().get_register(2), Ok(7));
+
+        for source in [
+            "LOAD r0 65535\nLOADH r1 r0",
+            "LOAD r0 65533\nSTOREM r0 r1",
+            "LOAD r0 1\nLOAD r1 2\nSUB r0 r1 r0\nLOADB r2 r0",
+        ] {
+            assert_eq!(run_memory_program(source).err(), Some(VMError::MemoryOutOfBounds), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_host_data_is_readable_with_loadm() {
+        let mut vm = VM::new();
+        vm.add_program(Assembler::new().compile("LOAD r0 1000\nLOADM r1 r0\nLOAD r0 1004\nLOADB r2 r0\nHLT").unwrap());
+        vm.load_memory(1000, &[0xDE, 0xAD, 0xBE, 0xEF, 0x2A]).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.get_register(1), Ok(0xDEAD_BEEF_u32 as i32));
+        assert_eq!(vm.get_register(2), Ok(0x2A));
+
+        assert_eq!(vm.load_memory(MEMORY_SIZE - 1, &[1, 2]), Err(VMError::MemoryOutOfBounds));
+        assert_eq!(vm.load_memory(usize::MAX, &[1]), Err(VMError::MemoryOutOfBounds));
+        assert_eq!(vm.load_memory(MEMORY_SIZE, &[]), Ok(()));
+    }
+
+    #[test]
+    fn test_stored_data_is_readable_by_the_host() {
+        let vm = run_memory_program("LOAD r0 2000\nLOAD r1 4660\nSTOREM r0 r1\nLOAD r0 2005\nSTOREB r0 r1\nHLT").unwrap();
+        assert_eq!(vm.read_memory(2000, 6), Ok(&[0x00, 0x00, 0x12, 0x34, 0x00, 0x34][..]));
+        assert_eq!(vm.read_memory(MEMORY_SIZE - 2, 2), Ok(&[0, 0][..]));
+        assert_eq!(vm.read_memory(MEMORY_SIZE - 2, 3), Err(VMError::MemoryOutOfBounds));
+        assert_eq!(vm.read_memory(1, usize::MAX), Err(VMError::MemoryOutOfBounds));
+    }
+
+    #[test]
+    fn test_data_section_follows_the_code() {
+        let source = "
+            LOAD r0 message
+            PRTS r0
+            LOAD r1 count ; a word
+            LOADM r2 r1
+            HLT
+            .data
+            message:
+            .asciiz \"a;b\\t\\\"c\\\"\" ; not part of the string
+            count:
+            .word -2
+        ";
+        let mut assembler = Assembler::new();
+        let bytecode = assembler.compile(source).unwrap();
+        // 14 bytes of code, then the data.
+        assert_eq!(assembler.symbols["message"], 14);
+        assert_eq!(assembler.symbols["count"], 22);
+        assert_eq!(&bytecode[14..26], b"a;b\t\"c\"\0\xff\xff\xff\xfe");
+
+        let mut vm = VM::new();
+        vm.capture_output();
+        vm.add_program(bytecode);
+        vm.run().unwrap();
+        assert_eq!(vm.take_output(), "a;b\t\"c\"\n");
+        assert_eq!(vm.get_register(2), Ok(-2));
+
+        for source in [".data\n.asciiz \"open", ".data\n.asciiz \"\\q\"", ".data\n.word 1.5", ".data\nHLT"] {
+            let err = Assembler::new().compile(source).unwrap_err();
+            assert!(err.to_string().starts_with("Syntax Error: Invalid data directive"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_load_word_through_a_data_label() {
+        let source = "LOAD r0 @limit\nLOAD r1 @.Lstep\nADD r0 r1 r2\nHLT\n.data\nlimit:\n.word 70000\n.Lstep:\n.word -5";
+        let bytecode = Assembler::new().compile(source).unwrap();
+        // Each load is an address LOAD and a LOADM; limit follows the
+        // 19 bytes of code.
+        assert_eq!(&bytecode[..7], &[1, 0, 0, 19, u8::from(Opcode::LOADM), 0, 0]);
+
+        let mut vm = VM::new();
+        vm.add_program(bytecode);
+        vm.run().unwrap();
+        assert_eq!(vm.get_register(0), Ok(70000));
+        assert_eq!(vm.get_register(2), Ok(69995));
+
+        let listing = listing(source).unwrap();
+        let line = listing.lines().nth(1).unwrap();
+        assert!(line.starts_with("0007  01 01 00 17 28 01 01 "));
+        assert!(line.ends_with("  LOAD r1 @.Lstep"));
+        assert!(matches!(
+            Assembler::new().compile("LOAD r0 @missing"),
+            Err(AssemblerError::LabelNotFound(label)) if label == "missing"
+        ));
+
+        // Mnemonics are case-insensitive, the sugar included.
+        let lowercase = Assembler::new().compile(&source.replace("LOAD", "load")).unwrap();
+        assert_eq!(lowercase, Assembler::new().compile(source).unwrap());
+    }
+
+    #[test]
+    fn test_source_map_names_assembly_lines() {
+        let source = "; start\nLOAD r0 @value\n\nloop:\nADD r0 r0 r0 ; double\nHLT\n.data\nvalue:\n.word 3";
+        let mut assembler = Assembler::new();
+        assembler.compile(source).unwrap();
+        let map = assembler.source_map();
+        // Both halves of the sugar, and every byte of an instruction.
+        let lines: Vec<Option<usize>> = [0, 3, 4, 6, 7, 10, 11].into_iter().map(|offset| map.line_at(offset)).collect();
+        assert_eq!(lines, [2, 2, 2, 2, 5, 5, 6].map(Some));
+
+        let shifted = map.map_lines(|line| (line != 5).then_some(line * 10));
+        assert_eq!(shifted.line_at(7), Some(20));
+        assert_eq!(shifted.line_at(11), Some(60));
+        assert_eq!(SourceMap::new().line_at(0), None);
+    }
+
+    #[test]
+    fn test_load_label_address_and_jump_through_it() {
+        let source = "
+            LOAD r0 target
+            JMP r0
+            LOAD r1 1
+            target:
+            LOAD r2 2
+            HLT
+        ";
+        let bytecode = Assembler::new().compile(source).unwrap();
+        // target sits after two 4-byte LOADs and a 2-byte JMP.
+        assert_eq!(&bytecode[..4], &[1, 0, 0, 10]);
+
+        let mut vm = VM::new();
+        vm.add_program(bytecode);
+        vm.run().unwrap();
+        assert_eq!(vm.get_register(1), Ok(0));
+        assert_eq!(vm.get_register(2), Ok(2));
+    }
+
+    #[test]
+    fn test_min_and_max_clamp() {
+        // Clamps r0 to 10..=20.
+        for (value, clamped) in [(5, 10), (15, 15), (25, 20)] {
+            let source = format!("LOAD r0 {}\nLOAD r1 10\nLOAD r2 20\nMAX r0 r1 r3\nMIN r3 r2 r3\nHLT", value);
+            let mut vm = VM::new();
+            vm.add_program(Assembler::new().compile(&source).unwrap());
+            vm.run().unwrap();
+            assert_eq!(vm.get_register(3), Ok(clamped));
+        }
+    }
+
+    #[test]
+    fn test_popcnt_and_clz() {
+        let program = Assembler::new().compile("LOAD r0 4096\nPOPCNT r1 r0\nCLZ r2 r0\nHLT").unwrap();
+        assert_eq!(&program[4..10], &[u8::from(Opcode::POPCNT), 1, 0, u8::from(Opcode::CLZ), 2, 0]);
+        let mut vm = VM::new();
+        vm.add_program(program);
+        vm.run().unwrap();
+        assert_eq!(vm.get_register(1), Ok(1));
+        assert_eq!(vm.get_register(2), Ok(19));
+    }
+
+    #[test]
+    fn test_abs() {
+        let program = Assembler::new().compile("LOAD r0 7\nLOAD r1 10\nSUB r0 r1 r2\nABS r3 r2\nHLT").unwrap();
+        let mut vm = VM::new();
+        vm.add_program(program);
+        vm.run().unwrap();
+        assert_eq!(vm.get_register(2), Ok(-3));
+        assert_eq!(vm.get_register(3), Ok(3));
+    }
+
+    #[test]
+    fn test_cmov_follows_equal_flag() {
+        // Selects 100 or 200 into r3 depending on whether r0 == r1.
+        for (a, selected) in [(5, 100), (6, 200)] {
+            let source = format!("LOAD r0 {}\nLOAD r1 5\nLOAD r2 100\nLOAD r3 200\nEQ r0 r1\nCMOV r3 r2\nHLT", a);
+            let mut vm = VM::new();
+            vm.add_program(Assembler::new().compile(&source).unwrap());
+            vm.run().unwrap();
+            assert_eq!(vm.get_register(3), Ok(selected), "{}", a);
+        }
+    }
+
+    #[test]
+    fn test_add64_carries_across_the_word_boundary() {
+        // r0:r1 = 0xffff_ffff and r2:r3 = 1.
+        let source = "LOAD r1 0\nSUBI r1 1\nLOAD r3 1\nADD64 r0:r1 r2:r3 r4:r5\nSUB64 r4:r5 r2:r3 r6:r7\nHLT";
+        let mut vm = VM::new();
+        vm.add_program(Assembler::new().compile(source).unwrap());
+        vm.run().unwrap();
+        assert_eq!((vm.get_register(4), vm.get_register(5)), (Ok(1), Ok(0)));
+        // And the borrow goes back the other way.
+        assert_eq!((vm.get_register(6), vm.get_register(7)), (Ok(0), Ok(-1)));
+
+        let listing = disasm::decode(&Assembler::new().compile(source).unwrap()).unwrap();
+        assert_eq!(listing[3].to_string(), "ADD64 r0:r1 r2:r3 r4:r5");
+    }
+
+    #[test]
+    fn test_adc_chains_a_carry() {
+        // (r1 r0) = (1, 0xffff_ffff) plus (r3 r2) = (0, 1), low words first.
+        let source = "LOAD r0 0\nSUBI r0 1\nLOAD r1 1\nLOAD r2 1\nADD r0 r2 r4\nADC r1 r3 r5\nHLT";
+        let mut vm = VM::new();
+        vm.add_program(Assembler::new().compile(source).unwrap());
+        vm.run().unwrap();
+        assert_eq!((vm.get_register(4), vm.get_register(5)), (Ok(0), Ok(2)));
+        assert!(!vm.get_carry_flag());
+
+        // And back: SBB borrows from the high word.
+        let source = source.replace("ADC r1 r3 r5\nHLT", "ADC r1 r3 r5\nSUB r4 r2 r6\nSBB r5 r3 r7\nHLT");
+        let mut vm = VM::new();
+        vm.add_program(Assembler::new().compile(&source).unwrap());
+        vm.run().unwrap();
+        assert_eq!((vm.get_register(6), vm.get_register(7)), (Ok(-1), Ok(1)));
+        assert!(!vm.get_carry_flag());
+
+        // 1 - 0xffff_ffff borrows.
+        vm.exec_raw(&[u8::from(Opcode::SUB), 2, 0, 3]).unwrap();
+        assert!(vm.get_carry_flag());
+        vm.reset();
+        assert!(!vm.get_carry_flag());
+    }
+
+    #[test]
+    fn test_register_pair_syntax() {
+        for pair in ["r0", "r1:r0", "r0:r2", "r31:r32"] {
+            let source = format!("ADD64 {} r2:r3 r4:r5", pair);
+            assert!(Assembler::new().compile(&source).is_err(), "{}", pair);
+        }
+        assert!(Assembler::new().compile("SUB64 r30:r31 r2:r3 r4:r5").is_ok());
+    }
+
+    #[test]
+    fn test_negative_register_index() {
+        let err = Assembler::new().compile("LOAD r-1 5").unwrap_err();
+        assert!(matches!(err, AssemblerError::SyntaxError(msg) if msg == "register index cannot be negative"));
+    }
+
+    #[test]
+    fn test_unknown_label() {
+        let err = Assembler::new().compile("LOAD r0 nowhere").unwrap_err();
+        assert!(matches!(err, AssemblerError::LabelNotFound(label) if label == "nowhere"));
+    }
+
+    #[test]
+    fn test_object_records_relocations_for_external_labels() {
+        let source = "start:\nLOAD r0 start\nLOAD r1 helper\nJMP r1\nlocal:\nHLT\nLOAD r2 local";
+        let object = Assembler::new().compile_object(source).unwrap();
+
+        let relocations: Vec<_> = object
+            .relocations
+            .iter()
+            .map(|relocation| (relocation.offset, relocation.label.as_str()))
+            .collect();
+        assert_eq!(relocations, [(2, "start"), (6, "helper"), (13, "local")]);
+        assert_eq!(object.symbols, HashMap::from([("start".to_string(), 0), ("local".to_string(), 10)]));
+        // Local labels resolve and the external one is left as zero. No padding.
+        assert_eq!(object.code, [1, 0, 0, 0, 1, 1, 0, 0, 6, 1, 0, 1, 2, 0, 10]);
+
+        // A plain compile still rejects the same source.
+        assert!(matches!(
+            Assembler::new().compile(source),
+            Err(AssemblerError::LabelNotFound(label)) if label == "helper"
+        ));
+    }
+
+    #[test]
+    fn test_link_resolves_labels_across_objects() {
+        let main = Assembler::new().compile_object("LOAD r0 helper\nJMP r0\nback:\nPRINT r1\nHLT").unwrap();
+        let helper = Assembler::new()
+            .compile_object("helper:\nLOAD r1 7\nLOAD r2 back\nJMP r2")
+            .unwrap();
+        let bytecode = link(&[main, helper]).unwrap();
+        // helper lands after main's 9 bytes; main is at 0, so back stays 6.
+        assert_eq!(&bytecode[..4], &[1, 0, 0, 9]);
+        assert_eq!(&bytecode[13..17], &[1, 2, 0, 6]);
+
+        let mut vm = VM::new();
+        vm.capture_output();
+        vm.add_program(bytecode);
+        vm.run().unwrap();
+        assert_eq!(vm.take_output(), "PRINT: 7\n");
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let object = |source| Assembler::new().compile_object(source).unwrap();
+
+        let err = link(&[object("LOAD r0 missing")]).unwrap_err();
+        assert!(matches!(err, AssemblerError::LabelNotFound(label) if label == "missing"));
+
+        let err = link(&[object("twice:\nHLT"), object("twice:\nNOP")]).unwrap_err();
+        assert!(matches!(err, AssemblerError::DuplicateSymbol(label) if label == "twice"));
+    }
+
+    #[test]
+    fn test_entry_directive_skips_leading_code() {
+        let mut assembler = Assembler::new();
+        let program = assembler
+            .compile(".entry start\nLOAD r0 1\nHLT\nstart:\nLOAD r1 2\nHLT")
+            .unwrap();
+        assert_eq!(assembler.entry_point(), Some(5));
+
+        let mut vm = VM::new();
+        vm.set_entry_point(assembler.entry_point().unwrap());
+        vm.add_program(program);
+        vm.run().unwrap();
+        assert_eq!(vm.get_register(0), Ok(0));
+        assert_eq!(vm.get_register(1), Ok(2));
+
+        vm.reset();
+        vm.run_once().unwrap();
+        assert_eq!(vm.get_register(1), Ok(2));
+    }
+
+    #[test]
+    fn test_entry_directive_needs_a_known_label() {
+        let err = Assembler::new().compile(".entry nowhere\nHLT").unwrap_err();
+        assert!(matches!(err, AssemblerError::LabelNotFound(label) if label == "nowhere"));
+    }
+
+    #[test]
+    fn test_equ_constant_in_load() {
+        let source = ".equ LIMIT 500\n.equ STEP 25 ; per round\nLOAD r0 LIMIT\nLOAD r1 STEP\nDIV r0 r1 r2\nHLT";
+        let mut assembler = Assembler::new();
+        let program = assembler.compile(source).unwrap();
+        assert_eq!(&program[..4], &[u8::from(Opcode::LOAD), 0, 0x01, 0xf4]);
+        // Recompiling defines the constants afresh.
+        assert_eq!(assembler.compile(source).unwrap(), program);
+
+        let mut vm = VM::new();
+        vm.add_program(program);
+        vm.run().unwrap();
+        assert_eq!(vm.get_register(2), Ok(20));
+
+        let listing = listing(source).unwrap();
+        assert!(listing.lines().nth(2).unwrap().starts_with("0000  01 00 01 f4"));
+    }
+
+    #[test]
+    fn test_equ_errors() {
+        for (source, message) in [
+            (".equ N 1\n.equ N 2\nHLT", "Syntax Error: Constant N is already defined"),
+            (".equ N\nHLT", "Syntax Error: Invalid .equ directive: .equ N"),
+            (".equ 5 5\nHLT", "Syntax Error: Invalid .equ directive: .equ 5 5"),
+            (".equ N 70000\nHLT", "Syntax Error: Invalid value: 70000"),
+        ] {
+            let err = Assembler::new().compile(source).unwrap_err();
+            assert_eq!(err.to_string(), message, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_unreachable_instructions_after_halt() {
+        let source = "LOAD r0 1\nHLT\nLOAD r1 2 ; dead\n\nPRINT r1\nloop:\nADD r0 r0 r0\nJMP r0\nstart:\nLOAD r2 3\nRET\nHLT";
+        assert_eq!(Assembler::unreachable_instructions(source), vec![3, 5, 12]);
+        assert!(Assembler::unreachable_instructions("LOAD r0 1\nJEQ r0\nHLT").is_empty());
+    }
+
+    #[test]
+    fn test_builder_matches_assembler() {
+        let source = "LOAD r0 10\nLOAD r1 32\nADD r0 r1 r2\nMOV r3 r2\nEQ r2 r3\nPRINT r2\nADDI r2 -5\nHLT";
+        let built = ProgramBuilder::new()
+            .load(0, 10)
+            .load(1, 32)
+            .add(0, 1, 2)
+            .mov(3, 2)
+            .eq(2, 3)
+            .print(2)
+            .instruction(Opcode::ADDI, vec![Operand::Register(2), Operand::SignedImmediate(-5)])
+            .hlt()
+            .build();
+        assert_eq!(built, Assembler::new().compile(source).unwrap());
+        assert_eq!(ProgramBuilder::new().hlt().build(), vec![0; 32]);
+    }
+
+    #[test]
+    fn test_listing_shows_addresses_and_bytes() {
+        let source = "; add two numbers\nLOAD r0 10\nLOAD r1 20\nloop:\nADD r0 r1 r2\nJMP r3 ; spin\nHLT";
+        let listing = listing(source).unwrap();
+        let addresses: Vec<&str> = listing.lines().map(|line| line[..4].trim()).collect();
+        assert_eq!(addresses, vec!["", "0000", "0004", "0008", "0008", "000c", "000e"]);
+
+        let lines: Vec<&str> = listing.lines().collect();
+        assert!(lines[1].starts_with("0000  01 00 00 0a "));
+        assert!(lines[1].ends_with("  LOAD r0 10"));
+        assert!(lines[5].starts_with("000c  06 03 "));
+        assert!(lines[5].ends_with("JMP r3 ; spin"));
+        assert_eq!(lines[0].trim(), "; add two numbers");
+    }
+
+    #[test]
+    fn test_signed_immediate_arithmetic() {
+        let program = Assembler::new()
+            .compile("LOAD r0 10\nADDI r0 -3\nLOAD r1 2\nSUBI r1 5\nMULI r1 -4\nHLT")
+            .unwrap();
+        assert_eq!(&program[4..8], &[u8::from(Opcode::ADDI), 0, 0xff, 0xfd]);
+
+        let mut vm = VM::new();
+        vm.add_program(program);
+        vm.run().unwrap();
+        assert_eq!(vm.get_register(0), Ok(7));
+        assert_eq!(vm.get_register(1), Ok(12));
+    }
+
+    #[test]
+    fn test_signed_immediate_out_of_range() {
+        let err = Assembler::new().compile("ADDI r0 40000").unwrap_err();
+        assert!(matches!(err, AssemblerError::SyntaxError(_)));
+    }
+
+    #[test]
+    fn test_call_depth_in_nested_calls() {
+        let source = "
+            LOAD r0 outer
+            CALL r0
+            HLT
+            outer:
+            LOAD r1 inner
+            CALL r1
+            RET
+            inner:
+            NOP
+            RET
+        ";
+        let mut vm = VM::new();
+        vm.add_program(Assembler::new().compile(source).unwrap());
+
+        let mut depths = Vec::new();
+        while vm.run_once().unwrap() {
+            depths.push(vm.call_depth());
+        }
+        // LOAD, CALL, LOAD, CALL, NOP, RET, RET.
+        assert_eq!(depths, vec![0, 1, 1, 2, 2, 1, 0]);
+    }
+
+    #[test]
+    fn test_infinite_recursion_hits_call_depth_cap() {
+        let mut vm = VM::new();
+        vm.set_max_call_depth(100);
+        vm.add_program(Assembler::new().compile("start:\nLOAD r0 start\nCALL r0").unwrap());
+
+        assert_eq!(vm.run(), Err(crate::vm::VMError::CallStackOverflow));
+        assert_eq!(vm.call_depth(), 100);
+    }
+
+    #[test]
+    fn test_nop_padding() {
+        let mut assembler = Assembler::new();
+        let zero_padded = assembler.compile("LOAD r0 1").unwrap();
+        assert!(zero_padded[4..].iter().all(|byte| *byte == 0));
+
+        assembler.set_nop_padding(true);
+        let nop_padded = assembler.compile("LOAD r0 1").unwrap();
+        assert_eq!(nop_padded.len(), 32);
+        assert_eq!(&nop_padded[..4], &zero_padded[..4]);
+        assert!(nop_padded[4..].iter().all(|byte| *byte == u8::from(Opcode::NOP)));
+    }
+
+    #[test]
+    fn test_registers_referenced() {
+        let program = Assembler::new()
+            .compile("LOAD r0 10\nLOAD r1 20\nADD r0 r1 r3\nPRINT r3\nHLT")
+            .unwrap();
+        assert_eq!(registers_referenced(&program), HashSet::from([0, 1, 3]));
+    }
+
+    #[test]
+    fn test_estimate_cost() {
+        let program = Assembler::new()
+            .compile("LOAD r0 10\nLOAD r1 20\nMUL r0 r1 r2\nPRINT r2\nHLT")
+            .unwrap();
+        let costs = HashMap::from([(Opcode::LOAD, 1), (Opcode::MUL, 3), (Opcode::PRINT, 5)]);
+        // Two loads, a multiply and a print; HLT and the padding are free.
+        assert_eq!(estimate_cost(&program, &costs), 10);
+        assert_eq!(estimate_cost(&program, &HashMap::new()), 0);
+    }
+
+    #[test]
+    fn test_program_hash_is_stable() {
+        let program = Assembler::new().compile("LOAD r0 7\nPRINT r0\nHLT").unwrap();
+        let same = Assembler::new().compile("LOAD r0 7\nPRINT r0\nHLT").unwrap();
+        assert_eq!(program_hash(&program), program_hash(&same));
+
+        let mut changed = program.clone();
+        changed[3] = 8;
+        assert_ne!(program_hash(&program), program_hash(&changed));
+
+        // Reference FNV-1a values pin the algorithm down.
+        assert_eq!(program_hash(&[]), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(program_hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+}
@@ -0,0 +1,378 @@
+use std::fmt;
+use std::ops::Deref;
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Expr {
+    Variable(String),
+    Literal(i64),
+    FloatLiteral(f64),
+    BinOp(Box<Spanned<Expr>>, BinOp, Box<Spanned<Expr>>),
+    Call { function: String, args: Vec<Spanned<Expr>> },
+    Read,
+    /// `cond ? then : else_`; only the chosen arm is evaluated.
+    Conditional {
+        cond: Box<Spanned<Expr>>,
+        then: Box<Spanned<Expr>>,
+        else_: Box<Spanned<Expr>>,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+}
+
+impl BinOp {
+    /// Binding strength; higher binds tighter. All operators are left
+    /// associative. A conditional binds looser than any of them, at 0.
+    pub fn precedence(&self) -> u8 {
+        match self {
+            BinOp::Mul | BinOp::Div => 3,
+            BinOp::Add | BinOp::Sub => 2,
+            _ => 1,
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Less => "<",
+            BinOp::Greater => ">",
+            BinOp::LessEqual => "<=",
+            BinOp::GreaterEqual => ">=",
+            BinOp::Equal => "==",
+            BinOp::NotEqual => "!=",
+        }
+    }
+
+    pub fn is_comparison(&self) -> bool {
+        matches!(
+            self,
+            BinOp::Less
+                | BinOp::Greater
+                | BinOp::LessEqual
+                | BinOp::GreaterEqual
+                | BinOp::Equal
+                | BinOp::NotEqual
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Type {
+    Int,
+    Float,
+    /// The type of a comparison. There is no `bool` keyword, so it never
+    /// appears in a declaration.
+    Bool,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Float => write!(f, "float"),
+            Type::Bool => write!(f, "bool"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Statement {
+    /// `int x = e;`, `float x = e;`, or `let x = e;` with no declared type.
+    Declare(String, Option<Type>, Spanned<Expr>),
+    Assign(String, Spanned<Expr>),
+    Print(Spanned<Expr>),
+    /// `Print("...")`. Strings aren't values, so a literal can only be
+    /// printed.
+    PrintString(String),
+    /// An expression evaluated for its effects, as in `f(x);`.
+    Expr(Spanned<Expr>),
+    Block(Vec<Spanned<Statement>>),
+    /// `else if` parses as an else block holding just the inner `if`.
+    If {
+        cond: Spanned<Expr>,
+        then: Vec<Spanned<Statement>>,
+        else_: Option<Vec<Spanned<Statement>>>,
+    },
+    While { cond: Spanned<Expr>, body: Vec<Spanned<Statement>> },
+    For {
+        init: Option<Box<Spanned<Statement>>>,
+        cond: Option<Spanned<Expr>>,
+        step: Option<Box<Spanned<Statement>>>,
+        body: Vec<Spanned<Statement>>,
+    },
+    Return(Spanned<Expr>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<Spanned<String>>,
+    pub body: Vec<Spanned<Statement>>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Program {
+    pub functions: Vec<Function>,
+    pub statements: Vec<Spanned<Statement>>,
+}
+
+/// A point in the source. `offset` is in bytes; `line` and `column` count
+/// from 1, with columns in characters.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// The source a token or node was parsed from; `end` is exclusive.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+/// An AST node with its span. It derefs to the node, and equality ignores
+/// the span so that trees parsed from differently formatted source compare
+/// equal.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Self {
+        Spanned { node, span }
+    }
+}
+
+impl<T> Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.node
+    }
+}
+
+impl<T: PartialEq> PartialEq for Spanned<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.node == other.node
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Spanned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.node.fmt(f)
+    }
+}
+
+// JSON for editor tooling. Enums use serde's default externally tagged
+// form, `{"Variant": ...}`, which only changes when a variant is renamed.
+#[cfg(feature = "serde")]
+impl Program {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Program> {
+        serde_json::from_str(json)
+    }
+}
+
+// Pretty-printing. The output parses back to an equal AST: operands are
+// parenthesized only where precedence or left associativity requires it.
+impl Program {
+    pub fn to_source(&self) -> String {
+        let mut source = String::new();
+        for function in &self.functions {
+            source.push_str(&function.to_string());
+            source.push('\n');
+        }
+        for statement in &self.statements {
+            source.push_str(&statement.to_string());
+            source.push('\n');
+        }
+        source
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let params: Vec<&str> = self.params.iter().map(|param| param.as_str()).collect();
+        write!(f, "fn {}({}) ", self.name, params.join(", "))?;
+        write_body(f, &self.body, 0)
+    }
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_statement(f, self, 0)
+    }
+}
+
+fn write_statement(f: &mut fmt::Formatter<'_>, statement: &Statement, indent: usize) -> fmt::Result {
+    write!(f, "{:width$}", "", width = indent * 4)?;
+    match statement {
+        Statement::Block(body) => write_body(f, body, indent),
+        Statement::If { cond, then, else_ } => {
+            write!(f, "if ({}) ", cond)?;
+            write_body(f, then, indent)?;
+            match else_ {
+                Some(else_) => {
+                    write!(f, " else ")?;
+                    write_body(f, else_, indent)
+                }
+                None => Ok(()),
+            }
+        }
+        Statement::While { cond, body } => {
+            write!(f, "while ({}) ", cond)?;
+            write_body(f, body, indent)
+        }
+        Statement::For { init, cond, step, body } => {
+            write!(f, "for (")?;
+            if let Some(init) = init {
+                write_clause(f, init)?;
+            }
+            write!(f, ";")?;
+            if let Some(cond) = cond {
+                write!(f, " {}", cond)?;
+            }
+            write!(f, ";")?;
+            if let Some(step) = step {
+                write!(f, " ")?;
+                write_clause(f, step)?;
+            }
+            write!(f, ") ")?;
+            write_body(f, body, indent)
+        }
+        Statement::Print(expr) => write!(f, "Print({});", expr),
+        Statement::PrintString(text) => write!(f, "Print(\"{}\");", escape(text)),
+        Statement::Expr(expr) => write!(f, "{};", expr),
+        Statement::Return(expr) => write!(f, "return {};", expr),
+        _ => {
+            write_clause(f, statement)?;
+            write!(f, ";")
+        }
+    }
+}
+
+/// The escapes string literals and the assembler's `.asciiz` accept, as
+/// the character after the backslash and the character it stands for.
+pub(crate) const ESCAPES: [(char, char); 5] = [('n', '\n'), ('t', '\t'), ('r', '\r'), ('"', '"'), ('\\', '\\')];
+
+/// `text` as it would be written between quotes.
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match ESCAPES.iter().find(|&&(_, value)| value == c) {
+            Some(&(name, _)) => {
+                escaped.push('\\');
+                escaped.push(name);
+            }
+            None => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// A declaration or assignment without its `;`, as in a `for` header.
+fn write_clause(f: &mut fmt::Formatter<'_>, statement: &Statement) -> fmt::Result {
+    match statement {
+        Statement::Declare(name, ty, expr) => match ty {
+            Some(ty) => write!(f, "{} {} = {}", ty, name, expr),
+            None => write!(f, "let {} = {}", name, expr),
+        },
+        Statement::Assign(name, expr) => write!(f, "{} = {}", name, expr),
+        other => write_statement(f, other, 0),
+    }
+}
+
+fn write_body(f: &mut fmt::Formatter<'_>, body: &[Spanned<Statement>], indent: usize) -> fmt::Result {
+    writeln!(f, "{{")?;
+    for statement in body {
+        write_statement(f, statement, indent + 1)?;
+        writeln!(f)?;
+    }
+    write!(f, "{:width$}}}", "", width = indent * 4)
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Variable(name) => write!(f, "{}", name),
+            Expr::Literal(value) => write!(f, "{}", value),
+            // Always keep a `.` so the literal lexes back as a float.
+            Expr::FloatLiteral(value) if value.fract() == 0.0 => write!(f, "{:.1}", value),
+            Expr::FloatLiteral(value) => write!(f, "{}", value),
+            Expr::BinOp(left, op, right) => {
+                write_operand(f, left, op.precedence() > precedence(left))?;
+                write!(f, " {} ", op.symbol())?;
+                write_operand(f, right, op.precedence() >= precedence(right))
+            }
+            Expr::Call { function, args } => {
+                write!(f, "{}(", function)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Read => write!(f, "read()"),
+            // Conditionals nest to the right, so only a condition needs
+            // parentheses.
+            Expr::Conditional { cond, then, else_ } => {
+                write_operand(f, cond, precedence(cond) == 0)?;
+                write!(f, " ? {} : {}", then, else_)
+            }
+        }
+    }
+}
+
+fn precedence(expr: &Expr) -> u8 {
+    match expr {
+        Expr::BinOp(_, op, _) => op.precedence(),
+        Expr::Conditional { .. } => 0,
+        _ => u8::MAX,
+    }
+}
+
+fn write_operand(f: &mut fmt::Formatter<'_>, expr: &Expr, parenthesize: bool) -> fmt::Result {
+    if parenthesize {
+        write!(f, "({})", expr)
+    } else {
+        write!(f, "{}", expr)
+    }
+}
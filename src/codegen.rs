@@ -1,54 +1,927 @@
-use crate::parser::{Program, Statement, Expr, BinOp};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
 
-pub fn codegen(program: Program) -> Vec<String> {
-    let mut asm = Vec::new();
-    let mut reg_counter = 0;
+use crate::asm::SourceMap;
+use crate::ast::{Program, BinOp, Span, escape};
+use crate::disasm::{DecodedInstruction, Operand};
+use crate::ir::{self, Bank, Body, Inst, VReg};
+use crate::opt;
+use crate::regalloc::{self, Allocation};
+use crate::vm::{Opcode, OperandKind};
 
-    for statement in program.statements {
-        match statement {
-            Statement::Declare(var, expr) => {
-                let reg = generate_expr(&expr, &mut asm, &mut reg_counter);
-                asm.push(format!("; {} is in r{}", var, reg));
+type CodegenResult<T> = Result<T, CodegenError>;
+
+/// Settings for `codegen_with`. The default is what `codegen` does.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodegenOptions {
+    /// 0 emits the code as lowered, which keeps a copy for every move
+    /// between variables. 1 has results written straight to where they are
+    /// moved and drops moves and jumps that do nothing. 2 also folds
+    /// constants and eliminates dead code first; see `opt`.
+    pub opt_level: u8,
+    /// Print the `; x is in r0` and `; line N` comments. Without the
+    /// latter, the source lines can't be recovered from the text.
+    pub emit_comments: bool,
+    pub backend: Backend,
+}
+
+impl Default for CodegenOptions {
+    fn default() -> Self {
+        CodegenOptions {
+            opt_level: 1,
+            emit_comments: true,
+            backend: Backend::Text,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// Lines of assembly, as `codegen`.
+    #[default]
+    Text,
+    /// Bytecode and its source map, as `codegen_bytes_with_map`.
+    Bytecode,
+}
+
+/// What `codegen_with` generated, depending on the backend.
+#[derive(Debug, PartialEq)]
+pub enum CodegenOutput {
+    Assembly(Vec<String>),
+    Bytecode(Vec<u8>, SourceMap),
+}
+
+/// The program as lines of assembly. A `; line N` comment comes before the
+/// code generated from each line of the source; see `source_line`.
+pub fn codegen(program: &Program) -> Result<Vec<String>, CodegenError> {
+    assembly(program, &CodegenOptions::default())
+}
+
+/// `codegen`, or one of the other backends, with the given settings.
+pub fn codegen_with(program: &Program, options: &CodegenOptions) -> Result<CodegenOutput, CodegenError> {
+    Ok(match options.backend {
+        Backend::Text => CodegenOutput::Assembly(assembly(program, options)?),
+        Backend::Bytecode => {
+            let (bytecode, source_map) = bytecode(program, options)?;
+            CodegenOutput::Bytecode(bytecode, source_map)
+        }
+    })
+}
+
+fn assembly(program: &Program, options: &CodegenOptions) -> CodegenResult<Vec<String>> {
+    Ok(generate(program, options)?
+        .iter()
+        .filter(|line| options.emit_comments || !matches!(line, Line::Comment(_) | Line::Source(_)))
+        .map(Line::to_string)
+        .collect())
+}
+
+/// The source line a `; line N` annotation of `codegen`'s output names.
+pub fn source_line(asm_line: &str) -> Option<usize> {
+    asm_line.strip_prefix("; line ")?.parse().ok()
+}
+
+/// Like assembling `codegen`'s output, but encodes the program directly,
+/// resolving labels by backpatching. The text backend stays the one to
+/// read; this one skips printing and re-parsing.
+pub fn codegen_bytes(program: &Program) -> Result<Vec<u8>, CodegenError> {
+    codegen_bytes_with_map(program).map(|(bytecode, _)| bytecode)
+}
+
+/// `codegen_bytes`, and the source line each instruction was generated
+/// from.
+pub fn codegen_bytes_with_map(program: &Program) -> Result<(Vec<u8>, SourceMap), CodegenError> {
+    bytecode(program, &CodegenOptions::default())
+}
+
+fn bytecode(program: &Program, options: &CodegenOptions) -> CodegenResult<(Vec<u8>, SourceMap)> {
+    let mut bytecode = Vec::new();
+    let mut source_map = SourceMap::new();
+    let mut labels = HashMap::new();
+    // Where each label operand goes, to fill in once every label is placed.
+    let mut patches = Vec::new();
+
+    for line in generate(program, options)? {
+        let (opcode, args) = match line {
+            Line::Instruction(opcode, args) => (opcode, args),
+            Line::Label(label) => {
+                labels.insert(label, bytecode.len());
+                continue;
             }
-            Statement::Print(expr) => {
-                let result_reg = generate_expr(&expr, &mut asm, &mut reg_counter);
-                asm.push(format!("PRINT r{}", result_reg));
+            Line::Asciiz(text) => {
+                bytecode.extend_from_slice(text.as_bytes());
+                bytecode.push(0);
+                continue;
             }
+            Line::Source(line) => {
+                source_map.insert(bytecode.len(), line);
+                continue;
+            }
+            Line::Comment(_) | Line::DataSection => continue,
+        };
+
+        let offset = bytecode.len();
+        let mut operand_offset = offset + 1;
+        let mut operands = Vec::new();
+        for (kind, arg) in opcode.operands().iter().zip(args) {
+            // `generate` has checked that registers, immediates and label
+            // addresses are in range, and an operand of the wrong bank is a
+            // bug there, not something to encode.
+            operands.push(match (kind, arg) {
+                (OperandKind::Register, Arg::Reg(Reg::Int(reg))) => Operand::Register(reg as u8),
+                (OperandKind::FloatRegister, Arg::Reg(Reg::Float(reg))) => Operand::FloatRegister(reg as u8),
+                (OperandKind::Immediate, Arg::Int(value)) => Operand::Immediate(value as u16),
+                (OperandKind::SignedImmediate, Arg::Int(value)) => Operand::SignedImmediate(value as i16),
+                (OperandKind::Immediate, Arg::Label(label)) => {
+                    patches.push((operand_offset, label));
+                    Operand::Immediate(0)
+                }
+                (OperandKind::Float, Arg::Float(value)) => Operand::Float(value),
+                (kind, arg) => unreachable!("{:?} operand for {:?}: {:?}", kind, opcode, arg),
+            });
+            operand_offset += kind.size();
         }
+        DecodedInstruction::new(offset, opcode, operands).encode(&mut bytecode);
     }
 
-    asm.push("HLT".to_string());
-    asm
+    for (offset, label) in patches {
+        let address = labels[&label] as u16;
+        bytecode[offset..offset + 2].copy_from_slice(&address.to_be_bytes());
+    }
+    // The same zero padding as `Assembler::compile`.
+    bytecode.resize(bytecode.len().max(32), 0);
+    Ok((bytecode, source_map))
 }
 
-fn generate_expr(expr: &Expr, asm: &mut Vec<String>, reg_counter: &mut usize) -> usize {
-    match expr {
-        Expr::Variable(var) => {
-            let reg = allocate_register(reg_counter);
-            asm.push(format!("; assuming {} is in r{}", var, reg));
-            reg
+// Lowers to the IR, then allocates registers and emits each body in turn.
+// Function bodies follow the top level's HLT.
+fn generate(program: &Program, options: &CodegenOptions) -> CodegenResult<Vec<Line>> {
+    let program: Cow<Program> = match options.opt_level {
+        0 | 1 => Cow::Borrowed(program),
+        _ => Cow::Owned(opt::eliminate_dead_code(opt::fold_constants(program.clone()))),
+    };
+    let mut module = ir::lower(&program)?;
+    let mut emitter = Emitter { lines: Vec::new() };
+    let limit = if module.functions.is_empty() {
+        SCRATCH
+    } else {
+        emitter.emit(Opcode::LOAD, vec![Reg::Int(STACK_POINTER).into(), Arg::Int(STACK_TOP.into())]);
+        STACK_POINTER
+    };
+
+    // Whether anything is stored from `SPILL_BASE` up, where the program
+    // mustn't reach.
+    let mut uses_memory = !module.functions.is_empty();
+    for body in std::iter::once(&mut module.top_level).chain(&mut module.functions) {
+        if options.opt_level > 0 {
+            ir::fold_moves(body);
+        }
+        let allocation = regalloc::allocate(body, limit)?;
+        uses_memory |= allocation.uses_slots();
+        if options.opt_level > 0 {
+            peephole(body, &allocation);
+        }
+        emitter.body(body, &allocation);
+    }
+
+    let mut lines = emitter.lines;
+    if !module.strings.is_empty() {
+        lines.push(Line::DataSection);
+        for (index, text) in module.strings.into_iter().enumerate() {
+            lines.push(Line::Label(ir::string_label(index)));
+            lines.push(Line::Asciiz(text));
+        }
+    }
+
+    // Jumps load their target with a 16-bit LOAD.
+    let mut offset = 0;
+    for line in &lines {
+        match line {
+            Line::Instruction(opcode, _) => offset += opcode.instruction_size(),
+            Line::Asciiz(text) => offset += text.len() + 1,
+            Line::Label(_) if offset > u16::MAX as usize => {
+                return Err(CodegenError::ProgramTooLarge { address: offset });
+            }
+            _ => {}
         }
-        Expr::Literal(value) => {
-            let reg = allocate_register(reg_counter);
-            asm.push(format!("LOAD r{} {}", reg, value));
-            reg
+    }
+    if uses_memory && offset > SPILL_BASE as usize {
+        return Err(CodegenError::ProgramTooLarge { address: offset });
+    }
+    Ok(lines)
+}
+
+/// A program codegen can't lower to something the assembler and VM
+/// accept.
+#[derive(Debug, PartialEq)]
+pub enum CodegenError {
+    /// A name with no binding in scope, which `semantic::check` rules out.
+    UnboundVariable { name: String, span: Span },
+    /// A call to a function the program doesn't define, which
+    /// `semantic::check` also rules out.
+    UndefinedFunction { name: String, span: Span },
+    /// An expression holding more temporaries at once than there are
+    /// registers, with no variable left to spill.
+    TooManyRegisters,
+    /// Source that codegen can't lower yet. Function definitions carry no
+    /// span.
+    UnsupportedConstruct { construct: &'static str, span: Option<Span> },
+    /// An integer literal too large for a 16-bit `LOAD` immediate.
+    LiteralOutOfRange { value: i64, span: Span },
+    /// A label past the addresses a 16-bit `LOAD` can hold, or a program
+    /// ending at `address`, past `SPILL_BASE`, that spills or calls.
+    ProgramTooLarge { address: usize },
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodegenError::UnboundVariable { name, span } => {
+                write!(f, "Unbound variable {} at {}", name, span.start)
+            }
+            CodegenError::UndefinedFunction { name, span } => {
+                write!(f, "Undefined function {} at {}", name, span.start)
+            }
+            CodegenError::TooManyRegisters => write!(f, "Expression needs more registers than the VM has"),
+            CodegenError::UnsupportedConstruct { construct, span: Some(span) } => {
+                write!(f, "Codegen does not support {} yet (at {})", construct, span.start)
+            }
+            CodegenError::UnsupportedConstruct { construct, span: None } => {
+                write!(f, "Codegen does not support {} yet", construct)
+            }
+            CodegenError::LiteralOutOfRange { value, span } => {
+                write!(f, "Integer literal {} at {} does not fit in 16 bits", value, span.start)
+            }
+            CodegenError::ProgramTooLarge { address } => {
+                write!(f, "Program reaches address {}, past the memory it may use", address)
+            }
         }
-        Expr::BinOp(left, op, right) => {
-            let left_reg = generate_expr(left, asm, reg_counter);
-            let right_reg = generate_expr(right, asm, reg_counter);
-            let result_reg = allocate_register(reg_counter);
-            match op {
-                BinOp::Add => {
-                    asm.push(format!("ADD r{} r{} r{}", left_reg, right_reg, result_reg));
+    }
+}
+
+impl std::error::Error for CodegenError {}
+
+// What codegen emits, printed as one line of assembly by `codegen`.
+#[derive(Debug)]
+enum Line {
+    Instruction(Opcode, Vec<Arg>),
+    Label(String),
+    Comment(String),
+    // The source line of the code that follows.
+    Source(usize),
+    // Starts the data section, which follows all the code.
+    DataSection,
+    Asciiz(String),
+}
+
+#[derive(Debug)]
+enum Arg {
+    Reg(Reg),
+    Int(i64),
+    Float(f64),
+    Label(String),
+}
+
+impl From<Reg> for Arg {
+    fn from(reg: Reg) -> Self {
+        Arg::Reg(reg)
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::Instruction(opcode, args) => {
+                write!(f, "{:?}", opcode)?;
+                for arg in args {
+                    match arg {
+                        Arg::Reg(reg) => write!(f, " {}", reg)?,
+                        Arg::Int(value) => write!(f, " {}", value)?,
+                        Arg::Float(value) => write!(f, " {:?}", value)?,
+                        Arg::Label(label) => write!(f, " {}", label)?,
+                    }
                 }
+                Ok(())
             }
-            result_reg
+            Line::Label(label) => write!(f, "{}:", label),
+            Line::Comment(text) => write!(f, "; {}", text),
+            Line::Source(line) => write!(f, "; line {}", line),
+            Line::DataSection => write!(f, ".data"),
+            Line::Asciiz(text) => write!(f, ".asciiz \"{}\"", escape(text)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RegisterUsage {
+    /// Most integer registers holding a live value at any one point.
+    pub max_live: usize,
+    /// Distinct integer registers the program uses; must not exceed the VM's 32.
+    pub allocated: usize,
+}
+
+/// Registers the top-level statements need, up to the first error if any,
+/// with no limit on how many and so no spilling.
+pub fn analyze(program: &Program) -> RegisterUsage {
+    regalloc::usage(&ir::lower_statements(program))
+}
+
+// Integer and float values live in separate register banks.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Reg {
+    Int(usize),
+    Float(usize),
+}
+
+impl Reg {
+    fn allocated(reg: VReg, allocation: &Allocation) -> Self {
+        match reg.bank {
+            Bank::Int => Reg::Int(allocation.register(reg)),
+            Bank::Float => Reg::Float(allocation.register(reg)),
+        }
+    }
+
+    // The integer or float form of an opcode, for this register's bank.
+    fn select(self, int: Opcode, float: Opcode) -> Opcode {
+        match self {
+            Reg::Int(_) => int,
+            Reg::Float(_) => float,
+        }
+    }
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reg::Int(reg) => write!(f, "r{}", reg),
+            Reg::Float(reg) => write!(f, "f{}", reg),
         }
     }
 }
 
-fn allocate_register(reg_counter: &mut usize) -> usize {
-    let reg = *reg_counter;
-    *reg_counter += 1;
-    reg
-}
\ No newline at end of file
+// Holds a spill slot's address, or a jump or call target, for the one
+// instruction that needs it; never allocated.
+const SCRATCH: usize = 31;
+
+// Spill slots start halfway up memory, clear of the program and its string
+// literals, which the VM maps in from address 0.
+pub(crate) const SPILL_BASE: u16 = 0x8000;
+
+// The calling convention. Arguments go in r0, r1, ... in order, and the
+// result comes back in r0. The caller saves every integer register live
+// across a call on the stack before it and restores them after, so a
+// function has nothing to preserve: it has no prologue, starts with its
+// parameters in the argument registers, and its epilogue moves the result
+// to r0 and returns. Falling off the end returns 0.
+//
+// In programs with functions r30 is the stack pointer, never allocated. It
+// starts at `STACK_TOP` and each push moves it down a word first. Spill
+// slots sit at fixed addresses from `SPILL_BASE`, so a recursive call would
+// overwrite them; functions never spill. Floats can't be saved either, so
+// none may be live across a call.
+const STACK_POINTER: usize = 30;
+const STACK_TOP: u16 = 65532;
+
+// Cleans up after register allocation: drops moves between registers that
+// got the same VM register, and jumps to a label that comes next anyway.
+fn peephole(body: &mut Body, allocation: &Allocation) {
+    let insts = std::mem::take(&mut body.insts);
+    for (index, inst) in insts.iter().enumerate() {
+        let redundant = match inst {
+            Inst::Move { dest, src } => allocation.register(*dest) == allocation.register(*src),
+            Inst::Jump(label) => insts[index + 1..]
+                .iter()
+                .map_while(|next| match next {
+                    Inst::Label(next) => Some(next),
+                    _ => None,
+                })
+                .any(|next| next == label),
+            _ => false,
+        };
+        if !redundant {
+            body.insts.push(inst.clone());
+        }
+    }
+}
+
+struct Emitter {
+    lines: Vec<Line>,
+}
+
+impl Emitter {
+    fn body(&mut self, body: &Body, allocation: &Allocation) {
+        let reg = |reg: VReg| Reg::allocated(reg, allocation);
+        let mut calls = 0;
+        for inst in &body.insts {
+            match inst {
+                Inst::Const { dest, value } => self.emit(Opcode::LOAD, vec![reg(*dest).into(), Arg::Int(*value)]),
+                Inst::FloatConst { dest, value } => self.emit(Opcode::FLOAD, vec![reg(*dest).into(), Arg::Float(*value)]),
+                Inst::Address { dest, label } => self.emit(Opcode::LOAD, vec![reg(*dest).into(), Arg::Label(label.clone())]),
+                Inst::Read { dest } => self.emit(Opcode::READ, vec![reg(*dest).into()]),
+                Inst::Move { dest, src } => {
+                    let dest = reg(*dest);
+                    self.emit(dest.select(Opcode::MOV, Opcode::FMOV), vec![dest.into(), reg(*src).into()]);
+                }
+                // The left operand is the first source, so SUB and DIV
+                // compute left - right and left / right.
+                Inst::Binary { dest, op, left, right } => {
+                    let left = reg(*left);
+                    let (int, float) = match op {
+                        BinOp::Add => (Opcode::ADD, Opcode::FADD),
+                        BinOp::Sub => (Opcode::SUB, Opcode::FSUB),
+                        BinOp::Mul => (Opcode::MUL, Opcode::FMUL),
+                        BinOp::Div => (Opcode::DIV, Opcode::FDIV),
+                        _ => unreachable!(),
+                    };
+                    self.emit(left.select(int, float), vec![left.into(), reg(*right).into(), reg(*dest).into()]);
+                }
+                Inst::Compare { op, left, right } => {
+                    let left = reg(*left);
+                    let (int, float) = comparison_opcodes(op);
+                    self.emit(left.select(int, float), vec![left.into(), reg(*right).into()]);
+                }
+                Inst::Select { dest, src } => self.emit(Opcode::CMOV, vec![reg(*dest).into(), reg(*src).into()]),
+                Inst::Print { src } => {
+                    let src = reg(*src);
+                    self.emit(src.select(Opcode::PRINT, Opcode::FPRINT), vec![src.into()]);
+                }
+                Inst::PrintString { src } => self.emit(Opcode::PRTS, vec![reg(*src).into()]),
+                Inst::Call { dest, function, args, .. } => {
+                    let saved = allocation.saved(calls);
+                    calls += 1;
+                    for &saved in saved {
+                        self.push(Reg::Int(saved));
+                    }
+                    // Through the stack, since an argument may be in another
+                    // argument's register.
+                    for &arg in args {
+                        self.push(reg(arg));
+                    }
+                    for index in (0..args.len()).rev() {
+                        self.pop(Reg::Int(index));
+                    }
+
+                    let scratch = Reg::Int(SCRATCH);
+                    self.emit(Opcode::LOAD, vec![scratch.into(), Arg::Label(ir::function_label(function))]);
+                    self.emit(Opcode::CALL, vec![scratch.into()]);
+                    if let Some(dest) = dest {
+                        // Restoring doesn't touch the scratch register.
+                        self.emit(Opcode::MOV, vec![scratch.into(), Reg::Int(0).into()]);
+                        for &saved in saved.iter().rev() {
+                            self.pop(Reg::Int(saved));
+                        }
+                        self.emit(Opcode::MOV, vec![reg(*dest).into(), scratch.into()]);
+                    }
+                }
+                Inst::Reload { dest, address } => {
+                    self.emit(Opcode::LOAD, vec![Reg::Int(SCRATCH).into(), Arg::Int((*address).into())]);
+                    self.emit(Opcode::LOADM, vec![reg(*dest).into(), Reg::Int(SCRATCH).into()]);
+                }
+                Inst::Spill { src, address } => {
+                    self.emit(Opcode::LOAD, vec![Reg::Int(SCRATCH).into(), Arg::Int((*address).into())]);
+                    self.emit(Opcode::STOREM, vec![Reg::Int(SCRATCH).into(), reg(*src).into()]);
+                }
+                Inst::Bind { var, reg: bound } => {
+                    let text = match allocation.slot(*bound) {
+                        Some(address) => format!("{} is spilled to {}", var, address),
+                        None => format!("{} is in {}", var, reg(*bound)),
+                    };
+                    self.lines.push(Line::Comment(text));
+                }
+                Inst::Source(line) => self.lines.push(Line::Source(*line)),
+                Inst::Label(label) => self.lines.push(Line::Label(label.clone())),
+                Inst::Jump(label) => self.jump(Opcode::JMP, label),
+                Inst::JumpUnless(label) => self.jump(Opcode::JNEQ, label),
+                Inst::Return(value) => {
+                    match value.map(reg) {
+                        Some(Reg::Int(0)) => {}
+                        Some(value) => self.emit(Opcode::MOV, vec![Reg::Int(0).into(), value.into()]),
+                        None => self.emit(Opcode::LOAD, vec![Reg::Int(0).into(), Arg::Int(0)]),
+                    }
+                    self.emit(Opcode::RET, vec![]);
+                }
+                Inst::Halt => self.emit(Opcode::HLT, vec![]),
+            }
+        }
+    }
+
+    fn push(&mut self, reg: Reg) {
+        let stack_pointer = Reg::Int(STACK_POINTER);
+        self.emit(Opcode::ADDI, vec![stack_pointer.into(), Arg::Int(-4)]);
+        self.emit(Opcode::STOREM, vec![stack_pointer.into(), reg.into()]);
+    }
+
+    fn pop(&mut self, reg: Reg) {
+        let stack_pointer = Reg::Int(STACK_POINTER);
+        self.emit(Opcode::LOADM, vec![reg.into(), stack_pointer.into()]);
+        self.emit(Opcode::ADDI, vec![stack_pointer.into(), Arg::Int(4)]);
+    }
+
+    fn jump(&mut self, opcode: Opcode, label: &str) {
+        let scratch = Reg::Int(SCRATCH);
+        self.emit(Opcode::LOAD, vec![scratch.into(), Arg::Label(label.to_string())]);
+        self.emit(opcode, vec![scratch.into()]);
+    }
+
+    fn emit(&mut self, opcode: Opcode, args: Vec<Arg>) {
+        self.lines.push(Line::Instruction(opcode, args));
+    }
+}
+
+// The integer and float opcodes for a comparison.
+fn comparison_opcodes(op: &BinOp) -> (Opcode, Opcode) {
+    match op {
+        BinOp::Less => (Opcode::LT, Opcode::FLT),
+        BinOp::Greater => (Opcode::GT, Opcode::FGT),
+        BinOp::LessEqual => (Opcode::LTE, Opcode::FLTE),
+        BinOp::GreaterEqual => (Opcode::GTE, Opcode::FGTE),
+        BinOp::Equal => (Opcode::EQ, Opcode::FEQ),
+        BinOp::NotEqual => (Opcode::NEQ, Opcode::FNEQ),
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_str;
+
+    // Without the line annotations, which have a test of their own.
+    fn lower(source: &str) -> Vec<String> {
+        let asm = codegen(&parse_str(source).unwrap()).unwrap();
+        asm.into_iter().filter(|line| source_line(line).is_none()).collect()
+    }
+
+    #[test]
+    fn test_analyze_nested_expression() {
+        // 1 and 2 are still live while 3 is loaded for the multiplication,
+        // and the results reuse their operands' registers.
+        let usage = analyze(&parse_str("Print(1 + 2 * 3);").unwrap());
+        assert_eq!(usage, RegisterUsage { max_live: 3, allocated: 3 });
+    }
+
+    #[test]
+    fn test_analyze_counts_variables_as_live() {
+        let usage = analyze(&parse_str("int a = 1; int b = 2; Print(a * b + 3); Print(a - b);").unwrap());
+        assert_eq!(usage.max_live, 4);
+    }
+
+    #[test]
+    fn test_left_operand_is_the_first_source() {
+        let asm = lower("Print(10 - 4); Print(6 / 2); float f = 1.5 - 0.5;");
+        assert_eq!(
+            asm[..11],
+            [
+                "LOAD r0 10",
+                "LOAD r1 4",
+                "SUB r0 r1 r0",
+                "PRINT r0",
+                "LOAD r0 6",
+                "LOAD r1 2",
+                "DIV r0 r1 r0",
+                "PRINT r0",
+                "FLOAD f0 1.5",
+                "FLOAD f1 0.5",
+                "FSUB f0 f1 f0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_long_chain_reuses_temporaries() {
+        let sum = (1..=40).map(|n| n.to_string()).collect::<Vec<_>>().join(" + ");
+        let program = parse_str(&format!("Print({});", sum)).unwrap();
+        assert_eq!(analyze(&program), RegisterUsage { max_live: 2, allocated: 2 });
+        let highest = codegen(&program)
+            .unwrap()
+            .iter()
+            .flat_map(|line| line.split_whitespace().skip(1))
+            .filter_map(|operand| operand.strip_prefix('r')?.parse::<usize>().ok())
+            .max();
+        assert_eq!(highest, Some(1));
+    }
+
+    #[test]
+    fn test_variable_registers_are_not_recycled_while_live() {
+        let asm = lower("int a = 1; Print(a + 2); { int b = a * 3; Print(b - a); } Print(a);");
+        assert_eq!(asm[1], "; a is in r0");
+        // Nothing after the declaration writes r0, the destination of a
+        // LOAD or the last operand of arithmetic.
+        for line in &asm[2..] {
+            let operands: Vec<&str> = line.split_whitespace().collect();
+            let written = match operands[0] {
+                "LOAD" => operands.get(1),
+                "ADD" | "SUB" | "MUL" | "DIV" => operands.get(3),
+                _ => None,
+            };
+            assert_ne!(written, Some(&"r0"), "{}", line);
+        }
+        assert_eq!(asm[asm.len() - 2], "PRINT r0");
+    }
+
+    fn spills(asm: &[String]) -> usize {
+        asm.iter().filter(|line| line.starts_with("STOREM")).count()
+    }
+
+    #[test]
+    fn test_spill_code_only_under_pressure() {
+        let small = lower("int a = 1; int b = a * 2; while (b < 10) { b += a; } Print(a ? b : 0);");
+        assert!(small.iter().all(|line| !line.contains("LOADM") && !line.contains("STOREM")));
+
+        // Dead variables free their registers, so only live ones count.
+        let declarations: String = (0..34).map(|n| format!("int v{} = {};", n, n)).collect();
+        assert_eq!(spills(&lower(&declarations)), 0);
+
+        // 31 registers hold 31 live variables. Declaring v31 spills it, as it
+        // is printed last, and storing it needs a register too, so v30 goes
+        // as well.
+        let prints: String = (0..34).map(|n| format!("Print(v{});", n)).collect();
+        let asm = lower(&format!("{} {}", declarations, prints));
+        assert_eq!(spills(&asm), 4);
+        assert!(asm.contains(&"; v31 is spilled to 32768".to_string()));
+        assert!(asm.contains(&"; v30 is spilled to 32772".to_string()));
+    }
+
+    #[test]
+    fn test_spilled_variables_are_reloaded_at_each_use() {
+        let declarations: String = (0..34).map(|n| format!("int v{} = {};", n, n)).collect();
+        let prints: String = (0..34).rev().map(|n| format!("Print(v{});", n)).collect();
+        let source = format!("{} while (v33 < 40) {{ v33 = v33 + v0 + 1; }} {}", declarations, prints);
+        let program = parse_str(&source).unwrap();
+        assert!(spills(&codegen(&program).unwrap()) > 0);
+        let (output, _) = run(codegen_bytes(&program).unwrap(), &[]);
+        let expected: String = [40].into_iter().chain((0..33).rev()).map(|n| format!("PRINT: {}\n", n)).collect();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_only_integer_variables_spill() {
+        // f is live furthest ahead, but floats have no memory opcodes, so an
+        // integer variable goes instead.
+        let declarations: String = (0..32).map(|n| format!("int v{} = {};", n, n)).collect();
+        let prints: String = (0..32).map(|n| format!("Print(v{});", n)).collect();
+        let source = format!("float f = 1.5; {} {} Print(f);", declarations, prints);
+        let program = parse_str(&source).unwrap();
+        let asm = codegen(&program).unwrap();
+        assert!(spills(&asm) > 0);
+        assert!(asm.contains(&"; f is in f0".to_string()));
+        crate::Assembler::new().compile(&asm.join("\n")).unwrap();
+        let (output, _) = run(codegen_bytes(&program).unwrap(), &[]);
+        let expected: String = (0..32).map(|n| format!("PRINT: {}\n", n)).collect();
+        assert_eq!(output, format!("{}PRINT: 1.5\n", expected));
+
+        // Running out of float registers is an error.
+        let declarations: String = (0..33).map(|n| format!("float v{} = {}.5;", n, n)).collect();
+        let prints: String = (0..33).map(|n| format!("Print(v{});", n)).collect();
+        let program = parse_str(&format!("{} {}", declarations, prints)).unwrap();
+        assert_eq!(codegen(&program), Err(CodegenError::TooManyRegisters));
+        assert_eq!(codegen_bytes(&program), Err(CodegenError::TooManyRegisters));
+    }
+
+    #[test]
+    fn test_dead_scope_registers_are_reused() {
+        let asm = lower("{ int a = 1; } { int b = 2; }");
+        assert_eq!(asm[0], "LOAD r0 1");
+        assert_eq!(asm[2], "LOAD r0 2");
+    }
+
+    #[test]
+    fn test_while_condition_is_inside_the_loop() {
+        let asm = lower("int i = 0; while (i < 3) { i = i + 1; }");
+        let head = asm.iter().position(|line| line == ".Lwhile_0:").unwrap();
+        let compare = asm.iter().position(|line| line.starts_with("LT ")).unwrap();
+        assert!(head < compare);
+        assert_eq!(asm[asm.len() - 4..], ["LOAD r31 .Lwhile_0", "JMP r31", ".Lendwhile_0:", "HLT"]);
+    }
+
+    #[test]
+    fn test_comparisons_branch_on_the_flag_or_materialize() {
+        let asm = lower("int a = 1; if (a < 2) { }");
+        assert_eq!(asm[2..5], ["LOAD r1 2", "LT r0 r1", "LOAD r31 .Lelse_0"]);
+        assert_eq!(asm[5], "JNEQ r31");
+
+        let asm = lower("int a = 1; int b = a < 2;");
+        assert_eq!(asm[2..7], ["LOAD r1 2", "LT r0 r1", "LOAD r0 0", "LOAD r1 1", "CMOV r0 r1"]);
+        assert_eq!(asm[7], "; b is in r0");
+    }
+
+    fn run(bytecode: Vec<u8>, input: &[i32]) -> (String, [i32; 32]) {
+        let mut vm = crate::VM::new();
+        vm.capture_output();
+        vm.set_input(input.iter().copied());
+        vm.add_program(bytecode);
+        vm.run().unwrap();
+        (vm.take_output(), *vm.get_registers())
+    }
+
+    #[test]
+    fn test_algebraic_identities() {
+        // y's copy of x lands in x's register, which is free by then.
+        let asm = lower("int x = read(); int y = x * 1 + 0; Print(y - y + 0 * x + 2);");
+        assert_eq!(asm, ["READ r0", "; x is in r0", "; y is in r0", "LOAD r0 2", "PRINT r0", "HLT"]);
+
+        // The read still happens.
+        let asm = lower("Print(read() * 0); Print(0 * (read() - 5));");
+        assert_eq!(asm.iter().filter(|line| line.starts_with("READ")).count(), 2);
+        assert_eq!(asm.iter().filter(|line| line.starts_with("MUL")).count(), 2);
+    }
+
+    #[test]
+    fn test_string_literals_go_in_the_data_section() {
+        let asm = lower("Print(\"hi\"); Print(1); Print(\"a\\t\\\"b\\\"\"); Print(\"hi\");");
+        assert_eq!(
+            asm,
+            [
+                "LOAD r0 .Lstr_0",
+                "PRTS r0",
+                "LOAD r0 1",
+                "PRINT r0",
+                "LOAD r0 .Lstr_1",
+                "PRTS r0",
+                "LOAD r0 .Lstr_0",
+                "PRTS r0",
+                "HLT",
+                ".data",
+                ".Lstr_0:",
+                ".asciiz \"hi\"",
+                ".Lstr_1:",
+                ".asciiz \"a\\t\\\"b\\\"\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_source_lines_are_annotated_and_mapped() {
+        let source = "int i = 3;\nwhile (i) {\n\n    i -= 1; }\nPrint(i);";
+        let program = parse_str(source).unwrap();
+        assert_eq!(
+            codegen(&program).unwrap(),
+            [
+                "; line 1",
+                "LOAD r0 3",
+                "; i is in r0",
+                ".Lwhile_0:",
+                "; line 2",
+                "LOAD r1 0",
+                "NEQ r0 r1",
+                "LOAD r31 .Lendwhile_0",
+                "JNEQ r31",
+                "; line 4",
+                "LOAD r1 1",
+                "SUB r0 r1 r0",
+                "; line 2",
+                "LOAD r31 .Lwhile_0",
+                "JMP r31",
+                ".Lendwhile_0:",
+                "; line 5",
+                "PRINT r0",
+                "HLT",
+            ]
+        );
+
+        let (bytecode, map) = codegen_bytes_with_map(&program).unwrap();
+        let lines: Vec<Option<usize>> = crate::disasm::instructions(&bytecode)
+            .map_while(Result::ok)
+            .take(11)
+            .map(|instruction| map.line_at(instruction.offset))
+            .collect();
+        let [one, two, four, five] = [1, 2, 4, 5].map(Some);
+        assert_eq!(lines, [one, two, two, two, two, four, four, two, two, five, five]);
+    }
+
+    #[test]
+    fn test_opt_level() {
+        let program = parse_str("int a = read(); a = a * (2 + 3); Print(a);").unwrap();
+        let asm = |opt_level| match codegen_with(&program, &CodegenOptions { opt_level, ..Default::default() }).unwrap() {
+            CodegenOutput::Assembly(asm) => asm,
+            CodegenOutput::Bytecode(..) => unreachable!(),
+        };
+        assert_eq!(asm(1), codegen(&program).unwrap());
+        // Without folding moves, the product goes through a temporary.
+        assert_eq!(asm(0)[6..8], ["MUL r0 r1 r1", "MOV r0 r1"]);
+        assert_eq!(asm(1)[3..7], ["LOAD r1 2", "LOAD r2 3", "ADD r1 r2 r1", "MUL r0 r1 r0"]);
+        assert_eq!(asm(2)[3..5], ["LOAD r1 5", "MUL r0 r1 r0"]);
+    }
+
+    #[test]
+    fn test_comments_can_be_left_out() {
+        let program = parse_str("int a = read();\nPrint(a);").unwrap();
+        let options = CodegenOptions { emit_comments: false, ..Default::default() };
+        assert_eq!(codegen_with(&program, &options).unwrap(), CodegenOutput::Assembly(vec!["READ r0".to_string(), "PRINT r0".to_string(), "HLT".to_string()]));
+        assert_eq!(codegen(&program).unwrap(), ["; line 1", "READ r0", "; a is in r0", "; line 2", "PRINT r0", "HLT"]);
+    }
+
+    #[test]
+    fn test_bytecode_backend() {
+        let program = parse_str("int a = read(); Print(a - 1);").unwrap();
+        let options = CodegenOptions { backend: Backend::Bytecode, ..Default::default() };
+        let (bytecode, source_map) = codegen_bytes_with_map(&program).unwrap();
+        assert_eq!(codegen_with(&program, &options).unwrap(), CodegenOutput::Bytecode(bytecode, source_map));
+    }
+
+    #[test]
+    fn test_output_assembles_whenever_codegen_succeeds() {
+        let ints: String = (0..34).map(|n| format!("int v{} = {};", n, n)).collect();
+        let int_prints: String = (0..34).map(|n| format!("Print(v{});", n)).collect();
+        let floats: String = (0..32).map(|n| format!("float g{} = {}.5;", n, n)).collect();
+        let float_prints: String = (0..32).map(|n| format!("Print(g{});", n)).collect();
+        let sources = [
+            format!("float f = 1.5; {} {} Print(f);", ints, int_prints),
+            format!("{} {} {} {}", floats, ints, int_prints, float_prints),
+            format!("{} float h = 0.5; {} Print(h);", floats, float_prints),
+            format!("{} while (v0 < 3) {{ v0 = v0 + 1; }} {}", ints, int_prints),
+        ];
+        let mut generated = 0;
+        for source in &sources {
+            let program = parse_str(source).unwrap();
+            if let Ok(asm) = codegen(&program) {
+                let assembled = crate::Assembler::new().compile(&asm.join("\n"));
+                assert_eq!(assembled.ok(), Some(codegen_bytes(&program).unwrap()), "{}", source);
+                generated += 1;
+            }
+        }
+        // The third has 33 floats live at once.
+        assert_eq!(generated, 3);
+    }
+
+    #[test]
+    fn test_backends_agree() {
+        let declarations: String = (0..40).map(|i| format!("int v{} = {};", i, i)).collect();
+        let spilled = format!("{} Print(v0 + v39); v0 = v1 * 2; Print(v0);", declarations);
+        let factorial = "fn f(n) { if (n <= 1) { return 1; } return n * f(n - 1); } fn main() { Print(f(6)); }";
+        let sources = [
+            factorial,
+            // a and b are live across the call, so the caller saves them.
+            "fn sq(n) { return n * n; } fn main() { int a = read(); int b = read(); Print(sq(a) + a * b); Print(b); }",
+            "fn sub(a, b, c) { return a - b - c; } fn main() { Print(sub(sub(read(), 1, 2), read(), 3)); }",
+            "fn show(n) { Print(n); return n; } fn main() { int i = read(); while (show(i)) { i -= 1; } }",
+            "int a = read(); int b = read(); Print(a * b - a / b);",
+            "float f = 1.5; float g = f * 2.0 - 0.5; if (g > f) { Print(g); } else { Print(f); }",
+            "int sum = 0; for (int i = 1; i <= 10; i += 1) { sum += i; } Print(sum);",
+            "int i = read(); while (i) { i -= 1; Print(i ? i : 100); }",
+            "int a = read(); int less = a < 3; let same = a == 3; Print(less + same);",
+            "int a = read(); if (a < 2) { } else if (a < 4) { Print(1); } else { Print(2); }",
+            "Print(\"x = \"); Print(read()); Print(\"done\\n\"); Print(\"x = \");",
+            &spilled,
+        ];
+        for source in sources {
+            let program = parse_str(source).unwrap();
+            let bytes = codegen_bytes(&program).unwrap();
+            let assembled = crate::Assembler::new()
+                .compile(&lower(source).join("\n"))
+                .unwrap();
+            assert_eq!(run(bytes.clone(), &[3, 2]), run(assembled.clone(), &[3, 2]), "{}", source);
+            assert_eq!(bytes, assembled, "{}", source);
+        }
+        let (output, _) = run(codegen_bytes(&parse_str(factorial).unwrap()).unwrap(), &[]);
+        assert_eq!(output, "PRINT: 720\n");
+    }
+
+    #[test]
+    fn test_codegen_errors() {
+        let error = |source: &str| codegen(&parse_str(source).unwrap()).unwrap_err().to_string();
+        // Unchecked programs may use names that were never declared.
+        assert_eq!(error("Print(1); Print(x);"), "Unbound variable x at 1:17");
+        assert_eq!(error("y = 2;"), "Unbound variable y at 1:1");
+        assert_eq!(error("Print(70000);"), "Integer literal 70000 at 1:7 does not fit in 16 bits");
+        assert_eq!(error("Print(f(1));"), "Undefined function f at 1:7");
+        assert_eq!(
+            error("fn f() { return 1; } fn main() { float x = 1.5; Print(f()); Print(x); return 0; }"),
+            "Codegen does not support float values live across a call yet (at 1:55)"
+        );
+
+        let nested = (1..=32).rev().fold("33".to_string(), |inner, n| format!("{} + ({})", n, inner));
+        let program = parse_str(&format!("Print({});", nested)).unwrap();
+        assert_eq!(codegen(&program), Err(CodegenError::TooManyRegisters));
+        assert_eq!(codegen_bytes(&program), Err(CodegenError::TooManyRegisters));
+
+        // Each Print is six bytes, so the label lands past 65535.
+        let prints = "Print(1);".repeat(11_000);
+        let program = parse_str(&format!("{} if (1) {{ }}", prints)).unwrap();
+        assert_eq!(codegen(&program), Err(CodegenError::ProgramTooLarge { address: 66_017 }));
+
+        // Past 32K the program would overlap the spill slots and the stack,
+        // but only matters if it uses them.
+        let prints = "Print(1);".repeat(5_500);
+        assert!(codegen(&parse_str(&prints).unwrap()).is_ok());
+        let program = parse_str(&format!("fn f() {{ return 1; }} fn main() {{ {} Print(f()); }}", prints)).unwrap();
+        assert!(matches!(codegen(&program), Err(CodegenError::ProgramTooLarge { .. })));
+        let declarations: String = (0..33).map(|n| format!("int v{} = {};", n, n)).collect();
+        let uses: String = (0..33).map(|n| format!("Print(v{});", n)).collect();
+        let program = parse_str(&format!("{} {} {}", declarations, prints, uses)).unwrap();
+        assert!(matches!(codegen_bytes(&program), Err(CodegenError::ProgramTooLarge { .. })));
+        let program = parse_str(&format!("{} {}", declarations, uses)).unwrap();
+        assert!(codegen(&program).is_ok());
+    }
+
+    #[test]
+    fn test_if_labels_are_unique() {
+        let source = "int a = 1; if (a) { if (a) { } } else { a = 2; } if (a) { }";
+        let labels: Vec<String> = lower(source)
+            .into_iter()
+            .filter(|line| line.ends_with(':'))
+            .collect();
+        assert_eq!(labels, [".Lelse_1:", ".Lelse_0:", ".Lendif_0:", ".Lelse_2:"]);
+    }
+}
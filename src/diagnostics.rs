@@ -0,0 +1,267 @@
+use std::io::{self, IsTerminal};
+
+use crate::asm::AssemblerError;
+use crate::ast::{Position, Span};
+use crate::codegen::CodegenError;
+use crate::parser::ParseError;
+use crate::semantic::SemanticError;
+use crate::typeck::TypeError;
+
+const RED: &str = "\x1b[1;31m";
+const CYAN: &str = "\x1b[1;36m";
+const RESET: &str = "\x1b[0m";
+
+/// An error ready to show to a user: the message, and the source line it
+/// happened on with the span underlined. Errors without a location (the
+/// assembler's, and a few semantic ones) render as just the message.
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Option<Span>,
+    pub hint: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, span: Option<Span>) -> Self {
+        Diagnostic {
+            message: message.into(),
+            span,
+            hint: None,
+        }
+    }
+
+    pub fn with_hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    /// Renders without color, as in:
+    ///
+    /// ```text
+    /// error: Undeclared variable total
+    ///  --> 2:7
+    ///   |
+    /// 2 | Print(total);
+    ///   |       ^^^^^
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        self.render_with_color(source, false)
+    }
+
+    /// Like `render`, with ANSI colors when `color` is set; see `use_color`.
+    pub fn render_with_color(&self, source: &str, color: bool) -> String {
+        let paint = |text: &str, style: &str| {
+            if color {
+                format!("{}{}{}", style, text, RESET)
+            } else {
+                text.to_string()
+            }
+        };
+
+        let mut lines = vec![format!("{}: {}", paint("error", RED), self.message)];
+        let mut gutter = String::new();
+        if let Some(span) = self.span {
+            let start = span.start;
+            let number = start.line.to_string();
+            gutter = " ".repeat(number.len());
+            let text = source.lines().nth(start.line - 1).unwrap_or("");
+
+            lines.push(format!("{}--> {}", gutter, start));
+            lines.push(format!("{} |", gutter));
+            lines.push(format!("{} | {}", number, text));
+            lines.push(format!(
+                "{} | {}{}",
+                gutter,
+                indent(text, start),
+                paint(&"^".repeat(underline_width(text, span)), RED)
+            ));
+        }
+        if let Some(hint) = &self.hint {
+            lines.push(format!("{} = {}: {}", gutter, paint("hint", CYAN), hint));
+        }
+        lines.join("\n")
+    }
+}
+
+// Whitespace up to `start`'s column, keeping tabs so the caret lines up.
+fn indent(text: &str, start: Position) -> String {
+    text.chars()
+        .take(start.column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect()
+}
+
+// At least one caret, and none past the end of the line.
+fn underline_width(text: &str, span: Span) -> usize {
+    let line_end = text.chars().count() + 1;
+    let end = if span.end.line == span.start.line {
+        span.end.column.min(line_end)
+    } else {
+        line_end
+    };
+    end.saturating_sub(span.start.column).max(1)
+}
+
+/// Whether to color diagnostics written to stderr: only on a terminal, and
+/// never when `NO_COLOR` is set.
+pub fn use_color() -> bool {
+    io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+}
+
+impl From<&ParseError> for Diagnostic {
+    fn from(err: &ParseError) -> Self {
+        let point = Span {
+            start: err.position,
+            end: err.position,
+        };
+        let message = format!("{}{}", err.kind.description(), err.detail());
+        let diagnostic = Diagnostic::new(message, Some(point));
+        if err.expected.as_deref() == Some("`;`") {
+            diagnostic.with_hint("the statement before this needs a `;`")
+        } else {
+            diagnostic
+        }
+    }
+}
+
+impl From<&SemanticError> for Diagnostic {
+    fn from(err: &SemanticError) -> Self {
+        match err {
+            SemanticError::UndeclaredVariable { name, span } => {
+                Diagnostic::new(format!("Undeclared variable {}", name), Some(*span))
+            }
+            SemanticError::DuplicateDeclaration {
+                name,
+                first_span,
+                second_span,
+            } => Diagnostic::new(
+                format!("Duplicate declaration of {}", name),
+                Some(*second_span),
+            )
+            .with_hint(format!(
+                "{} was first declared at {}",
+                name, first_span.start
+            )),
+            SemanticError::LiteralOutOfRange { value, span } => Diagnostic::new(
+                format!("Integer literal {} does not fit in 32 bits", value),
+                Some(*span),
+            ),
+            SemanticError::UndefinedFunction { .. }
+            | SemanticError::DuplicateFunction { .. }
+            | SemanticError::ArityMismatch { .. } => Diagnostic::new(err.to_string(), None),
+        }
+    }
+}
+
+impl From<&TypeError> for Diagnostic {
+    fn from(err: &TypeError) -> Self {
+        let message = format!(
+            "Type mismatch: expected {} but found {}",
+            err.expected, err.found
+        );
+        Diagnostic::new(message, Some(err.span))
+    }
+}
+
+impl From<&CodegenError> for Diagnostic {
+    fn from(err: &CodegenError) -> Self {
+        let span = match err {
+            CodegenError::UnboundVariable { span, .. }
+            | CodegenError::UndefinedFunction { span, .. }
+            | CodegenError::LiteralOutOfRange { span, .. } => Some(*span),
+            CodegenError::UnsupportedConstruct { span, .. } => *span,
+            CodegenError::TooManyRegisters | CodegenError::ProgramTooLarge { .. } => None,
+        };
+        // The span is shown underneath instead.
+        let message = match err {
+            CodegenError::UnboundVariable { name, .. } => format!("Unbound variable {}", name),
+            CodegenError::UndefinedFunction { name, .. } => format!("Undefined function {}", name),
+            CodegenError::UnsupportedConstruct { construct, .. } => {
+                format!("Codegen does not support {} yet", construct)
+            }
+            CodegenError::LiteralOutOfRange { value, .. } => {
+                format!("Integer literal {} does not fit in 16 bits", value)
+            }
+            _ => err.to_string(),
+        };
+        Diagnostic::new(message, span)
+    }
+}
+
+impl From<&AssemblerError> for Diagnostic {
+    fn from(err: &AssemblerError) -> Self {
+        Diagnostic::new(err.to_string(), None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_str;
+    use crate::{Assembler, codegen, semantic, typeck};
+
+    #[test]
+    fn test_missing_semicolon() {
+        let source = "int x = 1\nint y = 2;";
+        let errors = parse_str(source).unwrap_err();
+        assert_eq!(
+            Diagnostic::from(&errors[0]).render(source),
+            "\
+error: Unexpected token: expected `;`, found `int`
+ --> 2:1
+  |
+2 | int y = 2;
+  | ^
+  = hint: the statement before this needs a `;`"
+        );
+    }
+
+    #[test]
+    fn test_multi_character_span() {
+        let source = "int total = 0;\n\tPrint(totl + 1);";
+        let errors = semantic::check(&parse_str(source).unwrap()).unwrap_err();
+        assert_eq!(
+            Diagnostic::from(&errors[0]).render(source),
+            "\
+error: Undeclared variable totl
+ --> 2:8
+  |
+2 | \tPrint(totl + 1);
+  | \t      ^^^^"
+        );
+
+        let source = "float f = 1.5;\nint i = 2 * (f + 0.5);";
+        let errors = typeck::check(&parse_str(source).unwrap()).unwrap_err();
+        assert!(
+            Diagnostic::from(&errors[0])
+                .render(source)
+                .ends_with("2 | int i = 2 * (f + 0.5);\n  |             ^^^^^^^^^")
+        );
+    }
+
+    #[test]
+    fn test_codegen_error() {
+        let source = "Print(1);\nPrint(70000);";
+        let err = codegen(&parse_str(source).unwrap()).unwrap_err();
+        assert_eq!(
+            Diagnostic::from(&err).render(source),
+            "\
+error: Integer literal 70000 does not fit in 16 bits
+ --> 2:7
+  |
+2 | Print(70000);
+  |       ^^^^^"
+        );
+    }
+
+    #[test]
+    fn test_color_and_errors_without_a_location() {
+        let err = Assembler::new().compile("BOGUS r0").unwrap_err();
+        let diagnostic = Diagnostic::from(&err);
+        assert_eq!(diagnostic.render(""), "error: Unknown Instruction: BOGUS");
+        assert_eq!(
+            diagnostic.render_with_color("", true),
+            "\x1b[1;31merror\x1b[0m: Unknown Instruction: BOGUS"
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use std::fmt;
+
+use crate::vm::{Opcode, OperandKind, VMError};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Operand {
+    Register(u8),
+    FloatRegister(u8),
+    RegisterPair(u8),
+    Immediate(u16),
+    SignedImmediate(i16),
+    Float(f64),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DecodedInstruction {
+    pub offset: usize,
+    pub opcode: Opcode,
+    pub operands: Vec<Operand>,
+}
+
+impl DecodedInstruction {
+    pub fn new(offset: usize, opcode: Opcode, operands: Vec<Operand>) -> Self {
+        Self {
+            offset,
+            opcode,
+            operands,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.opcode.instruction_size()
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.push(u8::from(self.opcode));
+        for operand in &self.operands {
+            match operand {
+                Operand::Register(reg) | Operand::FloatRegister(reg) | Operand::RegisterPair(reg) => {
+                    out.push(*reg)
+                }
+                Operand::Immediate(value) => out.extend_from_slice(&value.to_be_bytes()),
+                Operand::SignedImmediate(value) => out.extend_from_slice(&value.to_be_bytes()),
+                Operand::Float(value) => out.extend_from_slice(&value.to_bits().to_be_bytes()),
+            }
+        }
+    }
+
+    pub fn register(&self, index: usize) -> Option<u8> {
+        match self.operands.get(index) {
+            Some(Operand::Register(reg)) => Some(*reg),
+            _ => None,
+        }
+    }
+
+    pub fn register_pair(&self, index: usize) -> Option<u8> {
+        match self.operands.get(index) {
+            Some(Operand::RegisterPair(reg)) => Some(*reg),
+            _ => None,
+        }
+    }
+
+    pub fn immediate(&self, index: usize) -> Option<u16> {
+        match self.operands.get(index) {
+            Some(Operand::Immediate(value)) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DecodedInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.opcode)?;
+        for operand in &self.operands {
+            match operand {
+                Operand::Register(reg) => write!(f, " r{}", reg)?,
+                Operand::FloatRegister(reg) => write!(f, " f{}", reg)?,
+                Operand::RegisterPair(reg) => write!(f, " r{}:r{}", reg, *reg as usize + 1)?,
+                Operand::Immediate(value) => write!(f, " {}", value)?,
+                Operand::SignedImmediate(value) => write!(f, " {}", value)?,
+                Operand::Float(value) => write!(f, " {:?}", value)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn decode(program: &[u8]) -> Result<Vec<DecodedInstruction>, VMError> {
+    instructions(program).collect()
+}
+
+/// Lazily decodes `program`. A truncated trailing instruction yields an
+/// error and ends the iteration.
+pub fn instructions(
+    program: &[u8],
+) -> impl Iterator<Item = Result<DecodedInstruction, VMError>> + '_ {
+    let mut pc = 0;
+    let mut failed = false;
+
+    std::iter::from_fn(move || {
+        if pc >= program.len() || failed {
+            return None;
+        }
+
+        let offset = pc;
+        let opcode = Opcode::from(program[pc]);
+        pc += 1;
+
+        let mut operands = Vec::new();
+        for kind in opcode.operands() {
+            if pc + kind.size() > program.len() {
+                failed = true;
+                return Some(Err(VMError::ProgramCounterOutOfBounds));
+            }
+            operands.push(match kind {
+                OperandKind::Register => Operand::Register(program[pc]),
+                OperandKind::FloatRegister => Operand::FloatRegister(program[pc]),
+                OperandKind::RegisterPair => Operand::RegisterPair(program[pc]),
+                OperandKind::Immediate => {
+                    Operand::Immediate(u16::from_be_bytes([program[pc], program[pc + 1]]))
+                }
+                OperandKind::SignedImmediate => Operand::SignedImmediate(i16::from_be_bytes([
+                    program[pc],
+                    program[pc + 1],
+                ])),
+                OperandKind::Float => Operand::Float(f64::from_bits(u64::from_be_bytes(
+                    program[pc..pc + 8].try_into().unwrap(),
+                ))),
+            });
+            pc += kind.size();
+        }
+
+        Some(Ok(DecodedInstruction::new(offset, opcode, operands)))
+    })
+}
+
+pub fn encode(instructions: &[DecodedInstruction]) -> Vec<u8> {
+    let mut bytecode = Vec::new();
+    for instruction in instructions {
+        instruction.encode(&mut bytecode);
+    }
+    bytecode
+}
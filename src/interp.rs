@@ -0,0 +1,764 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::ast::{BinOp, Expr, Function, Program, Spanned, Statement};
+
+/// A runtime value. Comparisons outside conditions evaluate to an int, 1 or
+/// 0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Int(i32),
+    Float(f64),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Float(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// Where `eval` sends `Print` and gets `read()` values from.
+pub trait InterpIo {
+    fn print(&mut self, value: Value);
+    fn print_str(&mut self, text: &str);
+    /// The next input value, or None when there is no more.
+    fn read(&mut self) -> Option<i32>;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum InterpError {
+    DivisionByZero,
+    InputUnavailable,
+    UndeclaredVariable(String),
+    UndefinedFunction(String),
+    ArityMismatch {
+        function: String,
+        expected: usize,
+        found: usize,
+    },
+    /// An int where a float is needed or the other way round.
+    TypeMismatch,
+}
+
+/// Runs `program` directly from its AST: the top-level statements, then
+/// `main` if the program defines functions.
+///
+/// Integer arithmetic follows the VM: a result that overflows `i32` is 0,
+/// and dividing by zero is an error. Float arithmetic is IEEE 754.
+pub fn eval(program: &Program, io: &mut dyn InterpIo) -> Result<(), InterpError> {
+    let mut interp = Interp {
+        functions: program
+            .functions
+            .iter()
+            .map(|function| (function.name.as_str(), function))
+            .collect(),
+        scopes: vec![HashMap::new()],
+        io,
+    };
+
+    interp.exec_block(&program.statements)?;
+    if interp.functions.contains_key("main") {
+        interp.call("main", Vec::new())?;
+    }
+    Ok(())
+}
+
+struct Interp<'a, 'io> {
+    functions: HashMap<&'a str, &'a Function>,
+    scopes: Vec<HashMap<String, Value>>,
+    io: &'io mut dyn InterpIo,
+}
+
+type Flow = Result<Option<Value>, InterpError>;
+
+impl Interp<'_, '_> {
+    // Returns the value of a `return` that ends the block early.
+    fn exec_block(&mut self, statements: &[Spanned<Statement>]) -> Flow {
+        self.scopes.push(HashMap::new());
+        let result = self.exec_statements(statements);
+        self.scopes.pop();
+        result
+    }
+
+    fn exec_statements(&mut self, statements: &[Spanned<Statement>]) -> Flow {
+        for statement in statements {
+            if let Some(value) = self.exec(statement)? {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    fn exec(&mut self, statement: &Statement) -> Flow {
+        match statement {
+            Statement::Declare(var, _, expr) => {
+                let value = self.eval_expr(expr)?;
+                if let Some(scope) = self.scopes.last_mut() {
+                    scope.insert(var.clone(), value);
+                }
+            }
+            Statement::Assign(var, expr) => {
+                let value = self.eval_expr(expr)?;
+                let slot = self
+                    .scopes
+                    .iter_mut()
+                    .rev()
+                    .find_map(|scope| scope.get_mut(var))
+                    .ok_or_else(|| InterpError::UndeclaredVariable(var.clone()))?;
+                *slot = value;
+            }
+            Statement::Print(expr) => {
+                let value = self.eval_expr(expr)?;
+                self.io.print(value);
+            }
+            Statement::PrintString(text) => self.io.print_str(text),
+            Statement::Expr(expr) => {
+                self.eval_expr(expr)?;
+            }
+            Statement::Return(expr) => return self.eval_expr(expr).map(Some),
+            Statement::Block(body) => return self.exec_block(body),
+            Statement::If { cond, then, else_ } => {
+                if self.eval_condition(cond)? {
+                    return self.exec_block(then);
+                } else if let Some(else_) = else_ {
+                    return self.exec_block(else_);
+                }
+            }
+            Statement::While { cond, body } => {
+                while self.eval_condition(cond)? {
+                    if let Some(value) = self.exec_block(body)? {
+                        return Ok(Some(value));
+                    }
+                }
+            }
+            Statement::For {
+                init,
+                cond,
+                step,
+                body,
+            } => {
+                self.scopes.push(HashMap::new());
+                let result = self.exec_for(init.as_deref(), cond.as_ref(), step.as_deref(), body);
+                self.scopes.pop();
+                return result;
+            }
+        }
+        Ok(None)
+    }
+
+    fn exec_for(
+        &mut self,
+        init: Option<&Spanned<Statement>>,
+        cond: Option<&Spanned<Expr>>,
+        step: Option<&Spanned<Statement>>,
+        body: &[Spanned<Statement>],
+    ) -> Flow {
+        if let Some(init) = init {
+            self.exec(init)?;
+        }
+        // A missing condition loops forever.
+        while match cond {
+            Some(cond) => self.eval_condition(cond)?,
+            None => true,
+        } {
+            if let Some(value) = self.exec_block(body)? {
+                return Ok(Some(value));
+            }
+            if let Some(step) = step {
+                self.exec(step)?;
+            }
+        }
+        Ok(None)
+    }
+
+    // A condition holds when its comparison does, or when it is nonzero.
+    fn eval_condition(&mut self, cond: &Expr) -> Result<bool, InterpError> {
+        if let Expr::BinOp(left, op, right) = cond
+            && op.is_comparison()
+        {
+            let left = self.eval_expr(left)?;
+            let right = self.eval_expr(right)?;
+            return compare(left, op, right);
+        }
+        match self.eval_expr(cond)? {
+            Value::Int(value) => Ok(value != 0),
+            Value::Float(value) => Ok(value != 0.0),
+        }
+    }
+
+    fn eval_expr(&mut self, expr: &Expr) -> Result<Value, InterpError> {
+        match expr {
+            Expr::Variable(var) => self
+                .scopes
+                .iter()
+                .rev()
+                .find_map(|scope| scope.get(var).copied())
+                .ok_or_else(|| InterpError::UndeclaredVariable(var.clone())),
+            // Wider literals don't assemble, so there's nothing to match.
+            Expr::Literal(value) => Ok(Value::Int(*value as i32)),
+            Expr::FloatLiteral(value) => Ok(Value::Float(*value)),
+            Expr::Read => self
+                .io
+                .read()
+                .map(Value::Int)
+                .ok_or(InterpError::InputUnavailable),
+            Expr::BinOp(left, op, right) => {
+                let left = self.eval_expr(left)?;
+                let right = self.eval_expr(right)?;
+                if op.is_comparison() {
+                    return compare(left, op, right).map(|holds| Value::Int(holds as i32));
+                }
+                arithmetic(left, op, right)
+            }
+            Expr::Conditional { cond, then, else_ } => {
+                if self.eval_condition(cond)? {
+                    self.eval_expr(then)
+                } else {
+                    self.eval_expr(else_)
+                }
+            }
+            Expr::Call { function, args } => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval_expr(arg))
+                    .collect::<Result<_, _>>()?;
+                self.call(function, args)
+            }
+        }
+    }
+
+    // A function sees only its parameters, and returns 0 if it falls off
+    // the end.
+    fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, InterpError> {
+        let function = *self
+            .functions
+            .get(name)
+            .ok_or_else(|| InterpError::UndefinedFunction(name.to_string()))?;
+        if function.params.len() != args.len() {
+            return Err(InterpError::ArityMismatch {
+                function: name.to_string(),
+                expected: function.params.len(),
+                found: args.len(),
+            });
+        }
+
+        let params = function.params.iter().map(|param| param.node.clone());
+        let caller = std::mem::replace(&mut self.scopes, vec![params.zip(args).collect()]);
+        let result = self.exec_block(&function.body);
+        self.scopes = caller;
+        Ok(result?.unwrap_or(Value::Int(0)))
+    }
+}
+
+fn arithmetic(left: Value, op: &BinOp, right: Value) -> Result<Value, InterpError> {
+    match (left, right) {
+        (Value::Int(a), Value::Int(b)) => {
+            let result = match op {
+                BinOp::Add => a.checked_add(b),
+                BinOp::Sub => a.checked_sub(b),
+                BinOp::Mul => a.checked_mul(b),
+                BinOp::Div if b == 0 => return Err(InterpError::DivisionByZero),
+                BinOp::Div => a.checked_div(b),
+                _ => unreachable!("comparisons are handled by `compare`"),
+            };
+            Ok(Value::Int(result.unwrap_or(0)))
+        }
+        (Value::Float(a), Value::Float(b)) => Ok(Value::Float(match op {
+            BinOp::Add => a + b,
+            BinOp::Sub => a - b,
+            BinOp::Mul => a * b,
+            BinOp::Div => a / b,
+            _ => unreachable!("comparisons are handled by `compare`"),
+        })),
+        _ => Err(InterpError::TypeMismatch),
+    }
+}
+
+fn compare(left: Value, op: &BinOp, right: Value) -> Result<bool, InterpError> {
+    let ordering = match (left, right) {
+        (Value::Int(a), Value::Int(b)) => a.partial_cmp(&b),
+        (Value::Float(a), Value::Float(b)) => a.partial_cmp(&b),
+        _ => return Err(InterpError::TypeMismatch),
+    };
+    // Every comparison with NaN is false except `!=`.
+    let Some(ordering) = ordering else {
+        return Ok(*op == BinOp::NotEqual);
+    };
+    Ok(match op {
+        BinOp::Less => ordering.is_lt(),
+        BinOp::Greater => ordering.is_gt(),
+        BinOp::LessEqual => ordering.is_le(),
+        BinOp::GreaterEqual => ordering.is_ge(),
+        BinOp::Equal => ordering.is_eq(),
+        BinOp::NotEqual => ordering.is_ne(),
+        _ => unreachable!("arithmetic is handled by `arithmetic`"),
+    })
+}
+
+impl fmt::Display for InterpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterpError::DivisionByZero => write!(f, "Division by zero"),
+            InterpError::InputUnavailable => write!(f, "No input available for read()"),
+            InterpError::UndeclaredVariable(name) => write!(f, "Undeclared variable {}", name),
+            InterpError::UndefinedFunction(name) => write!(f, "Undefined function: {}", name),
+            InterpError::ArityMismatch {
+                function,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Function {} takes {} argument(s) but {} were given",
+                function, expected, found
+            ),
+            InterpError::TypeMismatch => write!(f, "Operands of different types"),
+        }
+    }
+}
+
+impl std::error::Error for InterpError {}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::parser::parse_str;
+    use crate::pipeline;
+    use crate::vm::{VM, VMError};
+
+    #[derive(Default)]
+    struct Captured {
+        input: VecDeque<i32>,
+        output: String,
+    }
+
+    // Prints the way the VM does.
+    impl InterpIo for Captured {
+        fn print(&mut self, value: Value) {
+            self.output.push_str(&format!("PRINT: {}\n", value));
+        }
+
+        fn print_str(&mut self, text: &str) {
+            self.output.push_str(text);
+            self.output.push('\n');
+        }
+
+        fn read(&mut self) -> Option<i32> {
+            self.input.pop_front()
+        }
+    }
+
+    fn interpret(source: &str, input: &[i32]) -> Result<String, InterpError> {
+        let mut io = Captured {
+            input: input.iter().copied().collect(),
+            ..Captured::default()
+        };
+        eval(&parse_str(source).unwrap(), &mut io)?;
+        Ok(io.output)
+    }
+
+    fn run_vm(source: &str, input: &[i32]) -> Result<String, VMError> {
+        let mut vm = VM::new();
+        vm.add_program(pipeline::compile(source).unwrap());
+        vm.capture_output();
+        vm.set_input(input.iter().copied());
+        vm.run()?;
+        Ok(vm.take_output())
+    }
+
+    // Runs `source` both ways and returns the output they agree on.
+    fn differential(source: &str, input: &[i32]) -> String {
+        let interpreted = interpret(source, input).unwrap();
+        assert_eq!(interpreted, run_vm(source, input).unwrap(), "{}", source);
+        interpreted
+    }
+
+    #[test]
+    fn test_matches_pipeline_on_arithmetic() {
+        let output = differential(
+            "int a = 7; int b = 2; Print(a / b); Print(a - b * 5); Print((0 - a) / b);",
+            &[],
+        );
+        assert_eq!(output, "PRINT: 3\nPRINT: -3\nPRINT: -3\n");
+        differential(
+            "float x = 1.5; float y = x * 3.0; Print(y / 0.5); Print(x - y);",
+            &[],
+        );
+    }
+
+    #[test]
+    fn test_matches_pipeline_on_overflow() {
+        let output = differential(
+            "int a = 50000; int b = a * a; Print(b); int c = 45000 * a; Print(c + 1);",
+            &[],
+        );
+        assert_eq!(output, "PRINT: 0\nPRINT: 1\n");
+    }
+
+    #[test]
+    fn test_matches_pipeline_on_algebraic_identities() {
+        let output = differential(
+            "int x = read(); int y = x * 1 + 0; Print(y - y + 0 * x); Print(read() * 0 + x - 0);",
+            &[7, 9],
+        );
+        assert_eq!(output, "PRINT: 0\nPRINT: 7\n");
+    }
+
+    #[test]
+    fn test_matches_pipeline_on_calls() {
+        let sources = [
+            "fn fib(n) { if (n < 2) { return n; } return fib(n - 1) + fib(n - 2); } fn main() { Print(fib(read())); return 0; }",
+            "fn sub(a, b) { return a - b; } fn main() { return 0; } int x = read(); Print(x * 10 + sub(x, sub(1, x)));",
+            "fn f(n) { int t = n * 2; } fn main() { int k = 3; Print(f(k) + k); return 1; }",
+            "fn step(n) { Print(n); return n; } fn main() { int i = 3; while (step(i)) { i -= 1; } return 0; }",
+        ];
+        for source in sources {
+            differential(source, &[10]);
+        }
+        assert_eq!(differential(sources[0], &[10]), "PRINT: 55\n");
+        assert_eq!(differential(sources[1], &[10]), "PRINT: 119\n");
+    }
+
+    #[test]
+    fn test_matches_pipeline_on_loops_and_scopes() {
+        let sources = [
+            "int total = 0; for (int i = 1; i <= read(); i += 1) { total += i; } Print(total);",
+            "int n = read(); while (n) { Print(n); n -= 1; }",
+            "int x = 1; { int x = 2; Print(x); } Print(x); let y = x; y = 5; Print(x);",
+            "int i = 0; while (i != 3) { i += 1; } Print(i);",
+            "int a = read(); Print(a > 2 ? a - 2 : 0 ? 1 : a);",
+            "int a = read(); int b = a < 5; let c = a == 4; b = b + c; Print(b);",
+            "int a = read(); if (a > 3) { int b = a * 2; Print(b); } else if (a) { Print(1); }",
+            "int n = read(); while (n) { Print(\"n is\"); Print(n); n -= 1; } Print(\"n is\");",
+        ];
+        for source in sources {
+            differential(source, &[4, 4, 4, 4, 4, 4]);
+        }
+        assert_eq!(differential(sources[1], &[2]), "PRINT: 2\nPRINT: 1\n");
+    }
+
+    #[test]
+    fn test_runtime_errors_match_pipeline() {
+        let source = "int a = 1; int b = 0; Print(a / b);";
+        assert_eq!(interpret(source, &[]), Err(InterpError::DivisionByZero));
+        assert_eq!(run_vm(source, &[]), Err(VMError::DivisionByZero));
+
+        let source = "Print(read());";
+        assert_eq!(interpret(source, &[]), Err(InterpError::InputUnavailable));
+        assert_eq!(run_vm(source, &[]), Err(VMError::InputUnavailable));
+    }
+
+    #[test]
+    fn test_functions_and_returns() {
+        // Codegen doesn't support functions yet, so there's no pipeline
+        // output to compare against.
+        let source = "
+            fn fib(n) {
+                int a = 0; int b = 1;
+                for (int i = 0; i < n; i += 1) { int t = a + b; a = b; b = t; }
+                return a;
+            }
+            fn main() { Print(fib(10)); Print(fib(read())); }
+        ";
+        assert_eq!(interpret(source, &[1]).unwrap(), "PRINT: 55\nPRINT: 1\n");
+    }
+
+    #[test]
+    fn test_call_as_statement_discards_result() {
+        let source = "
+            fn show(n) { Print(n * 2); return n; }
+            fn main() { show(read()); show(4) + 1; }
+        ";
+        assert_eq!(interpret(source, &[3]).unwrap(), "PRINT: 6\nPRINT: 8\n");
+    }
+
+    // Random valid programs, run both ways. Generation is bounded: small
+    // integer expressions over variables in scope, loops with a counter and
+    // a fixed trip count, divisors that are nonzero literals, and helper
+    // functions that only call the ones defined before them.
+    mod properties {
+        use super::*;
+        use crate::ast::{
+            BinOp, Expr, Function, Position, Program, Span, Spanned, Statement, Type,
+        };
+        use crate::codegen::{Backend, CodegenOptions};
+        use crate::pipeline::CompileOptions;
+
+        const CASES: u64 = 300;
+        const INPUT: [i32; 8] = [3, -7, 0, 12, 1, 40000, -2, 5];
+        // At most two loops nest, each running at most three times.
+        const MAX_TRIPS: usize = 9;
+
+        // xorshift64*, so every case is reproducible from its seed.
+        struct Rng(u64);
+
+        impl Rng {
+            fn below(&mut self, n: u64) -> u64 {
+                self.0 ^= self.0 >> 12;
+                self.0 ^= self.0 << 25;
+                self.0 ^= self.0 >> 27;
+                self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) % n
+            }
+
+            fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+                &items[self.below(items.len() as u64) as usize]
+            }
+        }
+
+        fn spanned<T>(node: T) -> Spanned<T> {
+            let position = Position {
+                offset: 0,
+                line: 1,
+                column: 1,
+            };
+            Spanned::new(
+                node,
+                Span {
+                    start: position,
+                    end: position,
+                },
+            )
+        }
+
+        struct Generator {
+            rng: Rng,
+            // The variables in each enclosing block, and whether they may be
+            // assigned; loop counters may not.
+            scopes: Vec<Vec<(String, bool)>>,
+            names: usize,
+            reads: usize,
+            // The helpers defined so far, with their parameter counts.
+            functions: Vec<(String, usize)>,
+            // Off in helpers, which may run any number of times.
+            may_read: bool,
+        }
+
+        impl Generator {
+            fn program(seed: u64) -> Program {
+                let mut generator = Generator {
+                    rng: Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1),
+                    scopes: vec![Vec::new()],
+                    names: 0,
+                    reads: 0,
+                    functions: Vec::new(),
+                    may_read: true,
+                };
+                let mut functions: Vec<Function> = (0..generator.rng.below(4))
+                    .map(|index| generator.helper(index))
+                    .collect();
+                let statements = (0..1 + generator.rng.below(8))
+                    .flat_map(|_| generator.statement(2))
+                    .collect();
+                if !functions.is_empty() {
+                    generator.scopes = vec![Vec::new()];
+                    let body = generator.block(1);
+                    functions.push(Function {
+                        name: "main".to_string(),
+                        params: Vec::new(),
+                        body,
+                    });
+                }
+                Program {
+                    functions,
+                    statements,
+                }
+            }
+
+            // `fn hN(p, ...) { ...; return e; }`, seeing only its parameters.
+            fn helper(&mut self, index: u64) -> Function {
+                let params: Vec<String> = (0..1 + self.rng.below(3))
+                    .map(|param| format!("p{}", param))
+                    .collect();
+                self.scopes = vec![params.iter().map(|param| (param.clone(), true)).collect()];
+                self.may_read = false;
+                let mut body = self.block(1);
+                body.push(spanned(Statement::Return(self.expr(2))));
+                self.may_read = true;
+                self.scopes = vec![Vec::new()];
+
+                let name = format!("h{}", index);
+                self.functions.push((name.clone(), params.len()));
+                Function {
+                    name,
+                    params: params.into_iter().map(spanned).collect(),
+                    body,
+                }
+            }
+
+            fn declare(&mut self, assignable: bool) -> String {
+                let name = format!("v{}", self.names);
+                self.names += 1;
+                self.scopes
+                    .last_mut()
+                    .unwrap()
+                    .push((name.clone(), assignable));
+                name
+            }
+
+            fn variables(&self, assignable: bool) -> Vec<String> {
+                self.scopes
+                    .iter()
+                    .flatten()
+                    .filter(|(_, can_assign)| *can_assign || !assignable)
+                    .map(|(name, _)| name.clone())
+                    .collect()
+            }
+
+            fn statement(&mut self, depth: usize) -> Vec<Spanned<Statement>> {
+                let assignable = self.variables(true);
+                let statement = match self.rng.below(if depth == 0 { 3 } else { 5 }) {
+                    0 => {
+                        let expr = self.expr(2);
+                        let name = self.declare(true);
+                        Statement::Declare(name, Some(Type::Int), expr)
+                    }
+                    1 if !assignable.is_empty() => {
+                        let name = self.rng.pick(&assignable).clone();
+                        Statement::Assign(name, self.expr(2))
+                    }
+                    3 => Statement::If {
+                        cond: self.comparison(1),
+                        then: self.block(depth - 1),
+                        else_: (self.rng.below(2) == 0).then(|| self.block(depth - 1)),
+                    },
+                    4 => return self.counted_loop(depth),
+                    2 if !self.functions.is_empty() => Statement::Expr(self.call(1)),
+                    _ => Statement::Print(self.expr(2)),
+                };
+                vec![spanned(statement)]
+            }
+
+            // `int c = 0; while (c < n) { ...; c = c + 1; }`
+            fn counted_loop(&mut self, depth: usize) -> Vec<Spanned<Statement>> {
+                let counter = self.declare(false);
+                let variable = || spanned(Expr::Variable(counter.clone()));
+                let literal = |value| spanned(Expr::Literal(value));
+                let trips = self.rng.below(4) as i64;
+                let mut body = self.block(depth - 1);
+                body.push(spanned(Statement::Assign(
+                    counter.clone(),
+                    spanned(Expr::BinOp(
+                        Box::new(variable()),
+                        BinOp::Add,
+                        Box::new(literal(1)),
+                    )),
+                )));
+                vec![
+                    spanned(Statement::Declare(
+                        counter.clone(),
+                        Some(Type::Int),
+                        literal(0),
+                    )),
+                    spanned(Statement::While {
+                        cond: spanned(Expr::BinOp(
+                            Box::new(variable()),
+                            BinOp::Less,
+                            Box::new(literal(trips)),
+                        )),
+                        body,
+                    }),
+                ]
+            }
+
+            fn block(&mut self, depth: usize) -> Vec<Spanned<Statement>> {
+                self.scopes.push(Vec::new());
+                let statements = (0..1 + self.rng.below(3))
+                    .flat_map(|_| self.statement(depth))
+                    .collect();
+                self.scopes.pop();
+                statements
+            }
+
+            fn expr(&mut self, depth: usize) -> Spanned<Expr> {
+                let variables = self.variables(false);
+                let choices = if depth == 0 { 3 } else { 8 };
+                let expr = match self.rng.below(choices) {
+                    0 if !variables.is_empty() => Expr::Variable(self.rng.pick(&variables).clone()),
+                    1 if self.may_read && self.reads < INPUT.len() => {
+                        self.reads += 1;
+                        Expr::Read
+                    }
+                    3 | 4 => {
+                        let op = self.rng.pick(&[BinOp::Add, BinOp::Sub, BinOp::Mul]).clone();
+                        Expr::BinOp(
+                            Box::new(self.expr(depth - 1)),
+                            op,
+                            Box::new(self.expr(depth - 1)),
+                        )
+                    }
+                    5 => Expr::BinOp(
+                        Box::new(self.expr(depth - 1)),
+                        BinOp::Div,
+                        Box::new(spanned(Expr::Literal(1 + self.rng.below(9) as i64))),
+                    ),
+                    6 => Expr::Conditional {
+                        cond: Box::new(self.comparison(depth - 1)),
+                        then: Box::new(self.expr(depth - 1)),
+                        else_: Box::new(self.expr(depth - 1)),
+                    },
+                    7 if !self.functions.is_empty() => return self.call(depth - 1),
+                    _ => Expr::Literal(self.rng.below(1000) as i64),
+                };
+                spanned(expr)
+            }
+
+            fn call(&mut self, depth: usize) -> Spanned<Expr> {
+                let (function, params) = self.rng.pick(&self.functions).clone();
+                let args = (0..params).map(|_| self.expr(depth)).collect();
+                spanned(Expr::Call { function, args })
+            }
+
+            fn comparison(&mut self, depth: usize) -> Spanned<Expr> {
+                let op = self
+                    .rng
+                    .pick(&[
+                        BinOp::Less,
+                        BinOp::Greater,
+                        BinOp::LessEqual,
+                        BinOp::GreaterEqual,
+                        BinOp::Equal,
+                        BinOp::NotEqual,
+                    ])
+                    .clone();
+                spanned(Expr::BinOp(
+                    Box::new(self.expr(depth)),
+                    op,
+                    Box::new(self.expr(depth)),
+                ))
+            }
+        }
+
+        #[test]
+        fn test_random_programs_match_the_pipeline() {
+            // A read in a loop runs on every trip.
+            let input: Vec<i32> = INPUT
+                .iter()
+                .copied()
+                .cycle()
+                .take(INPUT.len() * MAX_TRIPS)
+                .collect();
+            let bytecode = CompileOptions {
+                codegen: CodegenOptions {
+                    backend: Backend::Bytecode,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            for seed in 0..CASES {
+                let source = Generator::program(seed).to_source();
+                let output = differential(&source, &input);
+
+                let mut vm = VM::new();
+                vm.add_program(pipeline::compile_with(&source, &bytecode).unwrap());
+                vm.capture_output();
+                vm.set_input(input.iter().copied());
+                vm.run().unwrap();
+                assert_eq!(vm.take_output(), output, "{}", source);
+            }
+        }
+    }
+}
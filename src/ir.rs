@@ -0,0 +1,1016 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::ast::{BinOp, Expr, Function, Program, Span, Spanned, Statement};
+use crate::codegen::CodegenError;
+use crate::opt::{fold_arithmetic, has_side_effects};
+
+type LowerResult<T> = Result<T, CodegenError>;
+
+/// The register bank a value lives in. Bools are integers.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+pub enum Bank {
+    Int,
+    Float,
+}
+
+/// A virtual register. Lowering makes as many as it needs, numbered from 0
+/// in each bank of each `Body`; register allocation maps them onto the
+/// VM's.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+pub struct VReg {
+    pub bank: Bank,
+    pub index: usize,
+}
+
+/// One instruction. Each writes at most one register, from at most two
+/// others. A comparison sets the VM's equal flag, which `Select` and
+/// `JumpUnless` then read.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Inst {
+    Const {
+        dest: VReg,
+        value: i64,
+    },
+    FloatConst {
+        dest: VReg,
+        value: f64,
+    },
+    /// The address of a label, such as a string literal's.
+    Address {
+        dest: VReg,
+        label: String,
+    },
+    Read {
+        dest: VReg,
+    },
+    Move {
+        dest: VReg,
+        src: VReg,
+    },
+    /// Arithmetic; `op` is never a comparison.
+    Binary {
+        dest: VReg,
+        op: BinOp,
+        left: VReg,
+        right: VReg,
+    },
+    /// Sets the flag to whether `left op right` holds.
+    Compare {
+        op: BinOp,
+        left: VReg,
+        right: VReg,
+    },
+    /// `dest = src` if the flag is set.
+    Select {
+        dest: VReg,
+        src: VReg,
+    },
+    Print {
+        src: VReg,
+    },
+    /// Prints the string at the address in `src`.
+    PrintString {
+        src: VReg,
+    },
+    /// `span` is for errors register allocation finds; the call to `main`
+    /// has none.
+    Call {
+        dest: Option<VReg>,
+        function: String,
+        args: Vec<VReg>,
+        span: Option<Span>,
+    },
+    /// Loads from and stores to a spill slot. Only register allocation adds
+    /// these.
+    Reload {
+        dest: VReg,
+        address: u16,
+    },
+    Spill {
+        src: VReg,
+        address: u16,
+    },
+    /// `var` is bound to `reg` from here on. Not a use of `reg`.
+    Bind {
+        var: String,
+        reg: VReg,
+    },
+    /// The source line of the instructions that follow.
+    Source(usize),
+    Label(String),
+    Jump(String),
+    /// Jumps to the label unless the flag is set.
+    JumpUnless(String),
+    /// Returns `reg`, or 0 when there is none.
+    Return(Option<VReg>),
+    Halt,
+}
+
+impl Inst {
+    /// The registers this instruction reads, in operand order.
+    pub fn uses(&self) -> Vec<VReg> {
+        match self {
+            Inst::Move { src, .. }
+            | Inst::Print { src }
+            | Inst::PrintString { src }
+            | Inst::Spill { src, .. } => vec![*src],
+            Inst::Binary { left, right, .. } | Inst::Compare { left, right, .. } => {
+                vec![*left, *right]
+            }
+            // The destination keeps its value when the flag is clear.
+            Inst::Select { dest, src } => vec![*dest, *src],
+            Inst::Call { args, .. } => args.clone(),
+            Inst::Return(Some(reg)) => vec![*reg],
+            _ => Vec::new(),
+        }
+    }
+
+    /// The register this instruction writes, if any.
+    pub fn def(&self) -> Option<VReg> {
+        match self {
+            Inst::Const { dest, .. }
+            | Inst::FloatConst { dest, .. }
+            | Inst::Address { dest, .. }
+            | Inst::Read { dest }
+            | Inst::Move { dest, .. }
+            | Inst::Binary { dest, .. }
+            | Inst::Select { dest, .. }
+            | Inst::Reload { dest, .. } => Some(*dest),
+            Inst::Call { dest, .. } => *dest,
+            _ => None,
+        }
+    }
+
+    /// Replaces every use and definition of `from` with `to`.
+    pub fn rename(&mut self, from: VReg, to: VReg) {
+        let registers: Vec<&mut VReg> = match self {
+            Inst::Const { dest, .. }
+            | Inst::FloatConst { dest, .. }
+            | Inst::Address { dest, .. }
+            | Inst::Read { dest }
+            | Inst::Reload { dest, .. } => vec![dest],
+            Inst::Move { dest, src } | Inst::Select { dest, src } => vec![dest, src],
+            Inst::Binary {
+                dest, left, right, ..
+            } => vec![dest, left, right],
+            Inst::Compare { left, right, .. } => vec![left, right],
+            Inst::Print { src } | Inst::PrintString { src } | Inst::Spill { src, .. } => {
+                vec![src]
+            }
+            Inst::Call { dest, args, .. } => dest.iter_mut().chain(args.iter_mut()).collect(),
+            Inst::Return(Some(reg)) => vec![reg],
+            _ => Vec::new(),
+        };
+        for reg in registers {
+            if *reg == from {
+                *reg = to;
+            }
+        }
+    }
+}
+
+/// Code with registers of its own: the top-level statements, or one
+/// function.
+#[derive(Debug, PartialEq)]
+pub struct Body {
+    /// The registers arguments arrive in, in order. Empty at the top level.
+    pub params: Vec<VReg>,
+    pub insts: Vec<Inst>,
+    /// Registers bound to a variable, which are the ones that may be
+    /// spilled.
+    pub variables: Vec<VReg>,
+    pub is_function: bool,
+    // Registers made so far in each bank.
+    counts: [usize; 2],
+}
+
+impl Body {
+    pub fn new(is_function: bool) -> Self {
+        Body {
+            params: Vec::new(),
+            insts: Vec::new(),
+            variables: Vec::new(),
+            is_function,
+            counts: [0; 2],
+        }
+    }
+
+    /// A register not used anywhere in the body yet.
+    pub fn fresh(&mut self, bank: Bank) -> VReg {
+        let count = &mut self.counts[bank as usize];
+        *count += 1;
+        VReg {
+            bank,
+            index: *count - 1,
+        }
+    }
+}
+
+/// A lowered program.
+#[derive(Debug, PartialEq)]
+pub struct Module {
+    /// The top-level statements, then the call to `main` if there is one,
+    /// then `Halt`.
+    pub top_level: Body,
+    /// Each starting at its `function_label`.
+    pub functions: Vec<Body>,
+    /// String literals in the order first printed, without repeats, each at
+    /// its `string_label`.
+    pub strings: Vec<String>,
+}
+
+/// Has instructions write straight to the register a `Move` right after
+/// them copies their result to, when nothing else reads the result. A
+/// `Select` keeps its destination, which it may leave unchanged.
+pub fn fold_moves(body: &mut Body) {
+    let mut reads: HashMap<VReg, usize> = HashMap::new();
+    for reg in body.insts.iter().flat_map(Inst::uses) {
+        *reads.entry(reg).or_default() += 1;
+    }
+
+    for inst in std::mem::take(&mut body.insts) {
+        if let Inst::Move { dest, src } = inst
+            && reads[&src] == 1
+            && !body.variables.contains(&src)
+            && let Some(last) = body.insts.last_mut()
+            && last.def() == Some(src)
+            && !matches!(last, Inst::Select { .. })
+        {
+            last.rename(src, dest);
+            continue;
+        }
+        body.insts.push(inst);
+    }
+}
+
+pub(crate) fn function_label(name: &str) -> String {
+    format!(".Lfn_{}", name)
+}
+
+pub(crate) fn string_label(index: usize) -> String {
+    format!(".Lstr_{}", index)
+}
+
+/// Lowers `program`. The top-level statements run first, then `main` if
+/// there is one, as in `interp::eval`.
+pub fn lower(program: &Program) -> LowerResult<Module> {
+    let mut lowering = Lowering::new(program);
+    for statement in &program.statements {
+        lowering.statement(statement)?;
+    }
+    if lowering.functions.contains(&"main") {
+        lowering.emit(Inst::Call {
+            dest: None,
+            function: "main".to_string(),
+            args: Vec::new(),
+            span: None,
+        });
+    }
+    lowering.emit(Inst::Halt);
+    let top_level = std::mem::replace(&mut lowering.body, Body::new(true));
+
+    let functions = program
+        .functions
+        .iter()
+        .map(|function| lowering.function(function))
+        .collect::<LowerResult<_>>()?;
+    Ok(Module {
+        top_level,
+        functions,
+        strings: lowering.strings,
+    })
+}
+
+/// The top-level statements, up to the first that fails to lower.
+pub(crate) fn lower_statements(program: &Program) -> Body {
+    let mut lowering = Lowering::new(program);
+    for statement in &program.statements {
+        if lowering.statement(statement).is_err() {
+            break;
+        }
+    }
+    lowering.body
+}
+
+struct Lowering<'a> {
+    functions: Vec<&'a str>,
+    body: Body,
+    scopes: Vec<HashMap<String, VReg>>,
+    label_counter: usize,
+    strings: Vec<String>,
+    // The line of the statement being lowered, and of the last
+    // `Inst::Source`, which `emit` adds when they differ. 0 is no line.
+    source_line: usize,
+    marked_line: usize,
+}
+
+impl<'a> Lowering<'a> {
+    fn new(program: &'a Program) -> Self {
+        Lowering {
+            functions: program
+                .functions
+                .iter()
+                .map(|function| function.name.as_str())
+                .collect(),
+            body: Body::new(false),
+            scopes: vec![HashMap::new()],
+            label_counter: 0,
+            strings: Vec::new(),
+            source_line: 0,
+            marked_line: 0,
+        }
+    }
+
+    // Parameters arrive as the body's first registers. Falling off the end
+    // returns 0.
+    fn function(&mut self, function: &Function) -> LowerResult<Body> {
+        let mut body = Body::new(true);
+        body.params = function
+            .params
+            .iter()
+            .map(|_| body.fresh(Bank::Int))
+            .collect();
+        body.variables = body.params.clone();
+        let params = function.params.iter().map(|param| param.node.clone());
+        self.scopes = vec![params.zip(body.params.iter().copied()).collect()];
+        self.body = body;
+
+        self.label(&function_label(&function.name));
+        for statement in &function.body {
+            self.statement(statement)?;
+        }
+        self.emit(Inst::Return(None));
+        Ok(std::mem::replace(&mut self.body, Body::new(true)))
+    }
+
+    fn string_label(&mut self, text: &str) -> String {
+        let index = match self.strings.iter().position(|string| string == text) {
+            Some(index) => index,
+            None => {
+                self.strings.push(text.to_string());
+                self.strings.len() - 1
+            }
+        };
+        string_label(index)
+    }
+
+    fn statement(&mut self, statement: &Spanned<Statement>) -> LowerResult<()> {
+        self.source_line = statement.span.start.line;
+        match &statement.node {
+            Statement::Declare(var, _, expr) => {
+                let simplified = simplify(expr);
+                let expr = simplified.as_ref().unwrap_or(expr);
+                let mut reg = self.expr(expr)?;
+                if matches!(expr.node, Expr::Variable(_)) {
+                    // Don't alias the other variable's register.
+                    let copy = self.body.fresh(reg.bank);
+                    self.emit(Inst::Move {
+                        dest: copy,
+                        src: reg,
+                    });
+                    reg = copy;
+                }
+                self.body.insts.push(Inst::Bind {
+                    var: var.clone(),
+                    reg,
+                });
+                self.body.variables.push(reg);
+                self.scopes.last_mut().unwrap().insert(var.clone(), reg);
+            }
+            Statement::Assign(var, expr) => {
+                let reg = self.expr(expr)?;
+                match self.lookup(var) {
+                    Some(dest) if dest != reg => self.emit(Inst::Move { dest, src: reg }),
+                    Some(_) => {}
+                    None => {
+                        return Err(CodegenError::UnboundVariable {
+                            name: var.clone(),
+                            span: statement.span,
+                        });
+                    }
+                }
+            }
+            Statement::Print(expr) => {
+                let src = self.expr(expr)?;
+                self.emit(Inst::Print { src });
+            }
+            Statement::PrintString(text) => {
+                let label = self.string_label(text);
+                let src = self.body.fresh(Bank::Int);
+                self.emit(Inst::Address { dest: src, label });
+                self.emit(Inst::PrintString { src });
+            }
+            Statement::Expr(expr) => {
+                self.expr(expr)?;
+            }
+            Statement::Block(body) => self.block(body)?,
+            Statement::Return(expr) if self.body.is_function => {
+                let reg = self.expr(expr)?;
+                self.emit(Inst::Return(Some(reg)));
+            }
+            Statement::Return(_) => {
+                return Err(CodegenError::UnsupportedConstruct {
+                    construct: "return outside a function",
+                    span: Some(statement.span),
+                });
+            }
+            Statement::If { cond, then, else_ } => {
+                let id = self.next_label_id();
+                let otherwise = format!(".Lelse_{}", id);
+                let end = format!(".Lendif_{}", id);
+
+                self.condition(cond, &otherwise)?;
+                self.block(then)?;
+                if else_.is_some() {
+                    self.emit(Inst::Jump(end.clone()));
+                }
+                self.label(&otherwise);
+                if let Some(else_) = else_ {
+                    self.block(else_)?;
+                    self.label(&end);
+                }
+            }
+            Statement::While { cond, body } => {
+                let id = self.next_label_id();
+                let head = format!(".Lwhile_{}", id);
+                let exit = format!(".Lendwhile_{}", id);
+
+                self.label(&head);
+                self.condition(cond, &exit)?;
+                self.block(body)?;
+                self.emit(Inst::Jump(head));
+                self.label(&exit);
+            }
+            Statement::For {
+                init,
+                cond,
+                step,
+                body,
+            } => {
+                let id = self.next_label_id();
+                let head = format!(".Lfor_{}", id);
+                let exit = format!(".Lendfor_{}", id);
+
+                // The induction variable is scoped to the loop.
+                self.scopes.push(HashMap::new());
+                if let Some(init) = init {
+                    self.statement(init)?;
+                }
+                self.label(&head);
+                // A missing condition loops forever.
+                if let Some(cond) = cond {
+                    self.condition(cond, &exit)?;
+                }
+                self.block(body)?;
+                if let Some(step) = step {
+                    self.statement(step)?;
+                }
+                self.emit(Inst::Jump(head));
+                self.label(&exit);
+                self.scopes.pop();
+            }
+        }
+        Ok(())
+    }
+
+    // Code after the block, like a loop's jump back, belongs to the line of
+    // the statement holding it.
+    fn block(&mut self, body: &[Spanned<Statement>]) -> LowerResult<()> {
+        let line = self.source_line;
+        self.scopes.push(HashMap::new());
+        for statement in body {
+            self.statement(statement)?;
+        }
+        self.scopes.pop();
+        self.source_line = line;
+        Ok(())
+    }
+
+    fn lookup(&self, var: &str) -> Option<VReg> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(var).copied())
+    }
+
+    // Falls through when `cond` holds and jumps to `false_label` otherwise.
+    fn condition(&mut self, cond: &Spanned<Expr>, false_label: &str) -> LowerResult<()> {
+        match &cond.node {
+            Expr::BinOp(left, op, right) if op.is_comparison() => {
+                self.comparison(left, op, right)?
+            }
+            _ => {
+                let reg = self.expr(cond)?;
+                let zero = self.body.fresh(reg.bank);
+                match zero.bank {
+                    Bank::Int => self.emit(Inst::Const {
+                        dest: zero,
+                        value: 0,
+                    }),
+                    Bank::Float => self.emit(Inst::FloatConst {
+                        dest: zero,
+                        value: 0.0,
+                    }),
+                }
+                self.emit(Inst::Compare {
+                    op: BinOp::NotEqual,
+                    left: reg,
+                    right: zero,
+                });
+            }
+        }
+        self.emit(Inst::JumpUnless(false_label.to_string()));
+        Ok(())
+    }
+
+    fn comparison(
+        &mut self,
+        left: &Spanned<Expr>,
+        op: &BinOp,
+        right: &Spanned<Expr>,
+    ) -> LowerResult<()> {
+        let left = self.expr(left)?;
+        let right = self.expr(right)?;
+        self.emit(Inst::Compare {
+            op: op.clone(),
+            left,
+            right,
+        });
+        Ok(())
+    }
+
+    fn expr(&mut self, expr: &Spanned<Expr>) -> LowerResult<VReg> {
+        match &expr.node {
+            Expr::Variable(var) => self
+                .lookup(var)
+                .ok_or_else(|| CodegenError::UnboundVariable {
+                    name: var.clone(),
+                    span: expr.span,
+                }),
+            Expr::Literal(value) => {
+                if u16::try_from(*value).is_err() {
+                    return Err(CodegenError::LiteralOutOfRange {
+                        value: *value,
+                        span: expr.span,
+                    });
+                }
+                let dest = self.body.fresh(Bank::Int);
+                self.emit(Inst::Const {
+                    dest,
+                    value: *value,
+                });
+                Ok(dest)
+            }
+            Expr::FloatLiteral(value) => {
+                let dest = self.body.fresh(Bank::Float);
+                self.emit(Inst::FloatConst {
+                    dest,
+                    value: *value,
+                });
+                Ok(dest)
+            }
+            Expr::Read => {
+                let dest = self.body.fresh(Bank::Int);
+                self.emit(Inst::Read { dest });
+                Ok(dest)
+            }
+            Expr::Call { function, args } => {
+                if !self.functions.contains(&function.as_str()) {
+                    return Err(CodegenError::UndefinedFunction {
+                        name: function.clone(),
+                        span: expr.span,
+                    });
+                }
+                let args = args
+                    .iter()
+                    .map(|arg| self.expr(arg))
+                    .collect::<LowerResult<_>>()?;
+                let dest = self.body.fresh(Bank::Int);
+                self.emit(Inst::Call {
+                    dest: Some(dest),
+                    function: function.clone(),
+                    args,
+                    span: Some(expr.span),
+                });
+                Ok(dest)
+            }
+            Expr::Conditional { cond, then, else_ } => {
+                let id = self.next_label_id();
+                let otherwise = format!(".Lelse_{}", id);
+                let end = format!(".Lendcond_{}", id);
+
+                // Each arm moves its value into the one result register.
+                self.condition(cond, &otherwise)?;
+                let then = self.expr(then)?;
+                let dest = self.body.fresh(then.bank);
+                self.emit(Inst::Move { dest, src: then });
+                self.emit(Inst::Jump(end.clone()));
+
+                self.label(&otherwise);
+                let else_ = self.expr(else_)?;
+                self.emit(Inst::Move { dest, src: else_ });
+                self.label(&end);
+                Ok(dest)
+            }
+            // A comparison's value is 1 if it holds and 0 otherwise.
+            Expr::BinOp(left, op, right) if op.is_comparison() => {
+                self.comparison(left, op, right)?;
+                let dest = self.body.fresh(Bank::Int);
+                let one = self.body.fresh(Bank::Int);
+                self.emit(Inst::Const { dest, value: 0 });
+                self.emit(Inst::Const {
+                    dest: one,
+                    value: 1,
+                });
+                self.emit(Inst::Select { dest, src: one });
+                Ok(dest)
+            }
+            Expr::BinOp(left, op, right) => {
+                if let Some(simplified) = simplify(expr) {
+                    return self.expr(&simplified);
+                }
+                // The semantic pass guarantees both operands have one type.
+                let left = self.expr(left)?;
+                let right = self.expr(right)?;
+                let dest = self.body.fresh(left.bank);
+                self.emit(Inst::Binary {
+                    dest,
+                    op: op.clone(),
+                    left,
+                    right,
+                });
+                Ok(dest)
+            }
+        }
+    }
+
+    fn emit(&mut self, inst: Inst) {
+        if self.source_line != self.marked_line {
+            self.body.insts.push(Inst::Source(self.source_line));
+            self.marked_line = self.source_line;
+        }
+        self.body.insts.push(inst);
+    }
+
+    fn label(&mut self, label: &str) {
+        self.body.insts.push(Inst::Label(label.to_string()));
+    }
+
+    fn next_label_id(&mut self) -> usize {
+        let id = self.label_counter;
+        self.label_counter += 1;
+        id
+    }
+}
+
+// Identities the AST folder can't use because one operand isn't a literal:
+// x + 0, 0 + x, x - 0, x * 1, 1 * x, x * 0 and x - x. Each holds under the
+// VM's overflow-to-zero arithmetic. x * 0 keeps an x with side effects, such
+// as reading input. Literals that meet once an operand is dropped are folded
+// as `opt::fold_constants` would. `None` when nothing changes.
+fn simplify(expr: &Spanned<Expr>) -> Option<Spanned<Expr>> {
+    let Expr::BinOp(left, op, right) = &expr.node else {
+        return None;
+    };
+    if op.is_comparison() {
+        return None;
+    }
+
+    let simplified_left = simplify(left);
+    let simplified_right = simplify(right);
+    let changed = simplified_left.is_some() || simplified_right.is_some();
+    let left = simplified_left.map_or(Cow::Borrowed(&**left), Cow::Owned);
+    let right = simplified_right.map_or(Cow::Borrowed(&**right), Cow::Owned);
+
+    let node = match (&left.node, op, &right.node) {
+        (_, BinOp::Add | BinOp::Sub, Expr::Literal(0)) | (_, BinOp::Mul, Expr::Literal(1)) => {
+            return Some(left.into_owned());
+        }
+        (Expr::Literal(0), BinOp::Add, _) | (Expr::Literal(1), BinOp::Mul, _) => {
+            return Some(right.into_owned());
+        }
+        (operand, BinOp::Mul, Expr::Literal(0)) | (Expr::Literal(0), BinOp::Mul, operand)
+            if !has_side_effects(operand) =>
+        {
+            Expr::Literal(0)
+        }
+        (Expr::Variable(a), BinOp::Sub, Expr::Variable(b)) if a == b => Expr::Literal(0),
+        (Expr::Literal(a), _, Expr::Literal(b))
+            if changed && fold_arithmetic(*a, op, *b).is_some() =>
+        {
+            Expr::Literal(fold_arithmetic(*a, op, *b)?)
+        }
+        _ if changed => Expr::BinOp(
+            Box::new(left.into_owned()),
+            op.clone(),
+            Box::new(right.into_owned()),
+        ),
+        _ => return None,
+    };
+    Some(Spanned::new(node, expr.span))
+}
+
+impl fmt::Display for VReg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.bank {
+            Bank::Int => write!(f, "%{}", self.index),
+            Bank::Float => write!(f, "%f{}", self.index),
+        }
+    }
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inst::Const { dest, value } => write!(f, "{} = {}", dest, value),
+            Inst::FloatConst { dest, value } => write!(f, "{} = {:?}", dest, value),
+            Inst::Address { dest, label } => write!(f, "{} = &{}", dest, label),
+            Inst::Read { dest } => write!(f, "{} = read", dest),
+            Inst::Move { dest, src } => write!(f, "{} = {}", dest, src),
+            Inst::Binary {
+                dest,
+                op,
+                left,
+                right,
+            } => write!(f, "{} = {} {} {}", dest, left, op.symbol(), right),
+            Inst::Compare { op, left, right } => {
+                write!(f, "flag = {} {} {}", left, op.symbol(), right)
+            }
+            Inst::Select { dest, src } => write!(f, "{} = {} if flag", dest, src),
+            Inst::Print { src } => write!(f, "print {}", src),
+            Inst::PrintString { src } => write!(f, "print string {}", src),
+            Inst::Call {
+                dest,
+                function,
+                args,
+                ..
+            } => {
+                if let Some(dest) = dest {
+                    write!(f, "{} = ", dest)?;
+                }
+                let args: Vec<String> = args.iter().map(VReg::to_string).collect();
+                write!(f, "call {}({})", function, args.join(", "))
+            }
+            Inst::Reload { dest, address } => write!(f, "{} = load {}", dest, address),
+            Inst::Spill { src, address } => write!(f, "store {} {}", address, src),
+            Inst::Bind { var, reg } => write!(f, "; {} is in {}", var, reg),
+            Inst::Source(line) => write!(f, "; line {}", line),
+            Inst::Label(label) => write!(f, "{}:", label),
+            Inst::Jump(label) => write!(f, "jump {}", label),
+            Inst::JumpUnless(label) => write!(f, "jump {} unless flag", label),
+            Inst::Return(Some(reg)) => write!(f, "return {}", reg),
+            Inst::Return(None) => write!(f, "return"),
+            Inst::Halt => write!(f, "halt"),
+        }
+    }
+}
+
+impl fmt::Display for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for inst in &self.insts {
+            writeln!(f, "{}", inst)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_str;
+
+    // Without the line annotations, which have a test of their own.
+    fn lines(body: &Body) -> Vec<String> {
+        body.insts
+            .iter()
+            .filter(|inst| !matches!(inst, Inst::Source(_)))
+            .map(Inst::to_string)
+            .collect()
+    }
+
+    fn top_level(source: &str) -> Vec<String> {
+        lines(&lower(&parse_str(source).unwrap()).unwrap().top_level)
+    }
+
+    #[test]
+    fn test_declare_and_assign() {
+        assert_eq!(
+            top_level("int a = 1; int b = a; a = b * 2; float f = 0.5;"),
+            [
+                "%0 = 1",
+                "; a is in %0",
+                "%1 = %0",
+                "; b is in %1",
+                "%2 = 2",
+                "%3 = %1 * %2",
+                "%0 = %3",
+                "%f0 = 0.5",
+                "; f is in %f0",
+                "halt",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_print_and_expression_statements() {
+        assert_eq!(
+            top_level("Print(1 < 2); Print(\"hi\"); read(); Print(\"hi\");"),
+            [
+                "%0 = 1",
+                "%1 = 2",
+                "flag = %0 < %1",
+                "%2 = 0",
+                "%3 = 1",
+                "%2 = %3 if flag",
+                "print %2",
+                "%4 = &.Lstr_0",
+                "print string %4",
+                "%5 = read",
+                "%6 = &.Lstr_0",
+                "print string %6",
+                "halt",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_blocks_scope_their_variables() {
+        assert_eq!(
+            top_level("int x = 1; { int x = 2; Print(x); } Print(x);"),
+            [
+                "%0 = 1",
+                "; x is in %0",
+                "%1 = 2",
+                "; x is in %1",
+                "print %1",
+                "print %0",
+                "halt",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_if_and_else() {
+        assert_eq!(
+            top_level("int a = read(); if (a) { Print(1); } else { Print(2.5); }"),
+            [
+                "%0 = read",
+                "; a is in %0",
+                "%1 = 0",
+                "flag = %0 != %1",
+                "jump .Lelse_0 unless flag",
+                "%2 = 1",
+                "print %2",
+                "jump .Lendif_0",
+                ".Lelse_0:",
+                "%f0 = 2.5",
+                "print %f0",
+                ".Lendif_0:",
+                "halt",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_loops() {
+        assert_eq!(
+            top_level("int i = 3; while (i > 0) { i = i - 1; }"),
+            [
+                "%0 = 3",
+                "; i is in %0",
+                ".Lwhile_0:",
+                "%1 = 0",
+                "flag = %0 > %1",
+                "jump .Lendwhile_0 unless flag",
+                "%2 = 1",
+                "%3 = %0 - %2",
+                "%0 = %3",
+                "jump .Lwhile_0",
+                ".Lendwhile_0:",
+                "halt",
+            ]
+        );
+        assert_eq!(
+            top_level("for (int i = 0; ; i = read()) { }"),
+            [
+                "%0 = 0",
+                "; i is in %0",
+                ".Lfor_0:",
+                "%1 = read",
+                "%0 = %1",
+                "jump .Lfor_0",
+                ".Lendfor_0:",
+                "halt",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_functions_calls_and_returns() {
+        let source = "fn f(a, b) { return a ? b : 0; } fn main() { Print(f(1, read())); }";
+        let module = lower(&parse_str(source).unwrap()).unwrap();
+        assert_eq!(lines(&module.top_level), ["call main()", "halt"]);
+        assert_eq!(
+            module.functions[0].params,
+            [0, 1].map(|index| VReg {
+                bank: Bank::Int,
+                index
+            })
+        );
+        assert_eq!(
+            lines(&module.functions[0]),
+            [
+                ".Lfn_f:",
+                "%2 = 0",
+                "flag = %0 != %2",
+                "jump .Lelse_0 unless flag",
+                "%3 = %1",
+                "jump .Lendcond_0",
+                ".Lelse_0:",
+                "%4 = 0",
+                "%3 = %4",
+                ".Lendcond_0:",
+                "return %3",
+                "return",
+            ]
+        );
+        assert_eq!(
+            lines(&module.functions[1]),
+            [
+                ".Lfn_main:",
+                "%0 = 1",
+                "%1 = read",
+                "%2 = call f(%0, %1)",
+                "print %2",
+                "return"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_source_lines_mark_the_first_instruction_of_each_line() {
+        let module = lower(&parse_str("int a = 1;\n\nPrint(a);\nPrint(a);").unwrap()).unwrap();
+        let insts: Vec<String> = module.top_level.insts.iter().map(Inst::to_string).collect();
+        assert_eq!(
+            insts,
+            [
+                "; line 1",
+                "%0 = 1",
+                "; a is in %0",
+                "; line 3",
+                "print %0",
+                "; line 4",
+                "print %0",
+                "halt"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fold_moves() {
+        let mut body =
+            lower(&parse_str("int a = 1; a = a + 2; a = a < 3; int b = a; Print(b);").unwrap())
+                .unwrap()
+                .top_level;
+        fold_moves(&mut body);
+        assert_eq!(
+            lines(&body),
+            [
+                "%0 = 1",
+                "; a is in %0",
+                "%1 = 2",
+                "%0 = %0 + %1",
+                "%3 = 3",
+                "flag = %0 < %3",
+                "%4 = 0",
+                "%5 = 1",
+                "%4 = %5 if flag",
+                "%0 = %4",
+                "%6 = %0",
+                "; b is in %6",
+                "print %6",
+                "halt",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_uses_defs_and_rename() {
+        let [a, b] = [0, 1].map(|index| VReg {
+            bank: Bank::Int,
+            index,
+        });
+        let mut select = Inst::Select { dest: a, src: b };
+        assert_eq!((select.uses(), select.def()), (vec![a, b], Some(a)));
+        select.rename(a, b);
+        assert_eq!(select, Inst::Select { dest: b, src: b });
+
+        let bind = Inst::Bind {
+            var: "x".to_string(),
+            reg: a,
+        };
+        assert_eq!((bind.uses(), bind.def()), (vec![], None));
+    }
+}
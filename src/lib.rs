@@ -1,11 +1,21 @@
 pub mod asm;
 pub mod vm;
+pub mod ast;
 pub mod parser;
 pub mod codegen;
+pub mod ir;
+mod regalloc;
 pub mod pipeline;
+pub mod disasm;
+pub mod opt;
+pub mod semantic;
+pub mod typeck;
+pub mod interp;
+pub mod diagnostics;
+pub mod lint;
 
 pub use asm::Assembler;
 pub use vm::VM;
 pub use parser::Parser;
 pub use codegen::codegen;
-pub use pipeline::pipeline;
\ No newline at end of file
+pub use pipeline::{pipeline, CompileError};
\ No newline at end of file
@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::ast::{Expr, Program, Span, Spanned, Statement};
+
+/// A likely mistake in a program that still compiles, or that the later
+/// passes reject less helpfully. Spans cover the whole declaration for
+/// variables and the name itself for uses.
+#[derive(Debug, PartialEq)]
+pub enum LintWarning {
+    /// A variable that is declared but never read. Assigning to it doesn't
+    /// count as a use.
+    UnusedVariable { name: String, span: Span },
+    /// A second declaration of a name in the same scope, with no block in
+    /// between to make it a shadow.
+    Redeclaration {
+        name: String,
+        first_span: Span,
+        second_span: Span,
+    },
+    /// `Print` of a name with no declaration in scope.
+    PrintOfUndefined { name: String, span: Span },
+}
+
+impl LintWarning {
+    fn span(&self) -> Span {
+        match self {
+            LintWarning::UnusedVariable { span, .. }
+            | LintWarning::PrintOfUndefined { span, .. } => *span,
+            LintWarning::Redeclaration { second_span, .. } => *second_span,
+        }
+    }
+}
+
+/// Warnings for `program` in source order. Works on the bare AST, so it can
+/// run on programs that `semantic::check` would reject.
+pub fn lint(program: &Program) -> Vec<LintWarning> {
+    let mut linter = Linter {
+        scopes: Vec::new(),
+        warnings: Vec::new(),
+    };
+
+    for function in &program.functions {
+        // Parameters are part of the signature, so they are never unused.
+        let params = function.params.iter().map(|param| {
+            let local = Local {
+                span: param.span,
+                used: true,
+            };
+            (param.node.clone(), local)
+        });
+        linter.scopes.push(params.collect());
+        linter.lint_block(&function.body);
+        linter.pop_scope();
+    }
+    linter.lint_block(&program.statements);
+
+    linter
+        .warnings
+        .sort_by_key(|warning| warning.span().start.offset);
+    linter.warnings
+}
+
+struct Local {
+    span: Span,
+    used: bool,
+}
+
+struct Linter {
+    scopes: Vec<HashMap<String, Local>>,
+    warnings: Vec<LintWarning>,
+}
+
+impl Linter {
+    fn lint_block(&mut self, statements: &[Spanned<Statement>]) {
+        self.scopes.push(HashMap::new());
+        for statement in statements {
+            self.lint_statement(statement);
+        }
+        self.pop_scope();
+    }
+
+    fn lint_statement(&mut self, statement: &Spanned<Statement>) {
+        match &statement.node {
+            Statement::Declare(var, _, expr) => {
+                self.lint_expr(expr);
+                self.declare(var, statement.span);
+            }
+            Statement::Assign(_, expr) | Statement::Expr(expr) | Statement::Return(expr) => {
+                self.lint_expr(expr)
+            }
+            Statement::Print(expr) => {
+                self.report_undefined(expr);
+                self.lint_expr(expr);
+            }
+            Statement::PrintString(_) => {}
+            Statement::Block(body) => self.lint_block(body),
+            Statement::If { cond, then, else_ } => {
+                self.lint_expr(cond);
+                self.lint_block(then);
+                if let Some(else_) = else_ {
+                    self.lint_block(else_);
+                }
+            }
+            Statement::While { cond, body } => {
+                self.lint_expr(cond);
+                self.lint_block(body);
+            }
+            Statement::For {
+                init,
+                cond,
+                step,
+                body,
+            } => {
+                self.scopes.push(HashMap::new());
+                if let Some(init) = init {
+                    self.lint_statement(init);
+                }
+                if let Some(cond) = cond {
+                    self.lint_expr(cond);
+                }
+                if let Some(step) = step {
+                    self.lint_statement(step);
+                }
+                self.lint_block(body);
+                self.pop_scope();
+            }
+        }
+    }
+
+    fn lint_expr(&mut self, expr: &Spanned<Expr>) {
+        match &expr.node {
+            Expr::Variable(var) => {
+                let local = self
+                    .scopes
+                    .iter_mut()
+                    .rev()
+                    .find_map(|scope| scope.get_mut(var));
+                if let Some(local) = local {
+                    local.used = true;
+                }
+            }
+            Expr::Literal(_) | Expr::FloatLiteral(_) | Expr::Read => {}
+            Expr::BinOp(left, _, right) => {
+                self.lint_expr(left);
+                self.lint_expr(right);
+            }
+            Expr::Conditional { cond, then, else_ } => {
+                self.lint_expr(cond);
+                self.lint_expr(then);
+                self.lint_expr(else_);
+            }
+            Expr::Call { args, .. } => {
+                for arg in args {
+                    self.lint_expr(arg);
+                }
+            }
+        }
+    }
+
+    // Every undeclared name in a printed expression.
+    fn report_undefined(&mut self, expr: &Spanned<Expr>) {
+        match &expr.node {
+            Expr::Variable(var) => {
+                if !self.scopes.iter().any(|scope| scope.contains_key(var)) {
+                    self.warnings.push(LintWarning::PrintOfUndefined {
+                        name: var.clone(),
+                        span: expr.span,
+                    });
+                }
+            }
+            Expr::Literal(_) | Expr::FloatLiteral(_) | Expr::Read => {}
+            Expr::BinOp(left, _, right) => {
+                self.report_undefined(left);
+                self.report_undefined(right);
+            }
+            Expr::Conditional { cond, then, else_ } => {
+                self.report_undefined(cond);
+                self.report_undefined(then);
+                self.report_undefined(else_);
+            }
+            Expr::Call { args, .. } => {
+                for arg in args {
+                    self.report_undefined(arg);
+                }
+            }
+        }
+    }
+
+    // Keeps the first declaration, as `semantic::check` does.
+    fn declare(&mut self, var: &str, span: Span) {
+        let Some(scope) = self.scopes.last_mut() else {
+            return;
+        };
+        match scope.get(var) {
+            Some(first) => self.warnings.push(LintWarning::Redeclaration {
+                name: var.to_string(),
+                first_span: first.span,
+                second_span: span,
+            }),
+            None => {
+                scope.insert(var.to_string(), Local { span, used: false });
+            }
+        }
+    }
+
+    fn pop_scope(&mut self) {
+        let Some(scope) = self.scopes.pop() else {
+            return;
+        };
+        for (name, local) in scope {
+            if !local.used {
+                self.warnings.push(LintWarning::UnusedVariable {
+                    name,
+                    span: local.span,
+                });
+            }
+        }
+    }
+}
+
+impl fmt::Display for LintWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LintWarning::UnusedVariable { name, span } => {
+                write!(f, "Unused variable {} at {}", name, span.start)
+            }
+            LintWarning::Redeclaration {
+                name,
+                first_span,
+                second_span,
+            } => write!(
+                f,
+                "{} is declared again at {} (first declared at {})",
+                name, second_span.start, first_span.start
+            ),
+            LintWarning::PrintOfUndefined { name, span } => {
+                write!(f, "Print of undefined variable {} at {}", name, span.start)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_str;
+
+    fn warnings(source: &str) -> Vec<String> {
+        lint(&parse_str(source).unwrap())
+            .iter()
+            .map(|warning| warning.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_unused_variables() {
+        let source = "int a = 1;\nint b = 2;\nb = 3;\nint c = a;\n{ int a = c; }\nfn f(n) { return 0; }\nfn main() { return 0; }";
+        assert_eq!(
+            warnings(source),
+            ["Unused variable b at 2:1", "Unused variable a at 5:3",]
+        );
+    }
+
+    #[test]
+    fn test_redeclaration_in_the_same_scope() {
+        let source = "int x = 1;\nPrint(x);\nlet x = 2;\n{ int x = 3; Print(x); }";
+        assert_eq!(
+            warnings(source),
+            ["x is declared again at 3:1 (first declared at 1:1)"]
+        );
+    }
+
+    #[test]
+    fn test_print_of_undefined_variable() {
+        let source = "int a = 1;\nPrint(a + b);\n{ int c = 2; }\nPrint(c ? a : c);";
+        assert_eq!(
+            warnings(source),
+            [
+                "Print of undefined variable b at 2:11",
+                "Unused variable c at 3:3",
+                "Print of undefined variable c at 4:7",
+                "Print of undefined variable c at 4:15",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clean_program_has_no_warnings() {
+        let source =
+            "int n = read(); for (int i = 0; i < n; i += 1) { Print(i); } if (n) { Print(n); }";
+        assert_eq!(warnings(source), Vec::<String>::new());
+    }
+}
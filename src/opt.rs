@@ -0,0 +1,1162 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::disasm::{DecodedInstruction, Operand};
+use crate::ast::{BinOp, Expr, Function, Program, Span, Spanned, Statement};
+use crate::vm::{Opcode, OperandKind};
+
+const REGISTER_COUNT: usize = 32;
+
+// Bytecode peephole pass: arithmetic whose operands are known constants is
+// rewritten in place into a LOAD of the result. LOAD and the three-register
+// arithmetic ops are both 4 bytes, so offsets (and therefore jump targets)
+// are unchanged. Operand LOADs made dead by the rewrite are only dropped in
+// straight-line programs that don't read memory, where shifting offsets
+// can't break a jump or an address into the program image. A LOAD
+// doesn't set the carry flag, so ADD and SUB are kept in programs that read
+// it.
+pub fn fold_constant_loads(instructions: &[DecodedInstruction]) -> Vec<DecodedInstruction> {
+    let leaders = block_leaders(instructions);
+    let reads_carry = instructions
+        .iter()
+        .any(|instruction| matches!(instruction.opcode, Opcode::ADC | Opcode::SBB));
+    let mut known: [Option<i32>; REGISTER_COUNT] = [None; REGISTER_COUNT];
+    let mut folded = Vec::with_capacity(instructions.len());
+
+    for instruction in instructions {
+        if leaders.contains(&instruction.offset) {
+            known = [None; REGISTER_COUNT];
+        }
+
+        let mut instruction = instruction.clone();
+        match instruction.opcode {
+            Opcode::LOAD => {
+                if let (Some(reg), Some(value)) =
+                    (instruction.register(0), instruction.immediate(1))
+                    && (reg as usize) < REGISTER_COUNT
+                {
+                    known[reg as usize] = Some(value as i32);
+                }
+            }
+            Opcode::ADD | Opcode::SUB if reads_carry => forget_written(&instruction, &mut known),
+            Opcode::ADD | Opcode::SUB | Opcode::MUL => {
+                if let Some(value) = evaluate(&instruction, &known)
+                    && let Some(dest) = instruction.register(2)
+                {
+                    known[dest as usize] = Some(value);
+                    if let Ok(imm) = u16::try_from(value) {
+                        instruction = DecodedInstruction::new(
+                            instruction.offset,
+                            Opcode::LOAD,
+                            vec![Operand::Register(dest), Operand::Immediate(imm)],
+                        );
+                    }
+                } else {
+                    forget_written(&instruction, &mut known);
+                }
+            }
+            _ => forget_written(&instruction, &mut known),
+        }
+        folded.push(instruction);
+    }
+
+    if folded.iter().any(|instruction| {
+        instruction.opcode.is_jump()
+            || matches!(
+                instruction.opcode,
+                Opcode::LOADM | Opcode::LOADB | Opcode::LOADH | Opcode::PRTS
+            )
+    }) {
+        folded
+    } else {
+        remove_dead_loads(folded)
+    }
+}
+
+// When the program jumps at all, any offset that appears as an immediate may
+// be a label address loaded for a computed jump, so constant knowledge can't
+// flow past it.
+fn block_leaders(instructions: &[DecodedInstruction]) -> HashSet<usize> {
+    let has_jumps = instructions
+        .iter()
+        .any(|instruction| instruction.opcode.is_jump());
+    let mut leaders = HashSet::new();
+    for instruction in instructions {
+        if has_jumps {
+            for operand in &instruction.operands {
+                if let Operand::Immediate(value) = operand {
+                    leaders.insert(*value as usize);
+                }
+            }
+        }
+        if instruction.opcode.is_jump() || instruction.opcode == Opcode::HLT {
+            leaders.insert(instruction.offset + instruction.size());
+        }
+    }
+    leaders
+}
+
+// Mirrors the VM's arithmetic, including its overflow-to-zero behavior.
+fn evaluate(
+    instruction: &DecodedInstruction,
+    known: &[Option<i32>; REGISTER_COUNT],
+) -> Option<i32> {
+    let reg1 = instruction.register(0)? as usize;
+    let reg2 = instruction.register(1)? as usize;
+    let reg3 = instruction.register(2)? as usize;
+    if reg1 >= REGISTER_COUNT || reg2 >= REGISTER_COUNT || reg3 >= REGISTER_COUNT {
+        return None;
+    }
+
+    let (a, b) = (known[reg1]?, known[reg2]?);
+    let result = match instruction.opcode {
+        Opcode::ADD => a.checked_add(b),
+        Opcode::SUB => a.checked_sub(b),
+        Opcode::MUL => a.checked_mul(b),
+        _ => return None,
+    };
+    Some(result.unwrap_or(0))
+}
+
+fn forget_written(instruction: &DecodedInstruction, known: &mut [Option<i32>; REGISTER_COUNT]) {
+    for reg in written_registers(instruction) {
+        if (reg as usize) < REGISTER_COUNT {
+            known[reg as usize] = None;
+        }
+    }
+}
+
+fn written_registers(instruction: &DecodedInstruction) -> Vec<u8> {
+    match instruction.opcode {
+        Opcode::LOAD
+        | Opcode::MOV
+        | Opcode::POPCNT
+        | Opcode::CLZ
+        | Opcode::ABS
+        | Opcode::CMOV
+        | Opcode::READ
+        | Opcode::ADDI
+        | Opcode::SUBI
+        | Opcode::MULI
+        | Opcode::LOADM
+        | Opcode::LOADB
+        | Opcode::LOADH => instruction.register(0).into_iter().collect(),
+        Opcode::ADD
+        | Opcode::SUB
+        | Opcode::MUL
+        | Opcode::DIV
+        | Opcode::CONCAT
+        | Opcode::MIN
+        | Opcode::MAX
+        | Opcode::ADC
+        | Opcode::SBB => instruction.register(2).into_iter().collect(),
+        Opcode::ADD64 | Opcode::SUB64 => pair_registers([instruction.register_pair(2)]),
+        _ => Vec::new(),
+    }
+}
+
+// Both registers of each pair.
+fn pair_registers<const N: usize>(pairs: [Option<u8>; N]) -> Vec<u8> {
+    pairs
+        .into_iter()
+        .flatten()
+        .flat_map(|high| [high, high.saturating_add(1)])
+        .collect()
+}
+
+fn read_registers(instruction: &DecodedInstruction) -> Vec<u8> {
+    match instruction.opcode {
+        Opcode::ADD
+        | Opcode::SUB
+        | Opcode::MUL
+        | Opcode::DIV
+        | Opcode::CONCAT
+        | Opcode::MIN
+        | Opcode::MAX
+        | Opcode::ADC
+        | Opcode::SBB
+        | Opcode::EQ
+        | Opcode::NEQ
+        | Opcode::GT
+        | Opcode::LT
+        | Opcode::GTE
+        | Opcode::LTE
+        | Opcode::CMOV
+        | Opcode::STOREM
+        | Opcode::STOREB
+        | Opcode::STOREH => {
+            instruction.register(0).into_iter().chain(instruction.register(1)).collect()
+        }
+        Opcode::MOV
+        | Opcode::POPCNT
+        | Opcode::CLZ
+        | Opcode::ABS
+        | Opcode::LOADM
+        | Opcode::LOADB
+        | Opcode::LOADH => {
+            instruction.register(1).into_iter().collect()
+        }
+        Opcode::JMP
+        | Opcode::JMPF
+        | Opcode::JEQ
+        | Opcode::JNEQ
+        | Opcode::CALL
+        | Opcode::PRINT
+        | Opcode::PRINTS
+        | Opcode::PRTS
+        | Opcode::ADDI
+        | Opcode::SUBI
+        | Opcode::MULI => instruction.register(0).into_iter().collect(),
+        Opcode::ADD64 | Opcode::SUB64 => {
+            pair_registers([instruction.register_pair(0), instruction.register_pair(1)])
+        }
+        _ => Vec::new(),
+    }
+}
+
+// A LOAD is dead when its register is overwritten before anything reads it.
+// Registers are observable once the program stops, so a HLT or invalid
+// opcode keeps everything before it alive.
+fn remove_dead_loads(instructions: Vec<DecodedInstruction>) -> Vec<DecodedInstruction> {
+    let dead: Vec<bool> = (0..instructions.len())
+        .map(|index| {
+            let instruction = &instructions[index];
+            if instruction.opcode != Opcode::LOAD {
+                return false;
+            }
+            let Some(reg) = instruction.register(0) else {
+                return false;
+            };
+
+            for later in &instructions[index + 1..] {
+                if matches!(later.opcode, Opcode::HLT | Opcode::IGL)
+                    || read_registers(later).contains(&reg)
+                {
+                    return false;
+                }
+                if written_registers(later).contains(&reg) {
+                    return true;
+                }
+            }
+            false
+        })
+        .collect();
+
+    let mut offset = 0;
+    let mut kept = Vec::new();
+    for (mut instruction, dead) in instructions.into_iter().zip(dead) {
+        if dead {
+            continue;
+        }
+        instruction.offset = offset;
+        offset += instruction.size();
+        kept.push(instruction);
+    }
+    kept
+}
+
+// Assembly pass over codegen's output, applying a few local patterns until
+// none matches:
+//
+// - `MOV r1 r1` is dropped.
+// - `LOAD r1 5` then `MOV r0 r1` becomes `LOAD r0 5` when r1 is dead after.
+// - A LOAD and jump to the label right after them are dropped when the
+//   target register is dead after.
+// - A comparison repeating the last one, with neither operand written in
+//   between, is dropped.
+//
+// A label may be reached from anywhere, so no pattern spans one. Liveness
+// scans do continue past labels, since they only follow the fall-through
+// path, and stop at the first jump. Lines that don't parse are left alone
+// and block every pattern.
+pub fn peephole(asm: Vec<String>) -> Vec<String> {
+    let mut lines: Vec<AsmLine> = asm.into_iter().map(AsmLine::parse).collect();
+    while apply_peephole(&mut lines) {}
+    lines.into_iter().map(|line| line.text).collect()
+}
+
+struct AsmLine {
+    text: String,
+    kind: LineKind,
+}
+
+enum LineKind {
+    Comment,
+    Label(String),
+    // Label operands decode as 0, which is enough to tell registers apart.
+    Instruction(DecodedInstruction, Vec<String>),
+    Unknown,
+}
+
+impl AsmLine {
+    fn parse(text: String) -> Self {
+        let line = text.trim();
+        let kind = if line.is_empty() || line.starts_with(';') {
+            LineKind::Comment
+        } else if let Some(label) = line.strip_suffix(':') {
+            LineKind::Label(label.trim().to_string())
+        } else {
+            parse_instruction(line).unwrap_or(LineKind::Unknown)
+        };
+        AsmLine { text, kind }
+    }
+
+    fn instruction(&self) -> Option<&DecodedInstruction> {
+        match &self.kind {
+            LineKind::Instruction(instruction, _) => Some(instruction),
+            _ => None,
+        }
+    }
+}
+
+fn parse_instruction(line: &str) -> Option<LineKind> {
+    let mut tokens = line.split_whitespace();
+    let opcode = Opcode::from_mnemonic(tokens.next()?)?;
+    let tokens: Vec<String> = tokens.map(str::to_string).collect();
+    if tokens.len() != opcode.operands().len() {
+        return None;
+    }
+
+    let register = |token: &str, prefix: char| token.strip_prefix(prefix)?.parse::<u8>().ok();
+    let mut operands = Vec::new();
+    for (kind, token) in opcode.operands().iter().zip(&tokens) {
+        operands.push(match kind {
+            OperandKind::Register => Operand::Register(register(token, 'r')?),
+            OperandKind::FloatRegister => Operand::FloatRegister(register(token, 'f')?),
+            OperandKind::RegisterPair => Operand::RegisterPair(register(token.split(':').next()?, 'r')?),
+            OperandKind::Immediate => Operand::Immediate(token.parse().unwrap_or(0)),
+            OperandKind::SignedImmediate => Operand::SignedImmediate(token.parse().ok()?),
+            OperandKind::Float => Operand::Float(token.parse().ok()?),
+        });
+    }
+    Some(LineKind::Instruction(DecodedInstruction::new(0, opcode, operands), tokens))
+}
+
+// One left-to-right sweep. Whether any pattern matched.
+fn apply_peephole(lines: &mut Vec<AsmLine>) -> bool {
+    let mut changed = false;
+    let mut index = 0;
+    while index < lines.len() {
+        if let Some(removed) = match_pattern(lines, index) {
+            changed = true;
+            // Later indices first, so earlier ones stay valid.
+            for remove in removed.into_iter().rev() {
+                lines.remove(remove);
+            }
+        } else {
+            index += 1;
+        }
+    }
+    changed
+}
+
+// The lines to remove if a pattern starts at `index`, in ascending order.
+// A rewrite of `index` itself happens here.
+fn match_pattern(lines: &mut [AsmLine], index: usize) -> Option<Vec<usize>> {
+    let LineKind::Instruction(instruction, tokens) = &lines[index].kind else {
+        return None;
+    };
+
+    match instruction.opcode {
+        Opcode::MOV | Opcode::FMOV if instruction.operands[0] == instruction.operands[1] => Some(vec![index]),
+        Opcode::LOAD => {
+            let loaded = instruction.register(0)?;
+            let value = tokens[1].clone();
+            let next = next_code(lines, index + 1)?;
+            let following = lines[next].instruction()?;
+
+            if following.opcode == Opcode::MOV && following.register(1) == Some(loaded) {
+                let dest = following.register(0)?;
+                if !dead_after(lines, next + 1, loaded) {
+                    return None;
+                }
+                lines[index] = AsmLine::parse(format!("LOAD r{} {}", dest, value));
+                return Some(vec![next]);
+            }
+
+            if matches!(following.opcode, Opcode::JMP | Opcode::JEQ | Opcode::JNEQ)
+                && following.register(0) == Some(loaded)
+            {
+                let target = next_code(lines, next + 1)?;
+                let falls_through = matches!(&lines[target].kind, LineKind::Label(label) if *label == value);
+                if falls_through && dead_after(lines, next + 1, loaded) {
+                    return Some(vec![index, next]);
+                }
+            }
+            None
+        }
+        opcode if is_integer_comparison(opcode) => {
+            repeats_last_comparison(lines, index).then(|| vec![index])
+        }
+        _ => None,
+    }
+}
+
+// Comments don't separate instructions; labels and unparsed lines do, and
+// are returned as they are.
+fn next_code(lines: &[AsmLine], from: usize) -> Option<usize> {
+    (from..lines.len()).find(|&index| !matches!(lines[index].kind, LineKind::Comment))
+}
+
+// Whether `reg` is overwritten before anything reads it, following
+// execution from `from`. Registers are observable once the program stops.
+fn dead_after(lines: &[AsmLine], from: usize, reg: u8) -> bool {
+    for line in &lines[from..] {
+        let instruction = match &line.kind {
+            LineKind::Comment | LineKind::Label(_) => continue,
+            LineKind::Unknown => return false,
+            LineKind::Instruction(instruction, _) => instruction,
+        };
+        if instruction.opcode.is_jump()
+            || matches!(instruction.opcode, Opcode::HLT | Opcode::IGL)
+            || read_registers(instruction).contains(&reg)
+        {
+            return false;
+        }
+        if written_registers(instruction).contains(&reg) {
+            return true;
+        }
+    }
+    false
+}
+
+fn repeats_last_comparison(lines: &[AsmLine], index: usize) -> bool {
+    let Some(comparison) = lines[index].instruction() else {
+        return false;
+    };
+    let operands = read_registers(comparison);
+
+    for line in lines[..index].iter().rev() {
+        let earlier = match &line.kind {
+            LineKind::Comment => continue,
+            LineKind::Label(_) | LineKind::Unknown => return false,
+            LineKind::Instruction(earlier, _) => earlier,
+        };
+        if earlier.opcode == comparison.opcode && earlier.operands == comparison.operands {
+            return true;
+        }
+        if earlier.opcode.is_jump()
+            || sets_flag(earlier.opcode)
+            || matches!(earlier.opcode, Opcode::HLT | Opcode::IGL)
+            || written_registers(earlier).iter().any(|reg| operands.contains(reg))
+        {
+            return false;
+        }
+    }
+    false
+}
+
+fn is_integer_comparison(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTE | Opcode::LTE
+    )
+}
+
+fn sets_flag(opcode: Opcode) -> bool {
+    is_integer_comparison(opcode)
+        || matches!(
+            opcode,
+            Opcode::FEQ | Opcode::FNEQ | Opcode::FGT | Opcode::FLT | Opcode::FGTE | Opcode::FLTE
+        )
+}
+
+// AST pass: arithmetic on integer literals is evaluated at compile time,
+// innermost first, so `2 + 3 * 4` becomes `14` and `x + 2 * 3` becomes
+// `x + 6`. Folded literals keep the span of the expression they replace.
+pub fn fold_constants(program: Program) -> Program {
+    Program {
+        functions: program
+            .functions
+            .into_iter()
+            .map(|function| Function {
+                body: fold_block(function.body),
+                ..function
+            })
+            .collect(),
+        statements: fold_block(program.statements),
+    }
+}
+
+fn fold_block(body: Vec<Spanned<Statement>>) -> Vec<Spanned<Statement>> {
+    body.into_iter().map(fold_statement).collect()
+}
+
+fn fold_statement(statement: Spanned<Statement>) -> Spanned<Statement> {
+    let node = match statement.node {
+        Statement::Declare(name, ty, expr) => Statement::Declare(name, ty, fold_expr(expr)),
+        Statement::Assign(name, expr) => Statement::Assign(name, fold_expr(expr)),
+        Statement::Print(expr) => Statement::Print(fold_expr(expr)),
+        Statement::Expr(expr) => Statement::Expr(fold_expr(expr)),
+        Statement::Return(expr) => Statement::Return(fold_expr(expr)),
+        Statement::PrintString(text) => Statement::PrintString(text),
+        Statement::Block(body) => Statement::Block(fold_block(body)),
+        Statement::If { cond, then, else_ } => Statement::If {
+            cond: fold_expr(cond),
+            then: fold_block(then),
+            else_: else_.map(fold_block),
+        },
+        Statement::While { cond, body } => Statement::While {
+            cond: fold_expr(cond),
+            body: fold_block(body),
+        },
+        Statement::For { init, cond, step, body } => Statement::For {
+            init: init.map(|init| Box::new(fold_statement(*init))),
+            cond: cond.map(fold_expr),
+            step: step.map(|step| Box::new(fold_statement(*step))),
+            body: fold_block(body),
+        },
+    };
+    Spanned::new(node, statement.span)
+}
+
+fn fold_expr(expr: Spanned<Expr>) -> Spanned<Expr> {
+    let node = match expr.node {
+        Expr::BinOp(left, op, right) => {
+            let (left, right) = (fold_expr(*left), fold_expr(*right));
+            let folded = match (&left.node, &right.node) {
+                (Expr::Literal(a), Expr::Literal(b)) => fold_arithmetic(*a, &op, *b),
+                _ => None,
+            };
+            match folded {
+                Some(value) => Expr::Literal(value),
+                None => Expr::BinOp(Box::new(left), op, Box::new(right)),
+            }
+        }
+        Expr::Call { function, args } => Expr::Call {
+            function,
+            args: args.into_iter().map(fold_expr).collect(),
+        },
+        Expr::Conditional { cond, then, else_ } => Expr::Conditional {
+            cond: Box::new(fold_expr(*cond)),
+            then: Box::new(fold_expr(*then)),
+            else_: Box::new(fold_expr(*else_)),
+        },
+        other => other,
+    };
+    Spanned::new(node, expr.span)
+}
+
+// Mirrors the VM's i32 arithmetic, where overflow gives zero. Division by
+// zero is left for the VM to report at runtime, and so is a result LOAD
+// can't encode (anything outside 0..=65535), which the VM computes.
+pub(crate) fn fold_arithmetic(a: i64, op: &BinOp, b: i64) -> Option<i64> {
+    let (a, b) = (i32::try_from(a).ok()?, i32::try_from(b).ok()?);
+    let result = match op {
+        BinOp::Add => a.checked_add(b),
+        BinOp::Sub => a.checked_sub(b),
+        BinOp::Mul => a.checked_mul(b),
+        BinOp::Div if b == 0 => return None,
+        BinOp::Div => a.checked_div(b),
+        _ => return None,
+    };
+    u16::try_from(result.unwrap_or(0)).ok().map(i64::from)
+}
+
+// AST pass: statements after a `return` in the same block are dropped, and
+// so are declarations of variables that are never read, along with every
+// assignment to them. A dropped initializer or value that reads input or
+// calls a function is kept as an expression statement. Print always stays.
+// Repeats until nothing changes, since one dead variable may be the only
+// reader of another.
+pub fn eliminate_dead_code(program: Program) -> Program {
+    let mut program = Program {
+        functions: program
+            .functions
+            .into_iter()
+            .map(|function| Function {
+                body: remove_unreachable(function.body),
+                ..function
+            })
+            .collect(),
+        statements: remove_unreachable(program.statements),
+    };
+
+    loop {
+        let mut liveness = Liveness::default();
+        liveness.visit_program(&program);
+        let mut sweep = Sweep {
+            scopes: Scopes::default(),
+            read: liveness.read,
+            removed: 0,
+        };
+        program = sweep.program(program);
+        if sweep.removed == 0 {
+            return program;
+        }
+    }
+}
+
+fn remove_unreachable(body: Vec<Spanned<Statement>>) -> Vec<Spanned<Statement>> {
+    let mut reachable = Vec::with_capacity(body.len());
+    for statement in body {
+        let node = match statement.node {
+            Statement::Block(body) => Statement::Block(remove_unreachable(body)),
+            Statement::If { cond, then, else_ } => Statement::If {
+                cond,
+                then: remove_unreachable(then),
+                else_: else_.map(remove_unreachable),
+            },
+            Statement::While { cond, body } => Statement::While {
+                cond,
+                body: remove_unreachable(body),
+            },
+            Statement::For { init, cond, step, body } => Statement::For {
+                init,
+                cond,
+                step,
+                body: remove_unreachable(body),
+            },
+            other => other,
+        };
+        let exits = always_returns(&node);
+        reachable.push(Spanned::new(node, statement.span));
+        if exits {
+            break;
+        }
+    }
+    reachable
+}
+
+// A loop body may not run at all, so loops never count.
+fn always_returns(statement: &Statement) -> bool {
+    let block_returns = |body: &[Spanned<Statement>]| body.iter().any(|statement| always_returns(statement));
+    match statement {
+        Statement::Return(_) => true,
+        Statement::Block(body) => block_returns(body),
+        Statement::If { then, else_: Some(else_), .. } => block_returns(then) && block_returns(else_),
+        _ => false,
+    }
+}
+
+/// Whether evaluating `expr` does more than compute a value: reading input,
+/// calling a function, or dividing by something that may be zero, which
+/// stops the VM.
+pub(crate) fn has_side_effects(expr: &Expr) -> bool {
+    match expr {
+        Expr::Read | Expr::Call { .. } => true,
+        Expr::Variable(_) | Expr::Literal(_) | Expr::FloatLiteral(_) => false,
+        Expr::BinOp(_, BinOp::Div, right) if !matches!(right.node, Expr::Literal(value) if value != 0) => true,
+        Expr::BinOp(left, _, right) => has_side_effects(&left.node) || has_side_effects(&right.node),
+        Expr::Conditional { cond, then, else_ } => {
+            has_side_effects(&cond.node) || has_side_effects(&then.node) || has_side_effects(&else_.node)
+        }
+    }
+}
+
+// Declarations are numbered in the order both passes meet them, so the
+// sweep can tell which ones the liveness pass saw read.
+#[derive(Default)]
+struct Scopes {
+    scopes: Vec<HashMap<String, usize>>,
+    declared: usize,
+}
+
+impl Scopes {
+    fn declare(&mut self, name: &str) -> usize {
+        let id = self.declared;
+        self.declared += 1;
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), id);
+        }
+        id
+    }
+
+    fn resolve(&self, name: &str) -> Option<usize> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).copied())
+    }
+}
+
+#[derive(Default)]
+struct Liveness {
+    scopes: Scopes,
+    read: HashSet<usize>,
+}
+
+impl Liveness {
+    fn visit_program(&mut self, program: &Program) {
+        for function in &program.functions {
+            // Parameters are never removed.
+            self.scopes.scopes.push(HashMap::new());
+            for param in &function.params {
+                let id = self.scopes.declare(&param.node);
+                self.read.insert(id);
+            }
+            self.visit_block(&function.body);
+            self.scopes.scopes.pop();
+        }
+        self.visit_block(&program.statements);
+    }
+
+    fn visit_block(&mut self, body: &[Spanned<Statement>]) {
+        self.scopes.scopes.push(HashMap::new());
+        for statement in body {
+            self.visit_statement(statement);
+        }
+        self.scopes.scopes.pop();
+    }
+
+    fn visit_statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Declare(var, _, expr) => {
+                self.visit_expr(expr);
+                self.scopes.declare(var);
+            }
+            Statement::Assign(_, expr)
+            | Statement::Print(expr)
+            | Statement::Expr(expr)
+            | Statement::Return(expr) => self.visit_expr(expr),
+            Statement::PrintString(_) => {}
+            Statement::Block(body) => self.visit_block(body),
+            Statement::If { cond, then, else_ } => {
+                self.visit_expr(cond);
+                self.visit_block(then);
+                if let Some(else_) = else_ {
+                    self.visit_block(else_);
+                }
+            }
+            Statement::While { cond, body } => {
+                self.visit_expr(cond);
+                self.visit_block(body);
+            }
+            Statement::For { init, cond, step, body } => {
+                self.scopes.scopes.push(HashMap::new());
+                if let Some(init) = init {
+                    self.visit_statement(init);
+                }
+                if let Some(cond) = cond {
+                    self.visit_expr(cond);
+                }
+                if let Some(step) = step {
+                    self.visit_statement(step);
+                }
+                self.visit_block(body);
+                self.scopes.scopes.pop();
+            }
+        }
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Variable(var) => {
+                if let Some(id) = self.scopes.resolve(var) {
+                    self.read.insert(id);
+                }
+            }
+            Expr::Literal(_) | Expr::FloatLiteral(_) | Expr::Read => {}
+            Expr::BinOp(left, _, right) => {
+                self.visit_expr(left);
+                self.visit_expr(right);
+            }
+            Expr::Conditional { cond, then, else_ } => {
+                self.visit_expr(cond);
+                self.visit_expr(then);
+                self.visit_expr(else_);
+            }
+            Expr::Call { args, .. } => {
+                for arg in args {
+                    self.visit_expr(arg);
+                }
+            }
+        }
+    }
+}
+
+// Walks the program exactly as `Liveness` does, dropping stores to
+// variables it never saw read.
+struct Sweep {
+    scopes: Scopes,
+    read: HashSet<usize>,
+    removed: usize,
+}
+
+impl Sweep {
+    fn program(&mut self, program: Program) -> Program {
+        let functions = program
+            .functions
+            .into_iter()
+            .map(|function| {
+                self.scopes.scopes.push(HashMap::new());
+                for param in &function.params {
+                    self.scopes.declare(&param.node);
+                }
+                let body = self.block(function.body);
+                self.scopes.scopes.pop();
+                Function { body, ..function }
+            })
+            .collect();
+        let statements = self.block(program.statements);
+        Program { functions, statements }
+    }
+
+    fn block(&mut self, body: Vec<Spanned<Statement>>) -> Vec<Spanned<Statement>> {
+        self.scopes.scopes.push(HashMap::new());
+        let body = body.into_iter().filter_map(|statement| self.statement(statement)).collect();
+        self.scopes.scopes.pop();
+        body
+    }
+
+    fn statement(&mut self, statement: Spanned<Statement>) -> Option<Spanned<Statement>> {
+        let span = statement.span;
+        let node = match statement.node {
+            Statement::Declare(var, ty, expr) => {
+                let id = self.scopes.declare(&var);
+                if self.read.contains(&id) {
+                    Statement::Declare(var, ty, expr)
+                } else {
+                    return self.drop_store(expr, span);
+                }
+            }
+            Statement::Assign(var, expr) => match self.scopes.resolve(&var) {
+                Some(id) if !self.read.contains(&id) => return self.drop_store(expr, span),
+                _ => Statement::Assign(var, expr),
+            },
+            Statement::Block(body) => Statement::Block(self.block(body)),
+            Statement::If { cond, then, else_ } => Statement::If {
+                cond,
+                then: self.block(then),
+                else_: else_.map(|else_| self.block(else_)),
+            },
+            Statement::While { cond, body } => Statement::While {
+                cond,
+                body: self.block(body),
+            },
+            Statement::For { init, cond, step, body } => {
+                self.scopes.scopes.push(HashMap::new());
+                let init = init.and_then(|init| self.statement(*init)).map(Box::new);
+                let step = step.and_then(|step| self.statement(*step)).map(Box::new);
+                let body = self.block(body);
+                self.scopes.scopes.pop();
+                Statement::For { init, cond, step, body }
+            }
+            other => other,
+        };
+        Some(Spanned::new(node, span))
+    }
+
+    fn drop_store(&mut self, expr: Spanned<Expr>, span: Span) -> Option<Spanned<Statement>> {
+        self.removed += 1;
+        has_side_effects(&expr).then(|| Spanned::new(Statement::Expr(expr), span))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::codegen;
+    use crate::disasm::{decode, encode};
+    use crate::parser::parse_str;
+    use crate::{Assembler, VM};
+
+    fn optimize(source: &str) -> Vec<String> {
+        let bytecode = Assembler::new().compile(source).unwrap();
+        let instructions = decode(&bytecode).unwrap();
+        fold_constant_loads(&instructions)
+            .iter()
+            .filter(|instruction| instruction.opcode != Opcode::HLT)
+            .map(|instruction| instruction.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_folds_constant_add_in_place() {
+        assert_eq!(
+            optimize("LOAD r0 2\nLOAD r1 3\nADD r0 r1 r2\nHLT"),
+            vec!["LOAD r0 2", "LOAD r1 3", "LOAD r2 5"]
+        );
+    }
+
+    #[test]
+    fn test_chain_collapses_to_single_load() {
+        let source = "LOAD r0 2\nLOAD r1 3\nADD r0 r1 r0\nLOAD r1 4\nMUL r0 r1 r0\nLOAD r1 0\nHLT";
+        assert_eq!(optimize(source), vec!["LOAD r0 20", "LOAD r1 0"]);
+    }
+
+    #[test]
+    fn test_respects_register_reuse() {
+        // r1 is overwritten after the ADD reads it, so the second ADD must see 10.
+        let source = "LOAD r0 1\nLOAD r1 2\nADD r0 r1 r2\nLOAD r1 10\nADD r0 r1 r3\nHLT";
+        let folded = optimize(source);
+        assert_eq!(
+            folded,
+            vec!["LOAD r0 1", "LOAD r2 3", "LOAD r1 10", "LOAD r3 11"]
+        );
+    }
+
+    #[test]
+    fn test_does_not_fold_division_or_unknowns() {
+        let source = "LOAD r0 6\nLOAD r1 3\nDIV r0 r1 r2\nADD r2 r1 r3\nHLT";
+        assert_eq!(
+            optimize(source),
+            vec!["LOAD r0 6", "LOAD r1 3", "DIV r0 r1 r2", "ADD r2 r1 r3"]
+        );
+    }
+
+    #[test]
+    fn test_unrepresentable_result_is_left_alone() {
+        let source = "LOAD r0 0\nLOAD r1 1\nSUB r0 r1 r2\nHLT";
+        assert_eq!(
+            optimize(source),
+            vec!["LOAD r0 0", "LOAD r1 1", "SUB r0 r1 r2"]
+        );
+    }
+
+    #[test]
+    fn test_knowledge_does_not_cross_possible_jump_targets() {
+        let instructions = vec![
+            DecodedInstruction::new(
+                0,
+                Opcode::LOAD,
+                vec![Operand::Register(0), Operand::Immediate(2)],
+            ),
+            DecodedInstruction::new(
+                4,
+                Opcode::LOAD,
+                vec![Operand::Register(1), Operand::Immediate(8)],
+            ),
+            DecodedInstruction::new(
+                8,
+                Opcode::ADD,
+                vec![
+                    Operand::Register(0),
+                    Operand::Register(0),
+                    Operand::Register(0),
+                ],
+            ),
+            DecodedInstruction::new(12, Opcode::JMP, vec![Operand::Register(1)]),
+        ];
+        assert_eq!(fold_constant_loads(&instructions), instructions);
+    }
+
+    #[test]
+    fn test_loads_stay_when_memory_is_read() {
+        // The first LOAD is dead, but dropping it would move the byte at 12
+        // that the LOADM reads.
+        let instructions = vec![
+            DecodedInstruction::new(
+                0,
+                Opcode::LOAD,
+                vec![Operand::Register(0), Operand::Immediate(7)],
+            ),
+            DecodedInstruction::new(
+                4,
+                Opcode::LOAD,
+                vec![Operand::Register(0), Operand::Immediate(12)],
+            ),
+            DecodedInstruction::new(
+                8,
+                Opcode::LOADM,
+                vec![Operand::Register(1), Operand::Register(0)],
+            ),
+            DecodedInstruction::new(11, Opcode::HLT, vec![]),
+        ];
+        assert_eq!(fold_constant_loads(&instructions), instructions);
+    }
+
+    #[test]
+    fn test_immediate_arithmetic_keeps_its_load() {
+        let source = "LOAD r0 5\nADDI r0 -2\nLOAD r1 1\nADD r0 r1 r2\nHLT";
+        assert_eq!(
+            optimize(source),
+            vec!["LOAD r0 5", "ADDI r0 -2", "LOAD r1 1", "ADD r0 r1 r2"]
+        );
+    }
+
+    #[test]
+    fn test_conditional_move_is_not_folded() {
+        // CMOV may or may not overwrite r0, so r0 is unknown afterwards and
+        // its LOAD stays alive.
+        let source = "LOAD r0 7\nLOAD r1 1\nEQ r0 r1\nCMOV r0 r1\nADD r0 r1 r2\nHLT";
+        assert_eq!(
+            optimize(source),
+            vec!["LOAD r0 7", "LOAD r1 1", "EQ r0 r1", "CMOV r0 r1", "ADD r0 r1 r2"]
+        );
+    }
+
+    #[test]
+    fn test_carry_keeps_add_and_sub() {
+        let source = "LOAD r0 2\nLOAD r1 3\nADD r0 r1 r2\nADC r0 r1 r3\nHLT";
+        assert_eq!(
+            optimize(source),
+            vec!["LOAD r0 2", "LOAD r1 3", "ADD r0 r1 r2", "ADC r0 r1 r3"]
+        );
+    }
+
+    #[test]
+    fn test_pair_arithmetic_overwrites_both_registers() {
+        // The LOADs are dead, and the ADD can't fold to LOAD r6 3.
+        let source = "LOAD r4 1\nLOAD r5 2\nADD64 r0:r1 r2:r3 r4:r5\nADD r4 r5 r6\nHLT";
+        assert_eq!(
+            optimize(source),
+            vec!["ADD64 r0:r1 r2:r3 r4:r5", "ADD r4 r5 r6"]
+        );
+    }
+
+    #[test]
+    fn test_folded_program_has_same_result() {
+        let source = "LOAD r0 7\nLOAD r1 6\nMUL r0 r1 r2\nLOAD r3 2\nSUB r2 r3 r2\nHLT";
+        let bytecode = Assembler::new().compile(source).unwrap();
+        let optimized = encode(&fold_constant_loads(&decode(&bytecode).unwrap()));
+        assert!(optimized.len() < bytecode.len());
+
+        let mut original_vm = VM::new();
+        original_vm.add_program(bytecode);
+        original_vm.run().unwrap();
+
+        let mut optimized_vm = VM::new();
+        optimized_vm.add_program(optimized);
+        optimized_vm.run().unwrap();
+
+        assert_eq!(original_vm.get_registers(), optimized_vm.get_registers());
+        assert_eq!(optimized_vm.get_register(2), Ok(40));
+    }
+
+    fn lines(asm: &[&str]) -> Vec<String> {
+        asm.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_peephole_shrinks_known_program() {
+        let source = "int a = read(); int b = read(); int c = a < b; if (a < b) { a = 5; } else { b = 2; } Print(a + b + c);";
+        let asm = codegen(&parse_str(source).unwrap()).unwrap();
+        let optimized = peephole(asm.clone());
+        let count = |asm: &[String]| {
+            asm.iter()
+                .filter(|line| !line.starts_with(';') && !line.ends_with(':'))
+                .count()
+        };
+        assert_eq!((count(&asm), count(&optimized)), (17, 16));
+        // The second `LT r0 r1` is gone; both branches already load directly.
+        assert_eq!(optimized.iter().filter(|line| *line == "LT r0 r1").count(), 1);
+        assert!(optimized.contains(&"LOAD r0 5".to_string()));
+        assert!(optimized.contains(&"LOAD r1 2".to_string()));
+
+        for input in [[3, 4], [4, 3]] {
+            let outputs: Vec<String> = [&asm, &optimized]
+                .iter()
+                .map(|asm| {
+                    let mut vm = VM::new();
+                    vm.add_program(Assembler::new().compile(&asm.join("\n")).unwrap());
+                    vm.capture_output();
+                    vm.set_input(input);
+                    vm.run().unwrap();
+                    vm.take_output()
+                })
+                .collect();
+            assert_eq!(outputs[0], outputs[1]);
+        }
+    }
+
+    #[test]
+    fn test_peephole_drops_jumps_to_the_next_line() {
+        let asm = lines(&["LOAD r2 .Lend", "JMP r2", ".Lend:", "LOAD r2 1", "MOV r3 r3", "HLT"]);
+        assert_eq!(peephole(asm), lines(&[".Lend:", "LOAD r2 1", "HLT"]));
+    }
+
+    #[test]
+    fn test_peephole_respects_labels_and_live_registers() {
+        let unchanged = [
+            // A jump to .L may reach the MOV with another value in r1.
+            lines(&["LOAD r1 5", ".L:", "MOV r0 r1", "LOAD r1 0", "HLT"]),
+            // ...or the second comparison with other operands.
+            lines(&["LT r0 r1", ".L:", "LT r0 r1", "HLT"]),
+            // r1 is read again after the MOV.
+            lines(&["LOAD r1 5", "MOV r0 r1", "PRINT r1", "HLT"]),
+            // r0 changes between the comparisons.
+            lines(&["LT r0 r1", "ADDI r0 1", "LT r0 r1", "HLT"]),
+        ];
+        for asm in unchanged {
+            assert_eq!(peephole(asm.clone()), asm);
+        }
+    }
+
+    fn folded_source(source: &str) -> String {
+        fold_constants(parse_str(source).unwrap()).to_source()
+    }
+
+    #[test]
+    fn test_fold_constants_to_a_single_literal() {
+        let program = fold_constants(parse_str("int x = 2 + 3 * 4;").unwrap());
+        assert!(matches!(
+            &*program.statements[0],
+            Statement::Declare(_, _, Spanned { node: Expr::Literal(14), .. })
+        ));
+    }
+
+    #[test]
+    fn test_fold_constants_leaves_variables() {
+        assert_eq!(folded_source("Print(x + 2 * 3);"), "Print(x + 6);\n");
+        assert_eq!(folded_source("Print((1 + 2) * x - 8 / 4);"), "Print(3 * x - 2);\n");
+        // Left associativity: `x + 1 + 2` is `(x + 1) + 2`, which has no
+        // constant subtree.
+        assert_eq!(folded_source("Print(x + 1 + 2);"), "Print(x + 1 + 2);\n");
+        assert_eq!(
+            folded_source("fn f(a) { return a * (2 + 2); } fn main() { while (f(1 + 1) < 2 * 5) { } return 0; }"),
+            "fn f(a) {\n    return a * 4;\n}\nfn main() {\n    while (f(2) < 10) {\n    }\n    return 0;\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_fold_constants_follows_vm_semantics() {
+        // Overflow gives zero, as in the VM.
+        assert_eq!(folded_source("Print(60000 * 60000);"), "Print(0);\n");
+        assert_eq!(folded_source("Print(7 / 2);"), "Print(3);\n");
+        // Division by zero is left for the VM to report, and a negative
+        // result can't be loaded as an immediate.
+        assert_eq!(folded_source("Print(1 / (2 - 2));"), "Print(1 / 0);\n");
+        assert_eq!(folded_source("Print(1 - 2);"), "Print(1 - 2);\n");
+        // Comparisons and floats are not folded.
+        assert_eq!(folded_source("while (1 < 2) { }"), "while (1 < 2) {\n}\n");
+        assert_eq!(folded_source("Print(1.5 + 2.5);"), "Print(1.5 + 2.5);\n");
+    }
+
+    #[test]
+    fn test_fold_constants_shrinks_codegen_output() {
+        let source = "int x = 2 + 3 * 4; Print(x);";
+        let plain = codegen(&parse_str(source).unwrap()).unwrap();
+        let folded = codegen(&fold_constants(parse_str(source).unwrap())).unwrap();
+        assert_eq!(plain.len(), 9);
+        assert_eq!(folded, vec!["; line 1", "LOAD r0 14", "; x is in r0", "PRINT r0", "HLT"]);
+    }
+
+    fn without_dead_code(source: &str) -> String {
+        eliminate_dead_code(parse_str(source).unwrap()).to_source()
+    }
+
+    #[test]
+    fn test_unused_declarations_emit_nothing() {
+        let source = "int a = 1; int b = a * 2; { int c = b; c = 4; } Print(7);";
+        let program = eliminate_dead_code(parse_str(source).unwrap());
+        assert_eq!(codegen(&program).unwrap(), vec!["; line 1", "LOAD r0 7", "PRINT r0", "HLT"]);
+    }
+
+    #[test]
+    fn test_variables_read_later_are_kept() {
+        assert_eq!(
+            without_dead_code("int x = 4; int y = 0; y = x; while (x) { x = x - 1; } Print(x);"),
+            "int x = 4;\nwhile (x) {\n    x = x - 1;\n}\nPrint(x);\n"
+        );
+        // A shadowed name is its own variable.
+        assert_eq!(
+            without_dead_code("int x = 1; { int x = 2; } Print(x);"),
+            "int x = 1;\n{\n}\nPrint(x);\n"
+        );
+    }
+
+    #[test]
+    fn test_dead_stores_keep_side_effects() {
+        assert_eq!(without_dead_code("int a = read(); Print(1);"), "read();\nPrint(1);\n");
+        assert_eq!(
+            without_dead_code("int a = read(); int b = 0; b = 6 / a; b = 6 / 2;"),
+            "int a = read();\n6 / a;\n"
+        );
+    }
+
+    #[test]
+    fn test_statements_after_return_are_removed() {
+        let source = "fn f(n) { if (n) { return 1; } else { return 2; } Print(3); } fn main() { while (1) { return 0; Print(1); } Print(2); return 0; }";
+        assert_eq!(
+            without_dead_code(source),
+            "fn f(n) {\n    if (n) {\n        return 1;\n    } else {\n        return 2;\n    }\n}\nfn main() {\n    while (1) {\n        return 0;\n    }\n    Print(2);\n    return 0;\n}\n"
+        );
+    }
+}
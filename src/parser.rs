@@ -1,248 +1,1882 @@
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
+use std::iter::FusedIterator;
+use std::num::IntErrorKind;
 use std::path::Path;
 
-// AST Definitions
-#[derive(Debug)]
-pub enum Expr {
-    Variable(String),
-    Literal(i64),
-    BinOp(Box<Expr>, BinOp, Box<Expr>),
-}
-
-#[derive(Debug)]
-pub enum BinOp {
-    Add,
-}
-
-#[derive(Debug)]
-pub enum Statement {
-    Declare(String, Expr),
-    Print(Expr),
-}
+use crate::ast::{BinOp, ESCAPES, Expr, Function, Position, Program, Span, Spanned, Statement, Type, escape};
+use crate::diagnostics::Diagnostic;
 
-#[derive(Debug)]
-pub struct Program {
-    pub statements: Vec<Statement>,
-}
+// Soft warnings about a parsed program; see `lint::lint`.
+pub use crate::lint::{LintWarning, lint};
 
-// Token Definitions
+/// A lexical token. New kinds of token may be added as the language grows.
 #[derive(Debug, PartialEq)]
-enum Token {
+#[non_exhaustive]
+pub enum Token {
     Int,
+    Float,
+    Let,
     Print,
+    If,
+    Else,
+    While,
+    For,
+    Fn,
+    Return,
+    Read,
     Ident(String),
     Literal(i64),
+    FloatLiteral(f64),
+    /// A string literal, with its escapes resolved.
+    Str(String),
     Equals,
     Plus,
+    Minus,
+    Star,
+    Slash,
+    PlusEquals,
+    MinusEquals,
+    StarEquals,
+    SlashEquals,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    EqualEqual,
+    NotEqual,
     Semicolon,
+    Comma,
+    Question,
+    Colon,
     LParen,
     RParen,
+    LBrace,
+    RBrace,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Token::Int => "int",
+            Token::Float => "float",
+            Token::Let => "let",
+            Token::Print => "Print",
+            Token::If => "if",
+            Token::Else => "else",
+            Token::While => "while",
+            Token::For => "for",
+            Token::Fn => "fn",
+            Token::Return => "return",
+            Token::Read => "read",
+            Token::Ident(name) => return write!(f, "identifier `{}`", name),
+            Token::Literal(value) => return write!(f, "number `{}`", value),
+            Token::FloatLiteral(value) => return write!(f, "number `{:?}`", value),
+            Token::Str(text) => return write!(f, "string `\"{}\"`", escape(text)),
+            Token::Equals => "=",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Star => "*",
+            Token::Slash => "/",
+            Token::PlusEquals => "+=",
+            Token::MinusEquals => "-=",
+            Token::StarEquals => "*=",
+            Token::SlashEquals => "/=",
+            Token::Less => "<",
+            Token::Greater => ">",
+            Token::LessEqual => "<=",
+            Token::GreaterEqual => ">=",
+            Token::EqualEqual => "==",
+            Token::NotEqual => "!=",
+            Token::Semicolon => ";",
+            Token::Comma => ",",
+            Token::Question => "?",
+            Token::Colon => ":",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+        };
+        write!(f, "`{}`", text)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ParseErrorKind {
+    UnexpectedCharacter,
+    /// A `/*` with no closing `*/`; the position is where the comment opened.
+    UnterminatedComment,
+    /// A string literal with no closing `"`; the position is where it opened.
+    UnterminatedString,
+    /// A backslash in a string literal followed by anything but `n`, `t`,
+    /// `r`, `"` or `\`.
+    InvalidEscape,
+    /// A malformed literal, such as `12ab` or `0xG`.
+    InvalidNumber,
+    /// An integer literal above `i64::MAX`. There is no unary minus, so
+    /// `i64::MIN` can't be written either; `0 - 9223372036854775808` is this
+    /// error too.
+    IntegerOverflow,
+    UnexpectedToken,
+    UnexpectedEndOfInput,
+    MissingMain,
+    ReturnOutsideFunction,
+}
+
+/// A lexer or parser error. `position` is where in the source it occurred;
+/// `found` and `expected` describe the offending input and what would have
+/// been accepted there, when that is meaningful.
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub position: Position,
+    pub found: Option<String>,
+    pub expected: Option<String>,
+}
+
+impl ParseError {
+    fn new(kind: ParseErrorKind, position: Position) -> Self {
+        ParseError {
+            kind,
+            position,
+            found: None,
+            expected: None,
+        }
+    }
+
+    fn found(mut self, found: impl ToString) -> Self {
+        self.found = Some(found.to_string());
+        self
+    }
+
+    fn expected(mut self, expected: impl ToString) -> Self {
+        self.expected = Some(expected.to_string());
+        self
+    }
 }
 
-// Lexer
-struct Lexer {
+impl ParseErrorKind {
+    pub fn description(self) -> &'static str {
+        match self {
+            ParseErrorKind::UnexpectedCharacter => "Unexpected character",
+            ParseErrorKind::UnterminatedComment => "Unterminated block comment",
+            ParseErrorKind::UnterminatedString => "Unterminated string literal",
+            ParseErrorKind::InvalidEscape => "Invalid escape in string literal",
+            ParseErrorKind::InvalidNumber => "Invalid number",
+            ParseErrorKind::IntegerOverflow => "Integer literal too large",
+            ParseErrorKind::UnexpectedToken => "Unexpected token",
+            ParseErrorKind::UnexpectedEndOfInput => "Unexpected end of input",
+            ParseErrorKind::MissingMain => "Program defines functions but no main function",
+            ParseErrorKind::ReturnOutsideFunction => "return outside of a function",
+        }
+    }
+}
+
+impl ParseError {
+    /// The error with the source line it occurred on and a caret under its
+    /// column; see `diagnostics::Diagnostic`.
+    pub fn render(&self, source: &str) -> String {
+        Diagnostic::from(self).render(source)
+    }
+
+    // What was expected and found, as the tail of a message.
+    pub(crate) fn detail(&self) -> String {
+        match (&self.expected, &self.found) {
+            (Some(expected), Some(found)) => format!(": expected {}, found {}", expected, found),
+            (Some(expected), None) => format!(": expected {}", expected),
+            (None, Some(found)) => format!(": found {}", found),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}{}", self.kind.description(), self.position, self.detail())
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+type ParseResult<T> = Result<T, ParseError>;
+
+/// Lexer errors share `ParseError`; their kinds are `UnexpectedCharacter`,
+/// `UnterminatedComment`, `UnterminatedString`, `InvalidEscape`,
+/// `InvalidNumber` and `IntegerOverflow`.
+pub type LexError = ParseError;
+
+/// Splits source into tokens, lazily. Iteration ends after the first
+/// error, since what follows a bad character can't be lexed reliably.
+pub struct Lexer {
     chars: Vec<char>,
     pos: usize,
+    // The source position of each char, plus one for the end of input.
+    positions: Vec<Position>,
+    failed: bool,
 }
 
 impl Lexer {
-    fn new(input: &str) -> Self {
+    pub fn new(input: &str) -> Self {
+        let chars: Vec<char> = input.chars().collect();
+        let mut positions = Vec::with_capacity(chars.len() + 1);
+        let mut position = Position { offset: 0, line: 1, column: 1 };
+        for &c in &chars {
+            positions.push(position);
+            position.offset += c.len_utf8();
+            if c == '\n' {
+                position.line += 1;
+                position.column = 1;
+            } else {
+                position.column += 1;
+            }
+        }
+        positions.push(position);
+
         Lexer {
-            chars: input.chars().collect(),
+            chars,
             pos: 0,
+            positions,
+            failed: false,
         }
     }
 
-    fn next_token(&mut self) -> Option<Token> {
-        self.skip_whitespace();
-        
+    /// The position just past the end of the source.
+    pub fn end(&self) -> Position {
+        self.position_at(self.chars.len())
+    }
+
+    fn position_at(&self, index: usize) -> Position {
+        self.positions[index]
+    }
+
+    fn next_token(&mut self) -> ParseResult<Option<(Token, Span)>> {
+        self.skip_whitespace_and_comments()?;
+
         if self.pos >= self.chars.len() {
-            return None;
+            return Ok(None);
         }
 
+        let start = self.pos;
         let c = self.chars[self.pos];
-        
-        match c {
-            'a'..='z' | 'A'..='Z' => self.parse_identifier(),
-            '0'..='9' => self.parse_number(),
-            '=' => {
+
+        let token = match c {
+            c if is_identifier_start(c) => self.parse_identifier(),
+            '0'..='9' => self.parse_number()?,
+            '"' => self.parse_string()?,
+            '=' => self.one_or_two('=', Token::Equals, Token::EqualEqual),
+            '<' => self.one_or_two('=', Token::Less, Token::LessEqual),
+            '>' => self.one_or_two('=', Token::Greater, Token::GreaterEqual),
+            '!' if self.chars.get(self.pos + 1) == Some(&'=') => {
+                self.pos += 2;
+                Token::NotEqual
+            }
+            '+' => self.one_or_two('=', Token::Plus, Token::PlusEquals),
+            '-' => self.one_or_two('=', Token::Minus, Token::MinusEquals),
+            '*' => self.one_or_two('=', Token::Star, Token::StarEquals),
+            '/' => self.one_or_two('=', Token::Slash, Token::SlashEquals),
+            ';' => {
                 self.pos += 1;
-                Some(Token::Equals)
+                Token::Semicolon
             }
-            '+' => {
+            ',' => {
                 self.pos += 1;
-                Some(Token::Plus)
+                Token::Comma
             }
-            ';' => {
+            '?' => {
                 self.pos += 1;
-                Some(Token::Semicolon)
+                Token::Question
+            }
+            ':' => {
+                self.pos += 1;
+                Token::Colon
             }
             '(' => {
                 self.pos += 1;
-                Some(Token::LParen)
+                Token::LParen
             }
             ')' => {
                 self.pos += 1;
-                Some(Token::RParen)
+                Token::RParen
             }
-            _ => panic!("Unexpected character: {} at position {}", c, self.pos),
-        }
+            '{' => {
+                self.pos += 1;
+                Token::LBrace
+            }
+            '}' => {
+                self.pos += 1;
+                Token::RBrace
+            }
+            _ => {
+                return Err(ParseError::new(ParseErrorKind::UnexpectedCharacter, self.position_at(start))
+                    .found(format!("`{}`", c)));
+            }
+        };
+        let span = Span {
+            start: self.position_at(start),
+            end: self.position_at(self.pos),
+        };
+        Ok(Some((token, span)))
     }
 
-    fn parse_identifier(&mut self) -> Option<Token> {
-        let start = self.pos;
-        while self.pos < self.chars.len() && self.chars[self.pos].is_alphanumeric() {
+    // Lexes `single`, or `double` when the next character is `second`.
+    fn one_or_two(&mut self, second: char, single: Token, double: Token) -> Token {
+        if self.chars.get(self.pos + 1) == Some(&second) {
+            self.pos += 2;
+            double
+        } else {
             self.pos += 1;
+            single
         }
+    }
+
+    fn parse_identifier(&mut self) -> Token {
+        let start = self.pos;
+        self.skip_identifier_chars();
         let ident: String = self.chars[start..self.pos].iter().collect();
 
         match ident.as_str() {
-            "int" => Some(Token::Int),
-            "Print" => Some(Token::Print),
-            _ => Some(Token::Ident(ident)),
+            "int" => Token::Int,
+            "float" => Token::Float,
+            "let" => Token::Let,
+            "Print" => Token::Print,
+            "if" => Token::If,
+            "else" => Token::Else,
+            "while" => Token::While,
+            "for" => Token::For,
+            "fn" => Token::Fn,
+            "return" => Token::Return,
+            "read" => Token::Read,
+            _ => Token::Ident(ident),
         }
     }
 
-    fn parse_number(&mut self) -> Option<Token> {
+    fn parse_number(&mut self) -> ParseResult<Token> {
+        let radix = match (self.chars[self.pos], self.chars.get(self.pos + 1)) {
+            ('0', Some('x' | 'X')) => Some(16),
+            ('0', Some('b' | 'B')) => Some(2),
+            _ => None,
+        };
+        if let Some(radix) = radix {
+            return self.parse_radix_number(radix);
+        }
+
         let start = self.pos;
         while self.pos < self.chars.len() && self.chars[self.pos].is_ascii_digit() {
             self.pos += 1;
         }
+        // A `.` only continues the literal when a digit follows it.
+        let is_float = self.chars.get(self.pos) == Some(&'.')
+            && self.chars.get(self.pos + 1).is_some_and(|c| c.is_ascii_digit());
+        if is_float {
+            self.pos += 1;
+            while self.pos < self.chars.len() && self.chars[self.pos].is_ascii_digit() {
+                self.pos += 1;
+            }
+        }
+
+        // `123abc` is one malformed token, not a number then an identifier.
+        let malformed = self.chars.get(self.pos).is_some_and(|&c| is_identifier_char(c));
+        self.skip_identifier_chars();
+
         let num: String = self.chars[start..self.pos].iter().collect();
-        Some(Token::Literal(num.parse().unwrap()))
+        let invalid = || ParseError::new(ParseErrorKind::InvalidNumber, self.position_at(start)).found(&num);
+        if malformed {
+            Err(invalid())
+        } else if is_float {
+            num.parse().map(Token::FloatLiteral).map_err(|_| invalid())
+        } else {
+            // Only digits remain, so the parse can only fail by overflowing.
+            num.parse().map(Token::Literal).map_err(|_| {
+                ParseError::new(ParseErrorKind::IntegerOverflow, self.position_at(start)).found(&num)
+            })
+        }
     }
 
-    fn skip_whitespace(&mut self) {
-        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+    // Stops at the closing quote; a string may span lines.
+    fn parse_string(&mut self) -> ParseResult<Token> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut text = String::new();
+        loop {
+            match self.chars.get(self.pos) {
+                Some('"') => break,
+                Some('\\') => {
+                    let escape = self.chars.get(self.pos + 1).copied();
+                    let Some(&(_, value)) = ESCAPES.iter().find(|&&(name, _)| Some(name) == escape) else {
+                        let error = ParseError::new(ParseErrorKind::InvalidEscape, self.position_at(self.pos));
+                        return Err(match escape {
+                            Some(c) => error.found(format!("`\\{}`", c)),
+                            None => error,
+                        });
+                    };
+                    text.push(value);
+                    self.pos += 2;
+                }
+                Some(&c) => {
+                    text.push(c);
+                    self.pos += 1;
+                }
+                None => {
+                    return Err(ParseError::new(ParseErrorKind::UnterminatedString, self.position_at(start))
+                        .expected("`\"`"));
+                }
+            }
+        }
+        self.pos += 1;
+        Ok(Token::Str(text))
+    }
+
+    // `0x` and `0b` literals. Underscores may separate digits, so the whole
+    // identifier-like run is taken and then validated.
+    fn parse_radix_number(&mut self, radix: u32) -> ParseResult<Token> {
+        let start = self.pos;
+        self.pos += 2;
+        self.skip_identifier_chars();
+
+        let text: String = self.chars[start..self.pos].iter().collect();
+        let digits: String = text[2..].chars().filter(|&c| c != '_').collect();
+        if digits.is_empty() {
+            return Err(ParseError::new(ParseErrorKind::InvalidNumber, self.position_at(start))
+                .found(&text)
+                .expected("digits"));
+        }
+        i64::from_str_radix(&digits, radix).map(Token::Literal).map_err(|err| {
+            let kind = match err.kind() {
+                IntErrorKind::PosOverflow => ParseErrorKind::IntegerOverflow,
+                _ => ParseErrorKind::InvalidNumber,
+            };
+            ParseError::new(kind, self.position_at(start)).found(&text)
+        })
+    }
+
+    fn skip_identifier_chars(&mut self) {
+        while self.pos < self.chars.len() && is_identifier_char(self.chars[self.pos]) {
             self.pos += 1;
         }
     }
+
+    fn skip_whitespace_and_comments(&mut self) -> ParseResult<()> {
+        loop {
+            while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+                self.pos += 1;
+            }
+
+            match (self.chars.get(self.pos), self.chars.get(self.pos + 1)) {
+                (Some('/'), Some('/')) => {
+                    while self.pos < self.chars.len() && self.chars[self.pos] != '\n' {
+                        self.pos += 1;
+                    }
+                }
+                (Some('/'), Some('*')) => {
+                    let start = self.pos;
+                    self.pos += 2;
+                    loop {
+                        match (self.chars.get(self.pos), self.chars.get(self.pos + 1)) {
+                            (Some('*'), Some('/')) => {
+                                self.pos += 2;
+                                break;
+                            }
+                            (Some(_), _) => self.pos += 1,
+                            (None, _) => {
+                                let start = self.position_at(start);
+                                return Err(ParseError::new(ParseErrorKind::UnterminatedComment, start)
+                                    .expected("`*/`"));
+                            }
+                        }
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
 }
 
-// Parser
-pub struct Parser {
-    tokens: Vec<Token>,
-    pos: usize,
+impl Iterator for Lexer {
+    type Item = Result<Spanned<Token>, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.next_token() {
+            Ok(token) => token.map(|(token, span)| Ok(Spanned::new(token, span))),
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl FusedIterator for Lexer {}
+
+/// Parses a token stream, pulling one token at a time.
+pub struct Parser<I> {
+    tokens: I,
+    current: Option<Spanned<Token>>,
+    // End of the last consumed token.
+    last_end: Option<Position>,
+    end: Position,
+    in_function: bool,
+    errors: Vec<ParseError>,
+    lex_error: Option<LexError>,
 }
 
-impl Parser {
-    fn new(tokens: Vec<Token>) -> Self {
-        Parser { tokens, pos: 0 }
+impl<I: Iterator<Item = Result<Spanned<Token>, LexError>>> Parser<I> {
+    /// `end` is where the source ends, for errors about running out of
+    /// tokens; see `Lexer::end`.
+    pub fn new(tokens: impl IntoIterator<IntoIter = I>, end: Position) -> Self {
+        let mut parser = Parser {
+            tokens: tokens.into_iter(),
+            current: None,
+            last_end: None,
+            end,
+            in_function: false,
+            errors: Vec::new(),
+            lex_error: None,
+        };
+        parser.advance();
+        parser
     }
 
-    fn parse(&mut self) -> Program {
+    /// Parses the whole stream, reporting every error found rather than
+    /// just the first. A lexer error ends the stream and is reported on its
+    /// own: the tokens around a bad character rarely parse, and the errors
+    /// that would cause are just noise.
+    pub fn parse(&mut self) -> Result<Program, Vec<ParseError>> {
+        let mut functions = Vec::new();
         let mut statements = Vec::new();
 
-        while self.pos < self.tokens.len() {
-            statements.push(self.parse_statement());
+        loop {
+            self.skip_empty_statements();
+            let result = match self.peek() {
+                None => break,
+                Some(Token::Fn) => self.parse_function().map(|function| functions.push(function)),
+                Some(_) => self.parse_statements().map(|parsed| statements.extend(parsed)),
+            };
+            if let Err(err) = result {
+                self.recover(err);
+                // A `}` with no block to close would stop recovery forever.
+                if let Some(Token::RBrace) = self.peek() {
+                    self.advance();
+                }
+            }
+        }
+
+        // Once a program defines functions, `main` is its entry point.
+        if !functions.is_empty() && !functions.iter().any(|function| function.name == "main") {
+            self.errors
+                .push(ParseError::new(ParseErrorKind::MissingMain, self.end).expected("`fn main`"));
+        }
+
+        if let Some(err) = self.lex_error.take() {
+            Err(vec![err])
+        } else if self.errors.is_empty() {
+            Ok(Program { functions, statements })
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    // Panic-mode recovery: records `err` and skips to the end of the broken
+    // statement, just past its `;` or up to the `}` closing its block, so
+    // the rest of the statement doesn't produce follow-on errors.
+    fn recover(&mut self, err: ParseError) {
+        self.errors.push(err);
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Semicolon => {
+                    self.advance();
+                    return;
+                }
+                Token::RBrace => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn parse_function(&mut self) -> ParseResult<Function> {
+        self.consume(Token::Fn)?;
+        let name = self.parse_ident()?;
+        self.consume(Token::LParen)?;
+
+        let mut params = Vec::new();
+        if !matches!(self.peek(), Some(Token::RParen)) {
+            params.push(self.parse_spanned(Self::parse_ident)?);
+            while let Some(Token::Comma) = self.peek() {
+                self.advance();
+                params.push(self.parse_spanned(Self::parse_ident)?);
+            }
         }
+        self.consume(Token::RParen)?;
+
+        self.in_function = true;
+        let body = self.parse_block();
+        self.in_function = false;
 
-        Program { statements }
+        Ok(Function { name, params, body: body? })
     }
 
-    fn parse_statement(&mut self) -> Statement {
+    // A stray `;` is an empty statement and produces nothing.
+    fn skip_empty_statements(&mut self) {
+        while let Some(Token::Semicolon) = self.peek() {
+            self.advance();
+        }
+    }
+
+    // A declaration may declare several variables.
+    fn parse_statements(&mut self) -> ParseResult<Vec<Spanned<Statement>>> {
         match self.peek() {
-            Some(Token::Int) => self.parse_declaration(),
+            Some(Token::Int | Token::Float | Token::Let) => self.parse_declaration(),
+            _ => Ok(vec![self.parse_statement()?]),
+        }
+    }
+
+    fn parse_statement(&mut self) -> ParseResult<Spanned<Statement>> {
+        let start = self.position();
+        let statement = match self.peek() {
             Some(Token::Print) => self.parse_print(),
-            _ => panic!("Unexpected token"),
+            Some(Token::If) => self.parse_if(),
+            Some(Token::While) => self.parse_while(),
+            Some(Token::For) => self.parse_for(),
+            Some(Token::LBrace) => Ok(Statement::Block(self.parse_block()?)),
+            Some(Token::Return) => self.parse_return(),
+            Some(Token::Ident(_) | Token::Literal(_) | Token::FloatLiteral(_) | Token::Read | Token::LParen) => {
+                let statement = self.parse_expr_statement()?;
+                self.consume(Token::Semicolon)?;
+                Ok(statement)
+            }
+            _ => Err(self.unexpected("statement")),
+        }?;
+        Ok(self.spanned(statement, start))
+    }
+
+    // An assignment if the expression is a bare name followed by `=` or
+    // `op=`, and otherwise the expression itself.
+    fn parse_expr_statement(&mut self) -> ParseResult<Statement> {
+        let expr = self.parse_expr()?;
+        let assigns = matches!(
+            self.peek(),
+            Some(Token::Equals | Token::PlusEquals | Token::MinusEquals | Token::StarEquals | Token::SlashEquals)
+        );
+        match &expr.node {
+            Expr::Variable(ident) if assigns => {
+                let ident = ident.clone();
+                self.finish_assignment(ident, expr)
+            }
+            _ => Ok(Statement::Expr(expr)),
+        }
+    }
+
+    fn parse_assignment(&mut self) -> ParseResult<Statement> {
+        let start = self.position();
+        let ident = self.parse_ident()?;
+        let target = self.spanned(Expr::Variable(ident.clone()), start);
+        self.finish_assignment(ident, target)
+    }
+
+    // `x op= e` desugars to `x = x op e`.
+    fn finish_assignment(&mut self, ident: String, target: Spanned<Expr>) -> ParseResult<Statement> {
+        let op = match self.peek() {
+            Some(Token::PlusEquals) => Some(BinOp::Add),
+            Some(Token::MinusEquals) => Some(BinOp::Sub),
+            Some(Token::StarEquals) => Some(BinOp::Mul),
+            Some(Token::SlashEquals) => Some(BinOp::Div),
+            _ => None,
+        };
+
+        match op {
+            Some(op) => {
+                self.advance();
+                let rhs = self.parse_expr()?;
+                Ok(Statement::Assign(ident, binary(target, op, rhs)))
+            }
+            None => {
+                self.consume(Token::Equals)?;
+                Ok(Statement::Assign(ident, self.parse_expr()?))
+            }
         }
     }
 
-    fn parse_declaration(&mut self) -> Statement {
-        self.consume(Token::Int);
-        let ident = self.parse_ident();
-        self.consume(Token::Equals);
-        let expr = self.parse_expr();
-        self.consume(Token::Semicolon);
-        Statement::Declare(ident, expr)
+    fn parse_for(&mut self) -> ParseResult<Statement> {
+        self.consume(Token::For)?;
+        self.consume(Token::LParen)?;
+
+        let init = match self.peek() {
+            Some(Token::Int | Token::Float | Token::Let) => {
+                Some(Box::new(self.parse_spanned(Self::parse_declarator)?))
+            }
+            Some(Token::Ident(_)) => Some(Box::new(self.parse_spanned(Self::parse_assignment)?)),
+            _ => None,
+        };
+        self.consume(Token::Semicolon)?;
+
+        let cond = match self.peek() {
+            Some(Token::Semicolon) => None,
+            _ => Some(self.parse_expr()?),
+        };
+        self.consume(Token::Semicolon)?;
+
+        let step = match self.peek() {
+            Some(Token::RParen) => None,
+            _ => Some(Box::new(self.parse_spanned(Self::parse_assignment)?)),
+        };
+        self.consume(Token::RParen)?;
+
+        let body = self.parse_block()?;
+        Ok(Statement::For { init, cond, step, body })
     }
 
-    fn parse_print(&mut self) -> Statement {
-        self.consume(Token::Print);
-        self.consume(Token::LParen);
-        let expr = self.parse_expr();
-        self.consume(Token::RParen);
-        self.consume(Token::Semicolon);
-        Statement::Print(expr)
+    fn parse_return(&mut self) -> ParseResult<Statement> {
+        if !self.in_function {
+            return Err(ParseError::new(ParseErrorKind::ReturnOutsideFunction, self.position()));
+        }
+        self.consume(Token::Return)?;
+        let expr = self.parse_expr()?;
+        self.consume(Token::Semicolon)?;
+        Ok(Statement::Return(expr))
     }
 
-    fn parse_expr(&mut self) -> Expr {
-        let mut expr = self.parse_primary();
+    fn parse_if(&mut self) -> ParseResult<Statement> {
+        self.consume(Token::If)?;
+        self.consume(Token::LParen)?;
+        let cond = self.parse_expr()?;
+        self.consume(Token::RParen)?;
+        let then = self.parse_block()?;
+        let else_ = if self.peek() == Some(&Token::Else) {
+            self.consume(Token::Else)?;
+            match self.peek() {
+                Some(Token::If) => Some(vec![self.parse_statement()?]),
+                _ => Some(self.parse_block()?),
+            }
+        } else {
+            None
+        };
+        Ok(Statement::If { cond, then, else_ })
+    }
 
-        while let Some(Token::Plus) = self.peek() {
-            self.pos += 1;
-            let right = self.parse_primary();
-            expr = Expr::BinOp(Box::new(expr), BinOp::Add, Box::new(right));
+    fn parse_while(&mut self) -> ParseResult<Statement> {
+        self.consume(Token::While)?;
+        self.consume(Token::LParen)?;
+        let cond = self.parse_expr()?;
+        self.consume(Token::RParen)?;
+        let body = self.parse_block()?;
+        Ok(Statement::While { cond, body })
+    }
+
+    fn parse_block(&mut self) -> ParseResult<Vec<Spanned<Statement>>> {
+        self.consume(Token::LBrace)?;
+        let mut statements = Vec::new();
+        loop {
+            self.skip_empty_statements();
+            if matches!(self.peek(), Some(Token::RBrace) | None) {
+                break;
+            }
+            match self.parse_statements() {
+                Ok(parsed) => statements.extend(parsed),
+                Err(err) => self.recover(err),
+            }
         }
+        self.consume(Token::RBrace)?;
+        Ok(statements)
+    }
+
+    // `int a = 1, b = a;` is one `Declare` per variable, in order, each
+    // after the `,` spanning from its name.
+    fn parse_declaration(&mut self) -> ParseResult<Vec<Spanned<Statement>>> {
+        let start = self.position();
+        let ty = self.parse_declared_type()?;
+        let first = self.parse_initializer(ty);
+        let mut declarations = vec![self.spanned(first?, start)];
+        while let Some(Token::Comma) = self.peek() {
+            self.advance();
+            if !matches!(self.peek(), Some(Token::Ident(_))) {
+                return Err(self.unexpected("variable name after `,`"));
+            }
+            let start = self.position();
+            let statement = self.parse_initializer(ty);
+            declarations.push(self.spanned(statement?, start));
+        }
+        self.consume(Token::Semicolon)?;
+        Ok(declarations)
+    }
+
+    fn parse_declarator(&mut self) -> ParseResult<Statement> {
+        let ty = self.parse_declared_type()?;
+        self.parse_initializer(ty)
+    }
+
+    fn parse_declared_type(&mut self) -> ParseResult<Option<Type>> {
+        let ty = match self.peek() {
+            Some(Token::Let) => None,
+            Some(Token::Float) => Some(Type::Float),
+            Some(Token::Int) => Some(Type::Int),
+            _ => return Err(self.unexpected("`int`, `float` or `let`")),
+        };
+        self.advance();
+        Ok(ty)
+    }
 
-        expr
+    // `name = expr`, the part of a declaration after the type.
+    fn parse_initializer(&mut self, ty: Option<Type>) -> ParseResult<Statement> {
+        let ident = self.parse_ident()?;
+        self.consume(Token::Equals)?;
+        let expr = self.parse_expr()?;
+        Ok(Statement::Declare(ident, ty, expr))
     }
 
-    fn parse_primary(&mut self) -> Expr {
-        match self.peek().unwrap() {
-            Token::Ident(name) => {
+    fn parse_print(&mut self) -> ParseResult<Statement> {
+        self.consume(Token::Print)?;
+        self.consume(Token::LParen)?;
+        let statement = match self.peek() {
+            Some(Token::Str(text)) => {
+                let text = text.clone();
+                self.advance();
+                Statement::PrintString(text)
+            }
+            _ => Statement::Print(self.parse_expr()?),
+        };
+        self.consume(Token::RParen)?;
+        self.consume(Token::Semicolon)?;
+        Ok(statement)
+    }
+
+    fn parse_expr(&mut self) -> ParseResult<Spanned<Expr>> {
+        self.parse_binary(0)
+    }
+
+    // Precedence climbing: parses operands joined by operators that bind
+    // tighter than `min`. Binary operators are left associative. `cond ?
+    // then : else_` binds loosest, at 0, and nests to the right.
+    fn parse_binary(&mut self, min: u8) -> ParseResult<Spanned<Expr>> {
+        let mut expr = self.parse_spanned(Self::parse_primary)?;
+
+        loop {
+            match self.peek() {
+                Some(Token::Question) if min == 0 => {
+                    self.advance();
+                    let then = self.parse_expr()?;
+                    self.consume(Token::Colon)?;
+                    let else_ = self.parse_expr()?;
+                    return Ok(conditional(expr, then, else_));
+                }
+                Some(token) => match binary_op(token) {
+                    Some(op) if op.precedence() > min => {
+                        self.advance();
+                        let right = self.parse_binary(op.precedence())?;
+                        expr = binary(expr, op, right);
+                    }
+                    _ => break,
+                },
+                None => break,
+            }
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_primary(&mut self) -> ParseResult<Expr> {
+        match self.peek() {
+            Some(Token::Ident(name)) => {
                 let name = name.clone();
-                self.pos += 1;
-                Expr::Variable(name)
+                self.advance();
+                if let Some(Token::LParen) = self.peek() {
+                    let args = self.parse_args()?;
+                    return Ok(Expr::Call { function: name, args });
+                }
+                Ok(Expr::Variable(name))
             }
-            Token::Literal(n) => {
+            Some(Token::Literal(n)) => {
                 let n = *n;
-                self.pos += 1;
-                Expr::Literal(n)
+                self.advance();
+                Ok(Expr::Literal(n))
             }
-            _ => panic!("Unexpected token in expression"),
+            Some(Token::FloatLiteral(n)) => {
+                let n = *n;
+                self.advance();
+                Ok(Expr::FloatLiteral(n))
+            }
+            Some(Token::Read) => {
+                self.advance();
+                self.consume(Token::LParen)?;
+                self.consume(Token::RParen)?;
+                Ok(Expr::Read)
+            }
+            Some(Token::LParen) => {
+                self.advance();
+                let expr = self.parse_expr()?;
+                self.consume(Token::RParen)?;
+                Ok(expr.node)
+            }
+            _ => Err(self.unexpected("expression")),
         }
     }
 
-    fn parse_ident(&mut self) -> String {
-        match self.peek().unwrap() {
-            Token::Ident(name) => {
+    fn parse_args(&mut self) -> ParseResult<Vec<Spanned<Expr>>> {
+        self.consume(Token::LParen)?;
+        let mut args = Vec::new();
+        if !matches!(self.peek(), Some(Token::RParen)) {
+            args.push(self.parse_expr()?);
+            while let Some(Token::Comma) = self.peek() {
+                self.advance();
+                args.push(self.parse_expr()?);
+            }
+        }
+        self.consume(Token::RParen)?;
+        Ok(args)
+    }
+
+    fn parse_ident(&mut self) -> ParseResult<String> {
+        match self.peek() {
+            Some(Token::Ident(name)) => {
                 let name = name.clone();
-                self.pos += 1;
-                name
+                self.advance();
+                Ok(name)
             }
-            _ => panic!("Expected identifier"),
+            _ => Err(self.unexpected("identifier")),
         }
     }
 
     fn peek(&self) -> Option<&Token> {
-        self.tokens.get(self.pos)
+        self.current.as_deref()
     }
 
-    fn consume(&mut self, expected: Token) {
-        if let Some(token) = self.peek() {
-            if std::mem::discriminant(token) == std::mem::discriminant(&expected) {
-                self.pos += 1;
-                return;
+    // Moves to the next token. After a lexer error there are none.
+    fn advance(&mut self) {
+        if let Some(token) = self.current.take() {
+            self.last_end = Some(token.span.end);
+        }
+        if self.lex_error.is_some() {
+            return;
+        }
+        match self.tokens.next() {
+            Some(Ok(token)) => self.current = Some(token),
+            Some(Err(err)) => self.lex_error = Some(err),
+            None => {}
+        }
+    }
+
+    // Where the current token starts, or the end of the source once all
+    // tokens are consumed.
+    fn position(&self) -> Position {
+        self.current.as_ref().map_or(self.end, |token| token.span.start)
+    }
+
+    // Spans `node` from `start` to the end of the last consumed token.
+    fn spanned<T>(&self, node: T, start: Position) -> Spanned<T> {
+        let end = self.last_end.unwrap_or(start);
+        Spanned::new(node, Span { start, end })
+    }
+
+    fn parse_spanned<T>(&mut self, parse: fn(&mut Self) -> ParseResult<T>) -> ParseResult<Spanned<T>> {
+        let start = self.position();
+        let node = parse(self)?;
+        Ok(self.spanned(node, start))
+    }
+
+    fn consume(&mut self, expected: Token) -> ParseResult<()> {
+        if let Some(token) = self.peek()
+            && std::mem::discriminant(token) == std::mem::discriminant(&expected)
+        {
+            self.advance();
+            return Ok(());
+        }
+        Err(self.unexpected(expected))
+    }
+
+    fn unexpected(&self, expected: impl ToString) -> ParseError {
+        let error = match self.peek() {
+            Some(token) => ParseError::new(ParseErrorKind::UnexpectedToken, self.position()).found(token),
+            None => ParseError::new(ParseErrorKind::UnexpectedEndOfInput, self.end),
+        };
+        error.expected(expected)
+    }
+}
+
+fn binary(left: Spanned<Expr>, op: BinOp, right: Spanned<Expr>) -> Spanned<Expr> {
+    let span = Span {
+        start: left.span.start,
+        end: right.span.end,
+    };
+    Spanned::new(Expr::BinOp(Box::new(left), op, Box::new(right)), span)
+}
+
+fn conditional(cond: Spanned<Expr>, then: Spanned<Expr>, else_: Spanned<Expr>) -> Spanned<Expr> {
+    let span = Span { start: cond.span.start, end: else_.span.end };
+    let expr = Expr::Conditional {
+        cond: Box::new(cond),
+        then: Box::new(then),
+        else_: Box::new(else_),
+    };
+    Spanned::new(expr, span)
+}
+
+// Identifiers start with an ASCII letter or `_` and continue with ASCII
+// letters, digits or `_`.
+fn is_identifier_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// The binary operator each token stands for. How tightly it binds is
+// `BinOp::precedence`, which the pretty-printer shares.
+fn binary_op(token: &Token) -> Option<BinOp> {
+    match token {
+        Token::Star => Some(BinOp::Mul),
+        Token::Slash => Some(BinOp::Div),
+        Token::Plus => Some(BinOp::Add),
+        Token::Minus => Some(BinOp::Sub),
+        Token::Less => Some(BinOp::Less),
+        Token::Greater => Some(BinOp::Greater),
+        Token::LessEqual => Some(BinOp::LessEqual),
+        Token::GreaterEqual => Some(BinOp::GreaterEqual),
+        Token::EqualEqual => Some(BinOp::Equal),
+        Token::NotEqual => Some(BinOp::NotEqual),
+        _ => None,
+    }
+}
+
+/// Why `parse_file` failed: the file couldn't be read, or its contents
+/// didn't parse.
+#[derive(Debug)]
+pub enum ParseFileError {
+    Io(std::io::Error),
+    Parse(Vec<ParseError>),
+}
+
+impl fmt::Display for ParseFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseFileError::Io(err) => write!(f, "{}", err),
+            ParseFileError::Parse(errors) => {
+                for (i, err) in errors.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{}", err)?;
+                }
+                Ok(())
             }
         }
-        panic!("Unexpected token");
     }
 }
 
-pub fn parse_file<P: AsRef<Path>>(path: P) -> Result<Program, std::io::Error> {
+impl std::error::Error for ParseFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseFileError::Io(err) => Some(err),
+            ParseFileError::Parse(errors) => errors.first().map(|err| err as _),
+        }
+    }
+}
+
+impl From<std::io::Error> for ParseFileError {
+    fn from(err: std::io::Error) -> Self {
+        ParseFileError::Io(err)
+    }
+}
+
+impl From<Vec<ParseError>> for ParseFileError {
+    fn from(errors: Vec<ParseError>) -> Self {
+        ParseFileError::Parse(errors)
+    }
+}
+
+pub fn parse_file<P: AsRef<Path>>(path: P) -> Result<Program, ParseFileError> {
     let mut file = File::open(path)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
 
-    let mut lexer = Lexer::new(&contents);
-    let mut tokens = Vec::new();
-    while let Some(token) = lexer.next_token() {
-        tokens.push(token);
-    }
+    Ok(parse_str(&contents)?)
+}
 
-    let mut parser = Parser::new(tokens);
-    Ok(parser.parse())
+/// Parses `source`, reporting every error found rather than just the first.
+pub fn parse_str(source: &str) -> Result<Program, Vec<ParseError>> {
+    let lexer = Lexer::new(source);
+    let end = lexer.end();
+    Parser::new(lexer, end).parse()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::ops::Deref;
+
+    fn single_error(source: &str) -> ParseError {
+        let mut errors = parse_str(source).unwrap_err();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        errors.remove(0)
+    }
+
+    fn temp_source(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn lex(source: &str) -> Vec<Token> {
+        Lexer::new(source).map(|token| token.unwrap().node).collect()
+    }
+
+    fn ident(name: &str) -> Token {
+        Token::Ident(name.to_string())
+    }
+
+    fn line_columns(span: Span) -> ((usize, usize), (usize, usize)) {
+        (
+            (span.start.line, span.start.column),
+            (span.end.line, span.end.column),
+        )
+    }
+
+    #[test]
+    fn test_token_spans_after_multibyte_characters_and_newlines() {
+        // `é` is two bytes but one column.
+        let spans: Vec<Span> = Lexer::new("// café\nint  x\n\t= 1;")
+            .map(|token| token.unwrap().span)
+            .collect();
+        assert_eq!(spans[0].start, Position { offset: 9, line: 2, column: 1 });
+        assert_eq!(spans[0].end, Position { offset: 12, line: 2, column: 4 });
+        assert_eq!(spans[1].start, Position { offset: 14, line: 2, column: 6 });
+        assert_eq!(spans[2].start, Position { offset: 17, line: 3, column: 2 });
+        assert_eq!(line_columns(spans[4]), ((3, 5), (3, 6)));
+
+        let err = single_error("/* ü */ @");
+        assert_eq!(err.position, Position { offset: 9, line: 1, column: 9 });
+        assert_eq!(err.to_string(), "Unexpected character at 1:9: found `@`");
+    }
+
+    #[test]
+    fn test_lexer_is_lazy_and_stops_at_an_error() {
+        let mut lexer = Lexer::new("int x = 1 @ 2; int y = 3;");
+        assert_eq!(lexer.next().unwrap().unwrap().node, Token::Int);
+        assert_eq!(lexer.next().unwrap().unwrap().node, ident("x"));
+        let rest: Vec<_> = lexer.by_ref().take(2).collect();
+        assert!(matches!(rest[..], [Ok(_), Ok(_)]));
+
+        let err = lexer.next().unwrap().unwrap_err();
+        assert_eq!((err.kind, err.position.offset), (ParseErrorKind::UnexpectedCharacter, 10));
+        assert!(lexer.next().is_none());
+        assert!(lexer.next().is_none());
+    }
+
+    #[test]
+    fn test_parser_consumes_any_token_stream() {
+        let lexer = Lexer::new("int x = 1;\nPrint(x);");
+        let end = lexer.end();
+        let tokens: Vec<_> = lexer.collect();
+        let program = Parser::new(tokens, end).parse().unwrap();
+        assert_eq!(program, parse_str("int x = 1; Print(x);").unwrap());
+
+        // The lexer error is all that's reported, though the statement it
+        // cuts short is also incomplete.
+        let lexer = Lexer::new("int = 1; Print(1 @");
+        let end = lexer.end();
+        let errors = Parser::new(lexer, end).parse().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParseErrorKind::UnexpectedCharacter);
+    }
+
+    #[test]
+    fn test_statements_and_expressions_carry_spans() {
+        let program = parse_str("int x = 1;\n  Print((x + 2) * 3);\nx += 4;").unwrap();
+        let print = &program.statements[1];
+        assert_eq!(line_columns(print.span), ((2, 3), (2, 22)));
+
+        let Statement::Print(expr) = &**print else {
+            panic!("unexpected statement: {:?}", print);
+        };
+        assert_eq!(line_columns(expr.span), ((2, 9), (2, 20)));
+        let Expr::BinOp(left, _, right) = &**expr else {
+            panic!("unexpected expression: {:?}", expr);
+        };
+        assert_eq!(line_columns(left.span), ((2, 9), (2, 16)));
+        assert_eq!(line_columns(right.span), ((2, 19), (2, 20)));
+
+        // The desugared `x = x + 4` spans the whole compound assignment.
+        let Statement::Assign(_, expr) = &*program.statements[2] else {
+            panic!("unexpected statement: {:?}", program.statements[2]);
+        };
+        assert_eq!(line_columns(program.statements[2].span), ((3, 1), (3, 8)));
+        assert_eq!(line_columns(expr.span), ((3, 1), (3, 7)));
+    }
+
+    #[test]
+    fn test_lex_identifiers_with_underscores() {
+        assert_eq!(
+            lex("my_var _leading __ x2y trailing_ a_1_b"),
+            vec![
+                ident("my_var"),
+                ident("_leading"),
+                ident("__"),
+                ident("x2y"),
+                ident("trailing_"),
+                ident("a_1_b"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_keywords_exactly() {
+        assert_eq!(
+            lex("int int_ _int integer while whilex Print print"),
+            vec![
+                Token::Int,
+                ident("int_"),
+                ident("_int"),
+                ident("integer"),
+                Token::While,
+                ident("whilex"),
+                Token::Print,
+                ident("print"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_digit_prefixed_identifier_is_an_error() {
+        for source in ["123abc", "1_000", "2.5x"] {
+            let err = Lexer::new(source).next().unwrap().unwrap_err();
+            assert_eq!(err.kind, ParseErrorKind::InvalidNumber);
+            assert_eq!(err.position.offset, 0);
+            assert_eq!(err.found.as_deref(), Some(source));
+        }
+        assert_eq!(lex("12 ab"), vec![Token::Literal(12), ident("ab")]);
+    }
+
+    #[test]
+    fn test_lex_hex_and_binary_literals() {
+        assert_eq!(
+            lex("0xFF 0x1f 0XdEaD_bEeF 0b1010 0B1111_0000 0x0 0b0"),
+            vec![
+                Token::Literal(0xFF),
+                Token::Literal(0x1f),
+                Token::Literal(0xdead_beef),
+                Token::Literal(0b1010),
+                Token::Literal(0b1111_0000),
+                Token::Literal(0),
+                Token::Literal(0),
+            ]
+        );
+        assert_eq!(lex("0x7FFF_FFFF_FFFF_FFFF"), vec![Token::Literal(i64::MAX)]);
+    }
+
+    #[test]
+    fn test_lex_decimal_literals_unchanged() {
+        assert_eq!(
+            lex("0 7 0123 42 1.5"),
+            vec![
+                Token::Literal(0),
+                Token::Literal(7),
+                Token::Literal(123),
+                Token::Literal(42),
+                Token::FloatLiteral(1.5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_malformed_radix_literals() {
+        for source in ["0x", "0x_", "0b", "0xG1", "0b102"] {
+            let err = Lexer::new(source).next().unwrap().unwrap_err();
+            assert_eq!((err.kind, err.position.offset), (ParseErrorKind::InvalidNumber, 0), "{}", source);
+            assert_eq!(err.found.as_deref(), Some(source));
+        }
+        let too_big = format!("0b1{}", "0".repeat(63));
+        for source in ["0x8000_0000_0000_0000", &too_big] {
+            let err = Lexer::new(source).next().unwrap().unwrap_err();
+            assert_eq!((err.kind, err.position.offset), (ParseErrorKind::IntegerOverflow, 0), "{}", source);
+            assert_eq!(err.found.as_deref(), Some(source));
+        }
+    }
+
+    #[test]
+    fn test_lex_string_literals() {
+        assert_eq!(
+            lex("\"a; b\" \"\\\"\\\\\\n\\t\\r\" \"é\nx\""),
+            vec![
+                Token::Str("a; b".to_string()),
+                Token::Str("\"\\\n\t\r".to_string()),
+                Token::Str("é\nx".to_string()),
+            ]
+        );
+
+        let err = Lexer::new("Print(\"ab\\q\");").last().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "Invalid escape in string literal at 1:10: found `\\q`");
+        let err = Lexer::new("x \"ab\\\"").last().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "Unterminated string literal at 1:3: expected `\"`");
+    }
+
+    #[test]
+    fn test_lex_decimal_exceeding_i64() {
+        assert_eq!(lex("9223372036854775807"), vec![Token::Literal(i64::MAX)]);
+        let err = Lexer::new("9223372036854775808").next().unwrap().unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::IntegerOverflow);
+        assert_eq!(err.found.as_deref(), Some("9223372036854775808"));
+
+        // Without a unary minus, i64::MIN is out of reach too.
+        let err = single_error("int x = 0 - 9223372036854775808;");
+        assert_eq!(err.to_string(), "Integer literal too large at 1:13: found 9223372036854775808");
+    }
+
+    #[test]
+    fn test_parenthesized_expressions() {
+        let program = parse_str("int x = (1 + 2) * 3;").unwrap();
+        let Statement::Declare(_, _, expr) = &*program.statements[0] else {
+            panic!("unexpected statement: {:?}", program.statements[0]);
+        };
+        let Expr::BinOp(left, BinOp::Mul, _) = &**expr else {
+            panic!("unexpected expression: {:?}", expr);
+        };
+        assert!(matches!(***left, Expr::BinOp(_, BinOp::Add, _)));
+    }
+
+    #[test]
+    fn test_parse_conditional_expressions() {
+        let program = parse_str("int m = a < b + 1 ? a : b;\nPrint(x ? 1 : y ? 2 : 3);").unwrap();
+        assert!(matches!(
+            &*program.statements[0],
+            Statement::Declare(_, _, Spanned { node: Expr::Conditional { cond, then, else_ }, span })
+                if matches!(cond.node, Expr::BinOp(_, BinOp::Less, _))
+                    && then.node == Expr::Variable("a".to_string())
+                    && else_.node == Expr::Variable("b".to_string())
+                    && line_columns(*span) == ((1, 9), (1, 26))
+        ));
+        assert!(matches!(
+            &*program.statements[1],
+            Statement::Print(Spanned { node: Expr::Conditional { cond, else_, .. }, .. })
+                if cond.node == Expr::Variable("x".to_string())
+                    && matches!(else_.node, Expr::Conditional { .. })
+        ));
+
+        let err = single_error("int m = a ? b;");
+        assert_eq!(err.to_string(), "Unexpected token at 1:14: expected `:`, found `;`");
+    }
+
+    #[test]
+    fn test_expression_parenthesization() {
+        let cases = [
+            ("1 + 2 * 3", "1 + 2 * 3"),
+            ("(1 + 2) * 3", "(1 + 2) * 3"),
+            ("1 - (2 - 3)", "1 - (2 - 3)"),
+            ("(1 - 2) - 3", "1 - 2 - 3"),
+            ("a / (b * c)", "a / (b * c)"),
+            ("((a))", "a"),
+            ("(a < b) == (c < d)", "a < b == (c < d)"),
+            ("f(1 + 2, (3))", "f(1 + 2, 3)"),
+            ("2.0 * 0.25", "2.0 * 0.25"),
+            ("a ? b : (c ? d : e)", "a ? b : c ? d : e"),
+            ("(a ? b : c) ? d : e", "(a ? b : c) ? d : e"),
+            ("1 + (a < b ? 2 : 3)", "1 + (a < b ? 2 : 3)"),
+        ];
+        for (source, expected) in cases {
+            let program = parse_str(&format!("Print({});", source)).unwrap();
+            let Statement::Print(expr) = &*program.statements[0] else {
+                panic!("unexpected statement: {:?}", program.statements[0]);
+            };
+            assert_eq!(expr.to_string(), expected);
+        }
+    }
+
+    // Every node as an s-expression with its byte span, so two parses
+    // compare equal only if their trees and spans both match.
+    fn shape(expr: &Spanned<Expr>) -> String {
+        let Span { start, end } = expr.span;
+        let node = match &expr.node {
+            Expr::BinOp(left, op, right) => format!("({} {} {})", op.symbol(), shape(left), shape(right)),
+            Expr::Conditional { cond, then, else_ } => {
+                format!("(? {} {} {})", shape(cond), shape(then), shape(else_))
+            }
+            Expr::Call { function, args } => {
+                let args: Vec<String> = args.iter().map(shape).collect();
+                format!("({} {})", function, args.join(" "))
+            }
+            other => other.to_string(),
+        };
+        format!("{}@{}..{}", node, start.offset, end.offset)
+    }
+
+    fn parse_shape(source: &str) -> String {
+        let program = parse_str(&format!("Print({});", source)).unwrap();
+        let Statement::Print(expr) = &*program.statements[0] else {
+            panic!("unexpected statement: {:?}", program.statements[0]);
+        };
+        shape(expr)
+    }
+
+    #[test]
+    fn test_expression_shapes() {
+        // Shapes produced by the layered parser that preceded the binding
+        // power table.
+        let cases = [
+            ("a + b * c", "(+ a@6..7 (* b@10..11 c@14..15)@10..15)@6..15"),
+            ("a * b + c", "(+ (* a@6..7 b@10..11)@6..11 c@14..15)@6..15"),
+            ("a - b - c", "(- (- a@6..7 b@10..11)@6..11 c@14..15)@6..15"),
+            ("a / b / c * d", "(* (/ (/ a@6..7 b@10..11)@6..11 c@14..15)@6..15 d@18..19)@6..19"),
+            ("a + b * c < d", "(< (+ a@6..7 (* b@10..11 c@14..15)@10..15)@6..15 d@18..19)@6..19"),
+            ("a < b == c > d", "(> (== (< a@6..7 b@10..11)@6..11 c@15..16)@6..16 d@19..20)@6..20"),
+            ("a + b * c < d - e / f", "(< (+ a@6..7 (* b@10..11 c@14..15)@10..15)@6..15 (- d@18..19 (/ e@22..23 f@26..27)@22..27)@18..27)@6..27"),
+            ("a <= b != c >= d == e", "(== (>= (!= (<= a@6..7 b@11..12)@6..12 c@16..17)@6..17 d@21..22)@6..22 e@26..27)@6..27"),
+            ("(a + b) * (c - d)", "(* (+ a@7..8 b@11..12)@6..13 (- c@17..18 d@21..22)@16..23)@6..23"),
+            ("a * (b + c) / d", "(/ (* a@6..7 (+ b@11..12 c@15..16)@10..17)@6..17 d@20..21)@6..21"),
+            ("f(a + b, g(c) * 2) - 1", "(- (f (+ a@8..9 b@12..13)@8..13 (* (g c@17..18)@15..19 2@22..23)@15..23)@6..24 1@27..28)@6..28"),
+            ("a < b ? c + 1 : d * 2", "(? (< a@6..7 b@10..11)@6..11 (+ c@14..15 1@18..19)@14..19 (* d@22..23 2@26..27)@22..27)@6..27"),
+            ("a ? b : c ? d : e", "(? a@6..7 b@10..11 (? c@14..15 d@18..19 e@22..23)@14..23)@6..23"),
+            ("a ? b ? c : d : e", "(? a@6..7 (? b@10..11 c@14..15 d@18..19)@10..19 e@22..23)@6..23"),
+            ("1 + 2.5 * read() - x", "(- (+ 1@6..7 (* 2.5@10..13 read()@16..22)@10..22)@6..22 x@25..26)@6..26"),
+            ("((a))", "a@6..11"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(parse_shape(source), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_mixed_precedence_chain() {
+        assert_eq!(
+            parse_shape("a * b + c < d - e / f == g ? h : i"),
+            "(? (== (< (+ (* a@6..7 b@10..11)@6..11 c@14..15)@6..15 (- d@18..19 (/ e@22..23 f@26..27)@22..27)@18..27)@6..27 g@31..32)@6..32 h@35..36 i@39..40)@6..40"
+        );
+    }
+
+    #[test]
+    fn test_pretty_printed_programs_round_trip() {
+        let programs = [
+            "int x = 1; Print(x);",
+            "let a = 1 - (2 - 3) * 4 / (5 + 6); float b = 1.0 / 3.0; a += 2; Print(a);",
+            "int i = 0; while (i < 10) { if_ = i; { int j = i * 2; Print(j); } i = i + 1; }",
+            "for (int i = 0; i <= 3; i += 1) { Print(i); } for (;;) { } int k = 0; for (k = 1; ; ) { }",
+            "fn add(a, b) { return a + b; } fn main() { int r = add(add(1, 2), read()); return r; }",
+            "int x = read(); while ((x - 1) * 2 != 2 + x) { x = (x + 1) - (x - 1) / 2; }",
+            "// comment\nint y = 0x10 + 0b11; { } ;",
+            "int a = read(); if (a > 1) { Print(a); } else if (a) { } else { a = 0; } if (a) { }",
+            "Print(\"tab\\there \\\"quoted\\\" \\\\ done\\n\"); Print(\"\");",
+        ];
+        for source in programs {
+            let program = parse_str(source).unwrap();
+            let printed = program.to_source();
+            let reparsed = parse_str(&printed).unwrap_or_else(|errors| {
+                panic!("{:?} in:\n{}", errors, printed);
+            });
+            assert_eq!(reparsed, program, "{}", printed);
+            assert_eq!(reparsed.to_source(), printed);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_round_trip() {
+        let source = "fn main() { return 0; }
+            let a = read();
+            Print(((1 + a) * (3 - (4 / (5 + 6.5)))) - f(a, (7 < 8) == 9, 1 * 2));
+            for (int i = 0; i < 3; i += 1) { while (i) { i = 0; } }";
+        let program = parse_str(source).unwrap();
+        let reparsed = Program::from_json(&program.to_json().unwrap()).unwrap();
+        assert_eq!(reparsed, program);
+
+        // Equality ignores spans, so check they survive separately.
+        let spans = |program: &Program| -> Vec<Span> {
+            program.statements.iter().map(|statement| statement.span).collect()
+        };
+        assert_eq!(spans(&reparsed), spans(&program));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_is_externally_tagged() {
+        let json = parse_str("Print(1 + x);").unwrap().to_json().unwrap();
+        assert!(json.contains(r#"{"Print":{"node":{"BinOp":[{"node":{"Literal":1}"#), "{}", json);
+        assert!(json.contains(r#""Add""#), "{}", json);
+        assert!(json.contains(r#"{"Variable":"x"}"#), "{}", json);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_malformed_json_is_an_error() {
+        for json in ["", "{", "[]", r#"{"functions":[],"statements":[{"node":{"Jump":1}}]}"#] {
+            assert!(Program::from_json(json).is_err(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn test_to_source_formatting() {
+        let program = parse_str("fn main() { int x = 1; while (x < 3) { x += 1; } return x; }").unwrap();
+        assert_eq!(
+            program.to_source(),
+            "fn main() {\n    int x = 1;\n    while (x < 3) {\n        x = x + 1;\n    }\n    return x;\n}\n"
+        );
+    }
 
     #[test]
     fn test_parser() {
-        let program = parse_file("example.sl").unwrap();
-        println!("{:#?}", program);
+        let program = parse_str("int x = 5;\nint y = x * 2;\nPrint(x + y);").unwrap();
+        assert_eq!(program.statements.len(), 3);
+        assert!(program.functions.is_empty());
+    }
+
+    #[test]
+    fn test_parse_file() {
+        let path = temp_source("iridium_parse_file_ok.sl", "int x = 1; Print(x);");
+        let program = parse_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(program.unwrap().statements.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_file_keeps_io_and_parse_errors_apart() {
+        let missing = parse_file(std::env::temp_dir().join("iridium_parse_file_missing.sl"));
+        assert!(matches!(missing, Err(ParseFileError::Io(_))));
+
+        let path = temp_source("iridium_parse_file_bad.sl", "int x = ;");
+        let malformed = parse_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(malformed, Err(ParseFileError::Parse(errors)) if errors.len() == 1));
+    }
+
+    #[test]
+    fn test_parse_while() {
+        let program = parse_str("int i = 0; while (i < 3) { i = i + 1; }").unwrap();
+        match &*program.statements[1] {
+            Statement::While { cond, body } => {
+                assert!(matches!(**cond, Expr::BinOp(_, BinOp::Less, _)));
+                assert!(matches!(&*body[0], Statement::Assign(name, _) if name == "i"));
+                assert_eq!(body.len(), 1);
+            }
+            other => panic!("expected while, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_if_else_chain() {
+        let program = parse_str("int a = 1; if (a) { a = 2; } else if (a < 2) { } else { }").unwrap();
+        match &*program.statements[1] {
+            Statement::If { cond, then, else_: Some(else_) } => {
+                assert!(matches!(**cond, Expr::Variable(_)));
+                assert_eq!(then.len(), 1);
+                assert!(matches!(
+                    else_.as_slice(),
+                    [Spanned { node: Statement::If { else_: Some(inner), .. }, .. }] if inner.is_empty()
+                ));
+            }
+            other => panic!("expected if, got {:?}", other),
+        }
+        assert!(matches!(*parse_str("if (1) { }").unwrap().statements[0], Statement::If { else_: None, .. }));
+    }
+
+    #[test]
+    fn test_parse_zero_arg_function() {
+        let program = parse_str("fn main() { Print(1); return 0; }").unwrap();
+        assert!(program.statements.is_empty());
+        let main = &program.functions[0];
+        assert_eq!(main.name, "main");
+        assert!(main.params.is_empty());
+        assert_eq!(main.body.len(), 2);
+        assert!(matches!(*main.body[0], Statement::Print(_)));
+        assert!(matches!(&*main.body[1], Statement::Return(expr) if **expr == Expr::Literal(0)));
+    }
+
+    #[test]
+    fn test_parse_multi_arg_function() {
+        let program = parse_str("fn add(a, b, c) { return a + b + c; } fn main() { return 0; }").unwrap();
+        let add = &program.functions[0];
+        assert_eq!(add.name, "add");
+        let params: Vec<&str> = add.params.iter().map(|param| param.as_str()).collect();
+        assert_eq!(params, vec!["a", "b", "c"]);
+        assert!(matches!(&*add.body[0], Statement::Return(expr) if matches!(**expr, Expr::BinOp(..))));
+    }
+
+    #[test]
+    fn test_parse_nested_calls() {
+        let program = parse_str("int y = add(mul(2, 3), add(1, 0)) + f();").unwrap();
+        let Statement::Declare(_, _, Spanned { node: Expr::BinOp(call, BinOp::Add, empty), .. }) =
+            &*program.statements[0]
+        else {
+            panic!("unexpected statement: {:?}", program.statements[0]);
+        };
+        assert!(matches!(&***empty, Expr::Call { function, args } if function == "f" && args.is_empty()));
+
+        let Expr::Call { function, args } = &***call else {
+            panic!("expected call, got {:?}", call);
+        };
+        assert_eq!(function, "add");
+        assert!(matches!(&args[..], [
+            Spanned { node: Expr::Call { function: inner, args: inner_args }, .. },
+            Spanned { node: Expr::Call { .. }, .. },
+        ] if inner == "mul" && inner_args.len() == 2));
+    }
+
+    #[test]
+    fn test_comments_are_skipped() {
+        let source = "
+            // leading comment
+            int x /* between tokens */ = 1; // line end
+            /* spans
+               several
+               lines */
+            Print(x); //
+        ";
+        let program = parse_str(source).unwrap();
+        assert_eq!(program.statements.len(), 2);
+        assert!(matches!(
+            &*program.statements[1],
+            Statement::Print(Spanned { node: Expr::Variable(name), .. }) if name == "x"
+        ));
+    }
+
+    #[test]
+    fn test_comment_markers_next_to_operators() {
+        let program = parse_str("int x = 6/*inline*/ /2;//x/=2;").unwrap();
+        assert!(matches!(
+            &*program.statements[0],
+            Statement::Declare(_, _, Spanned { node: Expr::BinOp(_, BinOp::Div, _), .. })
+        ));
+    }
+
+    #[test]
+    fn test_unterminated_block_comment_reports_opening() {
+        let err = single_error("/* ok */ int x = 1; /* unterminated */ /* here");
+        assert_eq!(err.kind, ParseErrorKind::UnterminatedComment);
+        assert_eq!(err.position.offset, 39);
+        assert_eq!(err.to_string(), "Unterminated block comment at 1:40: expected `*/`");
+    }
+
+    #[test]
+    fn test_parse_typed_declarations() {
+        let program = parse_str("int a = 1; float b = 2.75; let c = 2.5 * b;").unwrap();
+        assert!(matches!(
+            &*program.statements[0],
+            Statement::Declare(_, Some(Type::Int), Spanned { node: Expr::Literal(1), .. })
+        ));
+        assert!(matches!(
+            *program.statements[1],
+            Statement::Declare(_, Some(Type::Float), Spanned { node: Expr::FloatLiteral(value), .. }) if value == 2.75
+        ));
+        assert!(matches!(
+            &*program.statements[2],
+            Statement::Declare(_, None, Spanned { node: Expr::BinOp(..), .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_read() {
+        let program = parse_str("int x = read() * read();").unwrap();
+        assert!(matches!(
+            &*program.statements[0],
+            Statement::Declare(_, _, Spanned { node: Expr::BinOp(left, BinOp::Mul, right), .. })
+                if ***left == Expr::Read && ***right == Expr::Read
+        ));
+    }
+
+    #[test]
+    fn test_functions_require_main() {
+        let err = single_error("fn helper() { return 1; }");
+        assert_eq!(err.kind, ParseErrorKind::MissingMain);
+    }
+
+    #[test]
+    fn test_return_outside_function() {
+        let err = single_error("int x = 1; return 1;");
+        let position = Position { offset: 11, line: 1, column: 12 };
+        assert_eq!(err, ParseError::new(ParseErrorKind::ReturnOutsideFunction, position));
+    }
+
+    #[test]
+    fn test_render_puts_caret_under_error_column() {
+        let source = "int a = 1;\nint b = (a + 2;\nPrint(b);";
+        let rendered = single_error(source).render(source);
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines[0], "error: Unexpected token: expected `)`, found `;`");
+        assert_eq!(lines[1], " --> 2:15");
+        assert_eq!(lines[3], "2 | int b = (a + 2;");
+        assert_eq!(lines[4], "  |               ^");
+        assert_eq!(lines[4].find('^'), lines[3].find(';'));
+    }
+
+    #[test]
+    fn test_malformed_programs_report_error_kinds() {
+        use ParseErrorKind::*;
+        let cases = [
+            ("int x = 1 @ 2;", UnexpectedCharacter, 10),
+            ("int x = 99999999999999999999;", IntegerOverflow, 8),
+            ("int x = 12ab;", InvalidNumber, 8),
+            ("Print(1)", UnexpectedEndOfInput, 8),
+            ("int x = 1 Print(x);", UnexpectedToken, 10),
+            ("int = 1;", UnexpectedToken, 4),
+            ("int x 1;", UnexpectedToken, 6),
+            ("int x = ;", UnexpectedToken, 8),
+            ("x + 1 = 2;", UnexpectedToken, 6),
+            ("while (1 { }", UnexpectedToken, 9),
+            ("{ int x = 1;", UnexpectedEndOfInput, 12),
+            ("fn (a) { return a; }", UnexpectedToken, 3),
+            ("fn f(a b) { return a; } fn main() { return 0; }", UnexpectedToken, 7),
+            ("for (int i = 0; i < 3) { }", UnexpectedToken, 21),
+            ("Print(read(1));", UnexpectedToken, 11),
+            ("} int x = 1;", UnexpectedToken, 0),
+        ];
+        for (source, kind, position) in cases {
+            let err = single_error(source);
+            assert_eq!((err.kind, err.position.offset), (kind, position), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_missing_semicolon_is_a_single_error() {
+        let err = single_error("int x = 1\nint y = 2;\nPrint(x + y);\nwhile (x < y) { x += 1; }");
+        assert_eq!(err.kind, ParseErrorKind::UnexpectedToken);
+        assert_eq!((err.position.line, err.position.column), (2, 1));
+        assert_eq!(err.expected.as_deref(), Some("`;`"));
+    }
+
+    #[test]
+    fn test_reports_every_independent_error() {
+        let source = "\
+int a = ;
+Print(a);
+while (a < 3) {
+    a = a +;
+    Print(a);
+}
+int b 2;
+Print(b);";
+        let errors = parse_str(source).unwrap_err();
+        let found: Vec<(ParseErrorKind, usize, usize)> = errors
+            .iter()
+            .map(|err| (err.kind, err.position.line, err.position.column))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (ParseErrorKind::UnexpectedToken, 1, 9),
+                (ParseErrorKind::UnexpectedToken, 4, 12),
+                (ParseErrorKind::UnexpectedToken, 7, 7),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_error_display() {
+        let err = single_error("int x = 1 Print(x);");
+        assert_eq!(
+            err.to_string(),
+            "Unexpected token at 1:11: expected `;`, found `Print`"
+        );
+
+        let err = single_error("int x = 1 + ");
+        assert_eq!(err.to_string(), "Unexpected end of input at 1:13: expected expression");
+
+        let err = single_error("int y = x # 2;");
+        assert_eq!(err.to_string(), "Unexpected character at 1:11: found `#`");
+    }
+
+    #[test]
+    fn test_multiple_statements_per_line() {
+        let program = parse_str("int x = 1; Print(x); x = 2; Print(x);").unwrap();
+        assert_eq!(program.statements.len(), 4);
+    }
+
+    #[test]
+    fn test_empty_statements_are_skipped() {
+        let program = parse_str(";; int x = 1;; ; Print(x); { ; } ;").unwrap();
+        assert_eq!(program.statements.len(), 3);
+        assert!(matches!(&*program.statements[2], Statement::Block(body) if body.is_empty()));
+    }
+
+    #[test]
+    fn test_parse_multiple_declarators() {
+        let program = parse_str("int a = 1, b = 2, c = a + b;\nlet d = 1.5, e = d;").unwrap();
+        assert_eq!(program, parse_str("int a = 1; int b = 2; int c = a + b; let d = 1.5; let e = d;").unwrap());
+        let spans: Vec<_> = program.statements.iter().map(|statement| line_columns(statement.span)).collect();
+        assert_eq!(spans[..2], [((1, 1), (1, 10)), ((1, 12), (1, 17))]);
+        assert_eq!(spans[4], ((2, 14), (2, 19)));
+
+        let err = single_error("int a = 1, ;");
+        assert_eq!((err.kind, err.position.offset), (ParseErrorKind::UnexpectedToken, 11));
+        assert_eq!(err.to_string(), "Unexpected token at 1:12: expected variable name after `,`, found `;`");
+    }
+
+    #[test]
+    fn test_parse_nested_blocks() {
+        let program = parse_str("int x = 1; { int y = x; { Print(y); } }").unwrap();
+        match &*program.statements[1] {
+            Statement::Block(outer) => {
+                assert!(matches!(&*outer[0], Statement::Declare(name, _, _) if name == "y"));
+                assert!(matches!(&*outer[1], Statement::Block(inner) if inner.len() == 1));
+            }
+            other => panic!("expected block, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_compound_assignment_desugars() {
+        let program = parse_str("int x = 1; x += 2; x -= 3; x *= 4; x /= 5;").unwrap();
+        let ops: Vec<&BinOp> = program.statements[1..]
+            .iter()
+            .map(|statement| match &**statement {
+                Statement::Assign(name, Spanned { node: Expr::BinOp(left, op, right), .. }) => {
+                    assert_eq!(name, "x");
+                    assert!(matches!(&***left, Expr::Variable(v) if v == "x"));
+                    assert!(matches!(***right, Expr::Literal(_)));
+                    op
+                }
+                other => panic!("expected desugared assignment, got {:?}", other),
+            })
+            .collect();
+        assert!(matches!(ops[..], [BinOp::Add, BinOp::Sub, BinOp::Mul, BinOp::Div]));
+    }
+
+    #[test]
+    fn test_expression_statements() {
+        let program = parse_str("int x = 1; x + 1; f(x, 2); (x); x = 3;").unwrap();
+        assert!(matches!(&*program.statements[1], Statement::Expr(expr) if matches!(**expr, Expr::BinOp(..))));
+        assert!(matches!(
+            &*program.statements[2],
+            Statement::Expr(Spanned { node: Expr::Call { function, args }, .. }) if function == "f" && args.len() == 2
+        ));
+        assert!(matches!(&*program.statements[3], Statement::Expr(expr) if matches!(&**expr, Expr::Variable(v) if v == "x")));
+        assert!(matches!(&*program.statements[4], Statement::Assign(name, _) if name == "x"));
+        assert_eq!(program.statements[2].span.end.offset, 26);
+        assert_eq!(program.to_source(), "int x = 1;\nx + 1;\nf(x, 2);\nx;\nx = 3;\n");
+    }
+
+    #[test]
+    fn test_compound_assignment_takes_whole_expression() {
+        let program = parse_str("int x = 1; x *= 2 + 3;").unwrap();
+        match &*program.statements[1] {
+            Statement::Assign(_, Spanned { node: Expr::BinOp(_, BinOp::Mul, right), .. }) => {
+                assert!(matches!(***right, Expr::BinOp(_, BinOp::Add, _)));
+            }
+            other => panic!("expected desugared assignment, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_for_clauses() {
+        let program = parse_str("for (int i = 0; i < 10; i = i + 1) { Print(i); }").unwrap();
+        match &*program.statements[0] {
+            Statement::For { init, cond, step, body } => {
+                assert!(matches!(init.as_deref().map(Deref::deref), Some(Statement::Declare(name, _, _)) if name == "i"));
+                assert!(matches!(cond.as_deref(), Some(Expr::BinOp(_, BinOp::Less, _))));
+                assert!(matches!(step.as_deref().map(Deref::deref), Some(Statement::Assign(name, _)) if name == "i"));
+                assert_eq!(body.len(), 1);
+            }
+            other => panic!("expected for, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_for_with_empty_clauses() {
+        let program = parse_str("int i = 0; for (; i < 3;) { i = i + 1; } for (;;) { }").unwrap();
+        assert!(matches!(
+            *program.statements[1],
+            Statement::For { init: None, cond: Some(_), step: None, .. }
+        ));
+        assert!(matches!(
+            *program.statements[2],
+            Statement::For { init: None, cond: None, step: None, .. }
+        ));
     }
 }
\ No newline at end of file
@@ -1,23 +1,800 @@
-use crate::{Assembler, VM, parser, codegen};
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
 
-pub fn pipeline() -> Result<(), Box<dyn std::error::Error>> {
+use crate::asm::{AssemblerError, SourceMap};
+use crate::ast::Program;
+use crate::codegen::{Backend, CodegenError, CodegenOptions, CodegenOutput};
+use crate::parser::{ParseError, ParseFileError};
+use crate::semantic::{self, SemanticError};
+use crate::typeck::{self, TypeError};
+use crate::vm::VMError;
+use crate::{Assembler, VM, parser, codegen, opt};
 
-    let mut assembler = Assembler::new();
+#[derive(Debug)]
+pub enum CompileError {
+    Io(std::io::Error),
+    Parse(Vec<ParseError>),
+    Semantic(Vec<SemanticError>),
+    Type(Vec<TypeError>),
+    Codegen(CodegenError),
+    Assembler(AssemblerError),
+    Runtime(VMError),
+}
+
+/// Settings for `compile_with`. The default enables every optimization.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileOptions {
+    /// Evaluate integer arithmetic on literals at compile time.
+    pub fold_constants: bool,
+    /// Drop unreachable statements and stores to variables that are never
+    /// read. Turning it off keeps every statement in the output, which
+    /// helps when reading the generated assembly.
+    pub eliminate_dead_code: bool,
+    /// Clean up local waste in the generated assembly; see `opt::peephole`.
+    pub peephole: bool,
+    /// Passed to `codegen::codegen_with`. The bytecode backend skips the
+    /// assembler, and with it `peephole`, which works on the text.
+    pub codegen: CodegenOptions,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            fold_constants: true,
+            eliminate_dead_code: true,
+            peephole: true,
+            codegen: CodegenOptions::default(),
+        }
+    }
+}
+
+pub fn pipeline() -> Result<(), CompileError> {
+    run_file("example.sl")
+}
+
+/// Compiles and runs the file, printing the assembly and then the program's
+/// output. A runtime error also prints the source line that caused it.
+pub fn run_file<P: AsRef<Path>>(path: P) -> Result<(), CompileError> {
+    let compiled = compile_file(path)?;
+    println!("{}", compiled.asm_code);
+
+    let mut vm = compiled.load();
+    let result = vm.run();
+    if let (Err(_), Some(line)) = (&result, vm.source_line()) {
+        println!("at {}", line);
+    }
+    Ok(result?)
+}
 
-    let program = parser::parse_file("example.sl").unwrap();
+/// Like `run_file`, but writes to `out` instead of stdout. Output printed
+/// before a runtime error is still written.
+pub fn run_file_to<P: AsRef<Path>>(path: P, out: &mut dyn Write) -> Result<(), CompileError> {
+    let compiled = compile_file(path)?;
+    writeln!(out, "{}", compiled.asm_code)?;
 
-    let asm = codegen::codegen(program);
-    
-    let asm_code = asm.join("\n").to_string();
-    println!("{}", asm_code);
-    
+    let mut vm = compiled.load();
+    vm.capture_output();
+    let result = vm.run();
+    out.write_all(vm.take_output().as_bytes())?;
+    if let (Err(_), Some(line)) = (&result, vm.source_line()) {
+        writeln!(out, "at {}", line)?;
+    }
+    Ok(result?)
+}
+
+struct CompiledFile {
+    source: String,
+    asm_code: String,
+    bytecode: Vec<u8>,
+    // Offsets in `bytecode` to lines of `source`.
+    source_map: SourceMap,
+}
+
+impl CompiledFile {
+    fn load(self) -> VM {
+        let mut vm = VM::new();
+        vm.add_program(self.bytecode);
+        vm.set_source_map(self.source_map, &self.source);
+        vm
+    }
+}
+
+fn compile_file<P: AsRef<Path>>(path: P) -> Result<CompiledFile, CompileError> {
+    let source = std::fs::read_to_string(path)?;
+    let asm_code = lower(parser::parse_str(&source)?, &CompileOptions::default())?;
+    let mut assembler = Assembler::new();
     let bytecode = assembler.compile(&asm_code)?;
-    // println!("Bytecode: {:#?}", bytecode);
 
-    let mut vm = VM::new();
-    vm.add_program(bytecode);
+    // The assembler maps to lines of assembly, which codegen annotates with
+    // the source lines they came from.
+    let mut program_line = None;
+    let program_lines: Vec<Option<usize>> = asm_code
+        .lines()
+        .map(|line| {
+            program_line = codegen::source_line(line).or(program_line);
+            program_line
+        })
+        .collect();
+    let source_map = assembler.source_map().map_lines(|line| program_lines[line - 1]);
+
+    Ok(CompiledFile { source, asm_code, bytecode, source_map })
+}
+
+pub fn compile(source: &str) -> Result<Vec<u8>, CompileError> {
+    compile_with(source, &CompileOptions::default())
+}
+
+pub fn compile_with(source: &str, options: &CompileOptions) -> Result<Vec<u8>, CompileError> {
+    compile_program(parser::parse_str(source)?, options)
+}
+
+/// Compiles several files as one program: their functions are pooled and
+/// their statements run in the order the files are given, all in one
+/// top-level scope. A name declared or defined in two files is a semantic
+/// error, as it would be within one file.
+pub fn compile_many<P: AsRef<Path>>(paths: &[P]) -> Result<Vec<u8>, CompileError> {
+    let mut program = Program { functions: Vec::new(), statements: Vec::new() };
+    for path in paths {
+        let file = parser::parse_file(path)?;
+        program.functions.extend(file.functions);
+        program.statements.extend(file.statements);
+    }
+    compile_program(program, &CompileOptions::default())
+}
+
+fn compile_program(program: Program, options: &CompileOptions) -> Result<Vec<u8>, CompileError> {
+    match generate(program, options)? {
+        CodegenOutput::Assembly(asm_code) => Ok(Assembler::new().compile(&asm_code.join("\n"))?),
+        CodegenOutput::Bytecode(bytecode, _) => Ok(bytecode),
+    }
+}
+
+// Checks, optimizes and generates assembly for `program`, whatever backend
+// `options` asks for.
+fn lower(program: Program, options: &CompileOptions) -> Result<String, CompileError> {
+    let codegen = CodegenOptions { backend: Backend::Text, ..options.codegen.clone() };
+    let options = CompileOptions { codegen, ..options.clone() };
+    match generate(program, &options)? {
+        CodegenOutput::Assembly(asm_code) => Ok(asm_code.join("\n")),
+        CodegenOutput::Bytecode(..) => unreachable!("the text backend generates assembly"),
+    }
+}
+
+fn generate(mut program: Program, options: &CompileOptions) -> Result<CodegenOutput, CompileError> {
+    semantic::check(&program)?;
+    typeck::check(&program)?;
+    if options.fold_constants {
+        program = opt::fold_constants(program);
+    }
+    if options.eliminate_dead_code {
+        program = opt::eliminate_dead_code(program);
+    }
+    let mut output = codegen::codegen_with(&program, &options.codegen)?;
+    if options.peephole && let CodegenOutput::Assembly(asm_code) = &mut output {
+        *asm_code = opt::peephole(std::mem::take(asm_code));
+    }
+    Ok(output)
+}
+
+impl From<std::io::Error> for CompileError {
+    fn from(err: std::io::Error) -> Self {
+        CompileError::Io(err)
+    }
+}
 
-    vm.run()?;
+impl From<Vec<ParseError>> for CompileError {
+    fn from(errors: Vec<ParseError>) -> Self {
+        CompileError::Parse(errors)
+    }
+}
+
+impl From<ParseFileError> for CompileError {
+    fn from(err: ParseFileError) -> Self {
+        match err {
+            ParseFileError::Io(err) => CompileError::Io(err),
+            ParseFileError::Parse(errors) => CompileError::Parse(errors),
+        }
+    }
+}
+
+impl From<Vec<SemanticError>> for CompileError {
+    fn from(errors: Vec<SemanticError>) -> Self {
+        CompileError::Semantic(errors)
+    }
+}
+
+impl From<Vec<TypeError>> for CompileError {
+    fn from(errors: Vec<TypeError>) -> Self {
+        CompileError::Type(errors)
+    }
+}
 
+impl From<CodegenError> for CompileError {
+    fn from(err: CodegenError) -> Self {
+        CompileError::Codegen(err)
+    }
+}
+
+impl From<AssemblerError> for CompileError {
+    fn from(err: AssemblerError) -> Self {
+        CompileError::Assembler(err)
+    }
+}
+
+impl From<VMError> for CompileError {
+    fn from(err: VMError) -> Self {
+        CompileError::Runtime(err)
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::Io(err) => write!(f, "IO Error: {}", err),
+            CompileError::Parse(errors) => {
+                write!(f, "Parse Error: ")?;
+                write_errors(f, errors)
+            }
+            CompileError::Semantic(errors) => {
+                write!(f, "Semantic Error: ")?;
+                write_errors(f, errors)
+            }
+            CompileError::Type(errors) => {
+                write!(f, "Type Error: ")?;
+                write_errors(f, errors)
+            }
+            CompileError::Codegen(err) => write!(f, "Codegen Error: {}", err),
+            CompileError::Assembler(err) => write!(f, "Assembler Error: {}", err),
+            CompileError::Runtime(err) => write!(f, "Runtime Error: {}", err),
+        }
+    }
+}
+
+fn write_errors<E: fmt::Display>(f: &mut fmt::Formatter<'_>, errors: &[E]) -> fmt::Result {
+    for (i, err) in errors.iter().enumerate() {
+        if i > 0 {
+            write!(f, "; ")?;
+        }
+        write!(f, "{}", err)?;
+    }
     Ok(())
 }
+
+impl std::error::Error for CompileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CompileError::Io(err) => Some(err),
+            CompileError::Parse(errors) => errors.first().map(|err| err as _),
+            CompileError::Semantic(errors) => errors.first().map(|err| err as _),
+            CompileError::Type(errors) => errors.first().map(|err| err as _),
+            CompileError::Codegen(err) => Some(err),
+            CompileError::Assembler(err) => Some(err),
+            CompileError::Runtime(err) => Some(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ParseErrorKind;
+
+    fn run(source: &str) -> String {
+        run_with_input(source, [])
+    }
+
+    fn run_with_input<const N: usize>(source: &str, input: [i32; N]) -> String {
+        let mut vm = VM::new();
+        vm.set_input(input);
+        vm.capture_output();
+        vm.add_program(compile(source).unwrap());
+        vm.run().unwrap();
+        vm.take_output()
+    }
+
+    #[test]
+    fn test_missing_file_is_an_io_error() {
+        let err = run_file("does/not/exist.sl").unwrap_err();
+        assert!(matches!(err, CompileError::Io(_)));
+    }
+
+    fn temp_source(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn run_bytecode(bytecode: Vec<u8>) -> String {
+        let mut vm = VM::new();
+        vm.capture_output();
+        vm.add_program(bytecode);
+        vm.run().unwrap();
+        vm.take_output()
+    }
+
+    #[test]
+    fn test_compile_many_shares_one_namespace() {
+        let first = temp_source("iridium_many_first.sl", "int total = 4;");
+        let second = temp_source("iridium_many_second.sl", "total = total * 10; Print(total + 2);");
+        let one = temp_source("iridium_many_one.sl", "int total = 1; fn main() { return 1; }");
+        let two = temp_source("iridium_many_two.sl", "int total = 2; fn main() { return 2; }");
+
+        let merged = compile_many(&[&first, &second]).map(run_bytecode);
+        let reversed = compile_many(&[&second, &first]);
+        let collided = compile_many(&[&one, &two]);
+        for path in [first, second, one, two] {
+            std::fs::remove_file(path).unwrap();
+        }
+
+        assert_eq!(merged.unwrap(), "PRINT: 42\n");
+        assert!(matches!(reversed, Err(CompileError::Semantic(_))));
+        let Err(CompileError::Semantic(errors)) = collided else {
+            panic!("expected a collision, got {:?}", collided);
+        };
+        assert!(matches!(&errors[0], SemanticError::DuplicateFunction { name } if name == "main"));
+        assert!(matches!(&errors[1], SemanticError::DuplicateDeclaration { name, .. } if name == "total"));
+    }
+
+    #[test]
+    fn test_commented_program_runs() {
+        let source = "
+            // Sum the first three numbers.
+            int sum = 0; /* running total */
+            for (int i = 1; i <= 3; i += 1) {
+                sum += i; // accumulate
+            }
+            /*
+             * Print(0);
+             */
+            Print(sum);
+        ";
+        assert_eq!(run(source), "PRINT: 6\n");
+    }
+
+    #[test]
+    fn test_unterminated_comment_is_a_parse_error() {
+        let err = compile("Print(1); /* never closed").unwrap_err();
+        let CompileError::Parse(errors) = err else {
+            panic!("expected a parse error, got {:?}", err);
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].kind, errors[0].position.offset), (ParseErrorKind::UnterminatedComment, 10));
+    }
+
+    #[test]
+    fn test_arity_mismatch_is_a_semantic_error() {
+        let err = compile("fn f(a) { return a; } fn main() { return f(1, 2); }").unwrap_err();
+        assert!(matches!(&err, CompileError::Semantic(errors) if errors.len() == 1));
+    }
+
+    #[test]
+    fn test_register_exhaustion_is_a_codegen_error() {
+        // Temporaries aren't spilled, and 1 + (2 + (3 + ...)) holds every
+        // left operand at once.
+        let nested = (1..=32).rev().fold("33".to_string(), |inner, n| format!("{} + ({})", n, inner));
+        let options = CompileOptions { fold_constants: false, ..Default::default() };
+        let err = compile_with(&format!("Print({});", nested), &options).unwrap_err();
+        assert!(matches!(err, CompileError::Codegen(CodegenError::TooManyRegisters)));
+    }
+
+    #[test]
+    fn test_forty_live_variables_spill_to_memory() {
+        let declarations: String = (0..40).map(|n| format!("int v{} = {} * 3;", n, n)).collect();
+        let sum = (0..40).map(|n| format!("v{}", n)).collect::<Vec<_>>().join(" + ");
+        let source = format!("{} Print({}); v0 = v0 + v39; {{ int v1 = 1; Print(v1 + v0); }} Print(v1);", declarations, sum);
+        assert_eq!(run(&source), "PRINT: 2340\nPRINT: 118\nPRINT: 3\n");
+    }
+
+    #[test]
+    fn test_long_chain_assembles() {
+        let sum = (1..=40).map(|n| n.to_string()).collect::<Vec<_>>().join(" + ");
+        let source = format!("Print({});", sum);
+        let mut vm = VM::new();
+        vm.capture_output();
+        vm.add_program(compile_with(&source, &CompileOptions { fold_constants: false, ..Default::default() }).unwrap());
+        vm.run().unwrap();
+        assert_eq!(vm.take_output(), "PRINT: 820\n");
+    }
+
+    #[test]
+    fn test_constant_folding_can_be_disabled() {
+        let source = "int x = (2 + 3) * 4 * (6 - 1) + 1; Print(x - 1);";
+        let folded = compile(source).unwrap();
+        let unfolded = compile_with(source, &CompileOptions { fold_constants: false, ..Default::default() }).unwrap();
+        assert!(folded.len() < unfolded.len());
+        assert_eq!(run(source), "PRINT: 100\n");
+    }
+
+    #[test]
+    fn test_dead_code_elimination_can_be_disabled() {
+        let unused: String = (0..10).map(|n| format!("int u{} = {};", n, n)).collect();
+        let source = &format!("{} int x = 5; Print(x);", unused);
+        let options = CompileOptions { eliminate_dead_code: false, ..Default::default() };
+        assert!(compile(source).unwrap().len() < compile_with(source, &options).unwrap().len());
+        assert_eq!(run(source), "PRINT: 5\n");
+    }
+
+    #[test]
+    fn test_peephole_can_be_disabled() {
+        let source = "int a = read(); int b = read(); int c = a < b; if (a < b) { Print(c); } Print(a * b);";
+        let options = CompileOptions { peephole: false, ..Default::default() };
+        assert!(compile(source).unwrap().len() < compile_with(source, &options).unwrap().len());
+    }
+
+    #[test]
+    fn test_codegen_options_are_passed_through() {
+        let source = "int a = read(); a = a * 3; a = a - 1; a = a / 2; a = a + 4; Print(a); Print(a * a);";
+        let options = |codegen| CompileOptions { codegen, ..Default::default() };
+        let unoptimized = options(CodegenOptions { opt_level: 0, ..Default::default() });
+        assert!(compile(source).unwrap().len() < compile_with(source, &unoptimized).unwrap().len());
+
+        // The bytecode backend encodes what the assembler would, less what
+        // the text peephole removes.
+        let bytecode = CompileOptions { peephole: false, ..options(CodegenOptions { backend: Backend::Bytecode, ..Default::default() }) };
+        let assembled = CompileOptions { peephole: false, ..Default::default() };
+        let calls = "fn add(a, b) { return a + b; } fn main() { int x = read(); Print(add(x, 2) * x); }";
+        for source in [source, calls] {
+            assert_eq!(compile_with(source, &bytecode).unwrap(), compile_with(source, &assembled).unwrap(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_declared_variable_reads_its_own_register() {
+        let source = "int x = 2 + 3; Print(x);";
+        assert_eq!(run(source), "PRINT: 5\n");
+
+        let mut vm = VM::new();
+        vm.capture_output();
+        vm.add_program(compile_with(source, &CompileOptions { fold_constants: false, ..Default::default() }).unwrap());
+        vm.run().unwrap();
+        assert_eq!(vm.take_output(), "PRINT: 5\n");
+    }
+
+    #[test]
+    fn test_non_commutative_operators_keep_operand_order() {
+        let source = "Print(10 - 4); Print(6 / 2); int a = 20; int b = 3; Print(a - b * 4 / (b - 1));";
+        let mut vm = VM::new();
+        vm.capture_output();
+        vm.add_program(compile_with(source, &CompileOptions { fold_constants: false, ..Default::default() }).unwrap());
+        vm.run().unwrap();
+        assert_eq!(vm.take_output(), "PRINT: 6\nPRINT: 3\nPRINT: 14\n");
+        assert_eq!(run(source), "PRINT: 6\nPRINT: 3\nPRINT: 14\n");
+    }
+
+    #[test]
+    fn test_division_by_zero_is_a_runtime_error() {
+        let path = std::env::temp_dir().join("iridium_pipeline_div_zero.sl");
+        std::fs::write(&path, "int x = 1 / 0;").unwrap();
+        let err = run_file(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(err, CompileError::Runtime(VMError::DivisionByZero)));
+    }
+
+    #[test]
+    fn test_run_file_to_captures_assembly_and_output() {
+        let path = temp_source("iridium_pipeline_run_to.sl", "int x = 6; Print(x * 7);");
+        let mut out = Vec::new();
+        let result = run_file_to(&path, &mut out);
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+        let expected = "; line 1\nLOAD r0 6\n; x is in r0\nLOAD r1 7\nMUL r0 r1 r0\nPRINT r0\nHLT\nPRINT: 42\n";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+        let path = temp_source("iridium_pipeline_run_to_error.sl", "Print(1);\nint x = 1 / 0;");
+        let mut out = Vec::new();
+        let err = run_file_to(&path, &mut out).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(err, CompileError::Runtime(VMError::DivisionByZero)));
+        assert!(String::from_utf8(out).unwrap().ends_with("HLT\nPRINT: 1\nat line 2: int x = 1 / 0;\n"));
+    }
+
+    #[test]
+    fn test_runtime_error_reports_the_source_line() {
+        let source = "int a = 7;\nint b = 0;\nwhile (a) {\n    a = a / b;\n}\nPrint(a);\n";
+        let path = temp_source("iridium_pipeline_source_line.sl", source);
+        let mut out = Vec::new();
+        let err = run_file_to(&path, &mut out).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(err, CompileError::Runtime(VMError::DivisionByZero)));
+        assert!(String::from_utf8(out).unwrap().ends_with("\nat line 4: a = a / b;\n"));
+    }
+
+    #[test]
+    fn test_print_string_literals() {
+        let source = r#"
+            Print("Hello, \"world\"");
+            Print(7);
+            Print("tab\there");
+            Print("Hello, \"world\"");
+        "#;
+        assert_eq!(
+            run(source),
+            "Hello, \"world\"\nPRINT: 7\ntab\there\nHello, \"world\"\n"
+        );
+    }
+
+    #[test]
+    fn test_recursive_factorial() {
+        let source = "
+            fn factorial(n) {
+                if (n <= 1) {
+                    return 1;
+                }
+                return n * factorial(n - 1);
+            }
+            fn main() {
+                Print(factorial(6));
+                return 0;
+            }
+        ";
+        assert_eq!(run(source), "PRINT: 720\n");
+    }
+
+    #[test]
+    fn test_while_sums_one_to_five() {
+        let source = "
+            int sum = 0;
+            int i = 1;
+            while (i <= 5) {
+                sum = sum + i;
+                i = i + 1;
+            }
+            Print(sum);
+        ";
+        assert_eq!(run(source), "PRINT: 15\n");
+    }
+
+    #[test]
+    fn test_while_sums_one_to_ten_with_temporaries_in_the_body() {
+        // The condition reads `i` afresh each time round, and the body's
+        // temporaries are released every iteration, not just at the end.
+        let source = "
+            int sum = 0;
+            int i = 1;
+            while (i * 1 <= 10 - 0) {
+                sum = sum + (i * 2 - i) / 1 + (i - i) * (i + i);
+                i = i + 1;
+            }
+            Print(sum);
+        ";
+        assert_eq!(run(source), "PRINT: 55\n");
+    }
+
+    #[test]
+    fn test_while_with_false_condition_runs_zero_times() {
+        let source = "
+            int i = 10;
+            while (i < 5) {
+                Print(i);
+                i = i + 1;
+            }
+            Print(i);
+        ";
+        assert_eq!(run(source), "PRINT: 10\n");
+    }
+
+    #[test]
+    fn test_if_takes_then_branch() {
+        let source = "int a = read(); if (a > 2) { Print(1); } else { Print(2); } if (a) { Print(3); }";
+        assert_eq!(run_with_input(source, [5]), "PRINT: 1\nPRINT: 3\n");
+    }
+
+    #[test]
+    fn test_if_skips_untaken_branch() {
+        let source = "int a = read(); if (a > 2) { Print(1); } else { Print(2); } if (a) { Print(3); } Print(4);";
+        assert_eq!(run_with_input(source, [0]), "PRINT: 2\nPRINT: 4\n");
+    }
+
+    #[test]
+    fn test_nested_if_inside_else() {
+        let source = "
+            int a = read();
+            if (a < 0) {
+                Print(0);
+            } else {
+                if (a == 1) { Print(1); } else { Print(2); }
+                Print(3);
+            }
+            if (a < 1) { } else if (a < 2) { Print(4); } else { Print(5); }
+        ";
+        assert_eq!(run_with_input(source, [1]), "PRINT: 1\nPRINT: 3\nPRINT: 4\n");
+        assert_eq!(run_with_input(source, [7]), "PRINT: 2\nPRINT: 3\nPRINT: 5\n");
+        assert_eq!(run_with_input(source, [-1]), "PRINT: 0\n");
+    }
+
+    #[test]
+    fn test_comparisons_store_zero_or_one() {
+        let source = "
+            int a = read();
+            int less = a < 3;
+            let same = a == 3;
+            float f = 2.5;
+            int bigger = f > 1.0;
+            int nonnegative = a >= 0;
+            less = less + nonnegative;
+            Print(less); Print(same); Print(bigger);
+            if (a != 3) { Print(7); }
+        ";
+        assert_eq!(run_with_input(source, [1]), "PRINT: 2\nPRINT: 0\nPRINT: 1\nPRINT: 7\n");
+        assert_eq!(run_with_input(source, [3]), "PRINT: 1\nPRINT: 1\nPRINT: 1\n");
+    }
+
+    #[test]
+    fn test_conditional_expressions() {
+        let source = "int a = read(); int b = read(); int max = a > b ? a : b; Print(max); Print(a < b ? a : b);";
+        assert_eq!(run_with_input(source, [3, 8]), "PRINT: 8\nPRINT: 3\n");
+        assert_eq!(run_with_input(source, [8, 3]), "PRINT: 8\nPRINT: 3\n");
+
+        // Only the chosen arm runs, so only one value is read.
+        let source = "float x = 1 ? 2.5 : 0.5; Print(x); Print(0 ? read() : 4 ? 5 : read()); Print(read());";
+        assert_eq!(run_with_input(source, [7]), "PRINT: 2.5\nPRINT: 5\nPRINT: 7\n");
+    }
+
+    #[test]
+    fn test_expression_statements_are_evaluated_and_discarded() {
+        // The first read is discarded; the statement still consumes it.
+        let source = "read(); int a = read(); a * 2; a + (a ? read() : 0); Print(a);";
+        assert_eq!(run_with_input(source, [1, 2, 3]), "PRINT: 2\n");
+    }
+
+    #[test]
+    fn test_later_declarators_see_earlier_ones() {
+        let source = "int a = read(), b = a * 2, c = a + b; Print(c);";
+        assert_eq!(run_with_input(source, [3]), "PRINT: 9\n");
+    }
+
+    #[test]
+    fn test_reassignment_updates_variable() {
+        let source = "
+            int x = 1;
+            int n = 0;
+            while (n < 4) {
+                x = x + x;
+                n = n + 1;
+            }
+            x = x + 3;
+            Print(x);
+        ";
+        assert_eq!(run(source), "PRINT: 19\n");
+    }
+
+    fn undeclared_names(source: &str) -> Vec<String> {
+        match compile(source) {
+            Err(CompileError::Semantic(errors)) => errors
+                .into_iter()
+                .map(|err| match err {
+                    SemanticError::UndeclaredVariable { name, .. } => name,
+                    other => panic!("expected an undeclared variable, got {:?}", other),
+                })
+                .collect(),
+            other => panic!("expected a semantic error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_assignment_requires_declaration() {
+        assert_eq!(undeclared_names("int x = 1; y = x;"), ["y"]);
+    }
+
+    #[test]
+    fn test_compound_assignments_chain() {
+        let source = "
+            int x = 10;
+            x += 5;
+            x *= 3;
+            x -= 1;
+            x /= 4;
+            Print(x);
+        ";
+        assert_eq!(run(source), "PRINT: 11\n");
+    }
+
+    #[test]
+    fn test_compound_assignment_requires_declaration() {
+        // `y += 1` reads `y` as well as assigning it.
+        assert_eq!(undeclared_names("y += 1;"), ["y", "y"]);
+    }
+
+    #[test]
+    fn test_inner_scope_shadows_outer_variable() {
+        let source = "
+            int x = 1;
+            {
+                int x = 2;
+                x += 10;
+                Print(x);
+            }
+            Print(x);
+        ";
+        assert_eq!(run(source), "PRINT: 12\nPRINT: 1\n");
+    }
+
+    #[test]
+    fn test_variable_is_not_visible_after_its_block() {
+        assert_eq!(undeclared_names("{ int y = 1; } Print(y);"), ["y"]);
+    }
+
+    #[test]
+    fn test_for_induction_variable_ends_with_loop() {
+        let source = "for (int i = 0; i < 2; i += 1) { Print(i); } Print(i);";
+        assert_eq!(undeclared_names(source), ["i"]);
+    }
+
+    #[test]
+    fn test_read_two_numbers_and_print_product() {
+        let source = "
+            int a = read();
+            int b = read();
+            Print(a * b);
+        ";
+        assert_eq!(run_with_input(source, [6, 7]), "PRINT: 42\n");
+    }
+
+    #[test]
+    fn test_float_arithmetic_and_conditions() {
+        let source = "
+            float x = 0.5;
+            let step = 0.25;
+            while (x < 1.0) {
+                x += step;
+            }
+            Print(x * 3.0);
+            let n = 7;
+            Print(n / 2);
+        ";
+        assert_eq!(run(source), "PRINT: 3\nPRINT: 3\n");
+    }
+
+    #[test]
+    fn test_mixed_int_and_float_is_a_type_error() {
+        let err = compile("let a = 1; Print(a * 2.5);").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Type Error: Type mismatch at 1:22: expected int but found float"
+        );
+    }
+
+    #[test]
+    fn test_types_are_checked_after_names() {
+        // The undeclared name stops compilation before type checking, so
+        // the mismatch isn't reported too.
+        let err = compile("int a = 1.5; Print(b);").unwrap_err();
+        assert!(matches!(&err, CompileError::Semantic(errors) if errors.len() == 1), "{:?}", err);
+    }
+
+    #[test]
+    fn test_for_loop_counts() {
+        let source = "
+            int sum = 0;
+            for (int i = 1; i <= 4; i = i + 1) {
+                sum = sum + i;
+            }
+            Print(sum);
+        ";
+        assert_eq!(run(source), "PRINT: 10\n");
+    }
+
+    #[test]
+    fn test_for_loop_with_empty_step() {
+        let source = "
+            int i = 0;
+            for (; i < 3;) {
+                Print(i);
+                i = i + 1;
+            }
+        ";
+        assert_eq!(run(source), "PRINT: 0\nPRINT: 1\nPRINT: 2\n");
+    }
+
+    #[test]
+    fn test_for_loop_that_never_runs() {
+        let source = "
+            for (int i = 5; i < 5; i = i + 1) {
+                Print(i);
+            }
+            Print(0);
+        ";
+        assert_eq!(run(source), "PRINT: 0\n");
+    }
+}
@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+
+use crate::codegen::{CodegenError, RegisterUsage, SPILL_BASE};
+use crate::ir::{Bank, Body, Inst, VReg};
+
+const FLOAT_REGISTERS: usize = 32;
+
+/// Where each virtual register of a body ended up.
+#[derive(Debug, Default)]
+pub(crate) struct Allocation {
+    registers: HashMap<VReg, usize>,
+    // Variables that live in a memory slot instead, by its address.
+    spilled: HashMap<VReg, u16>,
+    // The integer registers live across each call, in the order the calls
+    // appear. The caller saves them.
+    saved: Vec<Vec<usize>>,
+    max_live: usize,
+}
+
+impl Allocation {
+    pub(crate) fn register(&self, reg: VReg) -> usize {
+        self.registers[&reg]
+    }
+
+    pub(crate) fn slot(&self, reg: VReg) -> Option<u16> {
+        self.spilled.get(&reg).copied()
+    }
+
+    pub(crate) fn uses_slots(&self) -> bool {
+        !self.spilled.is_empty()
+    }
+
+    pub(crate) fn saved(&self, call: usize) -> &[usize] {
+        &self.saved[call]
+    }
+}
+
+// The positions a register is live over. Instruction `i` reads its operands
+// at `2 * i` and writes its result at `2 * i + 1`, so a result can take the
+// register of an operand read for the last time.
+#[derive(Debug, Clone, Copy)]
+struct Interval {
+    reg: VReg,
+    start: usize,
+    end: usize,
+}
+
+/// Assigns each register of `body` one of the VM's, using the integer
+/// registers below `limit`. Scans the live intervals in order and takes the
+/// lowest free register. When there is none, the variable live furthest
+/// ahead is spilled: it gets a memory slot, each definition is stored to it
+/// and each use reloaded from it, through short-lived temporaries, and the
+/// scan starts again. Only integer variables spill: floats have no memory
+/// opcodes, so running out of float registers is an error. Function bodies
+/// never spill either, since a recursive call would overwrite the slots.
+pub(crate) fn allocate(body: &mut Body, limit: usize) -> Result<Allocation, CodegenError> {
+    if body.params.len() > limit {
+        return Err(CodegenError::TooManyRegisters);
+    }
+
+    let mut spilled = HashMap::new();
+    let (intervals, mut allocation) = loop {
+        let intervals = intervals(body);
+        match scan(body, &intervals, limit) {
+            Ok(allocation) => break (intervals, allocation),
+            Err(Some(victim)) if !body.is_function => {
+                let address = SPILL_BASE + 4 * spilled.len() as u16;
+                spill(body, victim, address);
+                spilled.insert(victim, address);
+            }
+            Err(_) => return Err(CodegenError::TooManyRegisters),
+        }
+    };
+    allocation.spilled = spilled;
+
+    let calls = body
+        .insts
+        .iter()
+        .enumerate()
+        .filter_map(|(index, inst)| match inst {
+            Inst::Call { args, span, .. } => Some((index, args.len(), *span)),
+            _ => None,
+        });
+    for (index, args, span) in calls {
+        if args > limit {
+            return Err(CodegenError::TooManyRegisters);
+        }
+        let across = intervals
+            .iter()
+            .filter(|interval| interval.start < 2 * index && interval.end > 2 * index + 1);
+        let mut saved = Vec::new();
+        for interval in across {
+            if interval.reg.bank == Bank::Float {
+                return Err(CodegenError::UnsupportedConstruct {
+                    construct: "float values live across a call",
+                    span,
+                });
+            }
+            saved.push(allocation.register(interval.reg));
+        }
+        saved.sort_unstable();
+        allocation.saved.push(saved);
+    }
+    Ok(allocation)
+}
+
+/// Registers `body` needs with as many as it likes, without spilling.
+pub(crate) fn usage(body: &Body) -> RegisterUsage {
+    let allocation = scan(body, &intervals(body), usize::MAX).unwrap_or_default();
+    let mut allocated: Vec<usize> = allocation
+        .registers
+        .iter()
+        .filter(|(reg, _)| reg.bank == Bank::Int)
+        .map(|(_, &index)| index)
+        .collect();
+    allocated.sort_unstable();
+    allocated.dedup();
+    RegisterUsage {
+        max_live: allocation.max_live,
+        allocated: allocated.len(),
+    }
+}
+
+// Sorted by start. A register live into a loop stays live until the jump
+// back, since the next iteration may read it again.
+fn intervals(body: &Body) -> Vec<Interval> {
+    let mut ranges: HashMap<VReg, (usize, usize)> = HashMap::new();
+    let mut touch = |reg: VReg, position: usize| {
+        let range = ranges.entry(reg).or_insert((position, position));
+        range.0 = range.0.min(position);
+        range.1 = range.1.max(position);
+    };
+    for &param in &body.params {
+        touch(param, 0);
+    }
+    for (index, inst) in body.insts.iter().enumerate() {
+        for reg in inst.uses() {
+            touch(reg, 2 * index);
+        }
+        if let Some(reg) = inst.def() {
+            touch(reg, 2 * index + 1);
+        }
+    }
+
+    let labels: HashMap<&str, usize> = body
+        .insts
+        .iter()
+        .enumerate()
+        .filter_map(|(index, inst)| match inst {
+            Inst::Label(label) => Some((label.as_str(), index)),
+            _ => None,
+        })
+        .collect();
+    let loops: Vec<(usize, usize)> = body
+        .insts
+        .iter()
+        .enumerate()
+        .filter_map(|(index, inst)| match inst {
+            Inst::Jump(label) | Inst::JumpUnless(label) => {
+                let head = labels[label.as_str()];
+                (head < index).then_some((2 * head, 2 * index + 1))
+            }
+            _ => None,
+        })
+        .collect();
+    // Repeated for loops nested in the one that extended a range.
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &(head, back) in &loops {
+            for range in ranges.values_mut() {
+                if range.0 < head && range.1 > head && range.1 < back {
+                    range.1 = back;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    let mut intervals: Vec<Interval> = ranges
+        .into_iter()
+        .map(|(reg, (start, end))| Interval { reg, start, end })
+        .collect();
+    intervals.sort_by_key(|interval| (interval.start, interval.reg));
+    intervals
+}
+
+// The allocation, or the variable to spill to make room, if any may be.
+fn scan(body: &Body, intervals: &[Interval], limit: usize) -> Result<Allocation, Option<VReg>> {
+    let mut allocation = Allocation::default();
+    let mut active: Vec<(Interval, usize)> = Vec::new();
+    let mut in_use: [Vec<bool>; 2] = [Vec::new(), Vec::new()];
+    let limits = [limit, FLOAT_REGISTERS];
+
+    for &interval in intervals {
+        active.retain(|(other, index)| {
+            let live = other.end >= interval.start;
+            if !live {
+                in_use[other.reg.bank as usize][*index] = false;
+            }
+            live
+        });
+
+        let bank = interval.reg.bank as usize;
+        let free = &mut in_use[bank];
+        let index = match body.params.iter().position(|&param| param == interval.reg) {
+            Some(index) => index,
+            None => match free.iter().position(|&used| !used) {
+                Some(index) => index,
+                None if free.len() < limits[bank] => free.len(),
+                None if interval.reg.bank == Bank::Float => return Err(None),
+                None => {
+                    let victim = active
+                        .iter()
+                        .map(|(other, _)| *other)
+                        .chain([interval])
+                        .filter(|other| {
+                            other.reg.bank == Bank::Int && body.variables.contains(&other.reg)
+                        })
+                        .max_by_key(|other| (other.end, other.reg));
+                    return Err(victim.map(|victim| victim.reg));
+                }
+            },
+        };
+        if free.len() <= index {
+            free.resize(index + 1, false);
+        }
+        free[index] = true;
+
+        active.push((interval, index));
+        allocation.registers.insert(interval.reg, index);
+        let live = active
+            .iter()
+            .filter(|(other, _)| other.reg.bank == Bank::Int)
+            .count();
+        allocation.max_live = allocation.max_live.max(live);
+    }
+    Ok(allocation)
+}
+
+// Moves `victim` to the slot at `address`. Each instruction that reads it
+// reads a fresh register loaded just before, and each one that writes it
+// writes a fresh register stored just after.
+fn spill(body: &mut Body, victim: VReg, address: u16) {
+    for inst in std::mem::take(&mut body.insts) {
+        if let Inst::Move { dest, src } = inst
+            && dest == victim
+        {
+            body.insts.push(Inst::Spill { src, address });
+            continue;
+        }
+        let reads = inst.uses().contains(&victim);
+        let writes = inst.def() == Some(victim);
+        if !reads && !writes {
+            body.insts.push(inst);
+            continue;
+        }
+
+        let temp = body.fresh(Bank::Int);
+        if reads {
+            body.insts.push(Inst::Reload {
+                dest: temp,
+                address,
+            });
+        }
+        let mut inst = inst;
+        inst.rename(victim, temp);
+        body.insts.push(inst);
+        if writes {
+            body.insts.push(Inst::Spill { src: temp, address });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::lower;
+    use crate::parser::parse_str;
+
+    fn allocated(source: &str, limit: usize) -> (Body, Allocation) {
+        let mut body = lower(&parse_str(source).unwrap()).unwrap().top_level;
+        let allocation = allocate(&mut body, limit).unwrap();
+        (body, allocation)
+    }
+
+    #[test]
+    fn test_results_reuse_dead_operands() {
+        let (body, allocation) = allocated("Print(1 + 2 * 3);", 31);
+        let registers: Vec<usize> = body
+            .insts
+            .iter()
+            .filter_map(Inst::def)
+            .map(|reg| allocation.register(reg))
+            .collect();
+        assert_eq!(registers, [0, 1, 2, 1, 0]);
+    }
+
+    #[test]
+    fn test_loop_variables_live_until_the_jump_back() {
+        // `n` is last read by the condition, but the next iteration reads it
+        // again, so the body's temporaries can't have its register.
+        let (body, allocation) = allocated(
+            "int n = read(); int i = 0; while (i < n) { i = i + 1; }",
+            31,
+        );
+        let n = VReg {
+            bank: Bank::Int,
+            index: 0,
+        };
+        for reg in body
+            .insts
+            .iter()
+            .filter_map(Inst::def)
+            .filter(|&reg| reg != n)
+        {
+            assert_ne!(allocation.register(reg), allocation.register(n), "{}", reg);
+        }
+    }
+
+    #[test]
+    fn test_spills_the_variable_live_furthest_ahead() {
+        // c is spilled first, and then b to make room for storing it.
+        let (body, allocation) = allocated(
+            "int a = 1; int b = 2; int c = 3; Print(a); Print(b); Print(c);",
+            2,
+        );
+        let [a, b, c] = [0, 1, 2].map(|index| VReg {
+            bank: Bank::Int,
+            index,
+        });
+        assert_eq!(allocation.slot(a), None);
+        assert_eq!(allocation.slot(b), Some(SPILL_BASE + 4));
+        assert_eq!(allocation.slot(c), Some(SPILL_BASE));
+        let insts: Vec<String> = body.insts.iter().skip(1).map(Inst::to_string).collect();
+        assert_eq!(
+            insts,
+            [
+                "%0 = 1",
+                "; a is in %0",
+                "%5 = 2",
+                "store 32772 %5",
+                "; b is in %1",
+                "%3 = 3",
+                "store 32768 %3",
+                "; c is in %2",
+                "print %0",
+                "%6 = load 32772",
+                "print %6",
+                "%4 = load 32768",
+                "print %4",
+                "halt",
+            ]
+        );
+
+        let mut body =
+            lower(&parse_str("fn main() { int a = 1; int b = 2; Print(a + b); }").unwrap())
+                .unwrap()
+                .functions
+                .remove(0);
+        assert_eq!(
+            allocate(&mut body, 1).unwrap_err(),
+            CodegenError::TooManyRegisters
+        );
+    }
+
+    #[test]
+    fn test_registers_live_across_calls_are_saved() {
+        let source =
+            "fn f(n) { return n; } fn main() { int a = read(); int b = read(); Print(f(a) + b); }";
+        let mut body = lower(&parse_str(source).unwrap())
+            .unwrap()
+            .functions
+            .remove(1);
+        let allocation = allocate(&mut body, 30).unwrap();
+        // b is read after the call; a isn't.
+        assert_eq!(allocation.saved(0), [1]);
+    }
+}
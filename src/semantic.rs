@@ -0,0 +1,385 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::ast::{Expr, Program, Span, Spanned, Statement};
+
+#[derive(Debug, PartialEq)]
+pub enum SemanticError {
+    /// A read of, or assignment to, a name with no declaration in scope.
+    UndeclaredVariable { name: String, span: Span },
+    UndefinedFunction { name: String },
+    /// A second function with the same name, as when merged files collide.
+    DuplicateFunction { name: String },
+    ArityMismatch { function: String, expected: usize, found: usize },
+    /// A second declaration of a name in the same scope. Shadowing a name
+    /// from an enclosing scope is fine.
+    DuplicateDeclaration { name: String, first_span: Span, second_span: Span },
+    /// An integer literal that doesn't fit in an i32 register. The lexer
+    /// accepts anything up to i64::MAX.
+    LiteralOutOfRange { value: i64, span: Span },
+}
+
+/// Resolves names: every variable must be declared in an enclosing scope
+/// and every call must name a defined function with the right arity. Also
+/// rejects integer literals above i32::MAX. Types are checked afterwards by
+/// `typeck::check`.
+pub fn check(program: &Program) -> Result<(), Vec<SemanticError>> {
+    let mut checker = Checker {
+        arities: program
+            .functions
+            .iter()
+            .map(|function| (function.name.as_str(), function.params.len()))
+            .collect(),
+        scopes: vec![HashMap::new()],
+        errors: Vec::new(),
+    };
+
+    let mut defined = HashMap::new();
+    for function in &program.functions {
+        if defined.insert(function.name.as_str(), ()).is_some() {
+            checker.errors.push(SemanticError::DuplicateFunction {
+                name: function.name.clone(),
+            });
+        }
+    }
+
+    for function in &program.functions {
+        checker.scopes.push(HashMap::new());
+        for param in &function.params {
+            checker.declare(&param.node, param.span);
+        }
+        checker.check_block(&function.body);
+        checker.scopes.pop();
+    }
+    checker.check_block(&program.statements);
+
+    if checker.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(checker.errors)
+    }
+}
+
+struct Checker<'a> {
+    arities: HashMap<&'a str, usize>,
+    scopes: Vec<HashMap<String, Span>>,
+    errors: Vec<SemanticError>,
+}
+
+impl Checker<'_> {
+    fn check_block(&mut self, statements: &[Spanned<Statement>]) {
+        self.scopes.push(HashMap::new());
+        for statement in statements {
+            self.check_statement(statement);
+        }
+        self.scopes.pop();
+    }
+
+    fn check_statement(&mut self, statement: &Spanned<Statement>) {
+        match &statement.node {
+            Statement::Declare(var, _, expr) => {
+                self.check_expr(expr);
+                self.declare(var, statement.span);
+            }
+            Statement::Assign(var, expr) => {
+                self.check_expr(expr);
+                if !self.is_declared(var) {
+                    self.errors.push(SemanticError::UndeclaredVariable {
+                        name: var.clone(),
+                        span: statement.span,
+                    });
+                }
+            }
+            Statement::Print(expr) | Statement::Expr(expr) | Statement::Return(expr) => {
+                self.check_expr(expr)
+            }
+            Statement::PrintString(_) => {}
+            Statement::Block(body) => self.check_block(body),
+            Statement::If { cond, then, else_ } => {
+                self.check_expr(cond);
+                self.check_block(then);
+                if let Some(else_) = else_ {
+                    self.check_block(else_);
+                }
+            }
+            Statement::While { cond, body } => {
+                self.check_expr(cond);
+                self.check_block(body);
+            }
+            Statement::For { init, cond, step, body } => {
+                self.scopes.push(HashMap::new());
+                if let Some(init) = init {
+                    self.check_statement(init);
+                }
+                if let Some(cond) = cond {
+                    self.check_expr(cond);
+                }
+                if let Some(step) = step {
+                    self.check_statement(step);
+                }
+                self.check_block(body);
+                self.scopes.pop();
+            }
+        }
+    }
+
+    fn check_expr(&mut self, expr: &Spanned<Expr>) {
+        match &expr.node {
+            Expr::Variable(var) => {
+                if !self.is_declared(var) {
+                    self.errors.push(SemanticError::UndeclaredVariable {
+                        name: var.clone(),
+                        span: expr.span,
+                    });
+                }
+            }
+            Expr::Literal(value) => {
+                if i32::try_from(*value).is_err() {
+                    self.errors.push(SemanticError::LiteralOutOfRange {
+                        value: *value,
+                        span: expr.span,
+                    });
+                }
+            }
+            Expr::FloatLiteral(_) | Expr::Read => {}
+            Expr::BinOp(left, _, right) => {
+                self.check_expr(left);
+                self.check_expr(right);
+            }
+            Expr::Conditional { cond, then, else_ } => {
+                self.check_expr(cond);
+                self.check_expr(then);
+                self.check_expr(else_);
+            }
+            Expr::Call { function, args } => {
+                match self.arities.get(function.as_str()) {
+                    None => self.errors.push(SemanticError::UndefinedFunction {
+                        name: function.clone(),
+                    }),
+                    Some(&expected) if expected != args.len() => {
+                        self.errors.push(SemanticError::ArityMismatch {
+                            function: function.clone(),
+                            expected,
+                            found: args.len(),
+                        })
+                    }
+                    Some(_) => {}
+                }
+                for arg in args {
+                    self.check_expr(arg);
+                }
+            }
+        }
+    }
+
+    // Keeps the first declaration when a name is declared twice.
+    fn declare(&mut self, var: &str, span: Span) {
+        let Some(scope) = self.scopes.last_mut() else { return };
+        match scope.get(var) {
+            Some(&first_span) => self.errors.push(SemanticError::DuplicateDeclaration {
+                name: var.to_string(),
+                first_span,
+                second_span: span,
+            }),
+            None => {
+                scope.insert(var.to_string(), span);
+            }
+        }
+    }
+
+    fn is_declared(&self, var: &str) -> bool {
+        self.scopes.iter().any(|scope| scope.contains_key(var))
+    }
+}
+
+impl fmt::Display for SemanticError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SemanticError::UndeclaredVariable { name, span } => {
+                write!(f, "Undeclared variable {} at {}", name, span.start)
+            }
+            SemanticError::UndefinedFunction { name } => write!(f, "Undefined function: {}", name),
+            SemanticError::DuplicateFunction { name } => write!(f, "Duplicate function: {}", name),
+            SemanticError::ArityMismatch { function, expected, found } => write!(
+                f,
+                "Function {} takes {} argument(s) but {} were given",
+                function, expected, found
+            ),
+            SemanticError::DuplicateDeclaration { name, first_span, second_span } => write!(
+                f,
+                "Duplicate declaration of {} at {} (first declared at {})",
+                name, second_span.start, first_span.start
+            ),
+            SemanticError::LiteralOutOfRange { value, span } => {
+                write!(f, "Integer literal {} at {} does not fit in 32 bits", value, span.start)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SemanticError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_str;
+
+    #[test]
+    fn test_arity_mismatch_in_nested_call() {
+        let program = parse_str(
+            "fn add(a, b) { return a + b; } fn main() { return add(1, add(2)); }",
+        ).unwrap();
+        assert_eq!(
+            check(&program),
+            Err(vec![SemanticError::ArityMismatch {
+                function: "add".to_string(),
+                expected: 2,
+                found: 1,
+            }])
+        );
+    }
+
+    #[test]
+    fn test_literal_above_i32_max() {
+        let program = parse_str("int a = 2147483647;\nint b = a + 2147483648;").unwrap();
+        let errors = check(&program).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], SemanticError::LiteralOutOfRange { value: 2147483648, .. }));
+        assert_eq!(
+            errors[0].to_string(),
+            "Integer literal 2147483648 at 2:13 does not fit in 32 bits"
+        );
+    }
+
+    #[test]
+    fn test_undefined_function() {
+        let program = parse_str("fn main() { return missing(); }").unwrap();
+        assert_eq!(
+            check(&program),
+            Err(vec![SemanticError::UndefinedFunction { name: "missing".to_string() }])
+        );
+    }
+
+    #[test]
+    fn test_duplicate_function() {
+        let program = parse_str("fn f() { return 1; } fn main() { return 2; } fn f(a) { return a; }").unwrap();
+        assert_eq!(
+            check(&program),
+            Err(vec![SemanticError::DuplicateFunction { name: "f".to_string() }])
+        );
+    }
+
+    fn undeclared(source: &str) -> Vec<(String, (usize, usize))> {
+        let errors = check(&parse_str(source).unwrap()).unwrap_err();
+        errors
+            .into_iter()
+            .map(|err| match err {
+                SemanticError::UndeclaredVariable { name, span } => {
+                    (name, (span.start.line, span.start.column))
+                }
+                other => panic!("expected an undeclared variable, got {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_use_before_declaration() {
+        assert_eq!(
+            undeclared("Print(y);\nint y = 1;\nint z = z;"),
+            vec![("y".to_string(), (1, 7)), ("z".to_string(), (3, 9))]
+        );
+    }
+
+    #[test]
+    fn test_undeclared_variables_in_nested_expressions() {
+        let source = "int a = 1;\nPrint(a + (2 * (b - a)));\nwhile (a < c) { a = a / d; }";
+        assert_eq!(
+            undeclared(source),
+            vec![
+                ("b".to_string(), (2, 17)),
+                ("c".to_string(), (3, 12)),
+                ("d".to_string(), (3, 25)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assignment_to_undeclared_variable() {
+        assert_eq!(
+            undeclared("{ int x = 1; }\n  x = 2;\nfor (i = 0; i < 3;) { }"),
+            vec![
+                ("x".to_string(), (2, 3)),
+                ("i".to_string(), (3, 6)),
+                ("i".to_string(), (3, 13)),
+            ]
+        );
+        let err = check(&parse_str("y = 1;").unwrap()).unwrap_err();
+        assert_eq!(err[0].to_string(), "Undeclared variable y at 1:1");
+    }
+
+    #[test]
+    fn test_declared_variables_pass() {
+        let source = "int a = 1; { let b = a; for (int i = 0; i < b; i += 1) { a = a + i; } } Print(a);";
+        assert_eq!(check(&parse_str(source).unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn test_well_formed_calls_pass() {
+        let program = parse_str("fn one() { return 1; } fn main() { return one() + one(); }").unwrap();
+        assert_eq!(check(&program), Ok(()));
+    }
+
+    // Each duplicate as (name, first position, second position).
+    fn duplicates(source: &str) -> Vec<(String, String, String)> {
+        let errors = check(&parse_str(source).unwrap()).unwrap_err();
+        errors
+            .into_iter()
+            .map(|err| match err {
+                SemanticError::DuplicateDeclaration { name, first_span, second_span } => (
+                    name,
+                    first_span.start.to_string(),
+                    second_span.start.to_string(),
+                ),
+                other => panic!("expected a duplicate declaration, got {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_duplicate_declaration_in_same_scope() {
+        assert_eq!(
+            duplicates("int x = 1;
+let x = 2;
+{ int y = 1; float y = 2.0; }"),
+            vec![
+                ("x".to_string(), "1:1".to_string(), "2:1".to_string()),
+                ("y".to_string(), "3:3".to_string(), "3:14".to_string()),
+            ]
+        );
+        let err = check(&parse_str("int a = 1; int a = 2;").unwrap()).unwrap_err();
+        assert_eq!(
+            err[0].to_string(),
+            "Duplicate declaration of a at 1:12 (first declared at 1:1)"
+        );
+    }
+
+    #[test]
+    fn test_shadowing_in_inner_scope_is_legal() {
+        let source = "
+            int x = 1;
+            { int x = 2; { let x = 3; } }
+            for (int x = 0; x < 3; x += 1) { int x = 4; }
+            fn f(n) { int n = 1; return n; }
+            fn main() { return f(1); }
+        ";
+        assert_eq!(check(&parse_str(source).unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn test_duplicate_function_parameter() {
+        assert_eq!(
+            duplicates("fn f(a, b, a) { return a + b; } fn main() { return 0; }"),
+            vec![("a".to_string(), "1:6".to_string(), "1:12".to_string())]
+        );
+    }
+}
@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::ast::{Expr, Program, Span, Spanned, Statement, Type};
+
+/// An expression whose type isn't the one its context needs. `span` covers
+/// the offending expression.
+#[derive(Debug, PartialEq)]
+pub struct TypeError {
+    pub expected: Type,
+    pub found: Type,
+    pub span: Span,
+}
+
+/// Checks every expression's type against where it is used. Runs after
+/// `semantic::check`, so every name is known to be declared.
+///
+/// Ints and floats never mix; there is no promotion. Comparisons are
+/// `bool`, which conditions accept and an int variable stores as 0 or 1;
+/// nothing else does. A condition may also be an int or a float, which is
+/// true when nonzero.
+pub fn check(program: &Program) -> Result<(), Vec<TypeError>> {
+    let mut checker = Checker {
+        scopes: vec![HashMap::new()],
+        errors: Vec::new(),
+    };
+
+    for function in &program.functions {
+        // Parameters and return values are ints.
+        let params = function
+            .params
+            .iter()
+            .map(|param| (param.node.clone(), Type::Int));
+        checker.scopes.push(params.collect());
+        checker.check_block(&function.body);
+        checker.scopes.pop();
+    }
+    checker.check_block(&program.statements);
+
+    if checker.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(checker.errors)
+    }
+}
+
+struct Checker {
+    scopes: Vec<HashMap<String, Type>>,
+    errors: Vec<TypeError>,
+}
+
+impl Checker {
+    fn check_block(&mut self, statements: &[Spanned<Statement>]) {
+        self.scopes.push(HashMap::new());
+        for statement in statements {
+            self.check_statement(statement);
+        }
+        self.scopes.pop();
+    }
+
+    fn check_statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Declare(var, declared, expr) => {
+                let found = self.check_expr(expr);
+                // `let` takes the initializer's type, which can't be bool.
+                let ty = match declared {
+                    Some(declared) => *declared,
+                    None if found == Some(Type::Bool) => Type::Int,
+                    None => found.unwrap_or(Type::Int),
+                };
+                self.expect(ty, stored(ty, found), expr);
+                if let Some(scope) = self.scopes.last_mut() {
+                    scope.insert(var.clone(), ty);
+                }
+            }
+            Statement::Assign(var, expr) => {
+                let found = self.check_expr(expr);
+                if let Some(expected) = self.lookup(var) {
+                    self.expect(expected, stored(expected, found), expr);
+                }
+            }
+            Statement::Print(expr) => {
+                // Codegen can print ints and floats.
+                let found = self.check_expr(expr);
+                if found == Some(Type::Bool) {
+                    self.expect(Type::Int, found, expr);
+                }
+            }
+            Statement::PrintString(_) => {}
+            Statement::Expr(expr) => {
+                self.check_expr(expr);
+            }
+            Statement::Return(expr) => {
+                let found = self.check_expr(expr);
+                self.expect(Type::Int, found, expr);
+            }
+            Statement::Block(body) => self.check_block(body),
+            Statement::If { cond, then, else_ } => {
+                self.check_expr(cond);
+                self.check_block(then);
+                if let Some(else_) = else_ {
+                    self.check_block(else_);
+                }
+            }
+            Statement::While { cond, body } => {
+                self.check_expr(cond);
+                self.check_block(body);
+            }
+            Statement::For {
+                init,
+                cond,
+                step,
+                body,
+            } => {
+                self.scopes.push(HashMap::new());
+                if let Some(init) = init {
+                    self.check_statement(init);
+                }
+                if let Some(cond) = cond {
+                    self.check_expr(cond);
+                }
+                if let Some(step) = step {
+                    self.check_statement(step);
+                }
+                self.check_block(body);
+                self.scopes.pop();
+            }
+        }
+    }
+
+    // Returns None only for a name with no declaration, which
+    // `semantic::check` has already reported.
+    fn check_expr(&mut self, expr: &Spanned<Expr>) -> Option<Type> {
+        match &expr.node {
+            Expr::Variable(var) => self.lookup(var),
+            Expr::Literal(_) | Expr::Read => Some(Type::Int),
+            Expr::FloatLiteral(_) => Some(Type::Float),
+            Expr::BinOp(left, op, right) => {
+                let left_ty = self.check_expr(left);
+                let right_ty = self.check_expr(right);
+                // Both operands are numbers of one type; the left one
+                // decides which.
+                let operand = match left_ty {
+                    Some(Type::Bool) => {
+                        self.expect(Type::Int, left_ty, left);
+                        None
+                    }
+                    ty => ty,
+                };
+                self.expect(operand.unwrap_or(Type::Int), right_ty, right);
+                if op.is_comparison() {
+                    Some(Type::Bool)
+                } else {
+                    operand.or(right_ty)
+                }
+            }
+            Expr::Conditional { cond, then, else_ } => {
+                self.check_expr(cond);
+                // Like operands, the arms share one type and the first
+                // decides which. Neither may be a comparison.
+                let then_ty = match self.check_expr(then) {
+                    Some(Type::Bool) => {
+                        self.expect(Type::Int, Some(Type::Bool), then);
+                        None
+                    }
+                    ty => ty,
+                };
+                let else_ty = self.check_expr(else_);
+                self.expect(then_ty.unwrap_or(Type::Int), else_ty, else_);
+                then_ty.or(else_ty)
+            }
+            Expr::Call { args, .. } => {
+                for arg in args {
+                    let found = self.check_expr(arg);
+                    self.expect(Type::Int, found, arg);
+                }
+                Some(Type::Int)
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: Type, found: Option<Type>, expr: &Spanned<Expr>) {
+        if let Some(found) = found
+            && found != expected
+        {
+            self.errors.push(TypeError {
+                expected,
+                found,
+                span: expr.span,
+            });
+        }
+    }
+
+    fn lookup(&self, var: &str) -> Option<Type> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(var).copied())
+    }
+}
+
+// What a variable of type `ty` holds when given a `found`.
+fn stored(ty: Type, found: Option<Type>) -> Option<Type> {
+    match found {
+        Some(Type::Bool) if ty == Type::Int => Some(Type::Int),
+        found => found,
+    }
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Type mismatch at {}: expected {} but found {}",
+            self.span.start, self.expected, self.found
+        )
+    }
+}
+
+impl std::error::Error for TypeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_str;
+
+    // Each error as (expected, found, line, column).
+    fn type_errors(source: &str) -> Vec<(Type, Type, usize, usize)> {
+        match check(&parse_str(source).unwrap()) {
+            Ok(()) => Vec::new(),
+            Err(errors) => errors
+                .into_iter()
+                .map(|err| {
+                    (
+                        err.expected,
+                        err.found,
+                        err.span.start.line,
+                        err.span.start.column,
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_declaration_and_assignment_mismatches() {
+        use Type::*;
+        assert_eq!(
+            type_errors("int a = 0.5;\nfloat b = 1;\nlet c = 1;\nc = b;\nb = b * 2.0;"),
+            vec![(Int, Float, 1, 9), (Float, Int, 2, 11), (Int, Float, 4, 5)]
+        );
+    }
+
+    #[test]
+    fn test_operands_must_share_a_type() {
+        use Type::*;
+        assert_eq!(
+            type_errors(
+                "let a = 1; float b = 2.5; Print(a + b); Print(b * (a - 1)); while (a < b) { }"
+            ),
+            vec![
+                (Int, Float, 1, 37),
+                (Float, Int, 1, 51),
+                (Int, Float, 1, 72)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_comparisons_are_only_conditions() {
+        use Type::*;
+        assert_eq!(
+            type_errors(
+                "int a = 1;\nPrint(a < 2);\nfloat b = a == a;\nint c = (a < 1) + 1;\nwhile ((a < 1) == (a < 2)) { }"
+            ),
+            vec![
+                (Int, Bool, 2, 7),
+                (Float, Bool, 3, 11),
+                (Int, Bool, 4, 9),
+                (Int, Bool, 5, 8),
+                (Int, Bool, 5, 19),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_int_variables_store_comparisons() {
+        let source = "int a = 1; int b = a < 2; let c = a == b; b = 1.5 > 0.5; c = b != a;";
+        assert_eq!(type_errors(source), Vec::new());
+    }
+
+    #[test]
+    fn test_int_and_float_conditions_are_accepted() {
+        let source = "int i = 3; float f = 1.5; while (i) { i -= 1; } for (; f; ) { f = 0.0; }";
+        assert_eq!(type_errors(source), Vec::new());
+    }
+
+    #[test]
+    fn test_returns_and_arguments_are_ints() {
+        use Type::*;
+        let source = "fn f(a) { return 1.5; }\nfn main() { return f(2.5) + f(1 < 2); }";
+        assert_eq!(
+            type_errors(source),
+            vec![(Int, Float, 1, 18), (Int, Float, 2, 22), (Int, Bool, 2, 31)]
+        );
+    }
+
+    #[test]
+    fn test_conditional_arms_share_a_type() {
+        use Type::*;
+        let source =
+            "int a = 1;\nfloat f = a ? 1.5 : 2.5;\nPrint(a ? 1 : 0.5);\nPrint(a ? a < 1 : 0);";
+        assert_eq!(
+            type_errors(source),
+            vec![(Int, Float, 3, 15), (Int, Bool, 4, 11)]
+        );
+    }
+
+    #[test]
+    fn test_type_error_display() {
+        let errors = check(&parse_str("float x = 1;").unwrap()).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "Type mismatch at 1:11: expected float but found int"
+        );
+    }
+
+    #[test]
+    fn test_well_typed_program_passes() {
+        let source = "
+            fn square(n) { return n * n; }
+            fn main() { int s = square(read()); return s; }
+            let a = 1.5; float b = a / 2.0; int c = 0x10;
+            for (int i = 0; i < c; i += 1) { c = c - i; }
+            while (b > a) { b = b - 0.5; Print(b); }
+        ";
+        assert_eq!(type_errors(source), Vec::new());
+    }
+}
@@ -1,6 +1,11 @@
+use std::collections::{HashSet, VecDeque};
 use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+use crate::asm::SourceMap;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum Opcode {
     HLT,
     IGL,
@@ -12,6 +17,82 @@ pub enum Opcode {
     JMP,
     JMPF,
     PRINT,
+    EQ,
+    NEQ,
+    GT,
+    LT,
+    GTE,
+    LTE,
+    JEQ,
+    JNEQ,
+    MOV,
+    NOP,
+    READ,
+    CONCAT,
+    PRINTS,
+    FLOAD,
+    FADD,
+    FSUB,
+    FMUL,
+    FDIV,
+    FMOV,
+    FPRINT,
+    FEQ,
+    FNEQ,
+    FGT,
+    FLT,
+    FGTE,
+    FLTE,
+    ADDI,
+    SUBI,
+    MULI,
+    CALL,
+    RET,
+    LOADM,
+    STOREM,
+    LOADB,
+    STOREB,
+    LOADH,
+    STOREH,
+    MIN,
+    MAX,
+    POPCNT,
+    CLZ,
+    ABS,
+    CMOV,
+    ADD64,
+    SUB64,
+    ADC,
+    SBB,
+    PRTS,
+}
+
+/// The kind of operand that follows an opcode byte in the bytecode.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OperandKind {
+    /// A single byte register index.
+    Register,
+    /// A single byte index into the float register bank.
+    FloatRegister,
+    /// A single byte index of the first of two adjacent integer registers,
+    /// which hold one 64-bit value with the high word first.
+    RegisterPair,
+    /// A big-endian 16-bit immediate.
+    Immediate,
+    /// A big-endian 16-bit two's complement immediate.
+    SignedImmediate,
+    /// A big-endian 64-bit IEEE 754 double.
+    Float,
+}
+
+impl OperandKind {
+    pub fn size(self) -> usize {
+        match self {
+            OperandKind::Register | OperandKind::FloatRegister | OperandKind::RegisterPair => 1,
+            OperandKind::Immediate | OperandKind::SignedImmediate => 2,
+            OperandKind::Float => 8,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -19,24 +100,201 @@ pub enum VMError {
     ProgramCounterOutOfBounds,
     DivisionByZero,
     RegisterOutOfBounds,
-    InvalidOpcode,
+    /// `byte`, at offset `pc`, isn't a known opcode.
+    InvalidOpcode { pc: usize, byte: u8 },
+    InputUnavailable,
+    StringTableFull,
+    InvalidStringHandle,
+    OpcodeDisabled(Opcode),
+    StackUnderflow,
+    CallStackOverflow,
+    StepLimitExceeded,
+    /// A memory access that isn't entirely within `MEMORY_SIZE`.
+    MemoryOutOfBounds,
+    /// A bytecode file with no version byte.
+    MissingVersion,
+    UnsupportedVersion(u8),
+}
+
+/// Instructions `run_untrusted` executes before giving up.
+pub const UNTRUSTED_MAX_STEPS: usize = 100_000;
+/// Call depth `run_untrusted` allows.
+pub const UNTRUSTED_MAX_CALL_DEPTH: usize = 1024;
+
+/// Runs arbitrary bytecode as safely as the VM allows, for fuzz targets
+/// and other untrusted input. Execution is capped at `UNTRUSTED_MAX_STEPS`
+/// instructions and `UNTRUSTED_MAX_CALL_DEPTH` calls, the string table has
+/// its usual bounds, output is discarded and `READ` never touches stdin.
+/// Any input must produce `Ok` or a `VMError`, never a panic.
+pub fn run_untrusted(bytes: &[u8]) -> Result<(), VMError> {
+    let mut vm = VM::new();
+    vm.capture_output();
+    vm.set_input([]);
+    vm.set_max_call_depth(UNTRUSTED_MAX_CALL_DEPTH);
+    vm.add_program(bytes.to_vec());
+
+    for _ in 0..UNTRUSTED_MAX_STEPS {
+        if vm.pc >= vm.program.len() || !vm.execute_instruction()? {
+            return Ok(());
+        }
+        // Dropped as it is produced so output can't accumulate.
+        vm.take_output();
+    }
+    Err(VMError::StepLimitExceeded)
+}
+
+/// The opcode numbering `u8::from(Opcode)` produces, written as the first
+/// byte of a bytecode file. Bump it whenever an existing opcode changes
+/// number, and teach `opcode_in` the old numbering.
+pub const BYTECODE_VERSION: u8 = 1;
+
+// The opcode `byte` stood for in `version`, or `None` if it was unused.
+fn opcode_in(version: u8, byte: u8) -> Option<Opcode> {
+    match version {
+        1 => match Opcode::from(byte) {
+            Opcode::IGL if byte != u8::from(Opcode::IGL) => None,
+            opcode => Some(opcode),
+        },
+        _ => None,
+    }
+}
+
+/// `program` as a bytecode file: the version byte, then the program.
+pub fn encode_file(program: &[u8]) -> Vec<u8> {
+    let mut file = Vec::with_capacity(program.len() + 1);
+    file.push(BYTECODE_VERSION);
+    file.extend_from_slice(program);
+    file
+}
+
+/// The program in a bytecode file written by `encode_file`, migrated to
+/// `BYTECODE_VERSION` if it was written by an older one.
+pub fn decode_file(file: &[u8]) -> Result<Vec<u8>, VMError> {
+    match file.split_first() {
+        None => Err(VMError::MissingVersion),
+        Some((&BYTECODE_VERSION, program)) => Ok(program.to_vec()),
+        Some((&version, program)) => migrate(version, program),
+    }
+}
+
+/// Rewrites `bytes`, numbered as in `old_version`, with the current opcode
+/// numbering. Operands are copied unchanged. Every byte is taken to be part
+/// of an instruction, so data appended after the code must be split off
+/// first.
+pub fn migrate(old_version: u8, bytes: &[u8]) -> Result<Vec<u8>, VMError> {
+    if old_version == 0 || old_version > BYTECODE_VERSION {
+        return Err(VMError::UnsupportedVersion(old_version));
+    }
+    remap(bytes, |byte| opcode_in(old_version, byte))
+}
+
+// `migrate` for the numbering `opcode_in` gives.
+fn remap(bytes: &[u8], opcode_in: impl Fn(u8) -> Option<Opcode>) -> Result<Vec<u8>, VMError> {
+    let mut migrated = Vec::with_capacity(bytes.len());
+    let mut pc = 0;
+    while pc < bytes.len() {
+        let byte = bytes[pc];
+        let opcode = opcode_in(byte).ok_or(VMError::InvalidOpcode { pc, byte })?;
+        let size: usize = opcode.operands().iter().map(|kind| kind.size()).sum();
+        let operands = bytes
+            .get(pc + 1..pc + 1 + size)
+            .ok_or(VMError::ProgramCounterOutOfBounds)?;
+        migrated.push(u8::from(opcode));
+        migrated.extend_from_slice(operands);
+        pc += 1 + size;
+    }
+    Ok(migrated)
+}
+
+/// Bytes of data memory, addressed by LOADM/STOREM and their byte and
+/// halfword forms, and by PRTS. Every address fits a LOAD immediate.
+pub const MEMORY_SIZE: usize = 64 * 1024;
+
+/// Most strings the string table holds at once.
+pub const MAX_STRINGS: usize = 1024;
+/// Most bytes of string data the string table holds at once.
+pub const MAX_STRING_BYTES: usize = 64 * 1024;
+
+/// A run captured by `VM::start_recording`: the pc of every instruction
+/// executed, and every value `READ` produced, both in order. `READ` is the
+/// only source of nondeterminism, so the inputs are enough to replay it.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ReplayLog {
+    pub pcs: Vec<usize>,
+    pub inputs: Vec<i32>,
 }
 
 #[derive(Debug)]
 pub struct VM {
     registers: [i32; 32],
+    float_registers: [f64; 32],
     pc: usize,
+    entry_point: usize,
     program: Vec<u8>,
     remainder: u32,
+    equal_flag: bool,
+    // Carry out of the last ADD or ADC, or borrow out of the last SUB or
+    // SBB, treating registers as unsigned.
+    carry_flag: bool,
+    // Return addresses pushed by CALL.
+    call_stack: Vec<usize>,
+    max_call_depth: Option<usize>,
+    // Instructions started since the last `restart`, and the offset of the
+    // latest.
+    instruction_count: u64,
+    instruction_start: Option<usize>,
+    // Debug info, and the source lines it refers to.
+    source_map: Option<(SourceMap, Vec<String>)>,
+    output: Option<String>,
+    input: Option<VecDeque<i32>>,
+    lenient: bool,
+    zero_register: bool,
+    disabled: HashSet<Opcode>,
+    // Arena of strings addressed by index. Nothing is ever freed while the
+    // program runs; there is no garbage collector, the whole arena is
+    // dropped by `reset()`. The bounds keep a looping CONCAT from growing it
+    // without limit.
+    strings: Vec<String>,
+    string_bytes: usize,
+    memory: Vec<u8>,
+    recording: Option<ReplayLog>,
+    #[cfg(feature = "test-coverage")]
+    executed: HashSet<Opcode>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> Self {
         Self {
             registers: [0; 32],
+            float_registers: [0.0; 32],
             program: Vec::new(),
             pc: 0,
+            entry_point: 0,
             remainder: 0,
+            equal_flag: false,
+            carry_flag: false,
+            call_stack: Vec::new(),
+            max_call_depth: None,
+            instruction_count: 0,
+            instruction_start: None,
+            source_map: None,
+            output: None,
+            input: None,
+            lenient: false,
+            zero_register: false,
+            disabled: HashSet::new(),
+            strings: Vec::new(),
+            string_bytes: 0,
+            memory: vec![0; MEMORY_SIZE],
+            recording: None,
+            #[cfg(feature = "test-coverage")]
+            executed: HashSet::new(),
         }
     }
 
@@ -52,15 +310,241 @@ impl VM {
         &self.registers
     }
 
+    pub fn get_float_register(&self, index: usize) -> Result<f64, VMError> {
+        self.float_registers
+            .get(index)
+            .copied()
+            .ok_or(VMError::RegisterOutOfBounds)
+    }
+
+    pub fn get_equal_flag(&self) -> bool {
+        self.equal_flag
+    }
+
+    pub fn get_carry_flag(&self) -> bool {
+        self.carry_flag
+    }
+
+    /// Writes each nonzero register as `rN: 0x........ (decimal)`. Negative
+    /// values show their two's complement bits.
+    pub fn dump_registers_hex<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for (index, value) in self.registers.iter().enumerate() {
+            if *value != 0 {
+                writeln!(writer, "r{}: {:#010x} ({})", index, value, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// A summary for debugging and test failure messages: pc, the nonzero
+    /// registers in the format of `dump_registers_hex` and then the nonzero
+    /// float registers, flags, remainder, call depth, the number of
+    /// instructions run and, given a source map, `source_line`. Its size is
+    /// bounded by the register count.
+    pub fn state_report(&self) -> String {
+        let mut lines = vec![format!("pc: {}", self.pc)];
+        for (index, value) in self.registers.iter().enumerate() {
+            if *value != 0 {
+                lines.push(format!("r{}: {:#010x} ({})", index, value, value));
+            }
+        }
+        for (index, value) in self.float_registers.iter().enumerate() {
+            if *value != 0.0 {
+                lines.push(format!("f{}: {}", index, value));
+            }
+        }
+        lines.push(format!("equal: {}, carry: {}", self.equal_flag, self.carry_flag));
+        lines.push(format!("remainder: {}", self.remainder));
+        lines.push(format!("call depth: {}", self.call_depth()));
+        lines.push(format!("instructions: {}", self.instruction_count));
+        if let Some(line) = self.source_line() {
+            lines.push(format!("source: {}", line));
+        }
+        lines.join("\n")
+    }
+
+    /// Attaches debug info: `map` from offsets in the program to lines of
+    /// `source`, as from `Assembler::source_map`.
+    pub fn set_source_map(&mut self, map: SourceMap, source: &str) {
+        self.source_map = Some((map, source.lines().map(str::to_string).collect()));
+    }
+
+    /// The source line of the last instruction run, as in `line 3: x = 1;`,
+    /// if there is a source map covering it. After a runtime error this is
+    /// the line that faulted.
+    pub fn source_line(&self) -> Option<String> {
+        let (map, source) = self.source_map.as_ref()?;
+        let line = map.line_at(self.instruction_start?)?;
+        let text = source.get(line - 1).map_or("", |text| text.trim());
+        Some(format!("line {}: {}", line, text))
+    }
+
+    /// Caps `call_depth`; a CALL beyond it fails with `CallStackOverflow`.
+    /// Unlimited by default.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = Some(depth);
+    }
+
+    /// Number of CALLs that haven't returned yet.
+    pub fn call_depth(&self) -> usize {
+        self.call_stack.len()
+    }
+
+    /// Buffers the output of `PRINT` instead of writing it to stdout.
+    pub fn capture_output(&mut self) {
+        self.output = Some(String::new());
+    }
+
+    /// Returns and clears everything printed since output capture was enabled.
+    pub fn take_output(&mut self) -> String {
+        self.output.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Feeds `READ` from `values` instead of stdin. Reading past the end is
+    /// `VMError::InputUnavailable`.
+    pub fn set_input<I: IntoIterator<Item = i32>>(&mut self, values: I) {
+        self.input = Some(values.into_iter().collect());
+    }
+
+    /// Starts a fresh `ReplayLog`, discarding any earlier one. Start it on a
+    /// freshly loaded VM so that `replay` can reproduce the run.
+    pub fn start_recording(&mut self) {
+        self.recording = Some(ReplayLog::default());
+    }
+
+    /// Stops recording and returns what was recorded, if anything.
+    pub fn stop_recording(&mut self) -> Option<ReplayLog> {
+        self.recording.take()
+    }
+
+    /// Resets to a freshly loaded state and runs the program again, feeding
+    /// `READ` from the log instead of the usual input, which is restored
+    /// afterwards.
+    pub fn replay(&mut self, log: &ReplayLog) -> Result<(), VMError> {
+        self.reset();
+        let input = self.input.replace(log.inputs.iter().copied().collect());
+        let result = self.run();
+        self.input = input;
+        result
+    }
+
+    /// Every opcode this VM has started executing since it was created,
+    /// including ones that then failed. Disabled opcodes fail before they
+    /// start, so they aren't included. Survives `reset`.
+    #[cfg(feature = "test-coverage")]
+    pub fn executed_opcodes(&self) -> HashSet<Opcode> {
+        self.executed.clone()
+    }
+
+    /// Adds `value` to the string table and returns its handle, which
+    /// programs pass around in registers.
+    pub fn intern_string(&mut self, value: &str) -> Result<i32, VMError> {
+        if self.strings.len() >= MAX_STRINGS || self.string_bytes + value.len() > MAX_STRING_BYTES {
+            return Err(VMError::StringTableFull);
+        }
+        self.string_bytes += value.len();
+        self.strings.push(value.to_string());
+        Ok((self.strings.len() - 1) as i32)
+    }
+
+    pub fn get_string(&self, handle: i32) -> Result<&str, VMError> {
+        usize::try_from(handle)
+            .ok()
+            .and_then(|index| self.strings.get(index))
+            .map(String::as_str)
+            .ok_or(VMError::InvalidStringHandle)
+    }
+
+    /// Makes `opcode` fail with `VMError::OpcodeDisabled` when executed, so
+    /// hosts can run untrusted bytecode without, say, I/O. Survives `reset()`.
+    pub fn disable_opcode(&mut self, opcode: Opcode) {
+        self.disabled.insert(opcode);
+    }
+
+    pub fn enable_opcode(&mut self, opcode: Opcode) {
+        self.disabled.remove(&opcode);
+    }
+
+    /// In lenient mode an invalid opcode halts the program instead of
+    /// returning `VMError::InvalidOpcode`.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
+
+    /// With a zero register, r0 always reads as 0: whatever an instruction
+    /// writes there, `LOAD r0 n` included, is discarded once it finishes.
+    /// Survives `reset()`.
+    pub fn set_zero_register(&mut self, enabled: bool) {
+        self.zero_register = enabled;
+        if enabled {
+            self.registers[0] = 0;
+        }
+    }
+
+    /// Offset execution starts from, for programs that don't begin with
+    /// code. Takes effect immediately and on every `reset()`.
+    pub fn set_entry_point(&mut self, offset: usize) {
+        self.entry_point = offset;
+        self.pc = offset;
+    }
+
+    /// Copies `bytes` into memory starting at `offset`, for programs that
+    /// read host-provided data. Fails with `MemoryOutOfBounds`, writing
+    /// nothing, unless all of it fits. `add_program` and `reset` overwrite
+    /// memory with the program image, zeroed past its end, so call this
+    /// after them.
+    pub fn load_memory(&mut self, offset: usize, bytes: &[u8]) -> Result<(), VMError> {
+        match offset.checked_add(bytes.len()) {
+            Some(end) if end <= self.memory.len() => {
+                self.memory[offset..end].copy_from_slice(bytes);
+                Ok(())
+            }
+            _ => Err(VMError::MemoryOutOfBounds),
+        }
+    }
+
+    /// The `len` bytes of memory at `offset`, for reading back what a
+    /// program stored.
+    pub fn read_memory(&self, offset: usize, len: usize) -> Result<&[u8], VMError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.memory.len() => Ok(&self.memory[offset..end]),
+            _ => Err(VMError::MemoryOutOfBounds),
+        }
+    }
+
+    /// Loads `program` and resets. The program is also mapped into data
+    /// memory from address 0, which is how the assembler's `.data` reaches
+    /// LOADM and PRTS.
     pub fn add_program(&mut self, program: Vec<u8>) {
         self.program = program;
         self.reset();
     }
 
+    /// Returns to a freshly loaded state: execution state as in `restart`,
+    /// plus an empty string table and memory holding just the program,
+    /// zeroed past its end. The program stays.
     pub fn reset(&mut self) {
-        self.pc = 0;
+        self.restart();
+        self.strings.clear();
+        self.string_bytes = 0;
+        let mapped = self.program.len().min(self.memory.len());
+        self.memory[..mapped].copy_from_slice(&self.program[..mapped]);
+        self.memory[mapped..].fill(0);
+    }
+
+    /// Rewinds execution to the entry point with cleared registers, flags
+    /// and call stack, so the loaded program can run again. Unlike `reset`,
+    /// the string table and memory keep their contents.
+    pub fn restart(&mut self) {
+        self.pc = self.entry_point;
         self.registers = [0; 32];
+        self.float_registers = [0.0; 32];
         self.remainder = 0;
+        self.equal_flag = false;
+        self.carry_flag = false;
+        self.call_stack.clear();
+        self.instruction_count = 0;
+        self.instruction_start = None;
     }
 
     pub fn run(&mut self) -> Result<(), VMError> {
@@ -76,12 +560,33 @@ impl VM {
         self.execute_instruction()
     }
 
+    /// Replaces the program with `bytes` and executes its first instruction.
+    /// Registers and flags are left untouched so consecutive calls can build
+    /// up state.
+    pub fn exec_raw(&mut self, bytes: &[u8]) -> Result<bool, VMError> {
+        self.program = bytes.to_vec();
+        self.pc = 0;
+        self.execute_instruction()
+    }
+
     fn execute_instruction(&mut self) -> Result<bool, VMError> {
         if self.pc >= self.program.len() {
             return Err(VMError::ProgramCounterOutOfBounds);
         }
+        if let Some(log) = &mut self.recording {
+            log.pcs.push(self.pc);
+        }
+        self.instruction_start = Some(self.pc);
 
-        match self.decode_opcode() {
+        let opcode = self.decode_opcode();
+        if self.disabled.contains(&opcode) {
+            return Err(VMError::OpcodeDisabled(opcode));
+        }
+        #[cfg(feature = "test-coverage")]
+        self.executed.insert(opcode);
+        self.instruction_count += 1;
+
+        match opcode {
             Opcode::HLT => {
                 return Ok(false);
             }
@@ -97,15 +602,33 @@ impl VM {
             }
             Opcode::ADD => {
                 let (reg1, reg2, reg3) = self.get_three_registers()?;
-                self.registers[reg3] = self.registers[reg1]
-                    .checked_add(self.registers[reg2])
-                    .unwrap_or(0); 
+                let (a, b) = (self.registers[reg1], self.registers[reg2]);
+                self.carry_flag = (a as u32).overflowing_add(b as u32).1;
+                self.registers[reg3] = a.checked_add(b).unwrap_or(0);
             }
             Opcode::SUB => {
                 let (reg1, reg2, reg3) = self.get_three_registers()?;
-                self.registers[reg3] = self.registers[reg1]
-                    .checked_sub(self.registers[reg2])
-                    .unwrap_or(0); 
+                let (a, b) = (self.registers[reg1], self.registers[reg2]);
+                self.carry_flag = (a as u32) < (b as u32);
+                self.registers[reg3] = a.checked_sub(b).unwrap_or(0);
+            }
+            // One word of a wider number, so these wrap instead of
+            // overflowing to zero.
+            Opcode::ADC | Opcode::SBB => {
+                let (reg1, reg2, reg3) = self.get_three_registers()?;
+                let (a, b) = (self.registers[reg1] as u32, self.registers[reg2] as u32);
+                let carry = u32::from(self.carry_flag);
+                let (result, carried) = if opcode == Opcode::ADC {
+                    let (sum, first) = a.overflowing_add(b);
+                    let (sum, second) = sum.overflowing_add(carry);
+                    (sum, first || second)
+                } else {
+                    let (difference, first) = a.overflowing_sub(b);
+                    let (difference, second) = difference.overflowing_sub(carry);
+                    (difference, first || second)
+                };
+                self.carry_flag = carried;
+                self.registers[reg3] = result as i32;
             }
             Opcode::MUL => {
                 let (reg1, reg2, reg3) = self.get_three_registers()?;
@@ -120,29 +643,296 @@ impl VM {
                     return Err(VMError::DivisionByZero);
                 }
 
-                self.registers[reg3] = self.registers[reg1] / self.registers[reg2];
-                self.remainder = (self.registers[reg1] % self.registers[reg2]) as u32;
+                // i32::MIN / -1 overflows to zero like the other arithmetic.
+                let (a, b) = (self.registers[reg1], self.registers[reg2]);
+                self.registers[reg3] = a.checked_div(b).unwrap_or(0);
+                self.remainder = a.checked_rem(b).unwrap_or(0) as u32;
             }
             Opcode::IGL => {
-                return Err(VMError::InvalidOpcode);
+                if self.lenient {
+                    return Ok(false);
+                }
+                let pc = self.pc - 1;
+                return Err(VMError::InvalidOpcode { pc, byte: self.program[pc] });
             }
             Opcode::JMP => {
-                let target = self.registers[self.next_8_bits().unwrap_or(0) as usize];
+                let target = self.registers[self.next_register()?];
                 self.pc = target as usize;
-            }            
+            }
             Opcode::JMPF => {
-                let value = self.registers[self.next_8_bits().unwrap_or(0) as usize];
-                self.pc += value as usize;
-            }       
+                let value = self.registers[self.next_register()?];
+                self.pc = self.pc.wrapping_add(value as usize);
+            }
+            Opcode::CALL => {
+                let target = self.registers[self.next_register()?];
+                if self.max_call_depth.is_some_and(|max| self.call_stack.len() >= max) {
+                    return Err(VMError::CallStackOverflow);
+                }
+                self.call_stack.push(self.pc);
+                self.pc = target as usize;
+            }
+            Opcode::RET => {
+                self.pc = self.call_stack.pop().ok_or(VMError::StackUnderflow)?;
+            }
             Opcode::PRINT => {
-                let value = self.registers[self.next_8_bits().unwrap_or(0) as usize];
-                println!("PRINT: {}", value);
-            }     
+                let value = self.registers[self.next_register()?];
+                self.write_output(&format!("PRINT: {}", value));
+            }
+            Opcode::PRINTS => {
+                let handle = self.registers[self.next_register()?];
+                let value = self.get_string(handle)?.to_string();
+                self.write_output(&value);
+            }
+            // Prints the NUL-terminated string at the address in a register.
+            Opcode::PRTS => {
+                let address = self.registers[self.next_register()?];
+                let start = self.memory_range(address, 0)?.start;
+                let length = self.memory[start..]
+                    .iter()
+                    .position(|&byte| byte == 0)
+                    .ok_or(VMError::MemoryOutOfBounds)?;
+                let value = String::from_utf8_lossy(&self.memory[start..start + length]).into_owned();
+                self.write_output(&value);
+            }
+            Opcode::CONCAT => {
+                let (reg1, reg2, reg3) = self.get_three_registers()?;
+                let joined = format!(
+                    "{}{}",
+                    self.get_string(self.registers[reg1])?,
+                    self.get_string(self.registers[reg2])?
+                );
+                self.registers[reg3] = self.intern_string(&joined)?;
+            }
+            Opcode::EQ => self.compare(|a, b| a == b)?,
+            Opcode::NEQ => self.compare(|a, b| a != b)?,
+            Opcode::GT => self.compare(|a, b| a > b)?,
+            Opcode::LT => self.compare(|a, b| a < b)?,
+            Opcode::GTE => self.compare(|a, b| a >= b)?,
+            Opcode::LTE => self.compare(|a, b| a <= b)?,
+            Opcode::JEQ => {
+                let target = self.registers[self.next_register()?];
+                if self.equal_flag {
+                    self.pc = target as usize;
+                }
+            }
+            Opcode::JNEQ => {
+                let target = self.registers[self.next_register()?];
+                if !self.equal_flag {
+                    self.pc = target as usize;
+                }
+            }
+            Opcode::MOV => {
+                let dest = self.next_register()?;
+                let src = self.next_register()?;
+                self.registers[dest] = self.registers[src];
+            }
+            Opcode::ADDI => self.immediate_arithmetic(i32::checked_add)?,
+            Opcode::SUBI => self.immediate_arithmetic(i32::checked_sub)?,
+            Opcode::MULI => self.immediate_arithmetic(i32::checked_mul)?,
+            Opcode::FLOAD => {
+                let register = self.next_register()?;
+                self.float_registers[register] = f64::from_bits(self.next_64_bits()?);
+            }
+            // Float arithmetic follows IEEE 754, so dividing by zero gives an
+            // infinity or NaN rather than an error.
+            Opcode::FADD => self.float_arithmetic(|a, b| a + b)?,
+            Opcode::FSUB => self.float_arithmetic(|a, b| a - b)?,
+            Opcode::FMUL => self.float_arithmetic(|a, b| a * b)?,
+            Opcode::FDIV => self.float_arithmetic(|a, b| a / b)?,
+            Opcode::FMOV => {
+                let dest = self.next_register()?;
+                let src = self.next_register()?;
+                self.float_registers[dest] = self.float_registers[src];
+            }
+            Opcode::FPRINT => {
+                let value = self.float_registers[self.next_register()?];
+                self.write_output(&format!("PRINT: {}", value));
+            }
+            Opcode::FEQ => self.float_compare(|a, b| a == b)?,
+            Opcode::FNEQ => self.float_compare(|a, b| a != b)?,
+            Opcode::FGT => self.float_compare(|a, b| a > b)?,
+            Opcode::FLT => self.float_compare(|a, b| a < b)?,
+            Opcode::FGTE => self.float_compare(|a, b| a >= b)?,
+            Opcode::FLTE => self.float_compare(|a, b| a <= b)?,
+            Opcode::LOADM => self.execute_load(4)?,
+            Opcode::LOADB => self.execute_load(1)?,
+            Opcode::LOADH => self.execute_load(2)?,
+            Opcode::STOREM => self.execute_store(4)?,
+            Opcode::STOREB => self.execute_store(1)?,
+            Opcode::STOREH => self.execute_store(2)?,
+            Opcode::MIN => {
+                let (reg1, reg2, reg3) = self.get_three_registers()?;
+                self.registers[reg3] = self.registers[reg1].min(self.registers[reg2]);
+            }
+            Opcode::MAX => {
+                let (reg1, reg2, reg3) = self.get_three_registers()?;
+                self.registers[reg3] = self.registers[reg1].max(self.registers[reg2]);
+            }
+            Opcode::POPCNT => {
+                let dest = self.next_register()?;
+                let src = self.next_register()?;
+                self.registers[dest] = self.registers[src].count_ones() as i32;
+            }
+            Opcode::CLZ => {
+                let dest = self.next_register()?;
+                let src = self.next_register()?;
+                self.registers[dest] = self.registers[src].leading_zeros() as i32;
+            }
+            // |i32::MIN| doesn't fit, so it saturates to i32::MAX.
+            Opcode::ABS => {
+                let dest = self.next_register()?;
+                let src = self.next_register()?;
+                self.registers[dest] = self.registers[src].saturating_abs();
+            }
+            Opcode::CMOV => {
+                let dest = self.next_register()?;
+                let src = self.next_register()?;
+                if self.equal_flag {
+                    self.registers[dest] = self.registers[src];
+                }
+            }
+            // The low words carry into the high ones. Overflow past 64 bits
+            // gives zero, as in the 32-bit arithmetic.
+            Opcode::ADD64 | Opcode::SUB64 => {
+                let a = self.next_register_pair()?;
+                let b = self.next_register_pair()?;
+                let dest = self.next_register_pair()?;
+                let (a, b) = (self.pair_value(a), self.pair_value(b));
+                let result = if opcode == Opcode::ADD64 {
+                    a.checked_add(b)
+                } else {
+                    a.checked_sub(b)
+                };
+                let result = result.unwrap_or(0);
+                self.registers[dest] = (result >> 32) as i32;
+                self.registers[dest + 1] = result as i32;
+            }
+            Opcode::NOP => {}
+            Opcode::READ => {
+                let register = self.next_register()?;
+                self.registers[register] = self.read_input()?;
+            }
         }
 
+        if self.zero_register {
+            self.registers[0] = 0;
+        }
         Ok(true)
     }
 
+    fn write_output(&mut self, line: &str) {
+        match &mut self.output {
+            Some(output) => {
+                output.push_str(line);
+                output.push('\n');
+            }
+            None => println!("{}", line),
+        }
+    }
+
+    fn read_input(&mut self) -> Result<i32, VMError> {
+        let value = self.next_input()?;
+        if let Some(log) = &mut self.recording {
+            log.inputs.push(value);
+        }
+        Ok(value)
+    }
+
+    fn next_input(&mut self) -> Result<i32, VMError> {
+        match &mut self.input {
+            Some(input) => input.pop_front().ok_or(VMError::InputUnavailable),
+            None => {
+                let mut line = String::new();
+                std::io::stdin()
+                    .read_line(&mut line)
+                    .map_err(|_| VMError::InputUnavailable)?;
+                line.trim().parse().map_err(|_| VMError::InputUnavailable)
+            }
+        }
+    }
+
+    fn compare(&mut self, op: fn(i32, i32) -> bool) -> Result<(), VMError> {
+        let reg1 = self.next_register()?;
+        let reg2 = self.next_register()?;
+        self.equal_flag = op(self.registers[reg1], self.registers[reg2]);
+        Ok(())
+    }
+
+    // Updates a register in place, overflowing to zero like the
+    // three-register forms.
+    fn immediate_arithmetic(&mut self, op: fn(i32, i32) -> Option<i32>) -> Result<(), VMError> {
+        let register = self.next_register()?;
+        let value = self.next_16_bits()? as i16 as i32;
+        self.registers[register] = op(self.registers[register], value).unwrap_or(0);
+        Ok(())
+    }
+
+    // `LOADM rd ra` and its narrower forms read `width` bytes at the address
+    // in `ra` into `rd`. Memory is big-endian like the bytecode, and bytes
+    // and halfwords are zero-extended.
+    fn execute_load(&mut self, width: usize) -> Result<(), VMError> {
+        let dest = self.next_register()?;
+        let address = self.registers[self.next_register()?];
+        let range = self.memory_range(address, width)?;
+        let value = self.memory[range]
+            .iter()
+            .fold(0u32, |value, &byte| (value << 8) | byte as u32);
+        self.registers[dest] = value as i32;
+        Ok(())
+    }
+
+    // `STOREM ra rs` and its narrower forms write the low `width` bytes of
+    // `rs` to the address in `ra`.
+    fn execute_store(&mut self, width: usize) -> Result<(), VMError> {
+        let address = self.registers[self.next_register()?];
+        let value = self.registers[self.next_register()?];
+        let range = self.memory_range(address, width)?;
+        self.memory[range].copy_from_slice(&value.to_be_bytes()[4 - width..]);
+        Ok(())
+    }
+
+    fn memory_range(&self, address: i32, width: usize) -> Result<Range<usize>, VMError> {
+        let start = usize::try_from(address).map_err(|_| VMError::MemoryOutOfBounds)?;
+        match start.checked_add(width) {
+            Some(end) if end <= self.memory.len() => Ok(start..end),
+            _ => Err(VMError::MemoryOutOfBounds),
+        }
+    }
+
+    fn float_arithmetic(&mut self, op: fn(f64, f64) -> f64) -> Result<(), VMError> {
+        let (reg1, reg2, reg3) = self.get_three_registers()?;
+        self.float_registers[reg3] = op(self.float_registers[reg1], self.float_registers[reg2]);
+        Ok(())
+    }
+
+    fn float_compare(&mut self, op: fn(f64, f64) -> bool) -> Result<(), VMError> {
+        let reg1 = self.next_register()?;
+        let reg2 = self.next_register()?;
+        self.equal_flag = op(self.float_registers[reg1], self.float_registers[reg2]);
+        Ok(())
+    }
+
+    fn next_register(&mut self) -> Result<usize, VMError> {
+        let register = self.next_8_bits()? as usize;
+        if register >= self.registers.len() {
+            return Err(VMError::RegisterOutOfBounds);
+        }
+        Ok(register)
+    }
+
+    fn next_register_pair(&mut self) -> Result<usize, VMError> {
+        let register = self.next_8_bits()? as usize;
+        if register + 1 >= self.registers.len() {
+            return Err(VMError::RegisterOutOfBounds);
+        }
+        Ok(register)
+    }
+
+    fn pair_value(&self, register: usize) -> i64 {
+        let high = i64::from(self.registers[register]) << 32;
+        high | i64::from(self.registers[register + 1] as u32)
+    }
+
     fn get_three_registers(&mut self) -> Result<(usize, usize, usize), VMError> {
         let reg1 = self.next_8_bits()? as usize;
         let reg2 = self.next_8_bits()? as usize;
@@ -181,6 +971,16 @@ impl VM {
         self.pc += 2;
         Ok(result)
     }
+
+    fn next_64_bits(&mut self) -> Result<u64, VMError> {
+        let bytes = self
+            .program
+            .get(self.pc..self.pc + 8)
+            .ok_or(VMError::ProgramCounterOutOfBounds)?;
+        let result = u64::from_be_bytes(bytes.try_into().unwrap());
+        self.pc += 8;
+        Ok(result)
+    }
 }
 
 impl From<u8> for Opcode {
@@ -195,20 +995,619 @@ impl From<u8> for Opcode {
             6 => Opcode::JMP,
             7 => Opcode::JMPF,
             8 => Opcode::PRINT,
+            9 => Opcode::EQ,
+            10 => Opcode::NEQ,
+            11 => Opcode::GT,
+            12 => Opcode::LT,
+            13 => Opcode::GTE,
+            14 => Opcode::LTE,
+            15 => Opcode::JEQ,
+            16 => Opcode::JNEQ,
+            17 => Opcode::MOV,
+            18 => Opcode::NOP,
+            19 => Opcode::READ,
+            20 => Opcode::CONCAT,
+            21 => Opcode::PRINTS,
+            22 => Opcode::FLOAD,
+            23 => Opcode::FADD,
+            24 => Opcode::FSUB,
+            25 => Opcode::FMUL,
+            26 => Opcode::FDIV,
+            27 => Opcode::FMOV,
+            28 => Opcode::FPRINT,
+            29 => Opcode::FEQ,
+            30 => Opcode::FNEQ,
+            31 => Opcode::FGT,
+            32 => Opcode::FLT,
+            33 => Opcode::FGTE,
+            34 => Opcode::FLTE,
+            35 => Opcode::ADDI,
+            36 => Opcode::SUBI,
+            37 => Opcode::MULI,
+            38 => Opcode::CALL,
+            39 => Opcode::RET,
+            40 => Opcode::LOADM,
+            41 => Opcode::STOREM,
+            42 => Opcode::LOADB,
+            43 => Opcode::STOREB,
+            44 => Opcode::LOADH,
+            45 => Opcode::STOREH,
+            46 => Opcode::MIN,
+            47 => Opcode::MAX,
+            48 => Opcode::POPCNT,
+            49 => Opcode::CLZ,
+            50 => Opcode::ABS,
+            51 => Opcode::CMOV,
+            52 => Opcode::ADD64,
+            53 => Opcode::SUB64,
+            54 => Opcode::ADC,
+            55 => Opcode::SBB,
+            56 => Opcode::PRTS,
             _ => Opcode::IGL,
         }
     }
 }
 
+impl From<Opcode> for u8 {
+    fn from(op: Opcode) -> Self {
+        match op {
+            Opcode::HLT => 0,
+            Opcode::LOAD => 1,
+            Opcode::ADD => 2,
+            Opcode::SUB => 3,
+            Opcode::MUL => 4,
+            Opcode::DIV => 5,
+            Opcode::JMP => 6,
+            Opcode::JMPF => 7,
+            Opcode::PRINT => 8,
+            Opcode::EQ => 9,
+            Opcode::NEQ => 10,
+            Opcode::GT => 11,
+            Opcode::LT => 12,
+            Opcode::GTE => 13,
+            Opcode::LTE => 14,
+            Opcode::JEQ => 15,
+            Opcode::JNEQ => 16,
+            Opcode::MOV => 17,
+            Opcode::NOP => 18,
+            Opcode::READ => 19,
+            Opcode::CONCAT => 20,
+            Opcode::PRINTS => 21,
+            Opcode::FLOAD => 22,
+            Opcode::FADD => 23,
+            Opcode::FSUB => 24,
+            Opcode::FMUL => 25,
+            Opcode::FDIV => 26,
+            Opcode::FMOV => 27,
+            Opcode::FPRINT => 28,
+            Opcode::FEQ => 29,
+            Opcode::FNEQ => 30,
+            Opcode::FGT => 31,
+            Opcode::FLT => 32,
+            Opcode::FGTE => 33,
+            Opcode::FLTE => 34,
+            Opcode::ADDI => 35,
+            Opcode::SUBI => 36,
+            Opcode::MULI => 37,
+            Opcode::CALL => 38,
+            Opcode::RET => 39,
+            Opcode::LOADM => 40,
+            Opcode::STOREM => 41,
+            Opcode::LOADB => 42,
+            Opcode::STOREB => 43,
+            Opcode::LOADH => 44,
+            Opcode::STOREH => 45,
+            Opcode::MIN => 46,
+            Opcode::MAX => 47,
+            Opcode::POPCNT => 48,
+            Opcode::CLZ => 49,
+            Opcode::ABS => 50,
+            Opcode::CMOV => 51,
+            Opcode::ADD64 => 52,
+            Opcode::SUB64 => 53,
+            Opcode::ADC => 54,
+            Opcode::SBB => 55,
+            Opcode::PRTS => 56,
+            Opcode::IGL => 255,
+        }
+    }
+}
+
+impl Opcode {
+    /// The operands encoded after this opcode, in order.
+    pub fn operands(self) -> &'static [OperandKind] {
+        use OperandKind::*;
+        match self {
+            Opcode::HLT | Opcode::IGL | Opcode::NOP | Opcode::RET => &[],
+            Opcode::LOAD => &[Register, Immediate],
+            Opcode::ADDI | Opcode::SUBI | Opcode::MULI => &[Register, SignedImmediate],
+            Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::DIV
+            | Opcode::CONCAT
+            | Opcode::MIN
+            | Opcode::MAX
+            | Opcode::ADC
+            | Opcode::SBB => &[Register, Register, Register],
+            Opcode::JMP
+            | Opcode::JMPF
+            | Opcode::JEQ
+            | Opcode::JNEQ
+            | Opcode::PRINT
+            | Opcode::PRINTS
+            | Opcode::PRTS
+            | Opcode::READ
+            | Opcode::CALL => &[Register],
+            Opcode::EQ
+            | Opcode::NEQ
+            | Opcode::GT
+            | Opcode::LT
+            | Opcode::GTE
+            | Opcode::LTE
+            | Opcode::MOV
+            | Opcode::POPCNT
+            | Opcode::CLZ
+            | Opcode::ABS
+            | Opcode::CMOV
+            | Opcode::LOADM
+            | Opcode::STOREM
+            | Opcode::LOADB
+            | Opcode::STOREB
+            | Opcode::LOADH
+            | Opcode::STOREH => &[Register, Register],
+            Opcode::ADD64 | Opcode::SUB64 => &[RegisterPair, RegisterPair, RegisterPair],
+            Opcode::FLOAD => &[FloatRegister, Float],
+            Opcode::FADD | Opcode::FSUB | Opcode::FMUL | Opcode::FDIV => {
+                &[FloatRegister, FloatRegister, FloatRegister]
+            }
+            Opcode::FPRINT => &[FloatRegister],
+            Opcode::FEQ
+            | Opcode::FNEQ
+            | Opcode::FGT
+            | Opcode::FLT
+            | Opcode::FGTE
+            | Opcode::FLTE
+            | Opcode::FMOV => &[FloatRegister, FloatRegister],
+        }
+    }
+
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Opcode> {
+        match mnemonic.to_uppercase().as_str() {
+            "HLT" => Some(Opcode::HLT),
+            "LOAD" => Some(Opcode::LOAD),
+            "ADD" => Some(Opcode::ADD),
+            "SUB" => Some(Opcode::SUB),
+            "MUL" => Some(Opcode::MUL),
+            "DIV" => Some(Opcode::DIV),
+            "JMP" => Some(Opcode::JMP),
+            "JMPF" => Some(Opcode::JMPF),
+            "PRINT" => Some(Opcode::PRINT),
+            "EQ" => Some(Opcode::EQ),
+            "NEQ" => Some(Opcode::NEQ),
+            "GT" => Some(Opcode::GT),
+            "LT" => Some(Opcode::LT),
+            "GTE" => Some(Opcode::GTE),
+            "LTE" => Some(Opcode::LTE),
+            "JEQ" => Some(Opcode::JEQ),
+            "JNEQ" => Some(Opcode::JNEQ),
+            "MOV" => Some(Opcode::MOV),
+            "NOP" => Some(Opcode::NOP),
+            "READ" => Some(Opcode::READ),
+            "CONCAT" => Some(Opcode::CONCAT),
+            "PRINTS" => Some(Opcode::PRINTS),
+            "FLOAD" => Some(Opcode::FLOAD),
+            "FADD" => Some(Opcode::FADD),
+            "FSUB" => Some(Opcode::FSUB),
+            "FMUL" => Some(Opcode::FMUL),
+            "FDIV" => Some(Opcode::FDIV),
+            "FMOV" => Some(Opcode::FMOV),
+            "FPRINT" => Some(Opcode::FPRINT),
+            "FEQ" => Some(Opcode::FEQ),
+            "FNEQ" => Some(Opcode::FNEQ),
+            "FGT" => Some(Opcode::FGT),
+            "FLT" => Some(Opcode::FLT),
+            "FGTE" => Some(Opcode::FGTE),
+            "FLTE" => Some(Opcode::FLTE),
+            "ADDI" => Some(Opcode::ADDI),
+            "SUBI" => Some(Opcode::SUBI),
+            "MULI" => Some(Opcode::MULI),
+            "CALL" => Some(Opcode::CALL),
+            "RET" => Some(Opcode::RET),
+            "LOADM" => Some(Opcode::LOADM),
+            "STOREM" => Some(Opcode::STOREM),
+            "LOADB" => Some(Opcode::LOADB),
+            "STOREB" => Some(Opcode::STOREB),
+            "LOADH" => Some(Opcode::LOADH),
+            "STOREH" => Some(Opcode::STOREH),
+            "MIN" => Some(Opcode::MIN),
+            "MAX" => Some(Opcode::MAX),
+            "POPCNT" => Some(Opcode::POPCNT),
+            "CLZ" => Some(Opcode::CLZ),
+            "ABS" => Some(Opcode::ABS),
+            "CMOV" => Some(Opcode::CMOV),
+            "ADD64" => Some(Opcode::ADD64),
+            "SUB64" => Some(Opcode::SUB64),
+            "ADC" => Some(Opcode::ADC),
+            "SBB" => Some(Opcode::SBB),
+            "PRTS" => Some(Opcode::PRTS),
+            _ => None,
+        }
+    }
+
+    /// Encoded size of the instruction in bytes, including the opcode.
+    pub fn instruction_size(self) -> usize {
+        1 + self.operands().iter().map(|kind| kind.size()).sum::<usize>()
+    }
+
+    /// Whether the instruction can transfer control somewhere other than
+    /// the next instruction. Calls and returns count.
+    pub fn is_jump(self) -> bool {
+        matches!(
+            self,
+            Opcode::JMP | Opcode::JMPF | Opcode::JEQ | Opcode::JNEQ | Opcode::CALL | Opcode::RET
+        )
+    }
+}
+
 impl fmt::Display for VMError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             VMError::ProgramCounterOutOfBounds => write!(f, "Program counter out of bounds"),
             VMError::DivisionByZero => write!(f, "Division by zero"),
             VMError::RegisterOutOfBounds => write!(f, "Register index out of bounds"),
-            VMError::InvalidOpcode => write!(f, "Encountered invalid opcode"),
+            VMError::InvalidOpcode { pc, byte } => {
+                write!(f, "Encountered invalid opcode {:#04x} at offset {}", byte, pc)
+            }
+            VMError::InputUnavailable => write!(f, "No input available for READ"),
+            VMError::StringTableFull => write!(f, "String table is full"),
+            VMError::InvalidStringHandle => write!(f, "Invalid string handle"),
+            VMError::OpcodeDisabled(opcode) => write!(f, "Opcode {:?} is disabled", opcode),
+            VMError::StackUnderflow => write!(f, "RET with an empty call stack"),
+            VMError::CallStackOverflow => write!(f, "Call stack depth limit exceeded"),
+            VMError::StepLimitExceeded => write!(f, "Step limit exceeded"),
+            VMError::MemoryOutOfBounds => write!(f, "Memory access out of bounds"),
+            VMError::MissingVersion => write!(f, "Bytecode file has no version byte"),
+            VMError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported bytecode version {}", version)
+            }
         }
     }
 }
 
 impl std::error::Error for VMError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exec_raw_add() {
+        let mut vm = VM::new();
+        vm.exec_raw(&[1, 0, 0, 7]).unwrap();
+        vm.exec_raw(&[1, 1, 0, 5]).unwrap();
+
+        assert_eq!(vm.exec_raw(&[2, 0, 1, 2]), Ok(true));
+        assert_eq!(vm.get_register(2), Ok(12));
+    }
+
+    #[test]
+    fn test_read_from_injected_input() {
+        let mut vm = VM::new();
+        vm.set_input([42]);
+        vm.exec_raw(&[19, 3]).unwrap();
+        assert_eq!(vm.get_register(3), Ok(42));
+        assert_eq!(vm.exec_raw(&[19, 3]), Err(VMError::InputUnavailable));
+    }
+
+    #[test]
+    fn test_replay_reproduces_a_recorded_run() {
+        let program = crate::Assembler::new()
+            .compile("READ r0\nREAD r1\nMUL r0 r1 r2\nEQ r2 r1\nCMOV r3 r0\nHLT")
+            .unwrap();
+        let mut vm = VM::new();
+        vm.add_program(program);
+        vm.set_input([6, 7, 99]);
+        vm.start_recording();
+        vm.run().unwrap();
+        let log = vm.stop_recording().unwrap();
+        let registers = *vm.get_registers();
+
+        assert_eq!(log.inputs, [6, 7]);
+        assert_eq!(log.pcs, [0, 2, 4, 8, 11, 14]);
+        assert_eq!(registers[2], 42);
+
+        vm.replay(&log).unwrap();
+        assert_eq!(vm.get_registers(), &registers);
+        // The input not consumed by the recorded run is left for later.
+        assert_eq!(vm.exec_raw(&[19, 5]), Ok(true));
+        assert_eq!(vm.get_register(5), Ok(99));
+    }
+
+    #[cfg(feature = "test-coverage")]
+    #[test]
+    fn test_executed_opcodes_cover_the_program() {
+        let source = "LOAD r0 5\nLOAD r1 .end\nEQ r0 r0\nJEQ r1\nPRINT r0\n.end:\nHLT";
+        let mut vm = VM::new();
+        vm.capture_output();
+        vm.add_program(crate::Assembler::new().compile(source).unwrap());
+        vm.run().unwrap();
+
+        let expected = HashSet::from([Opcode::LOAD, Opcode::EQ, Opcode::JEQ, Opcode::HLT]);
+        assert_eq!(vm.executed_opcodes(), expected);
+        vm.reset();
+        assert_eq!(vm.executed_opcodes(), expected);
+    }
+
+    #[test]
+    fn test_concat_and_print_strings() {
+        let mut vm = VM::new();
+        vm.capture_output();
+        let hello = vm.intern_string("hello, ").unwrap();
+        let world = vm.intern_string("world").unwrap();
+        vm.exec_raw(&[1, 0, 0, hello as u8]).unwrap();
+        vm.exec_raw(&[1, 1, 0, world as u8]).unwrap();
+
+        vm.exec_raw(&[20, 0, 1, 2]).unwrap();
+        vm.exec_raw(&[21, 2]).unwrap();
+
+        assert_eq!(vm.take_output(), "hello, world\n");
+        assert_eq!(vm.get_string(vm.get_register(2).unwrap()), Ok("hello, world"));
+
+        vm.reset();
+        assert_eq!(vm.get_string(hello), Err(VMError::InvalidStringHandle));
+    }
+
+    #[test]
+    fn test_string_table_is_bounded() {
+        let mut vm = VM::new();
+        let big = "x".repeat(MAX_STRING_BYTES);
+        assert!(vm.intern_string(&big).is_ok());
+        assert_eq!(vm.intern_string("y"), Err(VMError::StringTableFull));
+    }
+
+    #[test]
+    fn test_disabled_opcodes_fail() {
+        let mut vm = VM::new();
+        vm.capture_output();
+        vm.disable_opcode(Opcode::PRINT);
+        vm.disable_opcode(Opcode::READ);
+        vm.set_input([1]);
+
+        assert_eq!(vm.exec_raw(&[8, 0]), Err(VMError::OpcodeDisabled(Opcode::PRINT)));
+        assert_eq!(vm.exec_raw(&[19, 0]), Err(VMError::OpcodeDisabled(Opcode::READ)));
+        assert_eq!(vm.exec_raw(&[1, 0, 0, 7]), Ok(true));
+        assert_eq!(vm.take_output(), "");
+
+        vm.enable_opcode(Opcode::PRINT);
+        vm.exec_raw(&[8, 0]).unwrap();
+        assert_eq!(vm.take_output(), "PRINT: 7\n");
+    }
+
+    #[test]
+    fn test_restart_runs_the_program_again() {
+        // r0 = 6; r1 = 7; r2 = r0 * r1; r0 = r0 + r2; PRINT r0
+        let program = vec![1, 0, 0, 6, 1, 1, 0, 7, 4, 0, 1, 2, 2, 0, 2, 0, 8, 0, 0];
+        let mut vm = VM::new();
+        vm.capture_output();
+        vm.add_program(program);
+        vm.run().unwrap();
+        let registers = *vm.get_registers();
+
+        vm.restart();
+        assert_eq!(vm.get_registers(), &[0; 32]);
+        vm.run().unwrap();
+        assert_eq!(vm.get_registers(), &registers);
+        assert_eq!(vm.take_output(), "PRINT: 48\nPRINT: 48\n");
+    }
+
+    #[test]
+    fn test_ret_without_call_underflows() {
+        let mut vm = VM::new();
+        assert_eq!(vm.exec_raw(&[u8::from(Opcode::RET)]), Err(VMError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_dump_registers_hex() {
+        let mut vm = VM::new();
+        vm.exec_raw(&[1, 0, 0x00, 0xff]).unwrap();
+        vm.exec_raw(&[1, 3, 0x12, 0x34]).unwrap();
+        vm.exec_raw(&[3, 1, 0, 5]).unwrap();
+
+        let mut dump = Vec::new();
+        vm.dump_registers_hex(&mut dump).unwrap();
+        assert_eq!(
+            String::from_utf8(dump).unwrap(),
+            "r0: 0x000000ff (255)\nr3: 0x00001234 (4660)\nr5: 0xffffff01 (-255)\n"
+        );
+    }
+
+    #[test]
+    fn test_state_report() {
+        let program = crate::Assembler::new()
+            .compile("LOAD r0 7\nLOAD r1 2\nDIV r0 r1 r2\nEQ r2 r2\nLOAD r4 .Lf\nCALL r4\n.Lf:\nHLT")
+            .unwrap();
+        let mut vm = VM::new();
+        vm.add_program(program);
+        vm.run().unwrap();
+        assert_eq!(
+            vm.state_report(),
+            "pc: 22\nr0: 0x00000007 (7)\nr1: 0x00000002 (2)\nr2: 0x00000003 (3)\nr4: 0x00000015 (21)\n\
+             equal: true, carry: false\nremainder: 1\ncall depth: 1\ninstructions: 7"
+        );
+
+        vm.restart();
+        assert_eq!(
+            vm.state_report(),
+            "pc: 0\nequal: false, carry: false\nremainder: 0\ncall depth: 0\ninstructions: 0"
+        );
+    }
+
+    #[test]
+    fn test_run_untrusted_survives_random_bytes() {
+        // xorshift64, so the inputs are the same on every run.
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for i in 0..5000 {
+            let len = (next() % 64) as usize;
+            let program: Vec<u8> = (0..len)
+                .map(|_| {
+                    let byte = next() as u8;
+                    // Half the inputs keep bytes small so they mostly decode
+                    // as real opcodes and in-range registers.
+                    if i % 2 == 0 { byte % 48 } else { byte }
+                })
+                .collect();
+            let _ = run_untrusted(&program);
+        }
+    }
+
+    #[test]
+    fn test_run_untrusted_limits_steps() {
+        // LOAD r0 0; JMP r0
+        let spin = [1, 0, 0, 0, 6, 0];
+        assert_eq!(run_untrusted(&spin), Err(VMError::StepLimitExceeded));
+        assert_eq!(run_untrusted(&[1, 0, 0, 7, 0]), Ok(()));
+    }
+
+    #[test]
+    fn test_division_overflow_is_zero() {
+        let mut vm = VM::new();
+        vm.registers[0] = i32::MIN;
+        vm.registers[1] = -1;
+        vm.exec_raw(&[5, 0, 1, 2]).unwrap();
+        assert_eq!(vm.get_register(2), Ok(0));
+    }
+
+    #[test]
+    fn test_min_and_max_are_signed() {
+        let mut vm = VM::new();
+        let cases = [
+            (-1, 1, -1, 1),
+            (i32::MIN, i32::MAX, i32::MIN, i32::MAX),
+            (i32::MIN, -1, i32::MIN, -1),
+            (7, 7, 7, 7),
+        ];
+        for (a, b, min, max) in cases {
+            vm.registers[0] = a;
+            vm.registers[1] = b;
+            vm.exec_raw(&[u8::from(Opcode::MIN), 0, 1, 2]).unwrap();
+            vm.exec_raw(&[u8::from(Opcode::MAX), 1, 0, 3]).unwrap();
+            assert_eq!((vm.get_register(2), vm.get_register(3)), (Ok(min), Ok(max)), "{} {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_popcnt_and_clz_bit_patterns() {
+        let mut vm = VM::new();
+        let cases = [
+            (0, 0, 32),
+            (1, 1, 31),
+            (0b1011_0000, 3, 24),
+            (0x7FFF_FFFF, 31, 1),
+            (-1, 32, 0),
+            (i32::MIN, 1, 0),
+        ];
+        for (value, ones, zeros) in cases {
+            vm.registers[0] = value;
+            vm.exec_raw(&[u8::from(Opcode::POPCNT), 1, 0]).unwrap();
+            vm.exec_raw(&[u8::from(Opcode::CLZ), 2, 0]).unwrap();
+            assert_eq!((vm.get_register(1), vm.get_register(2)), (Ok(ones), Ok(zeros)), "{:#x}", value);
+        }
+    }
+
+    #[test]
+    fn test_abs_saturates_at_i32_min() {
+        let mut vm = VM::new();
+        for (value, abs) in [(0, 0), (-5, 5), (5, 5), (-i32::MAX, i32::MAX), (i32::MIN, i32::MAX)] {
+            vm.registers[0] = value;
+            vm.exec_raw(&[u8::from(Opcode::ABS), 1, 0]).unwrap();
+            assert_eq!(vm.get_register(1), Ok(abs), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_zero_register_discards_writes() {
+        let mut vm = VM::new();
+        vm.registers[0] = 9;
+        vm.set_zero_register(true);
+        assert_eq!(vm.get_register(0), Ok(0));
+
+        // LOAD r0 7; LOAD r1 5; ADD r1 r1 r0; ADD r0 r1 r2; HLT
+        vm.add_program(vec![1, 0, 0, 7, 1, 1, 0, 5, 2, 1, 1, 0, 2, 0, 1, 2, 0]);
+        vm.run().unwrap();
+        assert_eq!(vm.get_register(0), Ok(0));
+        assert_eq!(vm.get_register(2), Ok(5));
+
+        vm.set_zero_register(false);
+        vm.restart();
+        vm.run().unwrap();
+        assert_eq!(vm.get_register(0), Ok(10));
+        assert_eq!(vm.get_register(2), Ok(15));
+    }
+
+    #[test]
+    fn test_lenient_mode_halts_on_invalid_opcode() {
+        let program = vec![1, 0, 0, 5, 200];
+
+        let mut strict = VM::new();
+        strict.add_program(program.clone());
+        let err = strict.run().unwrap_err();
+        assert_eq!(err, VMError::InvalidOpcode { pc: 4, byte: 200 });
+        assert_eq!(err.to_string(), "Encountered invalid opcode 0xc8 at offset 4");
+
+        let mut lenient = VM::new();
+        lenient.set_lenient(true);
+        lenient.add_program(program);
+        assert_eq!(lenient.run(), Ok(()));
+        assert_eq!(lenient.get_register(0), Ok(5));
+    }
+
+    #[test]
+    fn test_migrate_a_v1_program() {
+        // LOAD r0 6; FLOAD f0 1.5; ADDI r0 -2; PRINT r0; HLT
+        let mut v1 = vec![1, 0, 0, 6, 22, 0];
+        v1.extend_from_slice(&1.5f64.to_be_bytes());
+        v1.extend_from_slice(&[35, 0, 0xff, 0xfe, 8, 0, 0]);
+        let file = [&[1], v1.as_slice()].concat();
+
+        let program = migrate(1, &v1).unwrap();
+        assert_eq!(program, v1);
+        assert_eq!(decode_file(&file), Ok(program.clone()));
+        assert_eq!(decode_file(&encode_file(&program)), Ok(program.clone()));
+
+        let mut vm = VM::new();
+        vm.capture_output();
+        vm.add_program(program);
+        vm.run().unwrap();
+        assert_eq!(vm.take_output(), "PRINT: 4\n");
+
+        // The same program in a numbering one above the current one. Only
+        // the opcodes move; the operands, including bytes that look like
+        // opcodes, pass through.
+        let mut shifted = v1.clone();
+        for pc in [0, 4, 14, 18, 20] {
+            shifted[pc] += 1;
+        }
+        let older = |byte: u8| opcode_in(1, byte.checked_sub(1)?);
+        assert_eq!(remap(&shifted, older), Ok(v1.clone()));
+        assert_eq!(remap(&[0], older), Err(VMError::InvalidOpcode { pc: 0, byte: 0 }));
+
+        assert_eq!(migrate(1, &[1, 0, 0]), Err(VMError::ProgramCounterOutOfBounds));
+        assert_eq!(
+            migrate(1, &[0, 200]),
+            Err(VMError::InvalidOpcode { pc: 1, byte: 200 })
+        );
+        assert_eq!(
+            decode_file(&[BYTECODE_VERSION + 1, 0]),
+            Err(VMError::UnsupportedVersion(BYTECODE_VERSION + 1))
+        );
+        assert_eq!(decode_file(&[]), Err(VMError::MissingVersion));
+    }
+}